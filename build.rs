@@ -0,0 +1,21 @@
+// Generates the gRPC service in `proto/` for the `grpc` feature, and the stored
+// commit messages for the `protobuf` feature, with a vendored `protoc` so
+// building doesn't need one installed. Only the server is generated.
+fn main() {
+  #[cfg(any(feature = "grpc", feature = "protobuf"))]
+  {
+    let protoc = protoc_bin_vendored::protoc_bin_path().unwrap();
+    std::env::set_var("PROTOC", protoc);
+  }
+  #[cfg(feature = "grpc")]
+  {
+    tonic_build::configure()
+      .build_client(false)
+      .compile(&["proto/event_source.proto"], &["proto"])
+      .unwrap();
+  }
+  #[cfg(feature = "protobuf")]
+  {
+    prost_build::compile_protos(&["proto/commit.proto"], &["proto"]).unwrap();
+  }
+}
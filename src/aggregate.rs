@@ -1,11 +1,79 @@
 use super::events::Event;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::default::Default;
+use std::error;
+use std::fmt;
 use uuid::Uuid;
 
-pub trait Aggregate: Default + Clone + Sized {
+pub trait Aggregate: Default + Clone + Sized + Serialize + DeserializeOwned {
   type Event: Event;
+  // Bump when the serialized state changes shape; snapshots written with an
+  // older version are ignored and the aggregate is rebuilt from its commits.
+  const SNAPSHOT_SCHEMA_VERSION: i64 = 0;
+  // Groups aggregates of one entity type, such as `order`, so their commits can
+  // be read together. Stored on every commit; empty means uncategorized.
+  const CATEGORY: &'static str = "";
   fn with_id(id: Uuid) -> Self;
   fn apply(&self, event: &Self::Event) -> Self;
+  // What replaying commits uses. The default clones the aggregate through
+  // `apply` for every event; aggregates that are costly to clone should
+  // override it to update themselves in place.
+  fn apply_mut(&mut self, event: &Self::Event) {
+    *self = self.apply(event);
+  }
+  // Like `apply_mut`, for aggregates that can refuse an event, such as one
+  // their state says cannot have happened. A failure stops the replay and is
+  // reported with the commit holding the event, rather than panicking.
+  fn try_apply_mut(&mut self, event: &Self::Event) -> Result<(), ApplyError> {
+    self.apply_mut(event);
+    Ok(())
+  }
+  // Whether the events applied so far ended the aggregate's stream. Commits to
+  // a deleted aggregate are refused, so whatever event deletes it is its last.
+  fn is_deleted(&self) -> bool {
+    false
+  }
   fn version(&self) -> i64;
   fn id(&self) -> Uuid;
 }
+
+// Where an aggregate is in its life, as `Client::stream_state` reports it. An
+// aggregate is created by its first commit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamState {
+  NotCreated,
+  Live,
+  Deleted,
+}
+
+impl fmt::Display for StreamState {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      StreamState::NotCreated => write!(f, "not created"),
+      StreamState::Live => write!(f, "live"),
+      StreamState::Deleted => write!(f, "deleted"),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct ApplyError {
+  pub reason: String,
+}
+
+impl ApplyError {
+  pub fn new<R: Into<String>>(reason: R) -> ApplyError {
+    ApplyError {
+      reason: reason.into(),
+    }
+  }
+}
+
+impl fmt::Display for ApplyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.reason)
+  }
+}
+
+impl error::Error for ApplyError {}
@@ -0,0 +1,186 @@
+use super::SchemaRegistry;
+use crate::codec::CodecError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::TryFutureExt;
+use hyper::body::to_bytes;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnectorBuilder;
+use serde_json::Value;
+use std::thread;
+use tokio::runtime::Builder;
+
+const CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+
+fn error(message: &str) -> CodecError {
+  CodecError::EncodingError(format!("schema registry: {}", message))
+}
+
+// A registry speaking Confluent Schema Registry's REST API, as Confluent's,
+// Redpanda's and Apicurio's registries do. Requests block, each on a thread of
+// its own so they can be made from async code too; `avro` caches what they
+// return, so there's one per schema.
+pub struct ConfluentSchemaRegistry {
+  base_url: String,
+  authorization: Option<String>,
+}
+
+impl ConfluentSchemaRegistry {
+  pub fn new(base_url: &str) -> ConfluentSchemaRegistry {
+    ConfluentSchemaRegistry {
+      base_url: String::from(base_url.trim_end_matches('/')),
+      authorization: None,
+    }
+  }
+
+  // For registries behind HTTP basic auth, such as Confluent Cloud's API keys.
+  pub fn with_basic_auth(mut self, username: &str, password: &str) -> ConfluentSchemaRegistry {
+    let credentials = STANDARD.encode(format!("{}:{}", username, password));
+    self.authorization = Some(format!("Basic {}", credentials));
+    self
+  }
+
+  fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value, CodecError> {
+    let mut request = Request::builder()
+      .method(method)
+      .uri(format!("{}{}", self.base_url, path))
+      .header("accept", CONTENT_TYPE);
+    if let Some(ref authorization) = self.authorization {
+      request = request.header("authorization", authorization.as_str());
+    }
+    let request = match body {
+      Some(body) => request
+        .header("content-type", CONTENT_TYPE)
+        .body(Body::from(body.to_string())),
+      None => request.body(Body::empty()),
+    }
+    .map_err(|err| error(&err.to_string()))?;
+    thread::spawn(move || {
+      let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| error(&err.to_string()))?;
+      let connector = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+      let client: Client<_, Body> = Client::builder().build(connector);
+      let (status, body) = runtime
+        .block_on(client.request(request).and_then(|response| {
+          let status = response.status();
+          to_bytes(response.into_body()).map_ok(move |body| (status, body))
+        }))
+        .map_err(|err| error(&err.to_string()))?;
+      if !status.is_success() {
+        let body = String::from_utf8_lossy(&body);
+        return Err(error(&format!("responded {}: {}", status, body)));
+      }
+      Ok(serde_json::from_slice(&body)?)
+    })
+    .join()
+    .unwrap_or_else(|_| Err(error("request thread panicked")))
+  }
+}
+
+impl SchemaRegistry for ConfluentSchemaRegistry {
+  fn register(&self, subject: &str, schema: &str) -> Result<u32, CodecError> {
+    let response = self.send(
+      Method::POST,
+      &format!("/subjects/{}/versions", subject),
+      Some(serde_json::json!({ "schema": schema })),
+    )?;
+    response["id"]
+      .as_u64()
+      .map(|id| id as u32)
+      .ok_or_else(|| error("no id in its response"))
+  }
+
+  fn schema(&self, id: u32) -> Result<(String, String), CodecError> {
+    let schema = self.send(Method::GET, &format!("/schemas/ids/{}", id), None)?;
+    let versions = self.send(Method::GET, &format!("/schemas/ids/{}/versions", id), None)?;
+    match (schema["schema"].as_str(), versions[0]["subject"].as_str()) {
+      (Some(schema), Some(subject)) => Ok((String::from(subject), String::from(schema))),
+      _ => Err(error(&format!("no subject or schema for id {}", id))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+
+  // Answers each connection with the next of `responses`, returning the
+  // request lines, authorization headers and bodies it received.
+  fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+      let mut received = Vec::new();
+      for response in responses {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+          let mut line = String::new();
+          reader.read_line(&mut line).unwrap();
+          let line = line.trim_end();
+          if line.is_empty() {
+            break;
+          }
+          let (name, value) = line.split_once(": ").unwrap_or((line, ""));
+          match name.to_lowercase().as_str() {
+            "content-length" => content_length = value.parse().unwrap(),
+            "authorization" => received.push(String::from(value)),
+            "" | "host" | "accept" | "content-type" => (),
+            _ => received.push(String::from(line)),
+          }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        if !body.is_empty() {
+          received.push(String::from_utf8(body).unwrap());
+        }
+        write!(
+          reader.get_mut(),
+          "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+          response.len(),
+          response
+        )
+        .unwrap();
+      }
+      received
+    });
+    (url, handle)
+  }
+
+  #[test]
+  fn it_registers_and_fetches_schemas() {
+    let (url, server) = serve(vec![
+      r#"{"id": 7}"#,
+      r#"{"schema": "\"string\""}"#,
+      r#"[{"subject": "order-events", "version": 1}]"#,
+    ]);
+    let registry = ConfluentSchemaRegistry::new(&url).with_basic_auth("key", "secret");
+
+    assert_eq!(registry.register("order-events", "\"string\"").unwrap(), 7);
+    assert_eq!(
+      registry.schema(7).unwrap(),
+      (String::from("order-events"), String::from("\"string\""))
+    );
+    assert_eq!(
+      server.join().unwrap(),
+      vec![
+        "POST /subjects/order-events/versions HTTP/1.1",
+        "Basic a2V5OnNlY3JldA==",
+        r#"{"schema":"\"string\""}"#,
+        "GET /schemas/ids/7 HTTP/1.1",
+        "Basic a2V5OnNlY3JldA==",
+        "GET /schemas/ids/7/versions HTTP/1.1",
+        "Basic a2V5OnNlY3JldA==",
+      ]
+    );
+  }
+}
@@ -0,0 +1,870 @@
+use crate::codec::CodecError;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "schema-registry")]
+pub mod confluent;
+pub mod registry;
+
+pub use self::registry::{InMemorySchemaRegistry, SchemaRegistry};
+
+// Registry framed payloads start with this byte and the writer schema's id as
+// four big-endian bytes, as Confluent's serializers frame them.
+const MAGIC: u8 = 0;
+
+fn error(message: &str) -> CodecError {
+  CodecError::EncodingError(format!("avro: {}", message))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schema {
+  Null,
+  Boolean,
+  Int,
+  Long,
+  Float,
+  Double,
+  Bytes,
+  String,
+  Array(Box<Schema>),
+  Map(Box<Schema>),
+  Union(Vec<Schema>),
+  Record {
+    name: String,
+    fields: Vec<Field>,
+  },
+  Enum {
+    name: String,
+    symbols: Vec<String>,
+    default: Option<String>,
+  },
+  Fixed {
+    name: String,
+    size: usize,
+  },
+  // A reference, by full name, to a type defined earlier in the schema.
+  Named(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+  pub name: String,
+  pub schema: Schema,
+  pub default: Option<Value>,
+}
+
+impl Schema {
+  fn type_name(&self) -> &str {
+    match *self {
+      Schema::Null => "null",
+      Schema::Boolean => "boolean",
+      Schema::Int => "int",
+      Schema::Long => "long",
+      Schema::Float => "float",
+      Schema::Double => "double",
+      Schema::Bytes => "bytes",
+      Schema::String => "string",
+      Schema::Array(_) => "array",
+      Schema::Map(_) => "map",
+      Schema::Union(_) => "union",
+      Schema::Record { ref name, .. }
+      | Schema::Enum { ref name, .. }
+      | Schema::Fixed { ref name, .. }
+      | Schema::Named(ref name) => name,
+    }
+  }
+}
+
+fn primitive(name: &str) -> Option<Schema> {
+  Some(match name {
+    "null" => Schema::Null,
+    "boolean" => Schema::Boolean,
+    "int" => Schema::Int,
+    "long" => Schema::Long,
+    "float" => Schema::Float,
+    "double" => Schema::Double,
+    "bytes" => Schema::Bytes,
+    "string" => Schema::String,
+    _ => return None,
+  })
+}
+
+fn qualify(name: &str, namespace: &str) -> String {
+  if name.contains('.') || namespace.is_empty() {
+    String::from(name)
+  } else {
+    format!("{}.{}", namespace, name)
+  }
+}
+
+// Records match by their name without its namespace, so types can move
+// between namespaces as the application does.
+fn short_name(name: &str) -> &str {
+  name.rsplit('.').next().unwrap_or(name)
+}
+
+fn attribute<'a>(object: &'a Map<String, Value>, name: &str) -> Result<&'a Value, CodecError> {
+  object
+    .get(name)
+    .ok_or_else(|| error(&format!("schema has no {}", name)))
+}
+
+struct Parser {
+  names: HashMap<String, Schema>,
+}
+
+impl Parser {
+  fn parse(&mut self, json: &Value, namespace: &str) -> Result<Schema, CodecError> {
+    match *json {
+      Value::String(ref name) => self.reference(name, namespace),
+      Value::Array(ref branches) => branches
+        .iter()
+        .map(|branch| self.parse(branch, namespace))
+        .collect::<Result<_, _>>()
+        .map(Schema::Union),
+      Value::Object(ref object) => self.parse_object(object, namespace),
+      _ => Err(error(&format!("{} is not a schema", json))),
+    }
+  }
+
+  fn reference(&self, name: &str, namespace: &str) -> Result<Schema, CodecError> {
+    if let Some(schema) = primitive(name) {
+      return Ok(schema);
+    }
+    [qualify(name, namespace), String::from(name)]
+      .iter()
+      .find(|full_name| self.names.contains_key(full_name.as_str()))
+      .map(|full_name| Schema::Named(full_name.clone()))
+      .ok_or_else(|| error(&format!("unknown type {}", name)))
+  }
+
+  fn parse_object(
+    &mut self,
+    object: &Map<String, Value>,
+    namespace: &str,
+  ) -> Result<Schema, CodecError> {
+    let type_name = match *attribute(object, "type")? {
+      Value::String(ref type_name) => type_name.as_str(),
+      ref schema => return self.parse(schema, namespace),
+    };
+    match type_name {
+      "array" => Ok(Schema::Array(Box::new(
+        self.parse(attribute(object, "items")?, namespace)?,
+      ))),
+      "map" => Ok(Schema::Map(Box::new(
+        self.parse(attribute(object, "values")?, namespace)?,
+      ))),
+      "record" | "error" | "enum" | "fixed" => self.parse_named(type_name, object, namespace),
+      _ => self.reference(type_name, namespace),
+    }
+  }
+
+  fn parse_named(
+    &mut self,
+    type_name: &str,
+    object: &Map<String, Value>,
+    namespace: &str,
+  ) -> Result<Schema, CodecError> {
+    let name = attribute(object, "name")?
+      .as_str()
+      .ok_or_else(|| error("name is not a string"))?;
+    let namespace = object
+      .get("namespace")
+      .and_then(Value::as_str)
+      .unwrap_or(namespace);
+    let name = qualify(name, namespace);
+    let namespace = name.rfind('.').map_or("", |dot| &name[..dot]);
+    // Defined before its fields are parsed, so records can refer to themselves.
+    self.names.insert(name.clone(), Schema::Null);
+    let schema = match type_name {
+      "enum" => Schema::Enum {
+        name: name.clone(),
+        symbols: attribute(object, "symbols")?
+          .as_array()
+          .and_then(|symbols| symbols.iter().map(|s| s.as_str().map(String::from)).collect())
+          .ok_or_else(|| error("symbols are not strings"))?,
+        default: object.get("default").and_then(Value::as_str).map(String::from),
+      },
+      "fixed" => Schema::Fixed {
+        name: name.clone(),
+        size: attribute(object, "size")?
+          .as_u64()
+          .ok_or_else(|| error("size is not a number"))? as usize,
+      },
+      _ => Schema::Record {
+        name: name.clone(),
+        fields: attribute(object, "fields")?
+          .as_array()
+          .ok_or_else(|| error("fields are not an array"))?
+          .iter()
+          .map(|field| self.parse_field(field, namespace))
+          .collect::<Result<_, _>>()?,
+      },
+    };
+    self.names.insert(name.clone(), schema.clone());
+    Ok(schema)
+  }
+
+  fn parse_field(&mut self, field: &Value, namespace: &str) -> Result<Field, CodecError> {
+    let object = field
+      .as_object()
+      .ok_or_else(|| error("field is not an object"))?;
+    Ok(Field {
+      name: attribute(object, "name")?
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| error("field name is not a string"))?,
+      schema: self.parse(attribute(object, "type")?, namespace)?,
+      default: object.get("default").cloned(),
+    })
+  }
+}
+
+// A schema parsed from its JSON definition, with the named types it defines.
+#[derive(Clone, Debug)]
+pub struct ParsedSchema {
+  root: Schema,
+  names: HashMap<String, Schema>,
+}
+
+impl ParsedSchema {
+  pub fn parse(schema: &str) -> Result<ParsedSchema, CodecError> {
+    let json: Value = serde_json::from_str(schema)?;
+    let mut parser = Parser {
+      names: HashMap::new(),
+    };
+    let root = parser.parse(&json, "")?;
+    Ok(ParsedSchema {
+      root,
+      names: parser.names,
+    })
+  }
+
+  pub fn root(&self) -> &Schema {
+    &self.root
+  }
+
+  fn resolve<'a>(&'a self, schema: &'a Schema) -> Result<&'a Schema, CodecError> {
+    match *schema {
+      Schema::Named(ref name) => self
+        .names
+        .get(name)
+        .ok_or_else(|| error(&format!("unknown type {}", name))),
+      _ => Ok(schema),
+    }
+  }
+
+  // `value`, in its serde JSON form, in Avro's binary encoding.
+  pub fn write(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = Vec::new();
+    self.write_value(&self.root, value, &mut bytes)?;
+    Ok(bytes)
+  }
+
+  // Reads a datum written with this schema as `reader`, following Avro's schema
+  // resolution: fields are matched by name, fields the reader doesn't have are
+  // skipped, and fields the writer didn't have take the reader's default.
+  pub fn read(&self, bytes: &[u8], reader: &ParsedSchema) -> Result<Value, CodecError> {
+    Resolver {
+      writer: self,
+      reader,
+      bytes,
+    }
+    .read(&self.root, &reader.root)
+  }
+
+  fn write_value(
+    &self,
+    schema: &Schema,
+    value: &Value,
+    bytes: &mut Vec<u8>,
+  ) -> Result<(), CodecError> {
+    let schema = self.resolve(schema)?;
+    let mismatch = || error(&format!("{} is not a {}", value, schema.type_name()));
+    match (schema, value) {
+      (Schema::Null, Value::Null) => (),
+      (Schema::Boolean, Value::Bool(boolean)) => bytes.push(u8::from(*boolean)),
+      (Schema::Int, Value::Number(number)) => {
+        let int = number.as_i64().filter(|&n| i32::try_from(n).is_ok());
+        write_long(bytes, int.ok_or_else(mismatch)?)
+      }
+      (Schema::Long, Value::Number(number)) => {
+        write_long(bytes, number.as_i64().ok_or_else(mismatch)?)
+      }
+      (Schema::Float, Value::Number(number)) => {
+        let float = number.as_f64().ok_or_else(mismatch)? as f32;
+        bytes.extend_from_slice(&float.to_le_bytes())
+      }
+      (Schema::Double, Value::Number(number)) => {
+        bytes.extend_from_slice(&number.as_f64().ok_or_else(mismatch)?.to_le_bytes())
+      }
+      (Schema::String, Value::String(string)) => write_bytes(bytes, string.as_bytes()),
+      (Schema::Bytes, _) => write_bytes(bytes, &bytes_of(value).ok_or_else(mismatch)?),
+      (Schema::Fixed { size, .. }, _) => {
+        let fixed = bytes_of(value).filter(|fixed| fixed.len() == *size);
+        bytes.extend(fixed.ok_or_else(mismatch)?)
+      }
+      (Schema::Enum { symbols, .. }, Value::String(symbol)) => {
+        let index = symbols.iter().position(|s| s == symbol);
+        write_long(bytes, index.ok_or_else(mismatch)? as i64)
+      }
+      (Schema::Array(items), Value::Array(values)) => {
+        if !values.is_empty() {
+          write_long(bytes, values.len() as i64);
+          for value in values {
+            self.write_value(items, value, bytes)?;
+          }
+        }
+        write_long(bytes, 0)
+      }
+      (Schema::Map(values), Value::Object(entries)) => {
+        if !entries.is_empty() {
+          write_long(bytes, entries.len() as i64);
+          for (key, value) in entries {
+            write_bytes(bytes, key.as_bytes());
+            self.write_value(values, value, bytes)?;
+          }
+        }
+        write_long(bytes, 0)
+      }
+      (Schema::Union(branches), _) => {
+        let (index, value) = self.union_branch(branches, value)?;
+        write_long(bytes, index as i64);
+        self.write_value(&branches[index], value, bytes)?
+      }
+      (Schema::Record { fields, .. }, Value::Object(object)) => {
+        for field in fields {
+          // Fields serde leaves out, such as empty event metadata, take their default.
+          let value = object
+            .get(&field.name)
+            .or(field.default.as_ref())
+            .ok_or_else(|| error(&format!("no value for field {}", field.name)))?;
+          self.write_value(&field.schema, value, bytes)?;
+        }
+      }
+      _ => return Err(mismatch()),
+    }
+    Ok(())
+  }
+
+  // The union branch to write `value` as. An object with a single key naming a
+  // record in the union, as serde writes enum variants, selects that record;
+  // anything else takes the first branch it fits.
+  fn union_branch<'v>(
+    &self,
+    branches: &[Schema],
+    value: &'v Value,
+  ) -> Result<(usize, &'v Value), CodecError> {
+    if let Value::Object(ref object) = *value {
+      if let (1, Some((tag, tagged))) = (object.len(), object.iter().next()) {
+        for (index, branch) in branches.iter().enumerate() {
+          if let Schema::Record { ref name, .. } = *self.resolve(branch)? {
+            if short_name(name) == tag {
+              return Ok((index, tagged));
+            }
+          }
+        }
+      }
+    }
+    for (index, branch) in branches.iter().enumerate() {
+      if fits(self.resolve(branch)?, value) {
+        return Ok((index, value));
+      }
+    }
+    Err(error(&format!("{} fits no branch of the union", value)))
+  }
+}
+
+fn fits(schema: &Schema, value: &Value) -> bool {
+  match (schema, value) {
+    (Schema::Null, Value::Null) | (Schema::Boolean, Value::Bool(_)) => true,
+    (Schema::Int, Value::Number(number)) => number
+      .as_i64()
+      .is_some_and(|n| i32::try_from(n).is_ok()),
+    (Schema::Long, Value::Number(number)) => number.as_i64().is_some(),
+    (Schema::Float, Value::Number(_)) | (Schema::Double, Value::Number(_)) => true,
+    (Schema::String, Value::String(_)) => true,
+    (Schema::Bytes, _) => bytes_of(value).is_some(),
+    (Schema::Fixed { size, .. }, _) => bytes_of(value).is_some_and(|fixed| fixed.len() == *size),
+    (Schema::Enum { symbols, .. }, Value::String(symbol)) => symbols.contains(symbol),
+    (Schema::Array(_), Value::Array(_)) => true,
+    (Schema::Map(_), Value::Object(_)) | (Schema::Record { .. }, Value::Object(_)) => true,
+    _ => false,
+  }
+}
+
+// Whether data written as `writer` can be read as `reader`.
+fn readable(writer: &Schema, reader: &Schema) -> bool {
+  match (writer, reader) {
+    (Schema::Int, Schema::Long)
+    | (Schema::Int, Schema::Float)
+    | (Schema::Int, Schema::Double)
+    | (Schema::Long, Schema::Float)
+    | (Schema::Long, Schema::Double)
+    | (Schema::Float, Schema::Double)
+    | (Schema::String, Schema::Bytes)
+    | (Schema::Bytes, Schema::String) => true,
+    (Schema::Record { name: written, .. }, Schema::Record { name, .. })
+    | (Schema::Enum { name: written, .. }, Schema::Enum { name, .. }) => {
+      short_name(written) == short_name(name)
+    }
+    (Schema::Fixed { name: written, size: written_size }, Schema::Fixed { name, size }) => {
+      short_name(written) == short_name(name) && written_size == size
+    }
+    _ => mem::discriminant(writer) == mem::discriminant(reader),
+  }
+}
+
+fn write_long(bytes: &mut Vec<u8>, value: i64) {
+  let mut value = ((value << 1) ^ (value >> 63)) as u64;
+  while value >= 0x80 {
+    bytes.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  bytes.push(value as u8);
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+  write_long(bytes, value.len() as i64);
+  bytes.extend_from_slice(value);
+}
+
+// Bytes as serde writes them, an array of numbers, or as a string.
+fn bytes_of(value: &Value) -> Option<Vec<u8>> {
+  match *value {
+    Value::String(ref string) => Some(string.as_bytes().to_vec()),
+    Value::Array(ref values) => values
+      .iter()
+      .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+      .collect(),
+    _ => None,
+  }
+}
+
+fn bytes_value(bytes: &[u8]) -> Value {
+  Value::Array(bytes.iter().map(|&byte| Value::from(byte)).collect())
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, CodecError> {
+  String::from_utf8(bytes.to_vec()).map_err(|err| error(&err.to_string()))
+}
+
+struct Resolver<'a> {
+  writer: &'a ParsedSchema,
+  reader: &'a ParsedSchema,
+  bytes: &'a [u8],
+}
+
+impl<'a> Resolver<'a> {
+  fn read(&mut self, writer: &'a Schema, reader: &'a Schema) -> Result<Value, CodecError> {
+    let writer = self.writer.resolve(writer)?;
+    let reader = self.reader.resolve(reader)?;
+    let unreadable = || {
+      error(&format!(
+        "{} can't be read as {}",
+        writer.type_name(),
+        reader.type_name()
+      ))
+    };
+    match (writer, reader) {
+      (Schema::Union(branches), _) => {
+        let index = self.read_long()?;
+        let branch = usize::try_from(index)
+          .ok()
+          .and_then(|index| branches.get(index))
+          .ok_or_else(|| error(&format!("no union branch {}", index)))?;
+        self.read(branch, reader)
+      }
+      (_, Schema::Union(branches)) => self.read_into_union(writer, branches),
+      (Schema::Null, Schema::Null) => Ok(Value::Null),
+      (Schema::Boolean, Schema::Boolean) => Ok(Value::Bool(self.take(1)?[0] != 0)),
+      (Schema::Int, Schema::Int) | (Schema::Int, Schema::Long) | (Schema::Long, Schema::Long) => {
+        Ok(Value::from(self.read_long()?))
+      }
+      (Schema::Int, Schema::Float)
+      | (Schema::Int, Schema::Double)
+      | (Schema::Long, Schema::Float)
+      | (Schema::Long, Schema::Double) => Ok(Value::from(self.read_long()? as f64)),
+      (Schema::Float, Schema::Float) | (Schema::Float, Schema::Double) => {
+        let float = f32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        Ok(Value::from(f64::from(float)))
+      }
+      (Schema::Double, Schema::Double) => {
+        Ok(Value::from(f64::from_le_bytes(self.take(8)?.try_into().unwrap())))
+      }
+      (Schema::String, Schema::String) | (Schema::Bytes, Schema::String) => {
+        let len = self.read_len()?;
+        utf8(self.take(len)?).map(Value::String)
+      }
+      (Schema::String, Schema::Bytes) | (Schema::Bytes, Schema::Bytes) => {
+        let len = self.read_len()?;
+        Ok(bytes_value(self.take(len)?))
+      }
+      (Schema::Fixed { size, .. }, Schema::Fixed { .. }) if readable(writer, reader) => {
+        Ok(bytes_value(self.take(*size)?))
+      }
+      (Schema::Enum { symbols: written, .. }, Schema::Enum { symbols, default, .. })
+        if readable(writer, reader) =>
+      {
+        let index = self.read_long()?;
+        let symbol = usize::try_from(index)
+          .ok()
+          .and_then(|index| written.get(index))
+          .ok_or_else(|| error(&format!("no enum symbol {}", index)))?;
+        if symbols.contains(symbol) {
+          return Ok(Value::String(symbol.clone()));
+        }
+        default
+          .clone()
+          .map(Value::String)
+          .ok_or_else(|| error(&format!("{} is not a symbol of {}", symbol, reader.type_name())))
+      }
+      (Schema::Array(written), Schema::Array(items)) => {
+        let mut values = Vec::new();
+        while let Some(count) = self.read_block()? {
+          for _ in 0..count {
+            values.push(self.read(written, items)?);
+          }
+        }
+        Ok(Value::Array(values))
+      }
+      (Schema::Map(written), Schema::Map(values)) => {
+        let mut entries = Map::new();
+        while let Some(count) = self.read_block()? {
+          for _ in 0..count {
+            let len = self.read_len()?;
+            let key = utf8(self.take(len)?)?;
+            entries.insert(key, self.read(written, values)?);
+          }
+        }
+        Ok(Value::Object(entries))
+      }
+      (Schema::Record { fields: written, .. }, Schema::Record { fields, .. })
+        if readable(writer, reader) =>
+      {
+        let mut object = Map::new();
+        for field in written {
+          match fields.iter().find(|read_as| read_as.name == field.name) {
+            Some(read_as) => {
+              let value = self.read(&field.schema, &read_as.schema)?;
+              object.insert(field.name.clone(), value);
+            }
+            None => self.skip(&field.schema)?,
+          }
+        }
+        for field in fields {
+          if !object.contains_key(&field.name) {
+            let default = field.default.clone().ok_or_else(|| {
+              error(&format!("field {} was not written and has no default", field.name))
+            })?;
+            object.insert(field.name.clone(), default);
+          }
+        }
+        Ok(Value::Object(object))
+      }
+      _ => Err(unreadable()),
+    }
+  }
+
+  // Reads into the first branch of the reader's union of the same type as the
+  // writer's, or else the first the writer's can be promoted to. Records are
+  // tagged with their name, as serde expects enum variants, when the union
+  // has more than one branch besides null.
+  fn read_into_union(
+    &mut self,
+    writer: &'a Schema,
+    branches: &'a [Schema],
+  ) -> Result<Value, CodecError> {
+    let branches = branches
+      .iter()
+      .map(|branch| self.reader.resolve(branch))
+      .collect::<Result<Vec<_>, _>>()?;
+    let branch = branches
+      .iter()
+      .find(|branch| {
+        mem::discriminant(writer) == mem::discriminant(**branch) && readable(writer, branch)
+      })
+      .or_else(|| branches.iter().find(|branch| readable(writer, branch)))
+      .cloned()
+      .ok_or_else(|| error(&format!("{} is in no branch of the union", writer.type_name())))?;
+    let value = self.read(writer, branch)?;
+    let choices = branches.iter().filter(|branch| ***branch != Schema::Null).count();
+    match *branch {
+      Schema::Record { ref name, .. } if choices > 1 => {
+        let mut tagged = Map::new();
+        tagged.insert(String::from(short_name(name)), value);
+        Ok(Value::Object(tagged))
+      }
+      _ => Ok(value),
+    }
+  }
+
+  // Reads past a value the reader has no field for.
+  fn skip(&mut self, writer: &'a Schema) -> Result<(), CodecError> {
+    let mut skipped = Resolver {
+      writer: self.writer,
+      reader: self.writer,
+      bytes: self.bytes,
+    };
+    skipped.read(writer, writer)?;
+    self.bytes = skipped.bytes;
+    Ok(())
+  }
+
+  fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+    if self.bytes.len() < len {
+      return Err(error("truncated datum"));
+    }
+    let (taken, rest) = self.bytes.split_at(len);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn read_long(&mut self) -> Result<i64, CodecError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+      let byte = self.take(1)?[0];
+      value |= u64::from(byte & 0x7f) << shift;
+      if byte & 0x80 == 0 {
+        return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+      }
+    }
+    Err(error("long is too long"))
+  }
+
+  fn read_len(&mut self) -> Result<usize, CodecError> {
+    let len = self.read_long()?;
+    usize::try_from(len).map_err(|_| error(&format!("negative length {}", len)))
+  }
+
+  // The number of items in the next block of an array or map; none after the last.
+  fn read_block(&mut self) -> Result<Option<usize>, CodecError> {
+    match self.read_long()? {
+      0 => Ok(None),
+      // A negative count is followed by the block's size in bytes.
+      count if count < 0 => {
+        self.read_long()?;
+        Ok(Some(count.unsigned_abs() as usize))
+      }
+      count => Ok(Some(count as usize)),
+    }
+  }
+}
+
+struct SchemaCache {
+  registry: Arc<dyn SchemaRegistry>,
+  // What this client writes each subject with, and reads it as.
+  own: HashMap<String, (u32, Arc<ParsedSchema>)>,
+  // Writer schemas by id, with the subject they're registered under.
+  written: HashMap<u32, (String, Arc<ParsedSchema>)>,
+}
+
+impl SchemaCache {
+  // The schema to read `subject` as: the client's own, or the writer's when it
+  // registered none.
+  fn reader(&self, subject: &str, writer: &Arc<ParsedSchema>) -> Arc<ParsedSchema> {
+    self
+      .own
+      .get(subject)
+      .map_or_else(|| Arc::clone(writer), |(_, own)| Arc::clone(own))
+  }
+}
+
+// The registry Avro payloads are written and read through, with the schemas
+// fetched from it so far. Clones share the cache, so a client and anything
+// else reading its commits, such as a projection, only fetch each schema once.
+#[derive(Clone)]
+pub struct Schemas {
+  cache: Arc<RwLock<SchemaCache>>,
+}
+
+impl Schemas {
+  pub fn new<R: SchemaRegistry + 'static>(registry: R) -> Schemas {
+    Schemas {
+      cache: Arc::new(RwLock::new(SchemaCache {
+        registry: Arc::new(registry),
+        own: HashMap::new(),
+        written: HashMap::new(),
+      })),
+    }
+  }
+
+  // Registers `schema` under `subject` as the one these schemas write it with
+  // and read it as; payloads written with the subject's other schemas are
+  // resolved against it. See `codec::events_subject` for the subjects the
+  // client writes.
+  pub fn register(&self, subject: &str, schema: &str) -> Result<u32, CodecError> {
+    let parsed = Arc::new(ParsedSchema::parse(schema)?);
+    let mut cache = self.cache.write().unwrap();
+    let id = cache.registry.register(subject, schema)?;
+    cache
+      .own
+      .insert(String::from(subject), (id, Arc::clone(&parsed)));
+    cache.written.insert(id, (String::from(subject), parsed));
+    Ok(id)
+  }
+
+  // `value` framed with the id of the schema registered for `subject`.
+  pub fn encode(&self, subject: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+    let cache = self.cache.read().unwrap();
+    let (id, ref schema) = *cache
+      .own
+      .get(subject)
+      .ok_or_else(|| error(&format!("no schema registered for {}", subject)))?;
+    let mut bytes = vec![MAGIC];
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes.extend(schema.write(value)?);
+    Ok(bytes)
+  }
+
+  pub fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+    if bytes.len() < 5 || bytes[0] != MAGIC {
+      return Err(error("payload isn't framed with a schema id"));
+    }
+    let id = u32::from_be_bytes(bytes[1..5].try_into().unwrap());
+    let (writer, reader) = self.schemas_for(id)?;
+    writer.read(&bytes[5..], &reader)
+  }
+
+  fn schemas_for(&self, id: u32) -> Result<(Arc<ParsedSchema>, Arc<ParsedSchema>), CodecError> {
+    {
+      let cache = self.cache.read().unwrap();
+      if let Some((subject, writer)) = cache.written.get(&id) {
+        return Ok((Arc::clone(writer), cache.reader(subject, writer)));
+      }
+    }
+    let mut cache = self.cache.write().unwrap();
+    let (subject, schema) = cache.registry.schema(id)?;
+    let writer = Arc::new(ParsedSchema::parse(&schema)?);
+    let reader = cache.reader(&subject, &writer);
+    cache.written.insert(id, (subject, Arc::clone(&writer)));
+    Ok((writer, reader))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ENVELOPES: &str = r#"{
+    "type": "array",
+    "items": {
+      "type": "record",
+      "name": "Envelope",
+      "namespace": "com.example.account",
+      "fields": [
+        {"name": "event_type", "type": "string"},
+        {"name": "schema_version", "type": "int"},
+        {"name": "data", "type": [
+          {"type": "enum", "name": "Closed", "symbols": ["Closed"]},
+          {"type": "record", "name": "Opened", "fields": [
+            {"name": "owner", "type": "string"},
+            {"name": "nickname", "type": ["null", "string"], "default": null}
+          ]},
+          {"type": "record", "name": "Deposited", "fields": [
+            {"name": "amount", "type": "long"},
+            {"name": "tags", "type": {"type": "map", "values": "double"}}
+          ]}
+        ]},
+        {"name": "metadata", "type": {"type": "map", "values": "string"}, "default": {}}
+      ]
+    }
+  }"#;
+
+  #[test]
+  fn it_round_trips_values_through_a_schema() {
+    let schema = ParsedSchema::parse(ENVELOPES).unwrap();
+    let envelopes = serde_json::json!([
+      {
+        "event_type": "Opened",
+        "schema_version": 1,
+        "data": {"Opened": {"owner": "alice", "nickname": "al"}},
+        "metadata": {"source": "test"}
+      },
+      {
+        "event_type": "Deposited",
+        "schema_version": 2,
+        "data": {"Deposited": {"amount": -5_000_000_000i64, "tags": {"rate": 0.5}}}
+      },
+      {"event_type": "Closed", "schema_version": 1, "data": "Closed"}
+    ]);
+    let bytes = schema.write(&envelopes).unwrap();
+    let mut expected = envelopes.clone();
+    expected[1]["metadata"] = serde_json::json!({});
+    expected[2]["metadata"] = serde_json::json!({});
+    assert_eq!(schema.read(&bytes, &schema).unwrap(), expected);
+
+    let unknown = serde_json::json!([{"event_type": "Opened", "schema_version": 1, "data": 7}]);
+    assert!(schema.write(&unknown).is_err());
+  }
+
+  #[test]
+  fn it_reads_data_written_with_older_schemas() {
+    let v1 = ParsedSchema::parse(
+      r#"{"type": "record", "name": "com.example.Account", "fields": [
+        {"name": "owner", "type": "string"},
+        {"name": "balance", "type": "int"},
+        {"name": "legacy", "type": {"type": "array", "items": "string"}},
+        {"name": "status", "type": {"type": "enum", "name": "Status", "symbols": ["Open", "Frozen"]}}
+      ]}"#,
+    )
+    .unwrap();
+    let v2 = ParsedSchema::parse(
+      r#"{"type": "record", "name": "Account", "namespace": "com.example.v2", "fields": [
+        {"name": "status", "type": {
+          "type": "enum", "name": "Status", "symbols": ["Open", "Closed"], "default": "Open"
+        }},
+        {"name": "balance", "type": ["null", "double"]},
+        {"name": "owner", "type": "string"},
+        {"name": "currency", "type": "string", "default": "USD"}
+      ]}"#,
+    )
+    .unwrap();
+    let written = v1
+      .write(&serde_json::json!({
+        "owner": "alice",
+        "balance": 12,
+        "legacy": ["a", "b"],
+        "status": "Frozen"
+      }))
+      .unwrap();
+
+    assert_eq!(
+      v1.read(&written, &v2).unwrap(),
+      serde_json::json!({
+        "owner": "alice",
+        "balance": 12.0,
+        "status": "Open",
+        "currency": "USD"
+      })
+    );
+
+    let v3 = ParsedSchema::parse(
+      r#"{"type": "record", "name": "Account", "fields": [
+        {"name": "owner", "type": "string"},
+        {"name": "opened_at", "type": "long"}
+      ]}"#,
+    )
+    .unwrap();
+    assert!(v1.read(&written, &v3).is_err());
+  }
+
+  #[test]
+  fn it_resolves_named_references() {
+    let schema = ParsedSchema::parse(
+      r#"{"type": "record", "name": "Node", "namespace": "list", "fields": [
+        {"name": "value", "type": "int"},
+        {"name": "next", "type": ["null", "list.Node"]}
+      ]}"#,
+    )
+    .unwrap();
+    let list = serde_json::json!({"value": 1, "next": {"value": 2, "next": null}});
+    let bytes = schema.write(&list).unwrap();
+    assert_eq!(bytes, vec![2, 2, 4, 0]);
+    assert_eq!(schema.read(&bytes, &schema).unwrap(), list);
+    assert!(ParsedSchema::parse(r#"{"type": "array", "items": "Missing"}"#).is_err());
+  }
+}
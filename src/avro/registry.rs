@@ -0,0 +1,47 @@
+use crate::codec::CodecError;
+use serde_json::Value;
+use std::sync::Mutex;
+
+// Where Avro schemas are registered and looked up by the id each payload
+// carries. Payloads can only be read while the registry still has their id.
+pub trait SchemaRegistry: Send + Sync {
+  // The id of `schema` under `subject`, registering it if it's new.
+  fn register(&self, subject: &str, schema: &str) -> Result<u32, CodecError>;
+  // The schema with `id`, and the subject it's registered under.
+  fn schema(&self, id: u32) -> Result<(String, String), CodecError>;
+}
+
+// Forgets its schemas with the process, so it suits tests, and applications
+// that register every schema they'll read before reading.
+#[derive(Default)]
+pub struct InMemorySchemaRegistry {
+  schemas: Mutex<Vec<(String, Value)>>,
+}
+
+impl SchemaRegistry for InMemorySchemaRegistry {
+  fn register(&self, subject: &str, schema: &str) -> Result<u32, CodecError> {
+    let schema: Value = serde_json::from_str(schema)?;
+    let mut schemas = self.schemas.lock().unwrap();
+    let index = match schemas
+      .iter()
+      .position(|(registered_subject, registered)| {
+        registered_subject == subject && *registered == schema
+      }) {
+      Some(index) => index,
+      None => {
+        schemas.push((String::from(subject), schema));
+        schemas.len() - 1
+      }
+    };
+    Ok(index as u32 + 1)
+  }
+
+  fn schema(&self, id: u32) -> Result<(String, String), CodecError> {
+    let schemas = self.schemas.lock().unwrap();
+    (id as usize)
+      .checked_sub(1)
+      .and_then(|index| schemas.get(index))
+      .map(|(subject, schema)| (subject.clone(), schema.to_string()))
+      .ok_or_else(|| CodecError::EncodingError(format!("avro: no schema with id {}", id)))
+  }
+}
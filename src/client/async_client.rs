@@ -0,0 +1,391 @@
+use super::{Client, ClientError};
+use crate::aggregate::Aggregate;
+use crate::command::{AsyncCommand, Command};
+use crate::commit::Commit;
+use crate::dispatch::DispatchDelegate;
+use crate::store::Store;
+use chrono::{DateTime, Utc};
+use either::Either;
+use futures::future::{self, FutureExt, TryFutureExt};
+use serde::Serialize;
+use std::future::Future;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// A `Client` for tokio services. Stores are synchronous, so each operation runs
+// on tokio's blocking thread pool rather than on the executor thread awaiting
+// it; operations on one `AsyncClient` and its clones run one at a time.
+pub struct AsyncClient<D: DispatchDelegate, S: Store> {
+  client: Arc<Mutex<Client<D, S>>>,
+}
+
+impl<D: DispatchDelegate, S: Store> Clone for AsyncClient<D, S> {
+  fn clone(&self) -> Self {
+    AsyncClient {
+      client: Arc::clone(&self.client),
+    }
+  }
+}
+
+impl<D, S> AsyncClient<D, S>
+where
+  D: DispatchDelegate + Send + 'static,
+  S: Store + Send + 'static,
+{
+  pub fn new(client: Client<D, S>) -> AsyncClient<D, S> {
+    AsyncClient {
+      client: Arc::new(Mutex::new(client)),
+    }
+  }
+
+  // Must be polled within a tokio runtime. A panicking operation panics the
+  // awaiting task too.
+  fn run<T, F>(&self, operation: F) -> impl Future<Output = T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut Client<D, S>) -> T + Send + 'static,
+  {
+    let client = Arc::clone(&self.client);
+    // The task is spawned when first polled, inside the caller's runtime.
+    future::lazy(move |_| {
+      tokio::task::spawn_blocking(move || operation(&mut client.lock().unwrap()))
+    })
+    .flatten()
+    .map(|result| result.unwrap_or_else(|err| panic::resume_unwind(err.into_panic())))
+  }
+
+  pub fn fetch_latest<A: Aggregate + Send + 'static>(
+    &self,
+    aggregate_id: Uuid,
+  ) -> impl Future<Output = Result<A, ClientError>> {
+    self.run(move |client| client.fetch_latest(aggregate_id))
+  }
+
+  pub fn fetch_at_version<A: Aggregate + Send + 'static>(
+    &self,
+    aggregate_id: Uuid,
+    version: i64,
+  ) -> impl Future<Output = Result<Option<A>, ClientError>> {
+    self.run(move |client| client.fetch_at_version(aggregate_id, version))
+  }
+
+  pub fn fetch_as_of<A: Aggregate + Send + 'static>(
+    &self,
+    aggregate_id: Uuid,
+    as_of: DateTime<Utc>,
+  ) -> impl Future<Output = Result<Option<A>, ClientError>> {
+    self.run(move |client| client.fetch_as_of(aggregate_id, as_of))
+  }
+
+  pub fn issue_command<C, M>(
+    &self,
+    aggregate: C::Aggregate,
+    command: C,
+    metadata: M,
+  ) -> impl Future<Output = Result<Commit, Either<ClientError, C::Error>>>
+  where
+    C: Command + 'static,
+    C::Aggregate: Send,
+    C::Error: Send,
+    M: Serialize + Send + 'static,
+  {
+    self.issue_command_with_id(aggregate, command, metadata, Uuid::new_v4())
+  }
+
+  pub fn issue_command_with_id<C, M>(
+    &self,
+    aggregate: C::Aggregate,
+    command: C,
+    metadata: M,
+    commit_id: Uuid,
+  ) -> impl Future<Output = Result<Commit, Either<ClientError, C::Error>>>
+  where
+    C: Command + 'static,
+    C::Aggregate: Send,
+    C::Error: Send,
+    M: Serialize + Send + 'static,
+  {
+    self.run(move |client| {
+      let result = client.issue_command_with_id(&aggregate, &command, &metadata, commit_id);
+      result.map(|outcome| outcome.commit)
+    })
+  }
+
+  pub fn issue_command_idempotently<C, M>(
+    &self,
+    aggregate: C::Aggregate,
+    command: C,
+    metadata: M,
+    idempotency_key: String,
+  ) -> impl Future<Output = Result<Commit, Either<ClientError, C::Error>>>
+  where
+    C: Command + 'static,
+    C::Aggregate: Send,
+    C::Error: Send,
+    M: Serialize + Send + 'static,
+  {
+    self.run(move |client| {
+      client.issue_command_idempotently(&aggregate, &command, &metadata, &idempotency_key)
+    })
+  }
+
+  // Validates the command and awaits its `apply` on the awaiting task, then
+  // commits its events like any other operation.
+  pub fn issue_async_command<C, M>(
+    &self,
+    aggregate: C::Aggregate,
+    command: C,
+    metadata: M,
+  ) -> impl Future<Output = Result<Commit, Either<ClientError, C::Error>>>
+  where
+    C: AsyncCommand + 'static,
+    C::Aggregate: Send,
+    <C::Aggregate as Aggregate>::Event: Send,
+    C::Error: Send,
+    M: Serialize + Send + 'static,
+  {
+    let client = self.clone();
+    // Applied when first polled, like `run`, so `apply` runs in the caller's runtime.
+    future::lazy(move |_| {
+      let applied = match command.validate(&aggregate) {
+        Ok(()) => command.apply(&aggregate).map_err(Either::Right).left_future(),
+        Err(errors) => future::err(Either::Left(ClientError::Invalid(errors))).right_future(),
+      };
+      applied.and_then(move |events| {
+        client
+          .run(move |client| {
+            client.issue_applied_command(&aggregate, &command, events, &metadata, Uuid::new_v4())
+          })
+          .map_err(Either::Left)
+      })
+    })
+    .flatten()
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::ClientBuilder;
+  use super::*;
+  use crate::command::{CommandFuture, ValidationErrors};
+  use crate::dispatch::NullDispatcher;
+  use crate::events::Event;
+  use crate::store::sqlite::SqliteStore;
+  use std::error::Error;
+  use std::fmt;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use tokio::runtime::Runtime;
+
+  #[derive(Debug)]
+  struct NeverFails;
+
+  impl fmt::Display for NeverFails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "never fails")
+    }
+  }
+
+  impl Error for NeverFails {}
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum CounterEvent {
+    Incremented,
+  }
+
+  impl Event for CounterEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone, Debug)]
+  struct Counter {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for Counter {
+    type Event = CounterEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Counter { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &CounterEvent) -> Counter {
+      Counter {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Debug, Clone)]
+  struct Increment;
+
+  impl Command for Increment {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn apply(&self, _counter: &Counter) -> Result<Vec<CounterEvent>, NeverFails> {
+      Ok(vec![CounterEvent::Incremented])
+    }
+  }
+
+  // Prices come from elsewhere, so adding to a counter takes a while.
+  #[derive(Debug, Clone)]
+  struct IncrementByPrice(i64);
+
+  impl AsyncCommand for IncrementByPrice {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn validate(&self, _counter: &Counter) -> Result<(), ValidationErrors> {
+      let mut errors = ValidationErrors::new();
+      if self.0 < 0 {
+        errors.add("price", "must not be negative");
+      }
+      errors.into_result()
+    }
+
+    fn apply(&self, _counter: &Counter) -> CommandFuture<Self> {
+      let price = self.0;
+      let lookup = tokio::time::sleep(::std::time::Duration::from_millis(10));
+      Box::pin(lookup.map(move |()| Ok((0..price).map(|_| CounterEvent::Incremented).collect())))
+    }
+  }
+
+  // Restocks from a supplier, noting whether it got as far as asking them.
+  #[derive(Debug, Clone)]
+  struct Restock {
+    quantity: i64,
+    supplier: String,
+    applied: Arc<AtomicBool>,
+  }
+
+  impl AsyncCommand for Restock {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn validate(&self, _counter: &Counter) -> Result<(), ValidationErrors> {
+      let mut errors = ValidationErrors::new();
+      if self.quantity <= 0 {
+        errors.add("quantity", "must be positive");
+      }
+      if self.supplier.is_empty() {
+        errors.add("supplier", "is required");
+      }
+      errors.into_result()
+    }
+
+    fn apply(&self, _counter: &Counter) -> CommandFuture<Self> {
+      self.applied.store(true, Ordering::SeqCst);
+      Box::pin(future::ok(vec![CounterEvent::Incremented]))
+    }
+  }
+
+  #[test]
+  fn it_issues_async_commands() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let client = ClientBuilder::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher {})
+      .finish()
+      .unwrap();
+    let client = AsyncClient::new(client);
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+
+    let counter = runtime
+      .block_on(client.fetch_latest::<Counter>(aggregate_id))
+      .unwrap();
+    let commit = runtime
+      .block_on(client.issue_async_command(counter.clone(), IncrementByPrice(3), ()))
+      .unwrap();
+    assert_eq!(commit.events_count, 3);
+    match runtime.block_on(client.issue_async_command(counter, IncrementByPrice(-1), ())) {
+      Err(Either::Left(ClientError::Invalid(errors))) => {
+        assert_eq!(errors.fields[0].field, "price")
+      }
+      other => panic!("unexpected {:?}", other.map(|commit| commit.commit_id)),
+    }
+    let counter = runtime
+      .block_on(client.fetch_latest::<Counter>(aggregate_id))
+      .unwrap();
+    assert_eq!(counter.version, 3);
+  }
+
+  #[test]
+  fn it_refuses_invalid_async_commands_before_applying_them() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let client = ClientBuilder::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher {})
+      .finish()
+      .unwrap();
+    let client = AsyncClient::new(client);
+    let runtime = Runtime::new().unwrap();
+    let counter = Counter::with_id(Uuid::new_v4());
+    let restock = Restock {
+      quantity: 0,
+      supplier: String::new(),
+      applied: Arc::new(AtomicBool::new(false)),
+    };
+
+    match runtime.block_on(client.issue_async_command(counter.clone(), restock.clone(), ())) {
+      Err(Either::Left(ClientError::Invalid(errors))) => {
+        let fields: Vec<_> = errors
+          .fields
+          .iter()
+          .map(|error| (error.field.as_str(), error.message.as_str()))
+          .collect();
+        assert_eq!(fields, vec![("quantity", "must be positive"), ("supplier", "is required")]);
+      }
+      other => panic!("unexpected {:?}", other.map(|commit| commit.commit_id)),
+    }
+    assert!(!restock.applied.load(Ordering::SeqCst));
+    let latest = runtime
+      .block_on(client.fetch_latest::<Counter>(counter.id))
+      .unwrap();
+    assert_eq!(latest.version, 0);
+  }
+
+  #[test]
+  fn it_runs_client_operations_off_the_executor() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let client = ClientBuilder::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher {})
+      .finish()
+      .unwrap();
+    let client = AsyncClient::new(client);
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+
+    let counter = runtime
+      .block_on(client.fetch_latest::<Counter>(aggregate_id))
+      .unwrap();
+    runtime
+      .block_on(client.issue_command(counter, Increment, ()))
+      .unwrap();
+    let counter = runtime
+      .block_on(client.clone().fetch_latest::<Counter>(aggregate_id))
+      .unwrap();
+    assert_eq!(counter.version, 1);
+    let before = runtime
+      .block_on(client.fetch_at_version::<Counter>(aggregate_id, 0))
+      .unwrap();
+    assert!(before.is_none());
+    let as_of = runtime
+      .block_on(client.fetch_as_of::<Counter>(aggregate_id, Utc::now()))
+      .unwrap();
+    assert_eq!(as_of.map(|counter| counter.version), Some(1));
+  }
+}
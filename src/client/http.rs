@@ -0,0 +1,428 @@
+use crate::aggregate::Aggregate;
+use crate::command::Command;
+use crate::commit::DeserializedCommit;
+use futures::future::{self, FutureExt, TryFutureExt};
+use futures::stream::{Stream, StreamExt};
+use hyper::body::{to_bytes, Bytes};
+use hyper::client::HttpConnector;
+use hyper::http::request::Builder;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Error as JsonError;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+use uuid::Uuid;
+
+pub type CommitStream =
+  Pin<Box<dyn Stream<Item = Result<DeserializedCommit, HttpClientError>> + Send>>;
+
+#[derive(Debug)]
+pub enum HttpClientError {
+  RequestError(String),
+  HttpError(hyper::Error),
+  WebSocketError(Box<WebSocketError>),
+  SerializationError(JsonError),
+  // The server's status and the error it gave.
+  ResponseError { status: StatusCode, error: String },
+}
+
+impl fmt::Display for HttpClientError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      HttpClientError::RequestError(ref err) => write!(f, "invalid request: {}", err),
+      HttpClientError::HttpError(ref err) => write!(f, "http error: {}", err),
+      HttpClientError::WebSocketError(ref err) => write!(f, "websocket error: {}", err),
+      HttpClientError::SerializationError(ref err) => write!(f, "serialization error: {}", err),
+      HttpClientError::ResponseError { status, ref error } => {
+        write!(f, "server responded {}: {}", status, error)
+      }
+    }
+  }
+}
+
+impl error::Error for HttpClientError {}
+
+impl From<JsonError> for HttpClientError {
+  fn from(error: JsonError) -> HttpClientError {
+    HttpClientError::SerializationError(error)
+  }
+}
+
+impl From<WebSocketError> for HttpClientError {
+  fn from(error: WebSocketError) -> HttpClientError {
+    HttpClientError::WebSocketError(Box::new(error))
+  }
+}
+
+impl From<hyper::http::Error> for HttpClientError {
+  fn from(error: hyper::http::Error) -> HttpClientError {
+    HttpClientError::RequestError(error.to_string())
+  }
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+  error: String,
+}
+
+fn json<T: DeserializeOwned>(status: StatusCode, body: &[u8]) -> Result<T, HttpClientError> {
+  if !status.is_success() {
+    let error = match serde_json::from_slice::<ErrorResponse>(body) {
+      Ok(response) => response.error,
+      Err(_) => String::from_utf8_lossy(body).into_owned(),
+    };
+    return Err(HttpClientError::ResponseError { status, error });
+  }
+  Ok(serde_json::from_slice(body)?)
+}
+
+// Talks to a remote `Server` with the same `Command` and `Aggregate` types a
+// colocated `Client` uses. Cloning it shares its connection pool.
+#[derive(Clone)]
+pub struct HttpEventStoreClient {
+  base_url: String,
+  headers: Vec<(&'static str, String)>,
+  client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpEventStoreClient {
+  // `base_url` is where the server is reached, such as `http://localhost:8080`.
+  pub fn new(base_url: &str) -> HttpEventStoreClient {
+    let connector = HttpsConnectorBuilder::new()
+      .with_native_roots()
+      .https_or_http()
+      .enable_http1()
+      .build();
+    HttpEventStoreClient {
+      base_url: String::from(base_url.trim_end_matches('/')),
+      headers: Vec::new(),
+      client: Client::builder().build(connector),
+    }
+  }
+
+  pub fn with_api_key(mut self, api_key: &str) -> HttpEventStoreClient {
+    self.headers.push(("x-api-key", String::from(api_key)));
+    self
+  }
+
+  pub fn with_bearer_token(mut self, token: &str) -> HttpEventStoreClient {
+    self
+      .headers
+      .push(("authorization", format!("Bearer {}", token)));
+    self
+  }
+
+  fn request(&self, method: Method, path: &str) -> Builder {
+    let mut request = Request::builder()
+      .method(method)
+      .uri(format!("{}{}", self.base_url, path));
+    for (name, value) in &self.headers {
+      request = request.header(*name, value.as_str());
+    }
+    request
+  }
+
+  fn send(
+    &self,
+    request: Result<Request<Body>, HttpClientError>,
+  ) -> impl Future<Output = Result<(StatusCode, Bytes), HttpClientError>> {
+    let client = self.client.clone();
+    future::ready(request)
+      .and_then(move |request| client.request(request).map_err(HttpClientError::HttpError))
+      .and_then(|response| {
+        let status = response.status();
+        to_bytes(response.into_body())
+          .map_ok(move |body| (status, body))
+          .map_err(HttpClientError::HttpError)
+      })
+  }
+
+  // `None` when the aggregate has no commits.
+  pub fn fetch_latest<A: Aggregate>(
+    &self,
+    aggregate_id: Uuid,
+  ) -> impl Future<Output = Result<Option<A>, HttpClientError>> {
+    let request = self
+      .request(Method::GET, &format!("/aggregate/{}/latest", aggregate_id))
+      .body(Body::empty())
+      .map_err(HttpClientError::from);
+    self.send(request).map(|response| match response? {
+      (StatusCode::NOT_FOUND, _) => Ok(None),
+      (status, body) => json(status, &body).map(Some),
+    })
+  }
+
+  // Commits only if the aggregate is still at `expected_version`, when given; a
+  // conflict is a `ResponseError` with status 409.
+  pub fn issue_command<C: Command + Serialize>(
+    &self,
+    aggregate_id: Uuid,
+    expected_version: Option<i64>,
+    command: &C,
+  ) -> impl Future<Output = Result<DeserializedCommit, HttpClientError>> {
+    self.post_command(
+      &format!("/commit/{}", aggregate_id),
+      expected_version,
+      command,
+    )
+  }
+
+  // For servers that accept several command types, each registered under an
+  // aggregate type.
+  pub fn issue_typed_command<C: Command + Serialize>(
+    &self,
+    aggregate_type: &str,
+    aggregate_id: Uuid,
+    expected_version: Option<i64>,
+    command: &C,
+  ) -> impl Future<Output = Result<DeserializedCommit, HttpClientError>> {
+    let path = format!("/commit/{}/{}", aggregate_type, aggregate_id);
+    self.post_command(&path, expected_version, command)
+  }
+
+  fn post_command<C: Serialize>(
+    &self,
+    path: &str,
+    expected_version: Option<i64>,
+    command: &C,
+  ) -> impl Future<Output = Result<DeserializedCommit, HttpClientError>> {
+    let mut request = self
+      .request(Method::POST, path)
+      .header("content-type", "application/json");
+    if let Some(expected_version) = expected_version {
+      request = request.header("if-match", format!("\"{}\"", expected_version));
+    }
+    let request = serde_json::to_vec(command)
+      .map_err(HttpClientError::from)
+      .and_then(|body| Ok(request.body(Body::from(body))?));
+    self.send(request).map(|response| {
+      let (status, body) = response?;
+      json(status, &body)
+    })
+  }
+
+  // The commits made against versions `from_version..=to_version`, oldest first.
+  pub fn list_commits(
+    &self,
+    aggregate_id: Uuid,
+    from_version: Option<i64>,
+    to_version: Option<i64>,
+  ) -> impl Future<Output = Result<Vec<DeserializedCommit>, HttpClientError>> {
+    let mut path = format!("/store/{}/commits", aggregate_id);
+    let bounds = [("from_version", from_version), ("to_version", to_version)];
+    let query: Vec<String> = bounds
+      .iter()
+      .filter_map(|&(name, value)| value.map(|value| format!("{}={}", name, value)))
+      .collect();
+    if !query.is_empty() {
+      path.push('?');
+      path.push_str(&query.join("&"));
+    }
+    let request = self
+      .request(Method::GET, &path)
+      .body(Body::empty())
+      .map_err(HttpClientError::from);
+    self.send(request).map(|response| {
+      let (status, body) = response?;
+      json(status, &body)
+    })
+  }
+
+  // The aggregate's commits as they are made, over the server's WebSocket.
+  // `from_commit_number` first replays the commits numbered after it. Only
+  // servers reached over plain http can be subscribed to.
+  pub fn subscribe(
+    &self,
+    aggregate_id: Uuid,
+    from_commit_number: Option<i64>,
+  ) -> impl Future<Output = Result<CommitStream, HttpClientError>> {
+    self.subscribe_to(&format!("/commits/{}", aggregate_id), from_commit_number)
+  }
+
+  pub fn subscribe_to_category(
+    &self,
+    category: &str,
+    from_commit_number: Option<i64>,
+  ) -> impl Future<Output = Result<CommitStream, HttpClientError>> {
+    self.subscribe_to(
+      &format!("/commits/category/{}", category),
+      from_commit_number,
+    )
+  }
+
+  fn subscribe_to(
+    &self,
+    path: &str,
+    from_commit_number: Option<i64>,
+  ) -> impl Future<Output = Result<CommitStream, HttpClientError>> {
+    let mut url = match self.base_url.strip_prefix("http") {
+      Some(rest) => format!("ws{}{}", rest, path),
+      None => format!("{}{}", self.base_url, path),
+    };
+    if let Some(commit_number) = from_commit_number {
+      url.push_str(&format!("?from_commit_number={}", commit_number));
+    }
+    let headers = self.headers.clone();
+    let request = url
+      .into_client_request()
+      .map_err(HttpClientError::from)
+      .and_then(|mut request| {
+        for (name, value) in headers {
+          let value = HeaderValue::from_str(&value)
+            .map_err(|err| HttpClientError::RequestError(err.to_string()))?;
+          request.headers_mut().insert(name, value);
+        }
+        Ok(request)
+      });
+    future::ready(request)
+      .and_then(|request| connect_async(request).map_err(HttpClientError::from))
+      .map_ok(|(websocket, _)| {
+        let commits = websocket
+          .take_while(|message| future::ready(!matches!(message, Ok(Message::Close(_)))))
+          .filter_map(|message| {
+            future::ready(match message {
+              Ok(Message::Text(json)) => Some(serde_json::from_str(&json).map_err(From::from)),
+              Ok(_) => None,
+              Err(err) => Some(Err(HttpClientError::from(err))),
+            })
+          });
+        Box::pin(commits) as CommitStream
+      })
+  }
+}
+
+#[cfg(all(test, feature = "httpd", feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::events::Event;
+  use crate::server::aggregate::{commit, get_latest, CommandSchemas};
+  use crate::server::dispatch::WebSocketSubscriptions;
+  use crate::server::state::with_store;
+  use crate::server::store::commit_list;
+  use crate::store::shared::SharedStore;
+  use crate::store::sqlite::SqliteStore;
+  use std::error::Error;
+  use tokio::runtime::Runtime;
+  use warp::Filter;
+
+  #[derive(Debug)]
+  struct NeverFails;
+
+  impl fmt::Display for NeverFails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "never fails")
+    }
+  }
+
+  impl Error for NeverFails {}
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum CounterEvent {
+    Incremented,
+  }
+
+  impl Event for CounterEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+  struct Counter {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for Counter {
+    type Event = CounterEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Counter { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &CounterEvent) -> Counter {
+      Counter {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  struct Increment;
+
+  impl Command for Increment {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn apply(&self, _counter: &Counter) -> Result<Vec<CounterEvent>, NeverFails> {
+      Ok(vec![CounterEvent::Incremented])
+    }
+  }
+
+  #[test]
+  fn it_reads_and_writes_through_a_remote_server() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let subscriptions = WebSocketSubscriptions::default();
+    let dispatch_subscriptions = subscriptions.clone();
+    let routes = get_latest::<_, Counter, _>(with_store(store.clone()))
+      .or(commit_list(with_store(store.clone())))
+      .or(subscriptions.commit_subscription(with_store(store.clone())))
+      .or(commit::<_, _, Increment, _, _>(
+        with_store(store),
+        &move || dispatch_subscriptions.clone(),
+        &CommandSchemas::default(),
+      ));
+    let runtime = Runtime::new().unwrap();
+    let address = {
+      let _entered = runtime.enter();
+      let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+      runtime.spawn(server);
+      address
+    };
+    let client = HttpEventStoreClient::new(&format!("http://{}/", address));
+    let aggregate_id = Uuid::new_v4();
+
+    let mut commits = runtime
+      .block_on(client.subscribe(aggregate_id, Some(0)))
+      .unwrap();
+    let latest = runtime.block_on(client.fetch_latest::<Counter>(aggregate_id));
+    assert_eq!(latest.unwrap(), None);
+
+    let committed = runtime
+      .block_on(client.issue_command(aggregate_id, None, &Increment))
+      .unwrap();
+    assert_eq!(committed.events[0]["event_type"], "Incremented");
+    let latest = runtime.block_on(client.fetch_latest::<Counter>(aggregate_id));
+    assert_eq!(latest.unwrap().map(|counter| counter.version), Some(1));
+
+    match runtime.block_on(client.issue_command(aggregate_id, Some(0), &Increment)) {
+      Err(HttpClientError::ResponseError { status, .. }) => {
+        assert_eq!(status, StatusCode::CONFLICT)
+      }
+      result => panic!("expected a conflict, got {:?}", result),
+    }
+    let listed = runtime
+      .block_on(client.list_commits(aggregate_id, Some(0), None))
+      .unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].commit_id, committed.commit_id);
+
+    let received = runtime.block_on(commits.next()).unwrap().unwrap();
+    assert_eq!(received.commit_id, committed.commit_id);
+  }
+}
@@ -0,0 +1,82 @@
+use super::middleware::{CommandContext, CommandMiddleware};
+use chrono::Utc;
+use serde_json::{Map, Value};
+
+// Contributes fields to the metadata of every commit a `Client` makes, such as
+// the user or tenant the current request acts for.
+pub trait MetadataProvider: Send {
+  fn metadata(&self, context: &CommandContext) -> Map<String, Value>;
+}
+
+impl<F: Fn(&CommandContext) -> Map<String, Value> + Send> MetadataProvider for F {
+  fn metadata(&self, context: &CommandContext) -> Map<String, Value> {
+    self(context)
+  }
+}
+
+// The same fields on every commit, e.g. the host or service that made it.
+#[derive(Clone, Debug, Default)]
+pub struct StaticMetadata {
+  fields: Map<String, Value>,
+}
+
+impl StaticMetadata {
+  pub fn with<V: Into<Value>>(mut self, key: &str, value: V) -> StaticMetadata {
+    self.fields.insert(String::from(key), value.into());
+    self
+  }
+}
+
+impl MetadataProvider for StaticMetadata {
+  fn metadata(&self, _context: &CommandContext) -> Map<String, Value> {
+    self.fields.clone()
+  }
+}
+
+// `event_source_version`: the version of this library that made the commit.
+pub struct LibraryVersion;
+
+impl MetadataProvider for LibraryVersion {
+  fn metadata(&self, _context: &CommandContext) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert(
+      String::from("event_source_version"),
+      Value::from(env!("CARGO_PKG_VERSION")),
+    );
+    fields
+  }
+}
+
+// `issued_at`: when the command was issued, as an RFC 3339 timestamp.
+pub struct IssuedAt;
+
+impl MetadataProvider for IssuedAt {
+  fn metadata(&self, _context: &CommandContext) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert(
+      String::from("issued_at"),
+      Value::from(Utc::now().to_rfc3339()),
+    );
+    fields
+  }
+}
+
+// Runs a provider as the `before` hook of a middleware. Fields the caller set
+// win over provided ones, and metadata that isn't an object or null is left as
+// it is.
+pub struct ProvidedMetadata<P>(pub P);
+
+impl<P: MetadataProvider> CommandMiddleware for ProvidedMetadata<P> {
+  fn before(&mut self, context: &mut CommandContext) -> Result<(), String> {
+    let provided = self.0.metadata(context);
+    if context.metadata.is_null() {
+      context.metadata = Value::Object(Map::new());
+    }
+    if let Value::Object(ref mut metadata) = context.metadata {
+      for (key, value) in provided {
+        metadata.entry(key).or_insert(value);
+      }
+    }
+    Ok(())
+  }
+}
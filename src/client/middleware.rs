@@ -0,0 +1,32 @@
+use crate::commit::Commit;
+use serde_json::Value;
+use std::fmt;
+use uuid::Uuid;
+
+// What a `CommandMiddleware` sees of a command `Client::issue_command` is about
+// to apply.
+pub struct CommandContext<'a> {
+  pub aggregate_id: Uuid,
+  // The version of the aggregate the command is applied to.
+  pub aggregate_version: i64,
+  pub category: &'static str,
+  pub command_type: &'static str,
+  pub command: &'a dyn fmt::Debug,
+  // Committed as the commit's metadata once every `before` hook has run, so
+  // hooks may add to it.
+  pub metadata: Value,
+}
+
+// Wraps every command a `Client` issues. `before` hooks run in the order the
+// middleware was added and `after` hooks in the reverse order, and only
+// middleware whose `before` ran has its `after` run.
+pub trait CommandMiddleware: Send {
+  // Returning an error rejects the command with `ClientError::Rejected`
+  // before it is applied.
+  fn before(&mut self, _context: &mut CommandContext) -> Result<(), String> {
+    Ok(())
+  }
+
+  // Sees the commit, or why the command was rejected, failed or not committed.
+  fn after(&mut self, _context: &CommandContext, _outcome: Result<&Commit, &dyn fmt::Display>) {}
+}
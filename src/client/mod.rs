@@ -0,0 +1,2337 @@
+#[cfg(feature = "async-client")]
+pub mod async_client;
+#[cfg(feature = "http-client")]
+pub mod http;
+pub mod metadata;
+pub mod middleware;
+
+use self::metadata::{MetadataProvider, ProvidedMetadata};
+use self::middleware::{CommandContext, CommandMiddleware};
+use crate::aggregate::{Aggregate, ApplyError, StreamState};
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{self, Codec, CodecError, PayloadCompression, Registries};
+use crate::command::{AsyncCommand, Command, StreamPrecondition, ValidationErrors};
+use crate::commit::*;
+use crate::dispatch::*;
+use crate::events::{decode_envelopes_with, decode_events_with, EventEnvelope};
+use crate::snapshot::compression::Compression;
+use crate::snapshot::policy::{SnapshotContext, SnapshotPolicy};
+use crate::snapshot::{Snapshot, SnapshotStore};
+use crate::store::*;
+use chrono::{DateTime, Utc};
+use either::Either;
+use serde::Serialize;
+use serde_json::{Error as JsonError, Map, Value};
+use std::any::type_name;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use uuid::Uuid;
+
+// The events a command makes.
+type CommandEvents<C> = Vec<<<C as Command>::Aggregate as Aggregate>::Event>;
+
+pub struct ClientBuilder<D: DispatchDelegate, S: Store> {
+  store: Option<S>,
+  dispatcher: Option<Dispatcher<D>>,
+  snapshot_store: Option<Box<dyn SnapshotStore + Send>>,
+  snapshot_policy: SnapshotPolicy,
+  snapshot_compression: Compression,
+  outbox_notifier: Option<OutboxNotifier>,
+  middlewares: Vec<Box<dyn CommandMiddleware>>,
+  codec: Codec,
+  registries: Registries,
+  payload_compression: PayloadCompression,
+  clock: Box<dyn Clock>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+  SerializationError(JsonError),
+  StoreError(Box<dyn StoreError>),
+  CompressionError(io::Error),
+  CodecError(CodecError),
+  // A `CommandMiddleware` refused the command.
+  Rejected(String),
+  // `Command::validate` refused the command.
+  Invalid(ValidationErrors),
+  // The aggregate refused an event of the commit it was being replayed from.
+  ApplyError {
+    aggregate_id: Uuid,
+    commit_id: Uuid,
+    error: ApplyError,
+  },
+  // The aggregate was deleted, so nothing more can be committed to it.
+  Deleted(Uuid),
+  // The aggregate's stream isn't in the state the command requires.
+  PreconditionFailed {
+    aggregate_id: Uuid,
+    precondition: StreamPrecondition,
+    actual: StreamState,
+  },
+  // A retention policy truncated the commits before `commit_sequence`, and no
+  // snapshot the client can use covers them, so the aggregate can't be rebuilt.
+  TruncatedHistory {
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  },
+  // `issue_commands` was given more than one command for the aggregate. Each
+  // command is applied to the aggregate it is paired with, so the later ones
+  // would be applied to a state the earlier ones already moved past.
+  DuplicateAggregate(Uuid),
+}
+
+#[derive(Debug)]
+pub struct ClientJsonError {
+  pub cause: JsonError,
+}
+
+impl fmt::Display for ClientError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ClientError::SerializationError(ref err) => write!(f, "serialization error: {}", err),
+      ClientError::StoreError(ref err) => write!(f, "store error: {}", err),
+      ClientError::CompressionError(ref err) => write!(f, "compression error: {}", err),
+      ClientError::CodecError(ref err) => write!(f, "codec error: {}", err),
+      ClientError::Rejected(ref reason) => write!(f, "command rejected: {}", reason),
+      ClientError::Invalid(ref errors) => write!(f, "{}", errors),
+      ClientError::ApplyError {
+        aggregate_id,
+        commit_id,
+        ref error,
+      } => write!(
+        f,
+        "aggregate {} could not apply commit {}: {}",
+        aggregate_id, commit_id, error
+      ),
+      ClientError::Deleted(aggregate_id) => write!(f, "aggregate {} was deleted", aggregate_id),
+      ClientError::PreconditionFailed {
+        aggregate_id,
+        precondition,
+        actual,
+      } => write!(
+        f,
+        "aggregate {} {} but is {}",
+        aggregate_id, precondition, actual
+      ),
+      ClientError::TruncatedHistory {
+        aggregate_id,
+        commit_sequence,
+      } => write!(
+        f,
+        "aggregate {} has no commits before commit_sequence {}",
+        aggregate_id, commit_sequence
+      ),
+      ClientError::DuplicateAggregate(aggregate_id) => write!(
+        f,
+        "aggregate {} has more than one command in the batch",
+        aggregate_id
+      ),
+    }
+  }
+}
+
+impl From<JsonError> for ClientError {
+  fn from(error: JsonError) -> ClientError {
+    ClientError::SerializationError(error)
+  }
+}
+
+impl From<CodecError> for ClientError {
+  fn from(error: CodecError) -> ClientError {
+    match error {
+      CodecError::JsonError(err) => ClientError::SerializationError(err),
+      CodecError::CompressionError(err) => ClientError::CompressionError(err),
+      err => ClientError::CodecError(err),
+    }
+  }
+}
+
+impl From<io::Error> for ClientError {
+  fn from(error: io::Error) -> ClientError {
+    ClientError::CompressionError(error)
+  }
+}
+
+impl From<Box<dyn StoreError>> for ClientError {
+  fn from(error: Box<dyn StoreError>) -> ClientError {
+    ClientError::StoreError(error)
+  }
+}
+
+// What `issue_command` produced: the command's events, the aggregate with them
+// applied, and the commit they were stored in.
+pub struct CommandOutcome<A: Aggregate> {
+  pub events: Vec<A::Event>,
+  pub aggregate: A,
+  pub commit: Commit,
+}
+
+impl<A: Aggregate> Borrow<Commit> for CommandOutcome<A> {
+  fn borrow(&self) -> &Commit {
+    &self.commit
+  }
+}
+
+pub struct Client<D: DispatchDelegate, S: Store> {
+  pub dispatcher: Dispatcher<D>,
+  pub store: S,
+  pub snapshot_store: Option<Box<dyn SnapshotStore + Send>>,
+  pub snapshot_policy: SnapshotPolicy,
+  pub snapshot_compression: Compression,
+  pub outbox_notifier: Option<OutboxNotifier>,
+  pub middlewares: Vec<Box<dyn CommandMiddleware>>,
+  pub codec: Codec,
+  pub registries: Registries,
+  pub payload_compression: PayloadCompression,
+  pub clock: Box<dyn Clock>,
+  // The commit_sequence of the newest commit this client has seen of each
+  // aggregate, with its commit_id unless it was restored from a snapshot.
+  heads: HashMap<Uuid, (i64, Option<Uuid>)>,
+}
+
+impl<D: DispatchDelegate, S: Store> Default for ClientBuilder<D, S> {
+  fn default() -> ClientBuilder<D, S> {
+    ClientBuilder {
+      dispatcher: None,
+      store: None,
+      snapshot_store: None,
+      snapshot_policy: SnapshotPolicy::default(),
+      snapshot_compression: Compression::default(),
+      outbox_notifier: None,
+      middlewares: Vec::new(),
+      codec: Codec::default(),
+      registries: Registries::default(),
+      payload_compression: PayloadCompression::default(),
+      clock: Box::new(SystemClock),
+    }
+  }
+}
+
+impl<D: DispatchDelegate, S: Store> ClientBuilder<D, S> {
+  pub fn with_store(mut self, s: S) -> ClientBuilder<D, S> {
+    self.store = Some(s);
+    self
+  }
+
+  pub fn with_dispatch_delegate(mut self, delegate: D) -> ClientBuilder<D, S> {
+    self.dispatcher = Some(Dispatcher::new(delegate));
+    self
+  }
+
+  pub fn with_snapshot_store<T: SnapshotStore + Send + 'static>(mut self, snapshot_store: T) -> ClientBuilder<D, S> {
+    self.snapshot_store = Some(Box::new(snapshot_store));
+    self
+  }
+
+  pub fn with_snapshot_policy(mut self, snapshot_policy: SnapshotPolicy) -> ClientBuilder<D, S> {
+    self.snapshot_policy = snapshot_policy;
+    self
+  }
+
+  // Leaves dispatch to a background outbox dispatcher, which is woken after each
+  // commit instead of dispatching inline.
+  pub fn with_outbox_notifier(mut self, notifier: OutboxNotifier) -> ClientBuilder<D, S> {
+    self.outbox_notifier = Some(notifier);
+    self
+  }
+
+  pub fn with_snapshot_compression(mut self, compression: Compression) -> ClientBuilder<D, S> {
+    self.snapshot_compression = compression;
+    self
+  }
+
+  // Wraps each command the client issues; see `CommandMiddleware`.
+  pub fn with_middleware<M: CommandMiddleware + 'static>(
+    mut self,
+    middleware: M,
+  ) -> ClientBuilder<D, S> {
+    self.middlewares.push(Box::new(middleware));
+    self
+  }
+
+  // Merges the provider's fields into the metadata of every command issued.
+  // Providers run as middleware, in the order they and any middleware were added.
+  pub fn with_metadata_provider<P: MetadataProvider + 'static>(
+    self,
+    provider: P,
+  ) -> ClientBuilder<D, S> {
+    self.with_middleware(ProvidedMetadata(provider))
+  }
+
+  // Encodes the events and metadata of new commits; commits already written
+  // are read in whichever encoding they were written with.
+  pub fn with_codec(mut self, codec: Codec) -> ClientBuilder<D, S> {
+    self.codec = codec;
+    self
+  }
+
+  // The registry `Codec::Avro` writes and reads schemas through. Clones of
+  // `schemas` share what has been fetched from it.
+  #[cfg(feature = "avro")]
+  pub fn with_avro_schemas(mut self, schemas: crate::avro::Schemas) -> ClientBuilder<D, S> {
+    self.registries.avro = Some(schemas);
+    self
+  }
+
+  // Compresses the encoded events and metadata of new commits once they reach
+  // `threshold` bytes.
+  pub fn with_payload_compression(
+    mut self,
+    compression: Compression,
+    threshold: usize,
+  ) -> ClientBuilder<D, S> {
+    self.payload_compression = PayloadCompression {
+      compression,
+      threshold,
+    };
+    self
+  }
+
+  // Stamps commits and snapshots with the clock's time instead of the system's.
+  pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> ClientBuilder<D, S> {
+    self.clock = Box::new(clock);
+    self
+  }
+
+  pub fn finish(self) -> Result<Client<D, S>, &'static str> {
+    if self.store.is_none() {
+      return Err("Cannot build a client; missing a store.");
+    }
+    if self.dispatcher.is_none() {
+      return Err("Cannot build a client; missing a dispatcher.");
+    }
+    Ok(Client {
+      store: self.store.unwrap(),
+      dispatcher: self.dispatcher.unwrap(),
+      snapshot_store: self.snapshot_store,
+      snapshot_policy: self.snapshot_policy,
+      snapshot_compression: self.snapshot_compression,
+      outbox_notifier: self.outbox_notifier,
+      middlewares: self.middlewares,
+      codec: self.codec,
+      registries: self.registries,
+      payload_compression: self.payload_compression,
+      clock: self.clock,
+      heads: HashMap::new(),
+    })
+  }
+}
+
+impl<D: DispatchDelegate, S: InlineProjectionStore> Client<D, S> {
+  // Registers a projection the store updates in the same transaction as each
+  // commit this client makes.
+  pub fn with_inline_projection<P: InlineProjection<S::Transaction> + Send + 'static>(
+    mut self,
+    projection: P,
+  ) -> Client<D, S> {
+    self.store.add_inline_projection(Box::new(projection));
+    self
+  }
+}
+
+impl<D: DispatchDelegate, S: Store> Client<D, S> {
+  // The commit_sequence of the newest commit to `aggregate_id` this client has
+  // fetched or made, or 0 if it has seen none.
+  pub fn commit_sequence(&self, aggregate_id: Uuid) -> i64 {
+    self.heads.get(&aggregate_id).map_or(0, |&(commit_sequence, _)| commit_sequence)
+  }
+  // `fetch_latest` returns a default aggregate for an id with no commits; this
+  // tells that apart from one that exists, and from one that was deleted.
+  pub fn stream_state<A: Aggregate>(&self, aggregate: &A) -> Result<StreamState, ClientError> {
+    if aggregate.is_deleted() {
+      return Ok(StreamState::Deleted);
+    }
+    match self.head_commit_sequence(aggregate.id())? {
+      0 => Ok(StreamState::NotCreated),
+      _ => Ok(StreamState::Live),
+    }
+  }
+
+  fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
+    let _span = info_span!(
+      "commit",
+      aggregate_id = %commit_attempt.aggregate_id,
+      version = commit_attempt.aggregate_version,
+      commit_id = %commit_attempt.commit_id
+    )
+    .entered();
+    let commit_number = self.store.commit(commit_attempt)?;
+    self.dispatch();
+    Ok(commit_number)
+  }
+
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    let _span = info_span!("commit_all", commits = commit_attempts.len()).entered();
+    let commit_numbers = self.store.commit_all(commit_attempts)?;
+    self.dispatch();
+    Ok(commit_numbers)
+  }
+
+  fn dispatch(&mut self) {
+    match self.outbox_notifier {
+      Some(ref notifier) => notifier.notify(),
+      None => {
+        let _span = info_span!("dispatch").entered();
+        let _unhandled_result = self.dispatcher.dispatch(&mut self.store);
+      }
+    }
+  }
+
+  // The events of the commits made against aggregate versions in `versions`,
+  // in the order they were committed. Each commit's events are decoded as the
+  // iterator reaches them; a failed read of the store is its only item.
+  pub fn events<A: Aggregate, R: RangeBounds<i64>>(
+    &self,
+    aggregate_id: Uuid,
+    versions: R,
+  ) -> impl Iterator<Item = Result<EventEnvelope<A::Event>, ClientError>> {
+    let min_version = match versions.start_bound() {
+      Bound::Included(&version) => version,
+      Bound::Excluded(&version) => version.saturating_add(1),
+      Bound::Unbounded => 0,
+    };
+    let max_version = match versions.end_bound() {
+      Bound::Included(&version) => version,
+      Bound::Excluded(&version) => version.saturating_sub(1),
+      Bound::Unbounded => i64::MAX,
+    };
+    let (commits, read_error) = match self.store.get_range(aggregate_id, min_version, max_version) {
+      Ok(commits) => (commits, None),
+      Err(err) => (Vec::new(), Some(ClientError::StoreError(err))),
+    };
+    let registries = self.registries.clone();
+    read_error.into_iter().map(Err).chain(commits.into_iter().flat_map(move |commit| {
+      match decode_envelopes_with::<A::Event>(&commit.serialized_events, &registries) {
+        Ok(envelopes) => envelopes.into_iter().map(Ok).collect(),
+        Err(err) => vec![Err(ClientError::from(err))],
+      }
+    }))
+  }
+
+  // Starts from the newest snapshot when a snapshot store is configured and only
+  // replays the commits made against its version or later. Snapshots written
+  // with a different `SNAPSHOT_SCHEMA_VERSION`, or whose state can't be read,
+  // are ignored and the aggregate is rebuilt from its commits, which fails with
+  // `TruncatedHistory` once a retention policy has removed the earliest ones.
+  pub fn fetch_latest<A: Aggregate>(
+    &mut self,
+    aggregate_id: Uuid,
+  ) -> Result<A, ClientError> {
+    let span = info_span!("fetch", %aggregate_id, version = tracing::field::Empty).entered();
+    let snapshot = match self.snapshot_store {
+      Some(ref snapshot_store) => snapshot_store
+        .get_latest_snapshot(aggregate_id)?
+        .filter(|snapshot| snapshot.schema_version == A::SNAPSHOT_SCHEMA_VERSION),
+      None => None,
+    };
+    let restored = snapshot.and_then(|snapshot| match snapshot.state_as::<A>() {
+      Ok(aggregate) => Some((aggregate, snapshot.aggregate_version, snapshot.commit_sequence)),
+      Err(err) => {
+        warn!("ignoring the snapshot of {}: {}", aggregate_id, err);
+        None
+      }
+    });
+    let (mut aggregate, min_version, mut commit_sequence): (A, i64, i64) =
+      restored.unwrap_or_else(|| (A::with_id(aggregate_id), 0, 0));
+    let mut head_commit_id = None;
+    let commits: Vec<Commit> = {
+      self
+        .store
+        .get_range(aggregate_id, min_version, i64::MAX)
+        .map_err(ClientError::StoreError)?
+    };
+    check_history(aggregate_id, commits.first(), commit_sequence)?;
+    for commit in commits {
+      let events: Vec<A::Event> =
+        decode_events_with(commit.serialized_events.as_slice(), &self.registries)?;
+      apply_events(&mut aggregate, &events, &commit)?;
+      commit_sequence = commit.commit_sequence;
+      head_commit_id = Some(commit.commit_id);
+    }
+    self.heads.insert(aggregate_id, (commit_sequence, head_commit_id));
+    span.record("version", aggregate.version());
+    Ok(aggregate)
+  }
+
+  // The aggregate as it was before the commit made against `version`: only the
+  // commits made against earlier versions are applied. `None` when the aggregate
+  // had no commits by then. Snapshots are not used, and the commit_sequence the
+  // client tracks is left alone.
+  pub fn fetch_at_version<A: Aggregate>(
+    &self,
+    aggregate_id: Uuid,
+    version: i64,
+  ) -> Result<Option<A>, ClientError> {
+    self.fetch_until(aggregate_id, Some(version), None)
+  }
+
+  // The aggregate as it was at `as_of`, with only the commits made by then applied.
+  pub fn fetch_as_of<A: Aggregate>(
+    &self,
+    aggregate_id: Uuid,
+    as_of: DateTime<Utc>,
+  ) -> Result<Option<A>, ClientError> {
+    self.fetch_until(aggregate_id, None, Some(as_of))
+  }
+
+  // Both bounds at once: only the commits made against versions before `version`
+  // and by `as_of` are applied.
+  pub fn fetch_until<A: Aggregate>(
+    &self,
+    aggregate_id: Uuid,
+    version: Option<i64>,
+    as_of: Option<DateTime<Utc>>,
+  ) -> Result<Option<A>, ClientError> {
+    let max_version = version.map_or(i64::MAX, |version| version - 1);
+    let mut commits = self.store.get_range(aggregate_id, 0, max_version)?;
+    if let Some(as_of) = as_of {
+      commits.retain(|commit| commit.commit_timestamp <= as_of);
+    }
+    fold_commits(aggregate_id, commits, &self.registries)
+  }
+
+  // Saves `aggregate` as the snapshot of its aggregate at the last commit this
+  // client fetched.
+  pub fn save_snapshot<A: Aggregate>(&mut self, aggregate: &A) -> Result<Snapshot, ClientError> {
+    let commit_sequence = self.commit_sequence(aggregate.id());
+    self.save_snapshot_at(aggregate, commit_sequence)
+  }
+
+  fn save_snapshot_at<A: Aggregate>(
+    &mut self,
+    aggregate: &A,
+    commit_sequence: i64,
+  ) -> Result<Snapshot, ClientError> {
+    let snapshot = Snapshot {
+      aggregate_id: aggregate.id(),
+      aggregate_version: aggregate.version(),
+      commit_sequence,
+      schema_version: A::SNAPSHOT_SCHEMA_VERSION,
+      snapshot_timestamp: self.clock.now(),
+      compression: self.snapshot_compression,
+      serialized_state: self
+        .snapshot_compression
+        .compress(&serde_json::to_vec(aggregate)?)?,
+    };
+    match self.snapshot_store {
+      Some(ref mut snapshot_store) => snapshot_store.save_snapshot(&snapshot)?,
+      None => {
+        return Err(ClientError::StoreError(
+          UnsupportedOperationError {
+            operation: "save_snapshot",
+          }
+          .into(),
+        ))
+      }
+    }
+    Ok(snapshot)
+  }
+
+  pub fn issue_command<C: Command, M: Serialize>(
+    &mut self,
+    aggregate: &C::Aggregate,
+    command: &C,
+    metadata: &M,
+  ) -> Result<CommandOutcome<C::Aggregate>, Either<ClientError, C::Error>> {
+    self.issue_command_with_id(aggregate, command, metadata, Uuid::new_v4())
+  }
+
+  // Issues the command at most once per `idempotency_key`, which is scoped to
+  // the aggregate; see `issue_command_once`.
+  pub fn issue_command_idempotently<C: Command, M: Serialize>(
+    &mut self,
+    aggregate: &C::Aggregate,
+    command: &C,
+    metadata: &M,
+    idempotency_key: &str,
+  ) -> Result<Commit, Either<ClientError, C::Error>> {
+    let commit_id = idempotent_commit_id(aggregate.id(), idempotency_key);
+    self.issue_command_once(aggregate, command, metadata, commit_id)
+  }
+
+  // Like `issue_command_with_id`, but a retry gets back the commit already made
+  // under `commit_id` instead of a `CommitIdConflict`, even if the aggregate has
+  // moved on since.
+  pub fn issue_command_once<C: Command, M: Serialize>(
+    &mut self,
+    aggregate: &C::Aggregate,
+    command: &C,
+    metadata: &M,
+    commit_id: Uuid,
+  ) -> Result<Commit, Either<ClientError, C::Error>> {
+    if let Ok(commit) = self.store.get_commit(&commit_id) {
+      return Ok(commit);
+    }
+    let result = self.issue_command_with_id(aggregate, command, metadata, commit_id);
+    match result.map(|outcome| outcome.commit) {
+      // A concurrent attempt committed first.
+      Err(Either::Left(ClientError::StoreError(ref err)))
+        if err.error_type()
+          == StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitIdConflict) =>
+      {
+        self
+          .store
+          .get_commit(&commit_id)
+          .map_err(ClientError::StoreError)
+          .map_err(Either::Left)
+      }
+      result => result,
+    }
+  }
+
+  // Commits under `commit_id`, so a retry with the same id fails with
+  // `CommitIdConflict` rather than committing the command twice.
+  pub fn issue_command_with_id<C: Command, M: Serialize>(
+    &mut self,
+    aggregate: &C::Aggregate,
+    command: &C,
+    metadata: &M,
+    commit_id: Uuid,
+  ) -> Result<CommandOutcome<C::Aggregate>, Either<ClientError, C::Error>> {
+    let _span = info_span!(
+      "issue_command",
+      aggregate_id = %aggregate.id(),
+      command = type_name::<C>()
+    )
+    .entered();
+    let command_type = type_name::<C>();
+    self.around_middleware(aggregate, command_type, command, metadata, |client, context| {
+      let events = client.apply_command(aggregate, command)?;
+      let event_metadata: Vec<_> =
+        events.iter().map(|event| command.event_metadata(event)).collect();
+      let outcome = match context {
+        Some(metadata) => {
+          client.commit_events(aggregate, events, &event_metadata, metadata, commit_id)
+        }
+        None => client.commit_events(aggregate, events, &event_metadata, metadata, commit_id),
+      };
+      outcome.map_err(Either::Left)
+    })
+  }
+
+  // Commits the events an `AsyncCommand` made, once its `apply` has resolved.
+  // The command goes through the middleware as if issued now, and is committed
+  // against the aggregate it was applied to.
+  pub fn issue_applied_command<C: AsyncCommand, M: Serialize>(
+    &mut self,
+    aggregate: &C::Aggregate,
+    command: &C,
+    events: Vec<<C::Aggregate as Aggregate>::Event>,
+    metadata: &M,
+    commit_id: Uuid,
+  ) -> Result<Commit, ClientError> {
+    let _span = info_span!(
+      "issue_applied_command",
+      aggregate_id = %aggregate.id(),
+      command = type_name::<C>()
+    )
+    .entered();
+    self.check_precondition(aggregate, command.precondition())?;
+    let event_metadata: Vec<_> = events.iter().map(|event| command.event_metadata(event)).collect();
+    let command_type = type_name::<C>();
+    self
+      .around_middleware(aggregate, command_type, command, metadata, |client, context| {
+        let outcome = match context {
+          Some(metadata) => {
+            client.commit_events(aggregate, events, &event_metadata, metadata, commit_id)
+          }
+          None => client.commit_events(aggregate, events, &event_metadata, metadata, commit_id),
+        };
+        outcome.map_err(Either::Left)
+      })
+      .map(|outcome| outcome.commit)
+      .map_err(Either::into_inner)
+  }
+
+  // Runs `commit` between the middleware's hooks. It gets the metadata the
+  // hooks settled on, or `None` when there is no middleware to change it.
+  fn around_middleware<A, M, T, E, F>(
+    &mut self,
+    aggregate: &A,
+    command_type: &'static str,
+    command: &dyn fmt::Debug,
+    metadata: &M,
+    commit: F,
+  ) -> Result<T, Either<ClientError, E>>
+  where
+    A: Aggregate,
+    M: Serialize,
+    T: Borrow<Commit>,
+    E: fmt::Display,
+    F: FnOnce(&mut Self, Option<&Value>) -> Result<T, Either<ClientError, E>>,
+  {
+    if self.middlewares.is_empty() {
+      return commit(self, None);
+    }
+    let mut context = CommandContext {
+      aggregate_id: aggregate.id(),
+      aggregate_version: aggregate.version(),
+      category: A::CATEGORY,
+      command_type,
+      command,
+      metadata: serde_json::to_value(metadata)
+        .map_err(ClientError::SerializationError)
+        .map_err(Either::Left)?,
+    };
+    let mut rejection = None;
+    let mut ran = 0;
+    for middleware in self.middlewares.iter_mut() {
+      if let Err(reason) = middleware.before(&mut context) {
+        rejection = Some(reason);
+        break;
+      }
+      ran += 1;
+    }
+    let result = match rejection {
+      None => commit(self, Some(&context.metadata)),
+      Some(reason) => Err(Either::Left(ClientError::Rejected(reason))),
+    };
+    for middleware in self.middlewares[..ran].iter_mut().rev() {
+      let outcome = result.as_ref().map(Borrow::borrow);
+      middleware.after(&context, outcome.map_err(|err| err as &dyn fmt::Display));
+    }
+    result
+  }
+
+  // Applies every command, then writes all of their commits in one store
+  // transaction, so either every command is committed or none is. Each command
+  // goes through the middleware as if issued alone, and a rejection of any one
+  // of them rejects them all. Each aggregate can have only one command.
+  pub fn issue_commands<C: Command, M: Serialize>(
+    &mut self,
+    commands: &[(&C::Aggregate, &C)],
+    metadata: &M,
+  ) -> Result<Vec<Commit>, Either<ClientError, C::Error>> {
+    let _span = info_span!(
+      "issue_commands",
+      commands = commands.len(),
+      command = type_name::<C>()
+    )
+    .entered();
+    let mut aggregate_ids = HashSet::with_capacity(commands.len());
+    for &(aggregate, _) in commands {
+      if !aggregate_ids.insert(aggregate.id()) {
+        return Err(Either::Left(ClientError::DuplicateAggregate(aggregate.id())));
+      }
+    }
+    let metadata = serde_json::to_value(metadata)
+      .map_err(ClientError::SerializationError)
+      .map_err(Either::Left)?;
+    let mut contexts = Vec::with_capacity(commands.len());
+    let mut ran = Vec::with_capacity(commands.len());
+    let mut rejection = None;
+    for &(aggregate, command) in commands {
+      let mut context = CommandContext {
+        aggregate_id: aggregate.id(),
+        aggregate_version: aggregate.version(),
+        category: C::Aggregate::CATEGORY,
+        command_type: type_name::<C>(),
+        command,
+        metadata: metadata.clone(),
+      };
+      let mut ran_for_command = 0;
+      for middleware in self.middlewares.iter_mut() {
+        if let Err(reason) = middleware.before(&mut context) {
+          rejection = Some(reason);
+          break;
+        }
+        ran_for_command += 1;
+      }
+      contexts.push(context);
+      ran.push(ran_for_command);
+      if rejection.is_some() {
+        break;
+      }
+    }
+    let result = match rejection {
+      None => self.commit_commands(commands, &contexts),
+      Some(reason) => Err(Either::Left(ClientError::Rejected(reason))),
+    };
+    for (index, context) in contexts.iter().enumerate().rev() {
+      let outcome = match result {
+        Ok(ref commits) => Ok(&commits[index]),
+        Err(ref err) => Err(err as &dyn fmt::Display),
+      };
+      for middleware in self.middlewares[..ran[index]].iter_mut().rev() {
+        middleware.after(context, outcome);
+      }
+    }
+    result
+  }
+
+  fn commit_commands<C: Command>(
+    &mut self,
+    commands: &[(&C::Aggregate, &C)],
+    contexts: &[CommandContext],
+  ) -> Result<Vec<Commit>, Either<ClientError, C::Error>> {
+    let mut commit_attempts = Vec::with_capacity(commands.len());
+    let mut events = Vec::with_capacity(commands.len());
+    for (&(aggregate, command), context) in commands.iter().zip(contexts) {
+      let aggregate_update_events = self.apply_command(aggregate, command)?;
+      let event_metadata: Vec<_> = aggregate_update_events
+        .iter()
+        .map(|event| command.event_metadata(event))
+        .collect();
+      let commit_attempt = self
+        .commit_attempt(
+          aggregate,
+          &aggregate_update_events,
+          &event_metadata,
+          &context.metadata,
+          Uuid::new_v4(),
+        )
+        .map_err(Either::Left)?;
+      commit_attempts.push(commit_attempt);
+      events.push(aggregate_update_events);
+    }
+    self
+      .commit_all(&commit_attempts)
+      .map_err(ClientError::StoreError)
+      .map_err(Either::Left)?;
+    let mut commits = Vec::with_capacity(commands.len());
+    let committed = commands.iter().zip(&commit_attempts).zip(events);
+    for ((&(aggregate, _), commit_attempt), events) in committed {
+      let outcome = self
+        .committed(aggregate, events, commit_attempt.commit_id)
+        .map_err(Either::Left)?;
+      commits.push(outcome.commit);
+    }
+    Ok(commits)
+  }
+
+  fn apply_command<C: Command>(
+    &self,
+    aggregate: &C::Aggregate,
+    command: &C,
+  ) -> Result<CommandEvents<C>, Either<ClientError, C::Error>> {
+    self
+      .check_precondition(aggregate, command.precondition())
+      .map_err(Either::Left)?;
+    command
+      .validate(aggregate)
+      .map_err(ClientError::Invalid)
+      .map_err(Either::Left)?;
+    let _span = info_span!("apply").entered();
+    command.apply(aggregate).map_err(Either::Right)
+  }
+
+  fn commit_events<A: Aggregate, M: Serialize>(
+    &mut self,
+    aggregate: &A,
+    events: Vec<A::Event>,
+    event_metadata: &[Map<String, Value>],
+    metadata: &M,
+    commit_id: Uuid,
+  ) -> Result<CommandOutcome<A>, ClientError> {
+    let commit_attempt =
+      self.commit_attempt(aggregate, &events, event_metadata, metadata, commit_id)?;
+    self.commit(&commit_attempt)?;
+    self.committed(aggregate, events, commit_attempt.commit_id)
+  }
+
+  // An aggregate this client hasn't fetched may still have commits.
+  fn head_commit_sequence(&self, aggregate_id: Uuid) -> Result<i64, ClientError> {
+    match self.heads.get(&aggregate_id) {
+      Some(&(commit_sequence, _)) => Ok(commit_sequence),
+      None => self
+        .store
+        .get_range(aggregate_id, 0, i64::MAX)
+        .map(|commits| commits.last().map_or(0, |commit| commit.commit_sequence))
+        .map_err(ClientError::from),
+    }
+  }
+
+  // The commit a new commit to the aggregate follows. A head restored from a
+  // snapshot, or never fetched, has to be read to learn its commit_id.
+  fn parent_commit_id(
+    &self,
+    aggregate_id: Uuid,
+    head_commit_sequence: i64,
+  ) -> Result<Option<Uuid>, ClientError> {
+    match self.heads.get(&aggregate_id) {
+      _ if head_commit_sequence == 0 => Ok(None),
+      Some(&(_, Some(commit_id))) => Ok(Some(commit_id)),
+      _ => Ok(self
+        .store
+        .get_range(aggregate_id, 0, i64::MAX)?
+        .into_iter()
+        .find(|commit| commit.commit_sequence == head_commit_sequence)
+        .map(|commit| commit.commit_id)),
+    }
+  }
+
+  fn check_precondition<A: Aggregate>(
+    &self,
+    aggregate: &A,
+    precondition: StreamPrecondition,
+  ) -> Result<(), ClientError> {
+    match self.stream_state(aggregate)? {
+      StreamState::Deleted => Err(ClientError::Deleted(aggregate.id())),
+      actual if precondition.allows(actual) => Ok(()),
+      actual => Err(ClientError::PreconditionFailed {
+        aggregate_id: aggregate.id(),
+        precondition,
+        actual,
+      }),
+    }
+  }
+
+  // Encodes the commit of `events` to the aggregate.
+  fn commit_attempt<A: Aggregate, M: Serialize>(
+    &mut self,
+    aggregate: &A,
+    events: &[A::Event],
+    event_metadata: &[Map<String, Value>],
+    metadata: &M,
+    commit_id: Uuid,
+  ) -> Result<CommitAttempt, ClientError> {
+    if aggregate.is_deleted() {
+      return Err(ClientError::Deleted(aggregate.id()));
+    }
+    #[cfg(feature = "bincode")]
+    {
+      if self.codec == Codec::Bincode && event_metadata.iter().any(|fields| !fields.is_empty()) {
+        return Err(ClientError::CodecError(CodecError::EncodingError(String::from(
+          "bincode commits can't hold per-event metadata",
+        ))));
+      }
+    }
+    let envelopes: Vec<EventEnvelope<_>> = events
+      .iter()
+      .enumerate()
+      .map(|(index, event)| {
+        let fields = event_metadata.get(index).cloned().unwrap_or_default();
+        EventEnvelope::from(event).with_metadata(fields)
+      })
+      .collect();
+    let events_buffer = self
+      .codec
+      .encode_as(&codec::events_subject(A::CATEGORY), &envelopes, &self.registries)
+      .and_then(|payload| self.payload_compression.compress(payload))?;
+    let metadata_buffer = self
+      .codec
+      .encode_as(&codec::metadata_subject(A::CATEGORY), metadata, &self.registries)
+      .and_then(|payload| self.payload_compression.compress(payload))?;
+    let head_commit_sequence = self.head_commit_sequence(aggregate.id())?;
+    let parent_commit_id = self.parent_commit_id(aggregate.id(), head_commit_sequence)?;
+    Ok(CommitAttempt {
+      aggregate_id: aggregate.id(),
+      aggregate_version: aggregate.version(),
+      category: String::from(A::CATEGORY),
+      tenant_id: String::new(),
+      parent_commit_id,
+      commit_id,
+      commit_timestamp: self.clock.now(),
+      commit_sequence: head_commit_sequence + 1,
+      serialized_metadata: metadata_buffer,
+      serialized_events: events_buffer,
+      events_count: events.len() as i64,
+    })
+  }
+
+  // Reads back a commit just made and brings the client up to date with it.
+  fn committed<A: Aggregate>(
+    &mut self,
+    aggregate: &A,
+    events: Vec<A::Event>,
+    commit_id: Uuid,
+  ) -> Result<CommandOutcome<A>, ClientError> {
+    let commit = self.store.get_commit(&commit_id)?;
+    self
+      .heads
+      .insert(commit.aggregate_id, (commit.commit_sequence, Some(commit.commit_id)));
+    let mut updated = aggregate.clone();
+    apply_events(&mut updated, &events, &commit)?;
+    // The command has already been committed, so a failed snapshot must not fail it.
+    let _unhandled_result = self.apply_snapshot_policy(&updated, &commit);
+    Ok(CommandOutcome {
+      events,
+      aggregate: updated,
+      commit,
+    })
+  }
+
+  fn apply_snapshot_policy<A: Aggregate>(
+    &mut self,
+    updated: &A,
+    commit: &Commit,
+  ) -> Result<(), ClientError> {
+    if let SnapshotPolicy::Never = self.snapshot_policy {
+      return Ok(());
+    }
+    let latest_snapshot = match self.snapshot_store {
+      Some(ref snapshot_store) => snapshot_store.get_latest_snapshot(commit.aggregate_id)?,
+      None => return Ok(()),
+    };
+    let due = self.snapshot_policy.should_snapshot(&SnapshotContext {
+      latest_snapshot: latest_snapshot.as_ref(),
+      commit,
+      aggregate_version: updated.version(),
+      now: self.clock.now(),
+    });
+    if due {
+      self.save_snapshot_at(updated, commit.commit_sequence)?;
+    }
+    Ok(())
+  }
+}
+
+// The commit id a command issued under `idempotency_key` gets, which is the
+// same every time the key is used against the aggregate.
+pub fn idempotent_commit_id(aggregate_id: Uuid, idempotency_key: &str) -> Uuid {
+  Uuid::new_v5(&aggregate_id, idempotency_key.as_bytes())
+}
+
+fn fold_commits<A: Aggregate>(
+  aggregate_id: Uuid,
+  mut commits: Vec<Commit>,
+  registries: &Registries,
+) -> Result<Option<A>, ClientError> {
+  if commits.is_empty() {
+    return Ok(None);
+  }
+  commits.sort_by_key(|commit| commit.aggregate_version);
+  check_history(aggregate_id, commits.first(), 0)?;
+  let mut aggregate = A::with_id(aggregate_id);
+  for commit in commits {
+    let events: Vec<A::Event> =
+      decode_events_with(commit.serialized_events.as_slice(), registries)?;
+    apply_events(&mut aggregate, &events, &commit)?;
+  }
+  Ok(Some(aggregate))
+}
+
+// Whether `first`, the first commit to replay after the one at `commit_sequence`,
+// follows it, rather than a gap a retention policy truncated; see
+// `store::retention`.
+fn check_history(
+  aggregate_id: Uuid,
+  first: Option<&Commit>,
+  commit_sequence: i64,
+) -> Result<(), ClientError> {
+  match first {
+    Some(commit) if commit.commit_sequence > commit_sequence + 1 => {
+      Err(ClientError::TruncatedHistory {
+        aggregate_id,
+        commit_sequence: commit.commit_sequence,
+      })
+    }
+    _ => Ok(()),
+  }
+}
+
+fn apply_events<A: Aggregate>(
+  aggregate: &mut A,
+  events: &[A::Event],
+  commit: &Commit,
+) -> Result<(), ClientError> {
+  for event in events {
+    aggregate
+      .try_apply_mut(event)
+      .map_err(|error| ClientError::ApplyError {
+        aggregate_id: commit.aggregate_id,
+        commit_id: commit.commit_id,
+        error,
+      })?;
+  }
+  Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::events::Event;
+  use super::super::snapshot::memory::InMemorySnapshotStore;
+  use super::super::snapshot::policy::SnapshotPolicy;
+  use super::super::store::sqlite::SqliteStore;
+  use super::super::subscription::EventTypeFilter;
+  use super::*;
+  use crate::clock::ManualClock;
+  #[cfg(feature = "cbor")]
+  use crate::codec::{self, EventCodec};
+  use chrono::{TimeZone, Utc};
+  use std::default::Default;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::{Arc, Mutex};
+  use tracing::field::{Field, Visit};
+  use tracing::span::{Attributes, Id, Record};
+  use tracing::Subscriber;
+  use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+  use uuid::Uuid;
+
+  struct MockDispatcher {
+    dispatched_id: Option<Uuid>,
+  }
+
+  impl DispatchDelegate for MockDispatcher {
+    fn dispatch(&mut self, commit: &Commit) -> Result<(), String> {
+      self.dispatched_id = Some(commit.commit_id);
+      Ok(())
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  enum MockEvent {
+    IncrementVersion,
+  }
+
+  impl Event for MockEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct MockAggregate {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for MockAggregate {
+    type Event = MockEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      MockAggregate { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &Self::Event) -> MockAggregate {
+      MockAggregate {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[test]
+  fn it_requires_store_and_dispatcher() {
+    assert!(ClientBuilder::<MockDispatcher, SqliteStore>::default()
+      .finish()
+      .is_err());
+    let dispatch_delegate = MockDispatcher {
+      dispatched_id: None,
+    };
+    assert_eq!(
+      "Cannot build a client; missing a store.",
+      ClientBuilder::<MockDispatcher, SqliteStore>::default()
+        .with_dispatch_delegate(dispatch_delegate)
+        .finish()
+        .err()
+        .unwrap()
+    );
+    assert_eq!(
+      "Cannot build a client; missing a dispatcher.",
+      ClientBuilder::<MockDispatcher, SqliteStore>::default()
+        .with_store(SqliteStore::with_new_in_memory_connection())
+        .finish()
+        .err()
+        .unwrap()
+    );
+  }
+
+  #[test]
+  fn it_dispatches() {
+    let dispatch_delegate = MockDispatcher {
+      dispatched_id: None,
+    };
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<MockDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(dispatch_delegate)
+      .finish()
+      .unwrap();
+    let commit_id = Uuid::new_v4();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id,
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    assert!(client.commit(&commit_attempt).is_ok());
+    assert_eq!(
+      Some(commit_id),
+      client.dispatcher.dispatch_delegate.dispatched_id
+    );
+  }
+
+  #[derive(Debug)]
+  struct MockError;
+
+  impl ::std::fmt::Display for MockError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+      write!(f, "MockError")
+    }
+  }
+
+  impl ::std::error::Error for MockError {}
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  struct MockCommand;
+
+  impl Command for MockCommand {
+    type Aggregate = MockAggregate;
+    type Error = MockError;
+
+    fn apply(&self, _aggregate: &MockAggregate) -> Result<Vec<MockEvent>, MockError> {
+      Ok(vec![MockEvent::IncrementVersion])
+    }
+  }
+
+  // Records whether it was applied, so tests can tell validation stopped it.
+  #[derive(Debug, Clone)]
+  struct Transfer {
+    amount: i64,
+    reference: String,
+    applied: Arc<AtomicBool>,
+  }
+
+  impl Command for Transfer {
+    type Aggregate = MockAggregate;
+    type Error = MockError;
+
+    fn validate(&self, _aggregate: &MockAggregate) -> Result<(), ValidationErrors> {
+      let mut errors = ValidationErrors::new();
+      if self.amount <= 0 {
+        errors.add("amount", "must be positive");
+      }
+      if self.reference.is_empty() {
+        errors.add("reference", "is required");
+      }
+      errors.into_result()
+    }
+
+    fn apply(&self, _aggregate: &MockAggregate) -> Result<Vec<MockEvent>, MockError> {
+      self.applied.store(true, Ordering::SeqCst);
+      Ok(vec![MockEvent::IncrementVersion])
+    }
+  }
+
+  #[test]
+  fn it_issues_commands_and_fetches_latest() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let commit = client
+      .issue_command(&aggregate, &MockCommand, &"metadata")
+      .unwrap()
+      .commit;
+    assert_eq!(commit.aggregate_id, aggregate.id());
+    assert_eq!(commit.events_count, 1);
+
+    let latest: MockAggregate = client.fetch_latest(aggregate.id()).unwrap();
+    assert_eq!(latest.version(), 1);
+  }
+
+  #[test]
+  fn it_fetches_aggregates_at_past_versions_and_times() {
+    let path = ::std::env::temp_dir().join(format!("event_source_past_{}.sqlite", Uuid::new_v4()));
+    let new_client = || {
+      let store = SqliteStore::with_new_connection_at_path(&path);
+      store.initialize();
+      ClientBuilder::<NullDispatcher, SqliteStore>::default()
+        .with_store(store)
+        .with_dispatch_delegate(NullDispatcher)
+        .finish()
+        .unwrap()
+    };
+    let aggregate_id = Uuid::new_v4();
+    let mut committed_at = Vec::new();
+    for _ in 0..3 {
+      let mut client = new_client();
+      let aggregate: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+      client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+      committed_at.push(Utc::now());
+    }
+    let client = new_client();
+
+    let version = |aggregate: Option<MockAggregate>| aggregate.map(|a| a.version());
+    assert_eq!(version(client.fetch_at_version(aggregate_id, 2).unwrap()), Some(2));
+    assert_eq!(version(client.fetch_at_version(aggregate_id, 0).unwrap()), None);
+    assert_eq!(version(client.fetch_as_of(aggregate_id, committed_at[1]).unwrap()), Some(2));
+    assert_eq!(
+      version(client.fetch_until(aggregate_id, Some(3), Some(committed_at[0])).unwrap()),
+      Some(1)
+    );
+    let before = committed_at[0] - ::chrono::Duration::seconds(60);
+    assert_eq!(version(client.fetch_as_of(aggregate_id, before).unwrap()), None);
+    let _ = ::std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn it_tracks_commit_sequences_per_aggregate() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let first_id = Uuid::new_v4();
+    let second_id = Uuid::new_v4();
+    for _ in 0..3 {
+      let first: MockAggregate = client.fetch_latest(first_id).unwrap();
+      client.issue_command(&first, &MockCommand, &"metadata").unwrap();
+    }
+    let second: MockAggregate = client.fetch_latest(second_id).unwrap();
+    let commit = client.issue_command(&second, &MockCommand, &"metadata").unwrap().commit;
+    assert_eq!(commit.commit_sequence, 1);
+    // Commands issued without refetching build on the previous commit.
+    let second = second.apply(&MockEvent::IncrementVersion);
+    let commit = client.issue_command(&second, &MockCommand, &"metadata").unwrap().commit;
+    assert_eq!(commit.commit_sequence, 2);
+
+    let first: MockAggregate = client.fetch_latest(first_id).unwrap();
+    assert_eq!(first.version(), 3);
+    assert_eq!(client.commit_sequence(first_id), 3);
+    let second: MockAggregate = client.fetch_latest(second_id).unwrap();
+    assert_eq!(second.version(), 2);
+    assert_eq!(client.commit_sequence(second_id), 2);
+    assert_eq!(client.commit_sequence(Uuid::new_v4()), 0);
+  }
+
+  #[test]
+  fn it_links_each_commit_to_its_parent() {
+    let path =
+      ::std::env::temp_dir().join(format!("event_source_parents_{}.sqlite", Uuid::new_v4()));
+    let new_client = || {
+      let store = SqliteStore::with_new_connection_at_path(&path);
+      store.initialize();
+      ClientBuilder::<NullDispatcher, SqliteStore>::default()
+        .with_store(store)
+        .with_dispatch_delegate(NullDispatcher)
+        .finish()
+        .unwrap()
+    };
+    let mut client = new_client();
+    let aggregate_id = Uuid::new_v4();
+    let aggregate: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    let first = client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+    assert_eq!(first.commit.parent_commit_id, None);
+    let second = client.issue_command(&first.aggregate, &MockCommand, &"metadata").unwrap();
+    assert_eq!(second.commit.parent_commit_id, Some(first.commit.commit_id));
+
+    // A client that never fetched the aggregate reads its head from the store.
+    let third = new_client()
+      .issue_command(&second.aggregate, &MockCommand, &"metadata")
+      .unwrap();
+    assert_eq!(third.commit.parent_commit_id, Some(second.commit.commit_id));
+    assert!(client.store.verify().unwrap().is_ok());
+    let _ = ::std::fs::remove_file(&path);
+  }
+
+  struct RecordingMiddleware {
+    name: &'static str,
+    calls: Arc<Mutex<Vec<String>>>,
+    reject: bool,
+  }
+
+  impl CommandMiddleware for RecordingMiddleware {
+    fn before(&mut self, context: &mut CommandContext) -> Result<(), String> {
+      self.calls.lock().unwrap().push(format!("before {}", self.name));
+      if self.reject {
+        return Err(format!("{} says no", self.name));
+      }
+      context.metadata[self.name] = serde_json::json!(context.aggregate_version);
+      Ok(())
+    }
+
+    fn after(&mut self, _context: &CommandContext, outcome: Result<&Commit, &dyn fmt::Display>) {
+      let outcome = match outcome {
+        Ok(commit) => commit.commit_sequence.to_string(),
+        Err(err) => err.to_string(),
+      };
+      self.calls.lock().unwrap().push(format!("after {} {}", self.name, outcome));
+    }
+  }
+
+  #[test]
+  fn it_runs_middleware_around_commands() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let middleware = |name, reject| RecordingMiddleware {
+      name,
+      calls: Arc::clone(&calls),
+      reject,
+    };
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_middleware(middleware("outer", false))
+      .with_middleware(middleware("inner", false))
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let commit = client
+      .issue_command(&aggregate, &MockCommand, &serde_json::json!({"user": "alice"}))
+      .unwrap()
+      .commit;
+    let metadata: serde_json::Value = serde_json::from_slice(&commit.serialized_metadata).unwrap();
+    assert_eq!(metadata, serde_json::json!({"user": "alice", "outer": 0, "inner": 0}));
+    assert_eq!(
+      *calls.lock().unwrap(),
+      vec!["before outer", "before inner", "after inner 1", "after outer 1"]
+    );
+
+    calls.lock().unwrap().clear();
+    client.middlewares.insert(1, Box::new(middleware("guard", true)));
+    match client.issue_command(&aggregate, &MockCommand, &()) {
+      Err(Either::Left(ClientError::Rejected(reason))) => assert_eq!(reason, "guard says no"),
+      _ => panic!("expected the command to be rejected"),
+    }
+    assert_eq!(
+      *calls.lock().unwrap(),
+      vec![
+        "before outer",
+        "before guard",
+        "after outer command rejected: guard says no"
+      ]
+    );
+    assert_eq!(client.store.get_range(aggregate.id(), 0, i64::MAX).unwrap().len(), 1);
+  }
+
+  #[cfg(feature = "cbor")]
+  #[test]
+  fn it_reads_commits_in_mixed_encodings() {
+    let path = ::std::env::temp_dir().join(format!("event_source_codec_{}.sqlite", Uuid::new_v4()));
+    let new_client = |codec| {
+      let store = SqliteStore::with_new_connection_at_path(&path);
+      store.initialize();
+      ClientBuilder::<NullDispatcher, SqliteStore>::default()
+        .with_store(store)
+        .with_dispatch_delegate(NullDispatcher)
+        .with_codec(codec)
+        .finish()
+        .unwrap()
+    };
+    let aggregate_id = Uuid::new_v4();
+    let mut json_client = new_client(Codec::Json);
+    let aggregate: MockAggregate = json_client.fetch_latest(aggregate_id).unwrap();
+    json_client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+
+    let mut cbor_client = new_client(Codec::Cbor);
+    let aggregate: MockAggregate = cbor_client.fetch_latest(aggregate_id).unwrap();
+    let commit = cbor_client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap().commit;
+    assert_eq!(codec::content_type(&commit.serialized_events), codec::Cbor::CONTENT_TYPE);
+    assert_eq!(commit.deserialize().unwrap().metadata, serde_json::json!("metadata"));
+
+    let latest: MockAggregate = json_client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 2);
+    let _ = ::std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn it_compresses_large_payloads() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_payload_compression(Compression::Zstd, 256)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let metadata = "note ".repeat(200);
+    let commit = client.issue_command(&aggregate, &MockCommand, &metadata).unwrap().commit;
+    assert!(commit.serialized_metadata.len() < metadata.len());
+    assert_eq!(commit.deserialize().unwrap().metadata, serde_json::json!(metadata));
+    // The events are under the threshold, so they are stored as they are.
+    assert_eq!(commit.serialized_events[0], b'[');
+    let latest: MockAggregate = client.fetch_latest(aggregate.id()).unwrap();
+    assert_eq!(latest.version(), 1);
+  }
+
+  #[cfg(feature = "avro")]
+  #[test]
+  fn it_reads_avro_commits_written_with_older_schemas() {
+    let schemas = crate::avro::Schemas::new(crate::avro::InMemorySchemaRegistry::default());
+    let events_subject = codec::events_subject(MockAggregate::CATEGORY);
+    let metadata_subject = codec::metadata_subject(MockAggregate::CATEGORY);
+    schemas
+      .register(
+        &events_subject,
+        r#"{"type": "array", "items": {"type": "record", "name": "Envelope", "fields": [
+          {"name": "event_type", "type": "string"},
+          {"name": "schema_version", "type": "int"},
+          {"name": "data", "type": {"type": "enum", "name": "MockEvent", "symbols": ["IncrementVersion"]}},
+          {"name": "metadata", "type": {"type": "map", "values": "string"}, "default": {}}
+        ]}}"#,
+      )
+      .unwrap();
+    schemas
+      .register(
+        &metadata_subject,
+        r#"{"type": "record", "name": "Metadata", "fields": [{"name": "actor", "type": "string"}]}"#,
+      )
+      .unwrap();
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_codec(Codec::Avro)
+      .with_avro_schemas(schemas.clone())
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let metadata = serde_json::json!({"actor": "alice"});
+    let commit = client.issue_command(&aggregate, &MockCommand, &metadata).unwrap().commit;
+    assert_eq!(codec::content_type(&commit.serialized_events), codec::Avro::CONTENT_TYPE);
+
+    schemas
+      .register(
+        &metadata_subject,
+        r#"{"type": "record", "name": "Metadata", "fields": [
+          {"name": "actor", "type": "string"},
+          {"name": "channel", "type": "string", "default": "web"}
+        ]}"#,
+      )
+      .unwrap();
+    assert_eq!(
+      codec::decode_with::<Value>(&commit.serialized_metadata, &client.registries).unwrap(),
+      serde_json::json!({"actor": "alice", "channel": "web"})
+    );
+    assert!(codec::decode::<Value>(&commit.serialized_metadata).is_err());
+    let latest: MockAggregate = client.fetch_latest(aggregate.id()).unwrap();
+    assert_eq!(latest.version(), 1);
+    let events = serde_json::json!([{"event_type": "Unknown", "schema_version": 1, "data": "Gone"}]);
+    assert!(Codec::Avro
+      .encode_as(&events_subject, &events, &client.registries)
+      .is_err());
+    assert!(Codec::Avro
+      .encode_as(&metadata_subject, &metadata, &Registries::default())
+      .is_err());
+  }
+
+  #[test]
+  fn it_merges_provided_metadata() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_metadata_provider(metadata::StaticMetadata::default().with("host", "web-1"))
+      .with_metadata_provider(metadata::LibraryVersion)
+      .with_metadata_provider(|context: &CommandContext| {
+        let mut fields = serde_json::Map::new();
+        fields.insert(String::from("category"), serde_json::json!(context.category));
+        fields.insert(String::from("user"), serde_json::json!("system"));
+        fields
+      })
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+
+    let commit = client
+      .issue_command(&aggregate, &MockCommand, &serde_json::json!({"user": "alice"}))
+      .unwrap()
+      .commit;
+    let metadata = commit.deserialize().unwrap().metadata;
+    assert_eq!(metadata["user"], "alice");
+    assert_eq!(metadata["host"], "web-1");
+    assert_eq!(metadata["category"], "");
+    assert_eq!(metadata["event_source_version"], env!("CARGO_PKG_VERSION"));
+
+    let aggregate = aggregate.apply(&MockEvent::IncrementVersion);
+    let commit = client.issue_command(&aggregate, &MockCommand, &()).unwrap().commit;
+    assert_eq!(commit.deserialize().unwrap().metadata["user"], "system");
+  }
+
+  struct RecordedSpan {
+    id: Id,
+    name: &'static str,
+    fields: HashMap<String, String>,
+  }
+
+  // The name and fields of each span, with the fields recorded after it opened.
+  #[derive(Clone, Default)]
+  struct SpanRecorder(Arc<Mutex<Vec<RecordedSpan>>>);
+
+  impl SpanRecorder {
+    // The fields of the latest span called `name`.
+    fn span(&self, name: &str) -> HashMap<String, String> {
+      let spans = self.0.lock().unwrap();
+      let span = spans.iter().rev().find(|span| span.name == name);
+      span.map(|span| span.fields.clone()).unwrap_or_else(|| panic!("no {} span", name))
+    }
+  }
+
+  struct FieldRecorder<'a>(&'a mut HashMap<String, String>);
+
+  impl<'a> Visit for FieldRecorder<'a> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+      self.0.insert(String::from(field.name()), format!("{:?}", value));
+    }
+  }
+
+  impl<S: Subscriber> Layer<S> for SpanRecorder {
+    fn on_new_span(&self, attributes: &Attributes, id: &Id, _context: Context<S>) {
+      let mut fields = HashMap::new();
+      attributes.record(&mut FieldRecorder(&mut fields));
+      self.0.lock().unwrap().push(RecordedSpan {
+        id: id.clone(),
+        name: attributes.metadata().name(),
+        fields,
+      });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, _context: Context<S>) {
+      let mut spans = self.0.lock().unwrap();
+      if let Some(span) = spans.iter_mut().rev().find(|span| span.id == *id) {
+        values.record(&mut FieldRecorder(&mut span.fields));
+      }
+    }
+  }
+
+  #[test]
+  fn it_traces_commits_and_fetches() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let recorder = SpanRecorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+    let commit = tracing::subscriber::with_default(subscriber, || {
+      let commit = client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap().commit;
+      let _: MockAggregate = client.fetch_latest(aggregate.id()).unwrap();
+      commit
+    });
+
+    let span = recorder.span("commit");
+    assert_eq!(span["aggregate_id"], aggregate.id().to_string());
+    assert_eq!(span["version"], "0");
+    assert_eq!(span["commit_id"], commit.commit_id.to_string());
+    let span = recorder.span("fetch");
+    assert_eq!(span["aggregate_id"], aggregate.id().to_string());
+    assert_eq!(span["version"], "1");
+  }
+
+  #[test]
+  fn it_stamps_commits_and_snapshots_with_its_clock() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let backfill_start = Utc.with_ymd_and_hms(2019, 3, 1, 12, 0, 0).unwrap();
+    let clock = ManualClock::new(backfill_start);
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .with_snapshot_policy(SnapshotPolicy::Every(::chrono::Duration::days(1)))
+      .with_clock(clock.clone())
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let aggregate = MockAggregate::with_id(aggregate_id);
+    let commit = client.issue_command(&aggregate, &MockCommand, &()).unwrap().commit;
+    assert_eq!(commit.commit_timestamp, backfill_start);
+    let snapshot = |client: &Client<NullDispatcher, SqliteStore>| {
+      let snapshot_store = client.snapshot_store.as_ref().unwrap();
+      snapshot_store.get_latest_snapshot(aggregate_id).unwrap().unwrap()
+    };
+    assert_eq!(snapshot(&client).snapshot_timestamp, backfill_start);
+
+    // An hour later is too soon for another snapshot; two days later is not.
+    clock.advance(::chrono::Duration::hours(1));
+    let aggregate = aggregate.apply(&MockEvent::IncrementVersion);
+    client.issue_command(&aggregate, &MockCommand, &()).unwrap();
+    assert_eq!(snapshot(&client).aggregate_version, 1);
+    clock.advance(::chrono::Duration::days(2));
+    let aggregate = aggregate.apply(&MockEvent::IncrementVersion);
+    client.issue_command(&aggregate, &MockCommand, &()).unwrap();
+    assert_eq!(snapshot(&client).aggregate_version, 3);
+
+    let as_of = backfill_start + ::chrono::Duration::minutes(30);
+    let past: Option<MockAggregate> = client.fetch_as_of(aggregate_id, as_of).unwrap();
+    assert_eq!(past.map(|aggregate| aggregate.version()), Some(1));
+  }
+
+  #[test]
+  fn it_issues_commands_once_per_idempotency_key() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let first = client
+      .issue_command_idempotently(&aggregate, &MockCommand, &(), "request-1")
+      .unwrap();
+    assert_eq!(first.commit_id, idempotent_commit_id(aggregate.id(), "request-1"));
+    // The retry is stale, but gets the first attempt's commit back.
+    let retry = client
+      .issue_command_idempotently(&aggregate, &MockCommand, &(), "request-1")
+      .unwrap();
+    assert_eq!(retry.commit_number, first.commit_number);
+
+    let aggregate: MockAggregate = client.fetch_latest(aggregate.id()).unwrap();
+    let second = client
+      .issue_command_idempotently(&aggregate, &MockCommand, &(), "request-2")
+      .unwrap();
+    assert_ne!(second.commit_id, first.commit_id);
+    assert_eq!(client.store.get_range(aggregate.id(), 0, i64::MAX).unwrap().len(), 2);
+  }
+
+  // Replays must not go through `apply`, which clones.
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct InPlaceAggregate {
+    id: Uuid,
+    events: Vec<String>,
+  }
+
+  impl Aggregate for InPlaceAggregate {
+    type Event = MockEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      InPlaceAggregate { id, events: Vec::new() }
+    }
+
+    fn apply(&self, _event: &MockEvent) -> InPlaceAggregate {
+      panic!("applied by cloning")
+    }
+
+    fn apply_mut(&mut self, event: &MockEvent) {
+      self.events.push(format!("{:?}", event));
+    }
+
+    fn version(&self) -> i64 {
+      self.events.len() as i64
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[test]
+  fn it_replays_events_in_place() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    for _ in 0..3 {
+      let aggregate: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+      client.issue_command(&aggregate, &MockCommand, &()).unwrap();
+    }
+
+    let latest: InPlaceAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.events.len(), 3);
+    let past: Option<InPlaceAggregate> = client.fetch_at_version(aggregate_id, 2).unwrap();
+    assert_eq!(past.unwrap().version(), 2);
+  }
+
+  // Refuses any event past its second.
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct CappedAggregate {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for CappedAggregate {
+    type Event = MockEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      CappedAggregate { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &MockEvent) -> CappedAggregate {
+      CappedAggregate {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    fn try_apply_mut(&mut self, event: &MockEvent) -> Result<(), ApplyError> {
+      if self.version == 2 {
+        return Err(ApplyError::new(format!("{:?} past the cap", event)));
+      }
+      self.apply_mut(event);
+      Ok(())
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[test]
+  fn it_reports_events_aggregates_refuse() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let mut commits = Vec::new();
+    for _ in 0..3 {
+      let aggregate: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+      commits.push(client.issue_command(&aggregate, &MockCommand, &()).unwrap().commit);
+    }
+
+    match client.fetch_latest::<CappedAggregate>(aggregate_id) {
+      Err(ClientError::ApplyError {
+        aggregate_id: refused_aggregate_id,
+        commit_id,
+        error,
+      }) => {
+        assert_eq!(refused_aggregate_id, aggregate_id);
+        assert_eq!(commit_id, commits[2].commit_id);
+        assert_eq!(error.reason, "IncrementVersion past the cap");
+      }
+      other => panic!("unexpected {:?}", other.map(|aggregate| aggregate.version())),
+    }
+    let past: Option<CappedAggregate> = client.fetch_at_version(aggregate_id, 2).unwrap();
+    assert_eq!(past.unwrap().version(), 2);
+    assert!(client.fetch_at_version::<CappedAggregate>(aggregate_id, 3).is_err());
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  enum AccountEvent {
+    Opened,
+    Closed,
+  }
+
+  impl Event for AccountEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct Account {
+    id: Uuid,
+    version: i64,
+    closed: bool,
+  }
+
+  impl Aggregate for Account {
+    type Event = AccountEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Account {
+        id,
+        ..Default::default()
+      }
+    }
+
+    fn apply(&self, event: &AccountEvent) -> Account {
+      Account {
+        id: self.id,
+        version: self.version + 1,
+        closed: match *event {
+          AccountEvent::Opened => false,
+          AccountEvent::Closed => true,
+        },
+      }
+    }
+
+    fn is_deleted(&self) -> bool {
+      self.closed
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  enum AccountCommand {
+    Open,
+    Close,
+  }
+
+  impl Command for AccountCommand {
+    type Aggregate = Account;
+    type Error = MockError;
+
+    fn precondition(&self) -> StreamPrecondition {
+      match *self {
+        AccountCommand::Open => StreamPrecondition::MustNotExist,
+        AccountCommand::Close => StreamPrecondition::MustExist,
+      }
+    }
+
+    fn apply(&self, _aggregate: &Account) -> Result<Vec<AccountEvent>, MockError> {
+      match *self {
+        AccountCommand::Open => Ok(vec![AccountEvent::Opened]),
+        AccountCommand::Close => Ok(vec![AccountEvent::Closed]),
+      }
+    }
+  }
+
+  #[test]
+  fn it_tracks_stream_lifecycles() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let account: Account = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(client.stream_state(&account).unwrap(), StreamState::NotCreated);
+    match client.issue_command(&account, &AccountCommand::Close, &()) {
+      Err(Either::Left(ClientError::PreconditionFailed {
+        precondition,
+        actual,
+        ..
+      })) => {
+        assert_eq!(precondition, StreamPrecondition::MustExist);
+        assert_eq!(actual, StreamState::NotCreated);
+      }
+      other => panic!("unexpected {:?}", other.map(|outcome| outcome.commit)),
+    }
+
+    client.issue_command(&account, &AccountCommand::Open, &()).unwrap();
+    let account: Account = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(client.stream_state(&account).unwrap(), StreamState::Live);
+    match client.issue_command(&account, &AccountCommand::Open, &()) {
+      Err(Either::Left(err @ ClientError::PreconditionFailed { .. })) => assert_eq!(
+        err.to_string(),
+        format!("aggregate {} must not exist but is live", aggregate_id)
+      ),
+      other => panic!("unexpected {:?}", other.map(|outcome| outcome.commit)),
+    }
+
+    client.issue_command(&account, &AccountCommand::Close, &()).unwrap();
+    let account: Account = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(client.stream_state(&account).unwrap(), StreamState::Deleted);
+    match client.issue_command(&account, &AccountCommand::Open, &()) {
+      Err(Either::Left(ClientError::Deleted(deleted_id))) => assert_eq!(deleted_id, aggregate_id),
+      other => panic!("unexpected {:?}", other.map(|outcome| outcome.commit)),
+    }
+    assert_eq!(client.commit_sequence(aggregate_id), 2);
+  }
+
+  #[test]
+  fn it_returns_what_commands_produced() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let outcome = client.issue_command(&aggregate, &MockCommand, &()).unwrap();
+    match outcome.events[..] {
+      [MockEvent::IncrementVersion] => {}
+      ref events => panic!("unexpected {:?}", events),
+    }
+    assert_eq!(outcome.aggregate.version(), 1);
+    assert_eq!(outcome.commit.events_count, 1);
+    assert_eq!(client.commit_sequence(aggregate.id()), outcome.commit.commit_sequence);
+
+    let outcome = client.issue_command(&outcome.aggregate, &MockCommand, &()).unwrap();
+    let latest: MockAggregate = client.fetch_latest(aggregate.id()).unwrap();
+    assert_eq!(outcome.aggregate.version(), latest.version());
+  }
+
+  #[test]
+  fn it_issues_commands_atomically() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let order = MockAggregate::with_id(Uuid::new_v4());
+    let stock = MockAggregate::with_id(Uuid::new_v4());
+    let commits = client
+      .issue_commands(&[(&order, &MockCommand), (&stock, &MockCommand)], &"checkout")
+      .unwrap();
+    assert_eq!(commits.len(), 2);
+    assert_eq!(commits[0].aggregate_id, order.id());
+    assert_eq!(commits[1].aggregate_id, stock.id());
+    assert_eq!(client.commit_sequence(stock.id()), 1);
+
+    // The stale stock aggregate conflicts, so the order isn't committed either.
+    let order = order.apply(&MockEvent::IncrementVersion);
+    match client.issue_commands(&[(&order, &MockCommand), (&stock, &MockCommand)], &"checkout") {
+      Err(Either::Left(ClientError::StoreError(err))) => assert_eq!(
+        err.error_type(),
+        StoreErrorType::DuplicateWriteError(StorageCommitConflict::AggregateVersionConflict)
+      ),
+      other => panic!("unexpected {:?}", other.map(|commits| commits.len())),
+    }
+    let latest: MockAggregate = client.fetch_latest(order.id()).unwrap();
+    assert_eq!(latest.version(), 1);
+  }
+
+  #[test]
+  fn it_refuses_invalid_commands_before_applying_them() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let transfer = Transfer {
+      amount: -5,
+      reference: String::new(),
+      applied: Arc::new(AtomicBool::new(false)),
+    };
+    match client.issue_command(&aggregate, &transfer, &()) {
+      Err(Either::Left(ClientError::Invalid(errors))) => {
+        let fields: Vec<_> = errors
+          .fields
+          .iter()
+          .map(|error| (error.field.as_str(), error.message.as_str()))
+          .collect();
+        assert_eq!(fields, vec![("amount", "must be positive"), ("reference", "is required")]);
+      }
+      other => panic!("unexpected {:?}", other.map(|outcome| outcome.commit.commit_id)),
+    }
+    assert!(!transfer.applied.load(Ordering::SeqCst));
+    assert!(client.store.get_range(aggregate.id(), 0, i64::MAX).unwrap().is_empty());
+
+    let transfer = Transfer {
+      amount: 5,
+      reference: String::from("invoice 12"),
+      ..transfer
+    };
+    client.issue_command(&aggregate, &transfer, &()).unwrap();
+    assert!(transfer.applied.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn it_refuses_two_commands_for_one_aggregate_in_a_batch() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let order = MockAggregate::with_id(Uuid::new_v4());
+    let stock = MockAggregate::with_id(Uuid::new_v4());
+    let batch = [(&order, &MockCommand), (&stock, &MockCommand), (&order, &MockCommand)];
+    match client.issue_commands(&batch, &"checkout") {
+      Err(Either::Left(ClientError::DuplicateAggregate(aggregate_id))) => {
+        assert_eq!(aggregate_id, order.id())
+      }
+      other => panic!("unexpected {:?}", other.map(|commits| commits.len())),
+    }
+    assert!(client.store.get_range(order.id(), 0, i64::MAX).unwrap().is_empty());
+    assert!(client.store.get_range(stock.id(), 0, i64::MAX).unwrap().is_empty());
+  }
+
+  #[test]
+  fn it_iterates_typed_events() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    for _ in 0..3 {
+      let aggregate: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+      client.issue_command(&aggregate, &MockCommand, &()).unwrap();
+    }
+
+    let events: Vec<EventEnvelope<MockEvent>> = client
+      .events::<MockAggregate, _>(aggregate_id, ..)
+      .collect::<Result<_, _>>()
+      .unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].event_type, "IncrementVersion");
+    assert_eq!(events[0].schema_version, 1);
+    assert_eq!(client.events::<MockAggregate, _>(aggregate_id, 1..2).count(), 1);
+    assert_eq!(client.events::<MockAggregate, _>(aggregate_id, 1..).count(), 2);
+    assert_eq!(client.events::<MockAggregate, _>(Uuid::new_v4(), ..).count(), 0);
+  }
+
+  #[derive(Debug, Clone)]
+  struct ActedCommand(String);
+
+  impl Command for ActedCommand {
+    type Aggregate = MockAggregate;
+    type Error = MockError;
+
+    fn apply(&self, _aggregate: &MockAggregate) -> Result<Vec<MockEvent>, MockError> {
+      Ok(vec![MockEvent::IncrementVersion, MockEvent::IncrementVersion])
+    }
+
+    fn event_metadata(&self, _event: &MockEvent) -> Map<String, Value> {
+      let mut fields = Map::new();
+      fields.insert(String::from("actor"), Value::from(self.0.clone()));
+      fields
+    }
+  }
+
+  #[test]
+  fn it_stores_metadata_with_each_event() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    let command = ActedCommand(String::from("teller-7"));
+    let outcome = client.issue_command(&aggregate, &command, &()).unwrap();
+
+    let events: Vec<EventEnvelope<MockEvent>> = client
+      .events::<MockAggregate, _>(aggregate.id(), ..)
+      .collect::<Result<_, _>>()
+      .unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|event| event.metadata["actor"] == "teller-7"));
+    let deserialized = outcome.commit.deserialize().unwrap();
+    assert_eq!(deserialized.events[1]["metadata"]["actor"], "teller-7");
+    let filter = EventTypeFilter::new(vec!["IncrementVersion"]);
+    assert!(filter.matches(&outcome.commit));
+
+    let commit = client.issue_command(&outcome.aggregate, &MockCommand, &()).unwrap().commit;
+    assert!(commit.deserialize().unwrap().events[0].get("metadata").is_none());
+  }
+
+  #[cfg(feature = "bincode")]
+  #[test]
+  fn it_refuses_event_metadata_in_bincode_commits() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_codec(Codec::Bincode)
+      .finish()
+      .unwrap();
+    let aggregate = MockAggregate::with_id(Uuid::new_v4());
+    match client.issue_command(&aggregate, &ActedCommand(String::from("teller-7")), &()) {
+      Err(Either::Left(ClientError::CodecError(_))) => {}
+      other => panic!("unexpected {:?}", other.map(|outcome| outcome.commit)),
+    }
+    client.issue_command(&aggregate, &MockCommand, &()).unwrap();
+    let events: Vec<EventEnvelope<MockEvent>> = client
+      .events::<MockAggregate, _>(aggregate.id(), ..)
+      .collect::<Result<_, _>>()
+      .unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].metadata.is_empty());
+  }
+
+  #[test]
+  fn it_resumes_from_the_latest_snapshot() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let aggregate = MockAggregate::with_id(aggregate_id);
+    client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+    let latest: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 1);
+
+    // A snapshot that claims a later version than the events show is what the
+    // client resumes from, which proves the earlier commit is not replayed.
+    let snapshot = client
+      .save_snapshot(&MockAggregate {
+        id: aggregate_id,
+        version: 5,
+      })
+      .unwrap();
+    assert_eq!(snapshot.aggregate_version, 5);
+    let latest: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 5);
+  }
+
+  #[test]
+  fn it_resumes_from_compressed_snapshots() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .with_snapshot_compression(Compression::Zstd)
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let aggregate = MockAggregate {
+      id: aggregate_id,
+      version: 3,
+    };
+    let snapshot = client.save_snapshot(&aggregate).unwrap();
+    assert_eq!(snapshot.compression, Compression::Zstd);
+    assert_ne!(snapshot.serialized_state, serde_json::to_vec(&aggregate).unwrap());
+    let latest: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 3);
+  }
+
+  #[test]
+  fn it_ignores_snapshots_from_another_schema_version() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let aggregate = MockAggregate::with_id(aggregate_id);
+    client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+    client
+      .snapshot_store
+      .as_mut()
+      .unwrap()
+      .save_snapshot(&Snapshot {
+        aggregate_id,
+        aggregate_version: 5,
+        commit_sequence: 1,
+        schema_version: MockAggregate::SNAPSHOT_SCHEMA_VERSION + 1,
+        snapshot_timestamp: Utc::now(),
+        compression: Compression::None,
+        serialized_state: serde_json::to_vec(&MockAggregate {
+          id: aggregate_id,
+          version: 5,
+        })
+        .unwrap(),
+      })
+      .unwrap();
+    let latest: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 1);
+  }
+
+  #[test]
+  fn it_replays_past_snapshots_it_cannot_read() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let aggregate = MockAggregate::with_id(aggregate_id);
+    client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+    let corrupt = Snapshot {
+      aggregate_id,
+      aggregate_version: 5,
+      commit_sequence: 1,
+      schema_version: MockAggregate::SNAPSHOT_SCHEMA_VERSION,
+      snapshot_timestamp: Utc::now(),
+      compression: Compression::Gzip,
+      serialized_state: b"not gzip".to_vec(),
+    };
+    assert!(corrupt.deserialize().is_err());
+    let snapshot_store = client.snapshot_store.as_mut().unwrap();
+    snapshot_store.save_snapshot(&corrupt).unwrap();
+    let latest: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 1);
+  }
+
+  #[test]
+  fn it_refuses_to_replay_truncated_histories() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let mut aggregate = MockAggregate::with_id(aggregate_id);
+    for _ in 0..3 {
+      client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+      aggregate = client.fetch_latest(aggregate_id).unwrap();
+    }
+    client.save_snapshot(&aggregate).unwrap();
+    // As `KeepFromSnapshot` retention would.
+    client.store.truncate_through(aggregate_id, 2).unwrap();
+    let latest: MockAggregate = client.fetch_latest(aggregate_id).unwrap();
+    assert_eq!(latest.version(), 3);
+
+    // Without the snapshot, the remaining commit alone would be folded from
+    // scratch into the wrong state.
+    client.snapshot_store = None;
+    match client.fetch_latest::<MockAggregate>(aggregate_id) {
+      Err(ClientError::TruncatedHistory {
+        commit_sequence: 3, ..
+      }) => {}
+      other => panic!("expected a truncated history, got {:?}", other.map(|a| a.version())),
+    }
+    assert!(matches!(
+      client.fetch_as_of::<MockAggregate>(aggregate_id, Utc::now()),
+      Err(ClientError::TruncatedHistory { .. })
+    ));
+  }
+
+  #[test]
+  fn it_snapshots_according_to_the_policy() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut client = ClientBuilder::<NullDispatcher, SqliteStore>::default()
+      .with_store(store)
+      .with_dispatch_delegate(NullDispatcher)
+      .with_snapshot_store(InMemorySnapshotStore::default())
+      .with_snapshot_policy(SnapshotPolicy::EveryEvents(2))
+      .finish()
+      .unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let mut aggregate = MockAggregate::with_id(aggregate_id);
+    let mut snapshot_versions = Vec::new();
+    for _ in 0..4 {
+      client.issue_command(&aggregate, &MockCommand, &"metadata").unwrap();
+      aggregate = client.fetch_latest(aggregate_id).unwrap();
+      let snapshot_store = client.snapshot_store.as_ref().unwrap();
+      snapshot_versions.push(
+        snapshot_store
+          .get_latest_snapshot(aggregate_id)
+          .unwrap()
+          .map(|s| s.aggregate_version),
+      );
+    }
+    assert_eq!(snapshot_versions, vec![None, Some(2), Some(2), Some(4)]);
+  }
+}
@@ -0,0 +1,46 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+// Where `Client` gets the time it stamps commits and snapshots with.
+pub trait Clock: Send {
+  fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> DateTime<Utc> {
+    Utc::now()
+  }
+}
+
+// A clock that only moves when told to, for tests that freeze time and for
+// backfills that stamp commits with historical times. Clones share the time, so
+// a clone kept outside the client can move the client's clock.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+  now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+  pub fn new(now: DateTime<Utc>) -> ManualClock {
+    ManualClock {
+      now: Arc::new(Mutex::new(now)),
+    }
+  }
+
+  pub fn set(&self, now: DateTime<Utc>) {
+    *self.now.lock().unwrap() = now;
+  }
+
+  pub fn advance(&self, by: Duration) {
+    *self.now.lock().unwrap() += by;
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> DateTime<Utc> {
+    *self.now.lock().unwrap()
+  }
+}
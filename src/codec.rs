@@ -0,0 +1,481 @@
+#[cfg(feature = "avro")]
+use crate::avro;
+#[cfg(feature = "protobuf")]
+use crate::protobuf;
+use crate::snapshot::compression::Compression;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Error as JsonError;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+
+// How `Client` encodes the events and metadata of the commits it makes. Every
+// encoding but JSON prefixes its payload with its `CONTENT_TYPE` byte, which no
+// JSON document starts with, so commits in different encodings can share a
+// store and JSON commits stay readable by everything that expects JSON.
+//
+// Stored payloads may carry other prefixes ahead of the content type, so each
+// layer has bytes of its own: codecs 0x01-0x03 and 0x10-0x1f, encryption
+// 0x04-0x05 and compression 0x06-0x07. JSON whitespace (0x09, 0x0a, 0x0d) is
+// never used.
+pub trait EventCodec {
+  const CONTENT_TYPE: u8;
+
+  fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError>;
+  fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+  JsonError(JsonError),
+  EncodingError(String),
+  // The content type byte names a codec this build doesn't include.
+  UnknownContentType(u8),
+  CompressionError(io::Error),
+}
+
+impl fmt::Display for CodecError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      CodecError::JsonError(ref err) => write!(f, "json error: {}", err),
+      CodecError::EncodingError(ref err) => write!(f, "encoding error: {}", err),
+      CodecError::UnknownContentType(content_type) => {
+        write!(f, "unknown content type {}", content_type)
+      }
+      CodecError::CompressionError(ref err) => write!(f, "compression error: {}", err),
+    }
+  }
+}
+
+impl ::std::error::Error for CodecError {}
+
+impl From<JsonError> for CodecError {
+  fn from(error: JsonError) -> CodecError {
+    CodecError::JsonError(error)
+  }
+}
+
+pub struct Json;
+
+impl EventCodec for Json {
+  const CONTENT_TYPE: u8 = 0;
+
+  fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    Ok(serde_json::to_vec(value)?)
+  }
+
+  fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    Ok(serde_json::from_slice(bytes)?)
+  }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl EventCodec for Cbor {
+  const CONTENT_TYPE: u8 = 1;
+
+  fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = vec![Self::CONTENT_TYPE];
+    Cbor::write(value, &mut bytes)?;
+    Ok(bytes)
+  }
+
+  fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    Cbor::from_slice(&bytes[1..])
+  }
+}
+
+// Plain CBOR, without the content type byte, as the HTTP API exchanges it.
+#[cfg(feature = "cbor")]
+impl Cbor {
+  pub fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = Vec::new();
+    Cbor::write(value, &mut bytes)?;
+    Ok(bytes)
+  }
+
+  pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    ciborium::de::from_reader(bytes).map_err(|err| CodecError::EncodingError(err.to_string()))
+  }
+
+  fn write<T: Serialize>(value: &T, bytes: &mut Vec<u8>) -> Result<(), CodecError> {
+    ciborium::ser::into_writer(value, bytes).map_err(|err| CodecError::EncodingError(err.to_string()))
+  }
+}
+
+// Structs are encoded as maps, so commits can be read without their types.
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl EventCodec for MessagePack {
+  const CONTENT_TYPE: u8 = 2;
+
+  fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = vec![Self::CONTENT_TYPE];
+    rmp_serde::encode::write_named(&mut bytes, value)
+      .map_err(|err| CodecError::EncodingError(err.to_string()))?;
+    Ok(bytes)
+  }
+
+  fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    rmp_serde::from_slice(&bytes[1..]).map_err(|err| CodecError::EncodingError(err.to_string()))
+  }
+}
+
+// Bincode isn't self-describing: its commits can only be decoded into the
+// types they were encoded from, so event type filters and the HTTP API can't
+// read them.
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl EventCodec for Bincode {
+  const CONTENT_TYPE: u8 = 3;
+
+  fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = vec![Self::CONTENT_TYPE];
+    bincode::serialize_into(&mut bytes, value)
+      .map_err(|err| CodecError::EncodingError(err.to_string()))?;
+    Ok(bytes)
+  }
+
+  fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    bincode::deserialize(&bytes[1..]).map_err(|err| CodecError::EncodingError(err.to_string()))
+  }
+}
+
+// Events and metadata as an `event_source.storage.Payload`, so protobuf readers
+// in any language can decode them; see `protobuf`.
+#[cfg(feature = "protobuf")]
+pub struct Protobuf;
+
+#[cfg(feature = "protobuf")]
+impl EventCodec for Protobuf {
+  const CONTENT_TYPE: u8 = 0x10;
+
+  fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = vec![Self::CONTENT_TYPE];
+    bytes.extend(protobuf::encode_payload(&serde_json::to_value(value)?)?);
+    Ok(bytes)
+  }
+
+  fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    Ok(serde_json::from_value(protobuf::decode_payload(&bytes[1..])?)?)
+  }
+}
+
+// Avro, with schemas from an `avro::Schemas` registry. The content type byte
+// is followed by the writer schema's id, framed as Confluent's serializers
+// frame it. It isn't an `EventCodec`, since writing takes the subject whose
+// schema to write with; see `Codec::encode_as`.
+#[cfg(feature = "avro")]
+pub struct Avro;
+
+#[cfg(feature = "avro")]
+impl Avro {
+  pub const CONTENT_TYPE: u8 = 0x11;
+
+  pub fn encode<T: Serialize>(
+    schemas: &avro::Schemas,
+    subject: &str,
+    value: &T,
+  ) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = vec![Self::CONTENT_TYPE];
+    bytes.extend(schemas.encode(subject, &serde_json::to_value(value)?)?);
+    Ok(bytes)
+  }
+
+  pub fn decode<T: DeserializeOwned>(
+    schemas: &avro::Schemas,
+    bytes: &[u8],
+  ) -> Result<T, CodecError> {
+    Ok(serde_json::from_value(schemas.decode(&bytes[1..])?)?)
+  }
+}
+
+// The schema registries of the codecs that need one, as a client is built with
+// them. Readers without them, such as event type filters and the HTTP API,
+// can't read those codecs' commits.
+#[derive(Clone, Default)]
+pub struct Registries {
+  #[cfg(feature = "avro")]
+  pub avro: Option<avro::Schemas>,
+}
+
+#[cfg(feature = "avro")]
+impl Registries {
+  fn avro(&self) -> Result<&avro::Schemas, CodecError> {
+    self.avro.as_ref().ok_or_else(|| {
+      CodecError::EncodingError(String::from(
+        "avro: no schema registry; see ClientBuilder::with_avro_schemas",
+      ))
+    })
+  }
+}
+
+// The schema registry subjects the client writes a category's events and
+// metadata under, such as `order-events`; `events` and `metadata` without one.
+pub fn events_subject(category: &str) -> String {
+  subject(category, "events")
+}
+
+pub fn metadata_subject(category: &str) -> String {
+  subject(category, "metadata")
+}
+
+fn subject(category: &str, payload: &str) -> String {
+  match category {
+    "" => String::from(payload),
+    category => format!("{}-{}", category, payload),
+  }
+}
+
+// The codec `ClientBuilder::with_codec` selects for new commits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+  #[default]
+  Json,
+  #[cfg(feature = "cbor")]
+  Cbor,
+  #[cfg(feature = "msgpack")]
+  MessagePack,
+  #[cfg(feature = "bincode")]
+  Bincode,
+  #[cfg(feature = "protobuf")]
+  Protobuf,
+  #[cfg(feature = "avro")]
+  Avro,
+}
+
+impl Codec {
+  pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+    match self {
+      Codec::Json => Json::encode(value),
+      #[cfg(feature = "cbor")]
+      Codec::Cbor => Cbor::encode(value),
+      #[cfg(feature = "msgpack")]
+      Codec::MessagePack => MessagePack::encode(value),
+      #[cfg(feature = "bincode")]
+      Codec::Bincode => Bincode::encode(value),
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf => Protobuf::encode(value),
+      #[cfg(feature = "avro")]
+      Codec::Avro => Err(CodecError::EncodingError(String::from(
+        "avro payloads need a subject; see Codec::encode_as",
+      ))),
+    }
+  }
+
+  // Like `encode`, writing Avro with the schema `registries` has for `subject`.
+  pub fn encode_as<T: Serialize>(
+    self,
+    subject: &str,
+    value: &T,
+    registries: &Registries,
+  ) -> Result<Vec<u8>, CodecError> {
+    match self {
+      #[cfg(feature = "avro")]
+      Codec::Avro => Avro::encode(registries.avro()?, subject, value),
+      codec => {
+        let _ = (subject, registries);
+        codec.encode(value)
+      }
+    }
+  }
+}
+
+// Prefix payloads `store::encrypted` has encrypted, ahead of everything else.
+// They have to be decrypted before they can be decompressed or decoded.
+pub const ENCRYPTED: u8 = 0x04;
+pub const SHREDDABLE: u8 = 0x05;
+
+// Prefix compressed payloads, ahead of any content type byte.
+const GZIP: u8 = 6;
+const ZSTD: u8 = 7;
+
+// Compresses encoded events and metadata of at least `threshold` bytes, when
+// that makes them smaller. `codec::decode` decompresses them whatever the
+// client reading them is configured with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PayloadCompression {
+  pub compression: Compression,
+  pub threshold: usize,
+}
+
+impl PayloadCompression {
+  pub fn compress(self, payload: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+    let prefix = match self.compression {
+      Compression::None => return Ok(payload),
+      Compression::Gzip => GZIP,
+      Compression::Zstd => ZSTD,
+    };
+    if payload.len() < self.threshold {
+      return Ok(payload);
+    }
+    let compressed = self
+      .compression
+      .compress(&payload)
+      .map_err(CodecError::CompressionError)?;
+    if compressed.len() + 1 >= payload.len() {
+      return Ok(payload);
+    }
+    let mut bytes = Vec::with_capacity(compressed.len() + 1);
+    bytes.push(prefix);
+    bytes.extend_from_slice(&compressed);
+    Ok(bytes)
+  }
+}
+
+// Serialized events or metadata with any compression undone.
+pub fn decompress(bytes: &[u8]) -> Result<Cow<'_, [u8]>, CodecError> {
+  let compression = match bytes.first() {
+    Some(&GZIP) => Compression::Gzip,
+    Some(&ZSTD) => Compression::Zstd,
+    _ => return Ok(Cow::Borrowed(bytes)),
+  };
+  compression
+    .decompress(&bytes[1..])
+    .map(Cow::Owned)
+    .map_err(CodecError::CompressionError)
+}
+
+// The content type of decompressed events or metadata; JSON when unprefixed.
+pub fn content_type(bytes: &[u8]) -> u8 {
+  match bytes.first() {
+    Some(&content_type) if content_type < ENCRYPTED || (0x10..=0x1f).contains(&content_type) => {
+      content_type
+    }
+    _ => Json::CONTENT_TYPE,
+  }
+}
+
+// Whether `decode` can read serialized events or metadata as they are stored.
+// Encrypted payloads have to be decrypted first, and Avro ones need a registry.
+pub fn decodable_at_rest(bytes: &[u8]) -> bool {
+  match bytes.first() {
+    Some(&ENCRYPTED) | Some(&SHREDDABLE) => false,
+    #[cfg(feature = "avro")]
+    _ => decompress(bytes).map_or(true, |bytes| content_type(&bytes) != Avro::CONTENT_TYPE),
+    #[cfg(not(feature = "avro"))]
+    _ => true,
+  }
+}
+
+// Decodes serialized events or metadata in whichever encoding they were written,
+// but for those that need a schema registry.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+  decode_with(bytes, &Registries::default())
+}
+
+// Like `decode`, reading the codecs that need a schema registry from `registries`.
+pub fn decode_with<T: DeserializeOwned>(
+  bytes: &[u8],
+  registries: &Registries,
+) -> Result<T, CodecError> {
+  let bytes = &*decompress(bytes)?;
+  match content_type(bytes) {
+    Json::CONTENT_TYPE => Json::decode(bytes),
+    #[cfg(feature = "cbor")]
+    Cbor::CONTENT_TYPE => Cbor::decode(bytes),
+    #[cfg(feature = "msgpack")]
+    MessagePack::CONTENT_TYPE => MessagePack::decode(bytes),
+    #[cfg(feature = "bincode")]
+    Bincode::CONTENT_TYPE => Bincode::decode(bytes),
+    #[cfg(feature = "protobuf")]
+    Protobuf::CONTENT_TYPE => Protobuf::decode(bytes),
+    #[cfg(feature = "avro")]
+    Avro::CONTENT_TYPE => Avro::decode(registries.avro()?, bytes),
+    content_type => {
+      let _ = registries;
+      Err(CodecError::UnknownContentType(content_type))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq)]
+  enum AccountEvent {
+    Opened { owner: String },
+    Deposited(i64),
+  }
+
+  fn events() -> Vec<AccountEvent> {
+    vec![
+      AccountEvent::Opened {
+        owner: String::from("alice"),
+      },
+      AccountEvent::Deposited(10),
+    ]
+  }
+
+  #[test]
+  fn it_leaves_json_unprefixed() {
+    let bytes = Codec::Json.encode(&events()).unwrap();
+    assert_eq!(bytes, serde_json::to_vec(&events()).unwrap());
+    assert_eq!(content_type(&bytes), Json::CONTENT_TYPE);
+    assert_eq!(decode::<Vec<AccountEvent>>(&bytes).unwrap(), events());
+  }
+
+  #[test]
+  fn it_decodes_every_enabled_codec() {
+    let codecs = vec![
+      Codec::Json,
+      #[cfg(feature = "cbor")]
+      Codec::Cbor,
+      #[cfg(feature = "msgpack")]
+      Codec::MessagePack,
+      #[cfg(feature = "bincode")]
+      Codec::Bincode,
+      #[cfg(feature = "protobuf")]
+      Codec::Protobuf,
+    ];
+    for codec in codecs {
+      let bytes = codec.encode(&events()).unwrap();
+      assert_eq!(
+        decode::<Vec<AccountEvent>>(&bytes).unwrap(),
+        events(),
+        "{:?}",
+        codec
+      );
+    }
+  }
+
+  #[test]
+  fn it_decodes_compressed_payloads() {
+    let events: Vec<AccountEvent> = (0..100).map(AccountEvent::Deposited).collect();
+    let payload = Codec::Json.encode(&events).unwrap();
+    for compression in &[Compression::Gzip, Compression::Zstd] {
+      let compressed = PayloadCompression {
+        compression: *compression,
+        threshold: 64,
+      }
+      .compress(payload.clone())
+      .unwrap();
+      assert!(compressed.len() < payload.len());
+      assert_eq!(decode::<Vec<AccountEvent>>(&compressed).unwrap(), events);
+    }
+    let below_threshold = PayloadCompression {
+      compression: Compression::Zstd,
+      threshold: payload.len() + 1,
+    };
+    assert_eq!(below_threshold.compress(payload.clone()).unwrap(), payload);
+  }
+
+  #[test]
+  fn it_rejects_content_types_it_cannot_decode() {
+    match decode::<Vec<AccountEvent>>(&[3, 0]) {
+      #[cfg(feature = "bincode")]
+      Err(CodecError::EncodingError(_)) => (),
+      #[cfg(not(feature = "bincode"))]
+      Err(CodecError::UnknownContentType(3)) => (),
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+}
@@ -1,13 +1,164 @@
-use super::aggregate::Aggregate;
+use super::aggregate::{Aggregate, StreamState};
+use serde_json::{Map, Value};
 use std::error::Error;
+use std::fmt;
 use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
 
 pub trait Command: Send + Sync + Clone + Debug {
   type Aggregate: Aggregate;
   type Error: Error;
 
+  // Checks the command against the aggregate before it is applied. Failing here
+  // means the command itself is wrong, and the server answers 422 with every
+  // field that is, where `apply` errors are answered 400.
+  fn validate(&self, _aggregate: &Self::Aggregate) -> Result<(), ValidationErrors> {
+    Ok(())
+  }
+
+  // What the command needs of the aggregate's stream, such as a `Create` that
+  // must not find one already there. Checked before `validate`.
+  fn precondition(&self) -> StreamPrecondition {
+    StreamPrecondition::Any
+  }
+
   fn apply(
     &self,
     aggregate: &Self::Aggregate,
   ) -> Result<Vec<<<Self as Command>::Aggregate as Aggregate>::Event>, Self::Error>;
+
+  // Fields stored with one of the events `apply` produced, beside the commit's
+  // metadata, for commands whose events come from different sub-operations.
+  // Read back from `EventEnvelope::metadata`. Bincode commits can't hold them.
+  fn event_metadata(
+    &self,
+    _event: &<<Self as Command>::Aggregate as Aggregate>::Event,
+  ) -> Map<String, Value> {
+    Map::new()
+  }
+}
+
+// A command that has to wait on something else to decide its events, such as a
+// pricing service or a uniqueness check. `AsyncClient::issue_async_command` and
+// `Server::register_async` issue them without blocking while `apply` runs. The
+// events are committed against the aggregate they were applied to, so a
+// concurrent commit to it in the meantime fails with a version conflict.
+pub trait AsyncCommand: Send + Sync + Clone + Debug {
+  type Aggregate: Aggregate;
+  type Error: Error;
+
+  fn validate(&self, _aggregate: &Self::Aggregate) -> Result<(), ValidationErrors> {
+    Ok(())
+  }
+
+  fn precondition(&self) -> StreamPrecondition {
+    StreamPrecondition::Any
+  }
+
+  fn apply(&self, aggregate: &Self::Aggregate) -> CommandFuture<Self>;
+
+  fn event_metadata(
+    &self,
+    _event: &<<Self as AsyncCommand>::Aggregate as Aggregate>::Event,
+  ) -> Map<String, Value> {
+    Map::new()
+  }
+}
+
+// Commands to deleted aggregates are refused whatever their precondition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamPrecondition {
+  Any,
+  MustExist,
+  MustNotExist,
+}
+
+impl StreamPrecondition {
+  pub fn allows(self, state: StreamState) -> bool {
+    match (self, state) {
+      (_, StreamState::Deleted) => false,
+      (StreamPrecondition::Any, _) => true,
+      (StreamPrecondition::MustExist, state) => state == StreamState::Live,
+      (StreamPrecondition::MustNotExist, state) => state == StreamState::NotCreated,
+    }
+  }
 }
+
+impl fmt::Display for StreamPrecondition {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      StreamPrecondition::Any => write!(f, "may be in any state"),
+      StreamPrecondition::MustExist => write!(f, "must exist"),
+      StreamPrecondition::MustNotExist => write!(f, "must not exist"),
+    }
+  }
+}
+
+// What `AsyncCommand::apply` returns. The future can't borrow the command or
+// the aggregate, so clone what it needs of them.
+pub type CommandFuture<C> = Pin<
+  Box<
+    dyn Future<
+        Output = Result<
+          Vec<<<C as AsyncCommand>::Aggregate as Aggregate>::Event>,
+          <C as AsyncCommand>::Error,
+        >,
+      > + Send,
+  >,
+>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+  pub field: String,
+  pub message: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationErrors {
+  pub fields: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+  pub fn new() -> ValidationErrors {
+    ValidationErrors::default()
+  }
+
+  pub fn add(&mut self, field: &str, message: &str) {
+    self.fields.push(FieldError {
+      field: String::from(field),
+      message: String::from(message),
+    });
+  }
+
+  pub fn with(mut self, field: &str, message: &str) -> ValidationErrors {
+    self.add(field, message);
+    self
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.fields.is_empty()
+  }
+
+  // `Ok` when nothing was added, for ending a `validate` that collects errors.
+  pub fn into_result(self) -> Result<(), ValidationErrors> {
+    if self.is_empty() {
+      Ok(())
+    } else {
+      Err(self)
+    }
+  }
+}
+
+impl fmt::Display for ValidationErrors {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "invalid command")?;
+    for (index, error) in self.fields.iter().enumerate() {
+      let separator = if index == 0 { ": " } else { ", " };
+      write!(f, "{}{} {}", separator, error.field, error.message)?;
+    }
+    Ok(())
+  }
+}
+
+impl Error for ValidationErrors {}
@@ -1,28 +1,72 @@
+use crate::codec::{self, CodecError};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
 
-#[derive(Clone, Debug)]
+// `Commit` and `CommitAttempt` are serialized as they are stored, for shipping
+// over queues, keeping in files and reading from other languages. In JSON:
+//
+//   aggregate_id         uuid string
+//   aggregate_version    integer
+//   category             string; "" when absent
+//   tenant_id            string; "" when absent
+//   parent_commit_id     uuid string or null; null when absent
+//   commit_id            uuid string
+//   commit_timestamp     RFC 3339 string
+//   commit_sequence      integer
+//   commit_number        integer; `Commit` only
+//   serialized_events    standard padded base64 of the events as the client
+//                        encoded them: a JSON array unless another codec or
+//                        payload compression was configured
+//   serialized_metadata  base64 of the metadata, encoded the same way
+//   events_count         integer
+//   dispatched           boolean; `Commit` only
+//
+// Fields are never renamed or removed; any added later are optional when read.
+// `DeserializedCommit` is the decoded view the HTTP API serves instead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Commit {
   pub aggregate_id: Uuid,
   pub aggregate_version: i64,
+  #[serde(default)]
+  pub category: String,
+  // The tenant the commit belongs to; see `TenantStore`. Empty for stores not
+  // shared between tenants.
+  #[serde(default)]
+  pub tenant_id: String,
+  // The aggregate's newest commit when the command was decided, alongside the
+  // aggregate_version it was decided against. None for an aggregate's first
+  // commit and for commits written before commits were linked.
+  #[serde(default)]
+  pub parent_commit_id: Option<Uuid>,
   pub commit_id: Uuid,
   pub commit_timestamp: DateTime<Utc>,
   pub commit_sequence: i64,
   pub commit_number: i64,
+  #[serde(with = "base64_bytes")]
   pub serialized_events: Vec<u8>,
+  #[serde(with = "base64_bytes")]
   pub serialized_metadata: Vec<u8>,
   pub events_count: i64,
   pub dispatched: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CommitAttempt {
   pub aggregate_id: Uuid,
   pub aggregate_version: i64,
+  #[serde(default)]
+  pub category: String,
+  #[serde(default)]
+  pub tenant_id: String,
+  #[serde(default)]
+  pub parent_commit_id: Option<Uuid>,
   pub commit_id: Uuid,
   pub commit_timestamp: DateTime<Utc>,
   pub commit_sequence: i64,
+  #[serde(with = "base64_bytes")]
   pub serialized_metadata: Vec<u8>,
+  #[serde(with = "base64_bytes")]
   pub serialized_events: Vec<u8>,
   pub events_count: i64,
 }
@@ -31,6 +75,12 @@ pub struct CommitAttempt {
 pub struct DeserializedCommit {
   pub aggregate_id: Uuid,
   pub aggregate_version: i64,
+  #[serde(default)]
+  pub category: String,
+  #[serde(default)]
+  pub tenant_id: String,
+  #[serde(default)]
+  pub parent_commit_id: Option<Uuid>,
   pub commit_id: Uuid,
   pub commit_timestamp: DateTime<Utc>,
   pub commit_sequence: i64,
@@ -41,13 +91,135 @@ pub struct DeserializedCommit {
   pub dispatched: bool,
 }
 
+mod base64_bytes {
+  use base64::engine::general_purpose::STANDARD;
+  use base64::Engine;
+  use serde::de::{Deserialize, Deserializer, Error};
+  use serde::Serializer;
+
+  pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(bytes))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD.decode(encoded).map_err(D::Error::custom)
+  }
+}
+
+impl<'a> From<&'a Commit> for CommitAttempt {
+  fn from(commit: &'a Commit) -> CommitAttempt {
+    CommitAttempt {
+      aggregate_id: commit.aggregate_id,
+      aggregate_version: commit.aggregate_version,
+      category: commit.category.clone(),
+      tenant_id: commit.tenant_id.clone(),
+      parent_commit_id: commit.parent_commit_id,
+      commit_id: commit.commit_id,
+      commit_timestamp: commit.commit_timestamp,
+      commit_sequence: commit.commit_sequence,
+      serialized_metadata: commit.serialized_metadata.clone(),
+      serialized_events: commit.serialized_events.clone(),
+      events_count: commit.events_count,
+    }
+  }
+}
+
+// An XXH3 hash of everything a commit was written with: every header but the
+// commit_number and dispatched flag the store assigns, and both payloads as
+// stored. Stores record it on write and check it on read to catch corruption.
+// Each field is length-prefixed so moving bytes between fields changes it.
+#[allow(clippy::too_many_arguments)]
+fn checksum_fields(
+  aggregate_id: &Uuid,
+  aggregate_version: &i64,
+  category: &str,
+  tenant_id: &str,
+  parent_commit_id: &Option<Uuid>,
+  commit_id: &Uuid,
+  commit_timestamp: &DateTime<Utc>,
+  commit_sequence: &i64,
+  serialized_metadata: &[u8],
+  serialized_events: &[u8],
+  events_count: &i64,
+) -> u64 {
+  let mut hasher = Xxh3::new();
+  let mut field = |bytes: &[u8]| {
+    hasher.update(&(bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+  };
+  field(aggregate_id.as_bytes());
+  field(&aggregate_version.to_le_bytes());
+  field(category.as_bytes());
+  field(tenant_id.as_bytes());
+  match *parent_commit_id {
+    Some(ref parent_commit_id) => field(parent_commit_id.as_bytes()),
+    None => field(&[]),
+  }
+  field(commit_id.as_bytes());
+  field(&commit_timestamp.timestamp().to_le_bytes());
+  field(&commit_timestamp.timestamp_subsec_nanos().to_le_bytes());
+  field(&commit_sequence.to_le_bytes());
+  field(serialized_metadata);
+  field(serialized_events);
+  field(&events_count.to_le_bytes());
+  hasher.digest()
+}
+
+impl CommitAttempt {
+  pub fn checksum(&self) -> u64 {
+    self.checksum_with_events(&self.serialized_events)
+  }
+
+  // The checksum of the commit this attempt is read back as when a store keeps
+  // its events as something other than the bytes it was given.
+  pub(crate) fn checksum_with_events(&self, serialized_events: &[u8]) -> u64 {
+    checksum_fields(
+      &self.aggregate_id,
+      &self.aggregate_version,
+      &self.category,
+      &self.tenant_id,
+      &self.parent_commit_id,
+      &self.commit_id,
+      &self.commit_timestamp,
+      &self.commit_sequence,
+      &self.serialized_metadata,
+      serialized_events,
+      &self.events_count,
+    )
+  }
+}
+
 impl Commit {
-  pub fn deserialize(&self) -> DeserializedCommit {
-    let events = serde_json::from_slice(self.serialized_events.as_slice()).unwrap();
-    let metadata = serde_json::from_slice(self.serialized_metadata.as_slice()).unwrap();
-    DeserializedCommit {
+  // Matches the checksum of the attempt it was committed from, unless it was
+  // corrupted since.
+  pub fn checksum(&self) -> u64 {
+    checksum_fields(
+      &self.aggregate_id,
+      &self.aggregate_version,
+      &self.category,
+      &self.tenant_id,
+      &self.parent_commit_id,
+      &self.commit_id,
+      &self.commit_timestamp,
+      &self.commit_sequence,
+      &self.serialized_metadata,
+      &self.serialized_events,
+      &self.events_count,
+    )
+  }
+
+  // Fails for events or metadata in a codec that needs a schema registry, or one
+  // this build doesn't include.
+  pub fn deserialize(&self) -> Result<DeserializedCommit, CodecError> {
+    let events = codec::decode(self.serialized_events.as_slice())?;
+    let metadata = codec::decode(self.serialized_metadata.as_slice())?;
+    Ok(DeserializedCommit {
       aggregate_id: self.aggregate_id,
       aggregate_version: self.aggregate_version,
+      category: self.category.clone(),
+      tenant_id: self.tenant_id.clone(),
+      parent_commit_id: self.parent_commit_id,
       commit_id: self.commit_id,
       commit_timestamp: self.commit_timestamp,
       commit_number: self.commit_number,
@@ -56,15 +228,15 @@ impl Commit {
       metadata,
       events_count: self.events_count,
       dispatched: self.dispatched,
-    }
+    })
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use super::Commit;
-  use uuid::Uuid;
+  use super::{Commit, CommitAttempt};
   use chrono::Utc;
+  use uuid::Uuid;
   #[test]
   fn deserialize() {
     let serialized_events = b"[{\"foo\": \"bar\"}, {\"baz\": \"bat\"}]".to_vec();
@@ -72,6 +244,9 @@ mod tests {
     let commit = Commit{
       aggregate_id: Uuid::new_v4(),
       aggregate_version: 18,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: 101,
       commit_number: 198,
@@ -82,7 +257,7 @@ mod tests {
       dispatched: true,
     };
 
-    let deserialized = commit.deserialize();
+    let deserialized = commit.deserialize().unwrap();
 
     assert_eq!(deserialized.aggregate_id, commit.aggregate_id);
     assert_eq!(deserialized.aggregate_version, commit.aggregate_version);
@@ -103,6 +278,52 @@ mod tests {
     assert_eq!(metadata_obj["foo2"], "bar2");
     assert_eq!(metadata_obj["baz2"], "bat2");
     assert_eq!(events_array[0].as_object().unwrap()["foo"], "bar");
+
+    // A content type this build can't read.
+    let undecodable = Commit {
+      serialized_events: vec![0x1f],
+      ..commit
+    };
+    assert!(undecodable.deserialize().is_err());
   }
 
+  #[test]
+  fn it_keeps_a_stable_wire_format() {
+    let commit_id = Uuid::parse_str("0f2a0d9e-6f36-4d4e-9d8f-2f1b4a3c5e7d").unwrap();
+    let aggregate_id = Uuid::parse_str("5b8c1c9e-3a5d-4f61-8d5c-1e2f3a4b5c6d").unwrap();
+    let wire = serde_json::json!({
+      "aggregate_id": aggregate_id,
+      "aggregate_version": 2,
+      "category": "order",
+      "tenant_id": "",
+      "parent_commit_id": null,
+      "commit_id": commit_id,
+      "commit_timestamp": "2020-01-02T03:04:05Z",
+      "commit_sequence": 3,
+      "commit_number": 7,
+      "serialized_events": "WyJQbGFjZWQiXQ==",
+      "serialized_metadata": "e30=",
+      "events_count": 1,
+      "dispatched": true,
+    });
+
+    let commit: Commit = serde_json::from_value(wire.clone()).unwrap();
+    assert_eq!(commit.serialized_events, b"[\"Placed\"]");
+    assert_eq!(commit.serialized_metadata, b"{}");
+    assert_eq!(commit.commit_timestamp.to_rfc3339(), "2020-01-02T03:04:05+00:00");
+    assert_eq!(serde_json::to_value(&commit).unwrap(), wire);
+
+    let attempt = CommitAttempt::from(&commit);
+    let mut attempt_wire = wire;
+    attempt_wire.as_object_mut().unwrap().remove("commit_number");
+    attempt_wire.as_object_mut().unwrap().remove("dispatched");
+    assert_eq!(serde_json::to_value(&attempt).unwrap(), attempt_wire);
+    // Fields added after the format was first shipped may be left out.
+    for field in &["category", "tenant_id", "parent_commit_id"] {
+      attempt_wire.as_object_mut().unwrap().remove(*field);
+    }
+    let attempt: CommitAttempt = serde_json::from_value(attempt_wire).unwrap();
+    assert_eq!(attempt.category, "");
+    assert_eq!(attempt.serialized_events, b"[\"Placed\"]");
+  }
 }
\ No newline at end of file
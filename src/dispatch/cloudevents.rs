@@ -0,0 +1,130 @@
+use crate::codec::CodecError;
+use crate::commit::{Commit, DeserializedCommit};
+use chrono::{DateTime, Utc};
+
+pub const CONTENT_TYPE: &str = "application/cloudevents+json";
+pub const SPEC_VERSION: &str = "1.0";
+
+// A commit as a CloudEvents 1.0 event in JSON structured mode. The commit's
+// JSON, as the HTTP API serves it, is the data; `sequence` is the commit_number,
+// following the sequence extension, so consumers can order and deduplicate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CloudEvent {
+  pub specversion: String,
+  pub id: String,
+  pub source: String,
+  #[serde(rename = "type")]
+  pub event_type: String,
+  pub time: DateTime<Utc>,
+  pub subject: String,
+  pub datacontenttype: String,
+  pub sequence: String,
+  pub data: DeserializedCommit,
+}
+
+// How commits become CloudEvents. The id is the commit_id and the subject the
+// aggregate_id. The source is `source`, followed by `/{category}` for
+// categorized commits, and the type is `{type_prefix}.{category}.committed`, or
+// `{type_prefix}.committed` without a category.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloudEventsFormat {
+  source: String,
+  type_prefix: String,
+}
+
+impl Default for CloudEventsFormat {
+  fn default() -> CloudEventsFormat {
+    CloudEventsFormat {
+      source: String::from("/event_source"),
+      type_prefix: String::from("event_source"),
+    }
+  }
+}
+
+impl CloudEventsFormat {
+  // A URI reference identifying this service, such as `https://orders.example.com`.
+  pub fn with_source(mut self, source: &str) -> CloudEventsFormat {
+    self.source = String::from(source.trim_end_matches('/'));
+    self
+  }
+
+  // Reverse-DNS style, such as `com.example.orders`.
+  pub fn with_type_prefix(mut self, type_prefix: &str) -> CloudEventsFormat {
+    self.type_prefix = String::from(type_prefix);
+    self
+  }
+
+  pub fn cloud_event(&self, commit: &Commit) -> Result<CloudEvent, CodecError> {
+    let (source, event_type) = match commit.category.as_str() {
+      "" => (self.source.clone(), format!("{}.committed", self.type_prefix)),
+      category => (
+        format!("{}/{}", self.source, category),
+        format!("{}.{}.committed", self.type_prefix, category),
+      ),
+    };
+    Ok(CloudEvent {
+      specversion: String::from(SPEC_VERSION),
+      id: commit.commit_id.to_string(),
+      source,
+      event_type,
+      time: commit.commit_timestamp,
+      subject: commit.aggregate_id.to_string(),
+      datacontenttype: String::from("application/json"),
+      sequence: commit.commit_number.to_string(),
+      data: commit.deserialize()?,
+    })
+  }
+
+  pub fn to_json(&self, commit: &Commit) -> Result<Vec<u8>, CodecError> {
+    Ok(serde_json::to_vec(&self.cloud_event(commit)?)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use uuid::Uuid;
+
+  fn commit(category: &str) -> Commit {
+    Commit {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::from(category),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 1,
+      commit_number: 42,
+      serialized_events: b"[\"Placed\"]".to_vec(),
+      serialized_metadata: b"{}".to_vec(),
+      events_count: 1,
+      dispatched: false,
+    }
+  }
+
+  #[test]
+  fn it_wraps_commits_as_cloud_events() {
+    let format = CloudEventsFormat::default()
+      .with_source("https://orders.example.com/")
+      .with_type_prefix("com.example");
+    let placed = commit("order");
+    let event: serde_json::Value =
+      serde_json::from_slice(&format.to_json(&placed).unwrap()).unwrap();
+
+    assert_eq!(event["specversion"], "1.0");
+    assert_eq!(event["id"], placed.commit_id.to_string());
+    assert_eq!(event["source"], "https://orders.example.com/order");
+    assert_eq!(event["type"], "com.example.order.committed");
+    assert_eq!(event["subject"], placed.aggregate_id.to_string());
+    assert_eq!(event["sequence"], "42");
+    assert_eq!(event["datacontenttype"], "application/json");
+    assert_eq!(event["data"]["events"], serde_json::json!(["Placed"]));
+    let time: DateTime<Utc> = serde_json::from_value(event["time"].clone()).unwrap();
+    assert_eq!(time, placed.commit_timestamp);
+
+    let uncategorized = CloudEventsFormat::default().cloud_event(&commit("")).unwrap();
+    assert_eq!(uncategorized.source, "/event_source");
+    assert_eq!(uncategorized.event_type, "event_source.committed");
+  }
+}
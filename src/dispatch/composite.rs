@@ -0,0 +1,352 @@
+use super::{DispatchDelegate, Retries, RetryPolicy};
+use crate::commit::Commit;
+use crate::store::Store;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailureMode {
+  // Stops at the first delegate that fails; the rest see the commit on a retry.
+  #[default]
+  FailFast,
+  // Offers the commit to every delegate and then reports any that failed.
+  BestEffort,
+}
+
+type DispatchFn = Box<dyn FnMut(&Commit) -> Result<(), String> + Send>;
+
+struct Target {
+  name: String,
+  dispatch: DispatchFn,
+}
+
+// Fans each commit out to several delegates, such as WebSocket subscribers and
+// a Kafka producer. Delegates that acknowledged a commit are skipped when it is
+// retried, so a retry only reaches the delegates that have not seen it yet.
+#[derive(Default)]
+pub struct CompositeDispatcher {
+  targets: Vec<Target>,
+  failure_mode: FailureMode,
+  acknowledged: HashMap<Uuid, HashSet<usize>>,
+}
+
+impl CompositeDispatcher {
+  pub fn new(failure_mode: FailureMode) -> CompositeDispatcher {
+    CompositeDispatcher {
+      failure_mode,
+      ..CompositeDispatcher::default()
+    }
+  }
+
+  pub fn with_delegate<D: DispatchDelegate + Send + 'static>(
+    mut self,
+    name: &str,
+    mut delegate: D,
+  ) -> CompositeDispatcher {
+    self.targets.push(Target {
+      name: String::from(name),
+      dispatch: Box::new(move |commit| delegate.dispatch(commit)),
+    });
+    self
+  }
+
+  // The delegates that have acknowledged a commit that is not yet fully
+  // dispatched. Empty once every delegate has it.
+  pub fn acknowledged_by(&self, commit_id: &Uuid) -> Vec<&str> {
+    self
+      .acknowledged
+      .get(commit_id)
+      .map_or_else(Vec::new, |indices| {
+        self
+          .targets
+          .iter()
+          .enumerate()
+          .filter(|&(index, _)| indices.contains(&index))
+          .map(|(_, target)| target.name.as_str())
+          .collect()
+      })
+  }
+}
+
+impl DispatchDelegate for CompositeDispatcher {
+  fn dispatch(&mut self, commit: &Commit) -> Result<(), String> {
+    let mut acknowledged = self
+      .acknowledged
+      .remove(&commit.commit_id)
+      .unwrap_or_default();
+    let mut errors = Vec::new();
+    for (index, target) in self.targets.iter_mut().enumerate() {
+      if acknowledged.contains(&index) {
+        continue;
+      }
+      match (target.dispatch)(commit) {
+        Ok(()) => {
+          acknowledged.insert(index);
+        }
+        Err(err) => {
+          errors.push(format!("{}: {}", target.name, err));
+          if self.failure_mode == FailureMode::FailFast {
+            break;
+          }
+        }
+      }
+    }
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      self.acknowledged.insert(commit.commit_id, acknowledged);
+      Err(errors.join("; "))
+    }
+  }
+}
+
+struct Consumer {
+  target: Target,
+  retries: Retries,
+}
+
+// Fans commits out like `CompositeDispatcher`, but records each delegate's
+// acknowledgements in the store under its name. Each delegate works through the
+// commits it has not acknowledged on its own, so a failing delegate only holds
+// itself back, and a delegate registered later is sent the whole history. A
+// commit is marked as dispatched once every registered delegate has it.
+pub struct AcknowledgingDispatcher {
+  consumers: Vec<Consumer>,
+  batch_size: i64,
+}
+
+impl Default for AcknowledgingDispatcher {
+  fn default() -> AcknowledgingDispatcher {
+    AcknowledgingDispatcher {
+      consumers: Vec::new(),
+      batch_size: 100,
+    }
+  }
+}
+
+impl AcknowledgingDispatcher {
+  pub fn new() -> AcknowledgingDispatcher {
+    AcknowledgingDispatcher::default()
+  }
+
+  // `name` keys the delegate's acknowledgements, so it must stay the same across
+  // restarts. Poisoning takes a commit away from every delegate, so a delegate's
+  // failures only ever back off.
+  pub fn with_delegate<D: DispatchDelegate + Send + 'static>(
+    mut self,
+    name: &str,
+    mut delegate: D,
+    retry_policy: RetryPolicy,
+  ) -> AcknowledgingDispatcher {
+    self.consumers.push(Consumer {
+      target: Target {
+        name: String::from(name),
+        dispatch: Box::new(move |commit| delegate.dispatch(commit)),
+      },
+      retries: Retries {
+        policy: RetryPolicy {
+          max_attempts: u32::MAX,
+          ..retry_policy
+        },
+        failures: HashMap::new(),
+      },
+    });
+    self
+  }
+
+  pub fn with_batch_size(mut self, batch_size: i64) -> AcknowledgingDispatcher {
+    self.batch_size = batch_size;
+    self
+  }
+
+  // Sends each delegate up to a batch of the commits it has not acknowledged.
+  // Returns the first error, prefixed with the delegate's name.
+  pub fn dispatch<S: Store>(&mut self, store: &mut S) -> Result<(), String> {
+    let names: Vec<String> = self
+      .consumers
+      .iter()
+      .map(|consumer| consumer.target.name.clone())
+      .collect();
+    let mut first_error = None;
+    for consumer in self.consumers.iter_mut() {
+      let commits = store
+        .get_unacknowledged_commits(&consumer.target.name, self.batch_size)
+        .map_err(|err| err.to_string())?;
+      let now = Instant::now();
+      let mut blocked_aggregates = HashSet::new();
+      for commit in commits {
+        if blocked_aggregates.contains(&commit.aggregate_id) {
+          continue;
+        }
+        if consumer.retries.is_waiting(&commit.commit_id, now) {
+          blocked_aggregates.insert(commit.aggregate_id);
+          continue;
+        }
+        match (consumer.target.dispatch)(&commit) {
+          Ok(()) => {
+            consumer.retries.succeeded(&commit.commit_id);
+            store
+              .acknowledge_commit(commit.commit_id, &consumer.target.name)
+              .map_err(|err| err.to_string())?;
+            let acknowledged = store
+              .get_acknowledgements(commit.commit_id)
+              .map_err(|err| err.to_string())?;
+            if !commit.dispatched && names.iter().all(|name| acknowledged.contains(name)) {
+              store
+                .mark_commit_as_dispatched(commit.commit_id)
+                .map_err(|err| err.to_string())?;
+            }
+          }
+          Err(err) => {
+            blocked_aggregates.insert(commit.aggregate_id);
+            consumer.retries.failed(store, commit.commit_id, &err)?;
+            first_error.get_or_insert(format!("{}: {}", consumer.target.name, err));
+          }
+        }
+      }
+    }
+    match first_error {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+  use std::sync::{Arc, Mutex};
+
+  struct FlakyDelegate {
+    failures_left: usize,
+    deliveries: Arc<Mutex<usize>>,
+  }
+
+  impl DispatchDelegate for FlakyDelegate {
+    fn dispatch(&mut self, _commit: &Commit) -> Result<(), String> {
+      if self.failures_left > 0 {
+        self.failures_left -= 1;
+        return Err(String::from("unavailable"));
+      }
+      *self.deliveries.lock().unwrap() += 1;
+      Ok(())
+    }
+  }
+
+  fn delegate(failures_left: usize) -> (FlakyDelegate, Arc<Mutex<usize>>) {
+    let deliveries = Arc::new(Mutex::new(0));
+    let delegate = FlakyDelegate {
+      failures_left,
+      deliveries: Arc::clone(&deliveries),
+    };
+    (delegate, deliveries)
+  }
+
+  fn commit() -> Commit {
+    Commit {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 0,
+      commit_number: 1,
+      serialized_events: b"[]".to_vec(),
+      serialized_metadata: b"null".to_vec(),
+      events_count: 0,
+      dispatched: false,
+    }
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[test]
+  fn it_tracks_acknowledgements_per_delegate_in_the_store() {
+    use crate::commit::CommitAttempt;
+    use crate::store::sqlite::SqliteStore;
+    use std::time::Duration;
+
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut commit_ids = Vec::new();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..2 {
+      let attempt = CommitAttempt::from(&Commit {
+        aggregate_id,
+        aggregate_version: version,
+        commit_sequence: version,
+        ..commit()
+      });
+      store.commit(&attempt).unwrap();
+      commit_ids.push(attempt.commit_id);
+    }
+    let no_backoff = RetryPolicy {
+      max_attempts: 1,
+      initial_backoff: Duration::from_secs(0),
+      max_backoff: Duration::from_secs(0),
+    };
+    let (websocket, websocket_deliveries) = delegate(0);
+    let (broker, broker_deliveries) = delegate(1);
+    let mut dispatcher = AcknowledgingDispatcher::new()
+      .with_delegate("websocket", websocket, no_backoff.clone())
+      .with_delegate("broker", broker, no_backoff.clone());
+
+    // The broker failing holds back neither the other delegate nor itself for
+    // long, and nothing is poisoned.
+    assert_eq!(
+      dispatcher.dispatch(&mut store),
+      Err(String::from("broker: unavailable"))
+    );
+    assert_eq!(*websocket_deliveries.lock().unwrap(), 2);
+    assert_eq!(*broker_deliveries.lock().unwrap(), 0);
+    assert_eq!(store.get_undispatched_commits().unwrap().len(), 2);
+    assert_eq!(dispatcher.dispatch(&mut store), Ok(()));
+    assert_eq!(*broker_deliveries.lock().unwrap(), 2);
+    assert!(store.get_undispatched_commits().unwrap().is_empty());
+
+    // A delegate added later replays the history on its own.
+    let (audit, audit_deliveries) = delegate(0);
+    let mut dispatcher = dispatcher.with_delegate("audit", audit, no_backoff);
+    assert_eq!(dispatcher.dispatch(&mut store), Ok(()));
+    assert_eq!(*audit_deliveries.lock().unwrap(), 2);
+    assert_eq!(*websocket_deliveries.lock().unwrap(), 2);
+    assert_eq!(
+      store.get_acknowledgements(commit_ids[0]).unwrap(),
+      vec!["audit", "broker", "websocket"]
+    );
+  }
+
+  #[test]
+  fn it_only_retries_delegates_that_have_not_acknowledged() {
+    for &(failure_mode, kafka_after_failure) in
+      &[(FailureMode::FailFast, 0), (FailureMode::BestEffort, 1)]
+    {
+      let (websocket, websocket_deliveries) = delegate(0);
+      let (broker, broker_deliveries) = delegate(1);
+      let (kafka, kafka_deliveries) = delegate(0);
+      let mut dispatcher = CompositeDispatcher::new(failure_mode)
+        .with_delegate("websocket", websocket)
+        .with_delegate("broker", broker)
+        .with_delegate("kafka", kafka);
+      let commit = commit();
+
+      assert_eq!(
+        dispatcher.dispatch(&commit),
+        Err(String::from("broker: unavailable"))
+      );
+      assert_eq!(*kafka_deliveries.lock().unwrap(), kafka_after_failure);
+      assert!(dispatcher
+        .acknowledged_by(&commit.commit_id)
+        .contains(&"websocket"));
+
+      assert_eq!(dispatcher.dispatch(&commit), Ok(()));
+      assert_eq!(*websocket_deliveries.lock().unwrap(), 1);
+      assert_eq!(*broker_deliveries.lock().unwrap(), 1);
+      assert_eq!(*kafka_deliveries.lock().unwrap(), 1);
+      assert!(dispatcher.acknowledged_by(&commit.commit_id).is_empty());
+    }
+  }
+}
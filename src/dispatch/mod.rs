@@ -0,0 +1,914 @@
+#[cfg(feature = "cloudevents")]
+pub mod cloudevents;
+pub mod composite;
+#[cfg(feature = "outbox")]
+pub mod outbox;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+use super::commit::Commit;
+use super::store::*;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub trait DispatchDelegate: Sized {
+  fn dispatch(&mut self, commit: &Commit) -> Result<(), String>;
+
+  // Delegates whose backends take batches, such as Kafka or SQS, should
+  // override this. On failure, `delivered` says how many leading commits of the
+  // batch went out before `commits[delivered]` failed.
+  fn dispatch_batch(&mut self, commits: &[Commit]) -> Result<(), BatchDispatchError> {
+    for (delivered, commit) in commits.iter().enumerate() {
+      self
+        .dispatch(commit)
+        .map_err(|error| BatchDispatchError { delivered, error })?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchDispatchError {
+  pub delivered: usize,
+  pub error: String,
+}
+
+// Splits a batch result into the results of each commit, leaving out the
+// commits after a failure, which were never attempted.
+fn batch_results(
+  commits: &[Commit],
+  result: Result<(), BatchDispatchError>,
+) -> Vec<(Uuid, Result<(), String>)> {
+  let (delivered, error) = match result {
+    Ok(()) => (commits.len(), None),
+    Err(err) => (err.delivered.min(commits.len()), Some(err.error)),
+  };
+  let mut results: Vec<(Uuid, Result<(), String>)> = commits[..delivered]
+    .iter()
+    .map(|commit| (commit.commit_id, Ok(())))
+    .collect();
+  if let Some(error) = error {
+    if let Some(failed) = commits.get(delivered) {
+      results.push((failed.commit_id, Err(error)));
+    }
+  }
+  results
+}
+
+// How often a commit is retried after its delegate fails, doubling the wait
+// after each failure, before it is poisoned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+  pub max_attempts: u32,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      max_attempts: 5,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(60),
+    }
+  }
+}
+
+impl RetryPolicy {
+  // The wait after the `attempts`th failure.
+  pub fn backoff(&self, attempts: u32) -> Duration {
+    let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+    cmp::min(
+      self.initial_backoff.saturating_mul(factor),
+      self.max_backoff,
+    )
+  }
+}
+
+struct Failure {
+  attempts: u32,
+  retry_at: Instant,
+}
+
+// Failed commits waiting out their backoff. Attempts are only counted in memory,
+// so a restarted dispatcher gives every commit a fresh set of attempts.
+#[derive(Default)]
+struct Retries {
+  policy: RetryPolicy,
+  failures: HashMap<Uuid, Failure>,
+}
+
+impl Retries {
+  fn is_waiting(&self, commit_id: &Uuid, now: Instant) -> bool {
+    self
+      .failures
+      .get(commit_id)
+      .is_some_and(|failure| failure.retry_at > now)
+  }
+
+  fn succeeded(&mut self, commit_id: &Uuid) {
+    self.failures.remove(commit_id);
+  }
+
+  // Schedules the next attempt, or poisons the commit once it is out of attempts.
+  // Returns whether the commit was poisoned.
+  fn failed<S: Store>(
+    &mut self,
+    store: &mut S,
+    commit_id: Uuid,
+    error: &str,
+  ) -> Result<bool, String> {
+    let attempts = self
+      .failures
+      .get(&commit_id)
+      .map_or(1, |failure| failure.attempts + 1);
+    if attempts >= self.policy.max_attempts {
+      // If the store cannot poison it, keep retrying at the longest backoff.
+      self.failures.insert(
+        commit_id,
+        Failure {
+          attempts,
+          retry_at: Instant::now() + self.policy.max_backoff,
+        },
+      );
+      store
+        .mark_commit_as_poisoned(commit_id, i64::from(attempts), error)
+        .map_err(|err| err.to_string())?;
+      self.failures.remove(&commit_id);
+      Ok(true)
+    } else {
+      self.failures.insert(
+        commit_id,
+        Failure {
+          attempts,
+          retry_at: Instant::now() + self.policy.backoff(attempts),
+        },
+      );
+      Ok(false)
+    }
+  }
+}
+
+// Runs on each commit before it reaches the delegate, to filter out commits
+// (such as internal events), or to transform or enrich them (such as stripping
+// PII from the metadata). Returning `None` skips the delegate and marks the
+// commit as dispatched; an error counts as a failed dispatch.
+pub trait DispatchMiddleware {
+  fn process(&mut self, commit: Commit) -> Result<Option<Commit>, String>;
+}
+
+impl<F: FnMut(Commit) -> Result<Option<Commit>, String>> DispatchMiddleware for F {
+  fn process(&mut self, commit: Commit) -> Result<Option<Commit>, String> {
+    self(commit)
+  }
+}
+
+// What one dispatch cycle did. Commits the middleware filtered out count as
+// dispatched, since they are marked as such.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DispatchReport {
+  pub dispatched: usize,
+  pub poisoned: usize,
+}
+
+impl DispatchReport {
+  fn failed(&mut self, poisoned: bool) {
+    if poisoned {
+      self.poisoned += 1;
+    }
+  }
+}
+
+enum Prepared {
+  Deliver(Commit),
+  Skipped,
+  Failed,
+}
+
+pub struct Dispatcher<D: DispatchDelegate> {
+  pub dispatch_delegate: D,
+  retries: Retries,
+  workers: usize,
+  batch_size: usize,
+  middleware: Vec<Box<dyn DispatchMiddleware + Send>>,
+}
+
+impl<D: DispatchDelegate> Dispatcher<D> {
+  pub fn new(delegate: D) -> Dispatcher<D> {
+    Dispatcher {
+      dispatch_delegate: delegate,
+      retries: Retries::default(),
+      workers: 1,
+      batch_size: 100,
+      middleware: Vec::new(),
+    }
+  }
+
+  // The most commits handed to `DispatchDelegate::dispatch_batch` at once.
+  pub fn with_batch_size(mut self, batch_size: usize) -> Dispatcher<D> {
+    self.batch_size = batch_size.max(1);
+    self
+  }
+
+  // Middleware runs in the order it is added.
+  pub fn with_middleware<M: DispatchMiddleware + Send + 'static>(
+    mut self,
+    middleware: M,
+  ) -> Dispatcher<D> {
+    self.middleware.push(Box::new(middleware));
+    self
+  }
+
+  fn apply_middleware(&mut self, commit: Commit) -> Result<Option<Commit>, String> {
+    let mut commit = commit;
+    for middleware in self.middleware.iter_mut() {
+      commit = match middleware.process(commit)? {
+        Some(commit) => commit,
+        None => return Ok(None),
+      };
+    }
+    Ok(Some(commit))
+  }
+
+  // Marks commits the middleware filtered out, and counts middleware errors as
+  // failed dispatches.
+  fn prepare<S: Store>(
+    &mut self,
+    store: &mut S,
+    commit: Commit,
+    first_error: &mut Option<String>,
+    report: &mut DispatchReport,
+  ) -> Result<Prepared, String> {
+    let commit_id = commit.commit_id;
+    match self.apply_middleware(commit) {
+      Ok(Some(commit)) => Ok(Prepared::Deliver(commit)),
+      Ok(None) => {
+        self.retries.succeeded(&commit_id);
+        store
+          .mark_commit_as_dispatched(commit_id)
+          .map_err(|err| err.to_string())?;
+        report.dispatched += 1;
+        Ok(Prepared::Skipped)
+      }
+      Err(err) => {
+        report.failed(self.retries.failed(store, commit_id, &err)?);
+        first_error.get_or_insert(err);
+        Ok(Prepared::Failed)
+      }
+    }
+  }
+
+  // How many threads `dispatch_in_parallel` spreads aggregates across.
+  pub fn with_workers(mut self, workers: usize) -> Dispatcher<D> {
+    self.workers = workers.max(1);
+    self
+  }
+
+  pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Dispatcher<D> {
+    self.retries.policy = policy;
+    self
+  }
+
+  // Commits that failed and are waiting to be retried.
+  pub fn pending_retries(&self) -> usize {
+    self.retries.failures.len()
+  }
+
+  // A failing commit is retried on later calls once its backoff has passed, and
+  // the rest of its aggregate waits behind it so each aggregate stays in order.
+  // Other aggregates carry on. Returns the first delegate error, if any.
+  pub fn dispatch<S: Store>(&mut self, store: &mut S) -> Result<(), String> {
+    self.dispatch_with_report(store).1
+  }
+
+  // Like `dispatch`, but also reports what the cycle got done before any error.
+  pub fn dispatch_with_report<S: Store>(
+    &mut self,
+    store: &mut S,
+  ) -> (DispatchReport, Result<(), String>) {
+    let mut report = DispatchReport::default();
+    let result = self.dispatch_reporting(store, &mut report);
+    (report, result)
+  }
+
+  fn dispatch_reporting<S: Store>(
+    &mut self,
+    store: &mut S,
+    report: &mut DispatchReport,
+  ) -> Result<(), String> {
+    let commits = store
+      .get_undispatched_commits()
+      .map_err(|err| err.to_string())?;
+    let now = Instant::now();
+    let mut blocked_aggregates = HashSet::new();
+    let mut first_error = None;
+    let mut pending = VecDeque::new();
+    for commit in commits {
+      if blocked_aggregates.contains(&commit.aggregate_id) {
+        continue;
+      }
+      if self.retries.is_waiting(&commit.commit_id, now) {
+        blocked_aggregates.insert(commit.aggregate_id);
+        continue;
+      }
+      let aggregate_id = commit.aggregate_id;
+      match self.prepare(store, commit, &mut first_error, report)? {
+        Prepared::Deliver(commit) => pending.push_back(commit),
+        Prepared::Skipped => (),
+        Prepared::Failed => {
+          blocked_aggregates.insert(aggregate_id);
+        }
+      }
+    }
+    loop {
+      let mut batch = Vec::new();
+      while batch.len() < self.batch_size {
+        match pending.pop_front() {
+          Some(commit) => {
+            if !blocked_aggregates.contains(&commit.aggregate_id) {
+              batch.push(commit);
+            }
+          }
+          None => break,
+        }
+      }
+      if batch.is_empty() {
+        break;
+      }
+      let result = self.dispatch_delegate.dispatch_batch(&batch);
+      let results = batch_results(&batch, result);
+      // Commits after a failure go back in line unless they belong to the
+      // failed aggregate.
+      for commit in batch.drain(results.len()..).rev() {
+        pending.push_front(commit);
+      }
+      for ((commit_id, result), commit) in results.into_iter().zip(batch.iter()) {
+        match result {
+          Ok(()) => {
+            self.retries.succeeded(&commit_id);
+            store
+              .mark_commit_as_dispatched(commit_id)
+              .map_err(|err| err.to_string())?;
+            report.dispatched += 1;
+          }
+          Err(err) => {
+            blocked_aggregates.insert(commit.aggregate_id);
+            report.failed(self.retries.failed(store, commit_id, &err)?);
+            first_error.get_or_insert(err);
+          }
+        }
+      }
+    }
+    match first_error {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<D: DispatchDelegate + Clone + Send> Dispatcher<D> {
+  // Like `dispatch`, but aggregates are handed out to `workers` threads, each
+  // with its own clone of the delegate. A worker delivers one aggregate's
+  // commits in commit order, in batches, so only commits of different aggregates can
+  // overtake each other. Commits are marked as dispatched once every worker has
+  // finished.
+  pub fn dispatch_in_parallel<S: Store>(&mut self, store: &mut S) -> Result<(), String> {
+    self.dispatch_in_parallel_with_report(store).1
+  }
+
+  pub fn dispatch_in_parallel_with_report<S: Store>(
+    &mut self,
+    store: &mut S,
+  ) -> (DispatchReport, Result<(), String>) {
+    if self.workers <= 1 {
+      return self.dispatch_with_report(store);
+    }
+    let mut report = DispatchReport::default();
+    let result = self.dispatch_in_parallel_reporting(store, &mut report);
+    (report, result)
+  }
+
+  fn dispatch_in_parallel_reporting<S: Store>(
+    &mut self,
+    store: &mut S,
+    report: &mut DispatchReport,
+  ) -> Result<(), String> {
+    let commits = store
+      .get_undispatched_commits()
+      .map_err(|err| err.to_string())?;
+    let now = Instant::now();
+    let mut lanes: Vec<Vec<Commit>> = Vec::new();
+    let mut lane_by_aggregate = HashMap::new();
+    let mut blocked_aggregates = HashSet::new();
+    let mut first_error = None;
+    for commit in commits {
+      if blocked_aggregates.contains(&commit.aggregate_id) {
+        continue;
+      }
+      if self.retries.is_waiting(&commit.commit_id, now) {
+        blocked_aggregates.insert(commit.aggregate_id);
+        continue;
+      }
+      let aggregate_id = commit.aggregate_id;
+      let commit = match self.prepare(store, commit, &mut first_error, report)? {
+        Prepared::Deliver(commit) => commit,
+        Prepared::Skipped => continue,
+        Prepared::Failed => {
+          blocked_aggregates.insert(aggregate_id);
+          continue;
+        }
+      };
+      let lane = *lane_by_aggregate
+        .entry(commit.aggregate_id)
+        .or_insert_with(|| {
+          lanes.push(Vec::new());
+          lanes.len() - 1
+        });
+      lanes[lane].push(commit);
+    }
+    let lanes = Mutex::new(VecDeque::from(lanes));
+    let results: Vec<(Uuid, Result<(), String>)> = thread::scope(|scope| {
+      let workers: Vec<_> = (0..self.workers)
+        .map(|_| {
+          let mut delegate = self.dispatch_delegate.clone();
+          let lanes = &lanes;
+          let batch_size = self.batch_size;
+          scope.spawn(move || {
+            let mut results = Vec::new();
+            loop {
+              let lane = match lanes.lock().unwrap().pop_front() {
+                Some(lane) => lane,
+                None => return results,
+              };
+              for batch in lane.chunks(batch_size) {
+                let result = delegate.dispatch_batch(batch);
+                let failed = result.is_err();
+                results.extend(batch_results(batch, result));
+                if failed {
+                  break;
+                }
+              }
+            }
+          })
+        })
+        .collect();
+      workers
+        .into_iter()
+        .flat_map(|worker| worker.join().expect("dispatch worker panicked"))
+        .collect()
+    });
+    for (commit_id, result) in results {
+      match result {
+        Ok(()) => {
+          self.retries.succeeded(&commit_id);
+          store
+            .mark_commit_as_dispatched(commit_id)
+            .map_err(|err| err.to_string())?;
+          report.dispatched += 1;
+        }
+        Err(err) => {
+          report.failed(self.retries.failed(store, commit_id, &err)?);
+          first_error.get_or_insert(err);
+        }
+      }
+    }
+    match first_error {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+}
+
+pub type DispatchFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+
+// For delegates that publish over the network, such as to Kafka, SNS or a
+// webhook, without blocking a thread while they wait.
+pub trait AsyncDispatchDelegate {
+  fn dispatch(&mut self, commit: &Commit) -> DispatchFuture;
+}
+
+pub struct AsyncDispatcher<D: AsyncDispatchDelegate> {
+  pub dispatch_delegate: D,
+}
+
+impl<D: AsyncDispatchDelegate> AsyncDispatcher<D> {
+  pub fn new(delegate: D) -> AsyncDispatcher<D> {
+    AsyncDispatcher {
+      dispatch_delegate: delegate,
+    }
+  }
+
+  // Dispatches the undispatched commits one at a time, marking each as
+  // dispatched once its future resolves. The store is only touched between
+  // dispatches.
+  pub fn dispatch<'a, S: Store>(&'a mut self, store: &'a mut S) -> Dispatch<'a, D, S> {
+    Dispatch {
+      delegate: &mut self.dispatch_delegate,
+      store,
+      commits: None,
+      in_flight: None,
+    }
+  }
+}
+
+pub struct Dispatch<'a, D: AsyncDispatchDelegate + 'a, S: Store + 'a> {
+  delegate: &'a mut D,
+  store: &'a mut S,
+  commits: Option<VecDeque<Commit>>,
+  in_flight: Option<(Uuid, DispatchFuture)>,
+}
+
+impl<'a, D: AsyncDispatchDelegate, S: Store> Future for Dispatch<'a, D, S> {
+  type Output = Result<(), String>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), String>> {
+    let this = self.get_mut();
+    loop {
+      if let Some((commit_id, ref mut dispatch)) = this.in_flight {
+        match dispatch.as_mut().poll(cx) {
+          Poll::Pending => return Poll::Pending,
+          Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+          Poll::Ready(Ok(())) => {
+            if let Err(err) = this.store.mark_commit_as_dispatched(commit_id) {
+              return Poll::Ready(Err(err.to_string()));
+            }
+          }
+        }
+        this.in_flight = None;
+      }
+      if this.commits.is_none() {
+        match this.store.get_undispatched_commits() {
+          Ok(commits) => this.commits = Some(commits.into()),
+          Err(err) => return Poll::Ready(Err(err.to_string())),
+        }
+      }
+      match this.commits.as_mut().and_then(|commits| commits.pop_front()) {
+        Some(commit) => this.in_flight = Some((commit.commit_id, this.delegate.dispatch(&commit))),
+        None => return Poll::Ready(Ok(())),
+      }
+    }
+  }
+}
+
+// Wakes an outbox dispatcher before its next poll, typically right after a
+// commit.
+#[derive(Clone, Default)]
+pub struct OutboxNotifier {
+  state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl OutboxNotifier {
+  pub fn notify(&self) {
+    let (ref notified, ref condvar) = *self.state;
+    *notified.lock().unwrap() = true;
+    condvar.notify_all();
+  }
+
+  // Waits until notified or until `timeout` passes, and clears the notification.
+  pub fn wait_timeout(&self, timeout: Duration) {
+    let (ref notified, ref condvar) = *self.state;
+    let guard = notified.lock().unwrap();
+    let (mut guard, _) = condvar
+      .wait_timeout_while(guard, timeout, |notified| !*notified)
+      .unwrap();
+    *guard = false;
+  }
+}
+
+pub struct NullDispatcher;
+impl DispatchDelegate for NullDispatcher {
+  fn dispatch(&mut self, _commit: &Commit) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+impl AsyncDispatchDelegate for NullDispatcher {
+  fn dispatch(&mut self, _commit: &Commit) -> DispatchFuture {
+    Box::pin(::std::future::ready(Ok(())))
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::commit::CommitAttempt;
+  use crate::store::sqlite::SqliteStore;
+  use chrono::Utc;
+  use std::sync::{Arc, Mutex};
+  use std::task::Waker;
+
+  // Resolves on its second poll, like a send waiting on the network.
+  struct YieldOnce {
+    yielded: bool,
+  }
+
+  impl Future for YieldOnce {
+    type Output = Result<(), String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), String>> {
+      if self.yielded {
+        Poll::Ready(Ok(()))
+      } else {
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+      }
+    }
+  }
+
+  struct RecordingDelegate {
+    commit_ids: Arc<Mutex<Vec<Uuid>>>,
+  }
+
+  impl AsyncDispatchDelegate for RecordingDelegate {
+    fn dispatch(&mut self, commit: &Commit) -> DispatchFuture {
+      self.commit_ids.lock().unwrap().push(commit.commit_id);
+      Box::pin(YieldOnce { yielded: false })
+    }
+  }
+
+  #[test]
+  fn it_dispatches_asynchronously() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..2 {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id,
+          aggregate_version: version,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: version,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: b"[]".to_vec(),
+          events_count: 0,
+        })
+        .unwrap();
+    }
+    let commit_ids = Arc::new(Mutex::new(Vec::new()));
+    let mut dispatcher = AsyncDispatcher::new(RecordingDelegate {
+      commit_ids: Arc::clone(&commit_ids),
+    });
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut polls = 0;
+    {
+      let mut dispatch = dispatcher.dispatch(&mut store);
+      loop {
+        polls += 1;
+        if let Poll::Ready(result) = Pin::new(&mut dispatch).poll(&mut cx) {
+          assert_eq!(result, Ok(()));
+          break;
+        }
+      }
+    }
+    assert_eq!(polls, 3);
+    assert_eq!(commit_ids.lock().unwrap().len(), 2);
+    assert!(store.get_undispatched_commits().unwrap().is_empty());
+  }
+
+  fn commit_to(store: &mut SqliteStore, aggregate_id: Uuid, aggregate_version: i64) -> Uuid {
+    let commit_id = Uuid::new_v4();
+    store
+      .commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id,
+        commit_timestamp: Utc::now(),
+        commit_sequence: aggregate_version,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: b"[]".to_vec(),
+        events_count: 0,
+      })
+      .unwrap();
+    commit_id
+  }
+
+  struct FailingDelegate {
+    failing_commit_id: Uuid,
+    dispatched: Vec<Uuid>,
+  }
+
+  impl DispatchDelegate for FailingDelegate {
+    fn dispatch(&mut self, commit: &Commit) -> Result<(), String> {
+      if commit.commit_id == self.failing_commit_id {
+        return Err(String::from("broker down"));
+      }
+      self.dispatched.push(commit.commit_id);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn it_poisons_commits_that_keep_failing() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let (stuck_id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
+    let poisoned_id = commit_to(&mut store, stuck_id, 0);
+    let blocked_id = commit_to(&mut store, stuck_id, 1);
+    let other_commit_id = commit_to(&mut store, other_id, 0);
+    let mut dispatcher = Dispatcher::new(FailingDelegate {
+      failing_commit_id: poisoned_id,
+      dispatched: Vec::new(),
+    })
+    .with_retry_policy(RetryPolicy {
+      max_attempts: 2,
+      initial_backoff: Duration::from_secs(0),
+      max_backoff: Duration::from_secs(0),
+    });
+
+    // The failing commit holds back the rest of its aggregate, but not others.
+    assert_eq!(
+      dispatcher.dispatch_with_report(&mut store),
+      (
+        DispatchReport { dispatched: 1, poisoned: 0 },
+        Err(String::from("broker down"))
+      )
+    );
+    assert_eq!(dispatcher.dispatch_delegate.dispatched, vec![other_commit_id]);
+    assert_eq!(dispatcher.pending_retries(), 1);
+
+    assert_eq!(
+      dispatcher.dispatch_with_report(&mut store),
+      (
+        DispatchReport { dispatched: 0, poisoned: 1 },
+        Err(String::from("broker down"))
+      )
+    );
+    assert_eq!(dispatcher.pending_retries(), 0);
+    let poisoned = store.get_poisoned_commits().unwrap();
+    assert_eq!(poisoned.len(), 1);
+    assert_eq!(poisoned[0].commit_id, poisoned_id);
+    assert_eq!(poisoned[0].attempts, 2);
+
+    assert_eq!(dispatcher.dispatch(&mut store), Ok(()));
+    assert_eq!(dispatcher.dispatch_delegate.dispatched, vec![other_commit_id, blocked_id]);
+    assert!(store.get_undispatched_commits().unwrap().is_empty());
+    assert!(store.requeue_poisoned_commit(poisoned_id).unwrap());
+    assert_eq!(store.get_undispatched_commits().unwrap().len(), 1);
+  }
+
+  #[derive(Clone)]
+  struct SlowDelegate {
+    delivered: Arc<Mutex<Vec<(Uuid, i64)>>>,
+  }
+
+  impl DispatchDelegate for SlowDelegate {
+    fn dispatch(&mut self, commit: &Commit) -> Result<(), String> {
+      thread::sleep(Duration::from_millis(1));
+      self
+        .delivered
+        .lock()
+        .unwrap()
+        .push((commit.aggregate_id, commit.aggregate_version));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn it_keeps_each_aggregate_in_order_when_dispatching_in_parallel() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+    for version in 0..5 {
+      for aggregate_id in aggregate_ids.iter() {
+        commit_to(&mut store, *aggregate_id, version);
+      }
+    }
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let mut dispatcher = Dispatcher::new(SlowDelegate {
+      delivered: Arc::clone(&delivered),
+    })
+    .with_workers(3);
+
+    assert_eq!(dispatcher.dispatch_in_parallel(&mut store), Ok(()));
+    let delivered = delivered.lock().unwrap();
+    assert_eq!(delivered.len(), 20);
+    for aggregate_id in aggregate_ids {
+      let versions: Vec<i64> = delivered
+        .iter()
+        .filter(|&&(id, _)| id == aggregate_id)
+        .map(|&(_, version)| version)
+        .collect();
+      assert_eq!(versions, vec![0, 1, 2, 3, 4]);
+    }
+    assert!(store.get_undispatched_commits().unwrap().is_empty());
+  }
+
+  #[test]
+  fn it_filters_and_transforms_commits_through_middleware() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let internal_id = commit_to(&mut store, Uuid::new_v4(), 0);
+    let public_id = commit_to(&mut store, Uuid::new_v4(), 0);
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let mut dispatcher = Dispatcher::new(SlowDelegate {
+      delivered: Arc::clone(&delivered),
+    })
+    .with_middleware(move |commit: Commit| {
+      Ok(if commit.commit_id == internal_id {
+        None
+      } else {
+        Some(commit)
+      })
+    })
+    .with_middleware(|mut commit: Commit| {
+      commit.aggregate_version = 42;
+      Ok(Some(commit))
+    });
+
+    assert_eq!(dispatcher.dispatch(&mut store), Ok(()));
+    assert_eq!(delivered.lock().unwrap().len(), 1);
+    assert_eq!(delivered.lock().unwrap()[0].1, 42);
+    assert!(store.get_undispatched_commits().unwrap().is_empty());
+    assert!(store.get_commit(&internal_id).unwrap().dispatched);
+    assert!(store.get_commit(&public_id).unwrap().dispatched);
+  }
+
+  // Takes whole batches, failing any batch that contains `failing_commit_id`
+  // after delivering the commits before it.
+  struct BatchingDelegate {
+    failing_commit_id: Uuid,
+    batches: Vec<Vec<Uuid>>,
+  }
+
+  impl DispatchDelegate for BatchingDelegate {
+    fn dispatch(&mut self, _commit: &Commit) -> Result<(), String> {
+      unreachable!("batching delegates take whole batches")
+    }
+
+    fn dispatch_batch(&mut self, commits: &[Commit]) -> Result<(), BatchDispatchError> {
+      self
+        .batches
+        .push(commits.iter().map(|commit| commit.commit_id).collect());
+      match commits
+        .iter()
+        .position(|commit| commit.commit_id == self.failing_commit_id)
+      {
+        Some(delivered) => Err(BatchDispatchError {
+          delivered,
+          error: String::from("rejected"),
+        }),
+        None => Ok(()),
+      }
+    }
+  }
+
+  #[test]
+  fn it_dispatches_backlogs_in_batches() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let (stuck_id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
+    let first = commit_to(&mut store, other_id, 0);
+    let failing = commit_to(&mut store, stuck_id, 0);
+    let blocked = commit_to(&mut store, stuck_id, 1);
+    let second = commit_to(&mut store, other_id, 1);
+    let third = commit_to(&mut store, other_id, 2);
+    let mut dispatcher = Dispatcher::new(BatchingDelegate {
+      failing_commit_id: failing,
+      batches: Vec::new(),
+    })
+    .with_batch_size(3);
+
+    assert_eq!(dispatcher.dispatch(&mut store), Err(String::from("rejected")));
+    // The commit after the failure is retried in the next batch, but the failed
+    // aggregate's later commit is held back.
+    assert_eq!(
+      dispatcher.dispatch_delegate.batches,
+      vec![vec![first, failing, blocked], vec![second, third]]
+    );
+    let undispatched: Vec<Uuid> = store
+      .get_undispatched_commits()
+      .unwrap()
+      .iter()
+      .map(|commit| commit.commit_id)
+      .collect();
+    assert_eq!(undispatched, vec![failing, blocked]);
+  }
+
+  #[test]
+  fn it_doubles_the_backoff_up_to_the_limit() {
+    let policy = RetryPolicy {
+      max_attempts: 10,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(1),
+    };
+    assert_eq!(policy.backoff(1), Duration::from_millis(100));
+    assert_eq!(policy.backoff(3), Duration::from_millis(400));
+    assert_eq!(policy.backoff(5), Duration::from_secs(1));
+    assert_eq!(policy.backoff(40), Duration::from_secs(1));
+  }
+}
@@ -0,0 +1,261 @@
+use super::{AsyncDispatchDelegate, OutboxNotifier, Retries, RetryPolicy};
+use crate::store::Store;
+use futures::{future, stream, FutureExt, StreamExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+const DEFAULT_BATCH_SIZE: i64 = 100;
+const DEFAULT_CONCURRENCY: usize = 8;
+
+// Dispatches undispatched commits in the background, including those written by
+// other processes. It polls every `poll_interval` and whenever its notifier is
+// woken. Up to `concurrency` commits of a batch are in flight at once, so
+// delegates must not rely on commits arriving in order unless it is 1.
+pub struct OutboxDispatcher<D: AsyncDispatchDelegate> {
+  pub dispatch_delegate: D,
+  batch_size: i64,
+  concurrency: usize,
+  poll_interval: Duration,
+  notifier: OutboxNotifier,
+  retries: Retries,
+}
+
+impl<D: AsyncDispatchDelegate> OutboxDispatcher<D> {
+  pub fn new(delegate: D) -> OutboxDispatcher<D> {
+    OutboxDispatcher {
+      dispatch_delegate: delegate,
+      batch_size: DEFAULT_BATCH_SIZE,
+      concurrency: DEFAULT_CONCURRENCY,
+      poll_interval: Duration::from_secs(1),
+      notifier: OutboxNotifier::default(),
+      retries: Retries::default(),
+    }
+  }
+
+  pub fn with_batch_size(mut self, batch_size: i64) -> OutboxDispatcher<D> {
+    self.batch_size = batch_size;
+    self
+  }
+
+  pub fn with_concurrency(mut self, concurrency: usize) -> OutboxDispatcher<D> {
+    self.concurrency = concurrency.max(1);
+    self
+  }
+
+  pub fn with_poll_interval(mut self, poll_interval: Duration) -> OutboxDispatcher<D> {
+    self.poll_interval = poll_interval;
+    self
+  }
+
+  pub fn with_retry_policy(mut self, policy: RetryPolicy) -> OutboxDispatcher<D> {
+    self.retries.policy = policy;
+    self
+  }
+
+  // Hand this to `ClientBuilder::with_outbox_notifier` to dispatch right after
+  // each commit.
+  pub fn notifier(&self) -> OutboxNotifier {
+    self.notifier.clone()
+  }
+
+  // Dispatches one batch and marks the commits that were delivered. Failed
+  // commits are retried in later batches per the retry policy. Returns how many
+  // were dispatched, or the first delegate error once the rest of the batch has
+  // settled.
+  pub fn dispatch_batch<S: Store>(
+    &mut self,
+    runtime: &Runtime,
+    store: &mut S,
+  ) -> Result<usize, String> {
+    let commits = store
+      .get_undispatched_commits_up_to(self.batch_size)
+      .map_err(|err| err.to_string())?;
+    let now = Instant::now();
+    let retries = &self.retries;
+    let delegate = &mut self.dispatch_delegate;
+    let results: Vec<(Uuid, Result<(), String>)> = runtime.block_on(
+      stream::iter(commits)
+        .filter(|commit| future::ready(!retries.is_waiting(&commit.commit_id, now)))
+        .map(|commit| {
+          let commit_id = commit.commit_id;
+          delegate
+            .dispatch(&commit)
+            .map(move |result| (commit_id, result))
+        })
+        .buffer_unordered(self.concurrency)
+        .collect(),
+    );
+    let mut dispatched = 0;
+    let mut first_error = None;
+    for (commit_id, result) in results {
+      match result {
+        Ok(()) => {
+          self.retries.succeeded(&commit_id);
+          store
+            .mark_commit_as_dispatched(commit_id)
+            .map_err(|err| err.to_string())?;
+          dispatched += 1;
+        }
+        Err(err) => {
+          self.retries.failed(store, commit_id, &err)?;
+          first_error.get_or_insert(err);
+        }
+      }
+    }
+    match first_error {
+      Some(err) => Err(err),
+      None => Ok(dispatched),
+    }
+  }
+}
+
+impl<D: AsyncDispatchDelegate + Send + 'static> OutboxDispatcher<D> {
+  // Runs on its own thread with its own store and runtime until stopped.
+  pub fn spawn<S, F>(mut self, store_factory: F) -> OutboxHandle
+  where
+    S: Store,
+    F: FnOnce() -> S + Send + 'static,
+  {
+    let notifier = self.notifier.clone();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let last_error = Arc::new(Mutex::new(None));
+    let thread = {
+      let stopped = Arc::clone(&stopped);
+      let last_error = Arc::clone(&last_error);
+      thread::spawn(move || {
+        let runtime = match Runtime::new() {
+          Ok(runtime) => runtime,
+          Err(err) => {
+            *last_error.lock().unwrap() = Some(err.to_string());
+            return;
+          }
+        };
+        let mut store = store_factory();
+        while !stopped.load(Ordering::SeqCst) {
+          match self.dispatch_batch(&runtime, &mut store) {
+            Ok(dispatched) if dispatched as i64 >= self.batch_size => continue,
+            Ok(_) => (),
+            Err(err) => *last_error.lock().unwrap() = Some(err),
+          }
+          self.notifier.wait_timeout(self.poll_interval);
+        }
+      })
+    };
+    OutboxHandle {
+      notifier,
+      stopped,
+      last_error,
+      thread,
+    }
+  }
+}
+
+pub struct OutboxHandle {
+  notifier: OutboxNotifier,
+  stopped: Arc<AtomicBool>,
+  last_error: Arc<Mutex<Option<String>>>,
+  thread: JoinHandle<()>,
+}
+
+impl OutboxHandle {
+  pub fn notifier(&self) -> OutboxNotifier {
+    self.notifier.clone()
+  }
+
+  // The most recent error from a batch; dispatching carries on regardless.
+  pub fn last_error(&self) -> Option<String> {
+    self.last_error.lock().unwrap().clone()
+  }
+
+  // Finishes the current batch and waits for the dispatcher thread to exit.
+  pub fn stop(self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    self.notifier.notify();
+    let _panicked = self.thread.join();
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::commit::{Commit, CommitAttempt};
+  use crate::dispatch::DispatchFuture;
+  use crate::store::sqlite::SqliteStore;
+  use chrono::Utc;
+  use std::env;
+  use std::fs;
+
+  #[derive(Clone)]
+  struct RecordingDelegate {
+    commit_ids: Arc<Mutex<Vec<Uuid>>>,
+  }
+
+  impl AsyncDispatchDelegate for RecordingDelegate {
+    fn dispatch(&mut self, commit: &Commit) -> DispatchFuture {
+      self.commit_ids.lock().unwrap().push(commit.commit_id);
+      Box::pin(::std::future::ready(Ok(())))
+    }
+  }
+
+  fn commit_to(store: &mut SqliteStore, aggregate_id: Uuid, aggregate_version: i64) {
+    store
+      .commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_timestamp: Utc::now(),
+        commit_sequence: aggregate_version,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: b"[]".to_vec(),
+        events_count: 0,
+      })
+      .unwrap();
+  }
+
+  #[test]
+  fn it_dispatches_commits_written_elsewhere_in_the_background() {
+    let path = env::temp_dir().join(format!("event_source_outbox_{}.sqlite", Uuid::new_v4()));
+    let mut writer = SqliteStore::with_new_connection_at_path(&path);
+    writer.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..3 {
+      commit_to(&mut writer, aggregate_id, version);
+    }
+    let commit_ids = Arc::new(Mutex::new(Vec::new()));
+    let store_path = path.clone();
+    let handle = OutboxDispatcher::new(RecordingDelegate {
+      commit_ids: Arc::clone(&commit_ids),
+    })
+    .with_batch_size(2)
+    .with_poll_interval(Duration::from_secs(60))
+    .spawn(move || SqliteStore::with_new_connection_at_path(&store_path));
+
+    let wait_for = |count: usize| {
+      for _ in 0..200 {
+        if commit_ids.lock().unwrap().len() >= count {
+          return;
+        }
+        thread::sleep(Duration::from_millis(10));
+      }
+    };
+    wait_for(3);
+    assert_eq!(commit_ids.lock().unwrap().len(), 3);
+
+    // The poll interval is a minute, so only the notification wakes it.
+    commit_to(&mut writer, aggregate_id, 3);
+    handle.notifier().notify();
+    wait_for(4);
+    assert_eq!(commit_ids.lock().unwrap().len(), 4);
+    assert!(handle.last_error().is_none());
+    handle.stop();
+    assert!(writer.get_undispatched_commits().unwrap().is_empty());
+    fs::remove_file(&path).unwrap();
+  }
+}
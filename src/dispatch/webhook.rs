@@ -0,0 +1,374 @@
+#[cfg(feature = "cloudevents")]
+use super::cloudevents::{self, CloudEventsFormat};
+use super::{AsyncDispatchDelegate, DispatchFuture, RetryPolicy};
+use crate::commit::Commit;
+use futures::future::{self, FutureExt};
+use hmac::{Hmac, KeyInit, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub const SIGNATURE_HEADER: &str = "x-event-source-signature";
+pub const COMMIT_ID_HEADER: &str = "x-event-source-commit-id";
+
+#[derive(Clone, Debug)]
+pub struct WebhookEndpoint {
+  pub url: String,
+  pub secret: Vec<u8>,
+  pub timeout: Duration,
+}
+
+impl WebhookEndpoint {
+  pub fn new(url: &str, secret: &[u8]) -> WebhookEndpoint {
+    WebhookEndpoint {
+      url: String::from(url),
+      secret: secret.to_vec(),
+      timeout: Duration::from_secs(10),
+    }
+  }
+
+  pub fn with_timeout(mut self, timeout: Duration) -> WebhookEndpoint {
+    self.timeout = timeout;
+    self
+  }
+}
+
+// The `sha256=`-prefixed hex HMAC of a request body, as sent in
+// `SIGNATURE_HEADER`. Receivers recompute it with their copy of the secret.
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any length");
+  mac.update(body);
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Default)]
+struct EndpointState {
+  consecutive_failures: u32,
+  retry_at: Option<Instant>,
+  // Commits this endpoint accepted while another endpoint failed them.
+  delivered: HashSet<Uuid>,
+}
+
+struct Target {
+  endpoint: WebhookEndpoint,
+  state: Arc<Mutex<EndpointState>>,
+}
+
+// POSTs each commit as JSON to every endpoint, signed with the endpoint's
+// secret. An endpoint that fails is skipped until its backoff passes, and the
+// commit fails until every endpoint has accepted it; endpoints that already
+// accepted it are not sent it again.
+pub struct WebhookDispatcher {
+  client: Client<HttpsConnector<HttpConnector>>,
+  targets: Vec<Target>,
+  retry_policy: RetryPolicy,
+  #[cfg(feature = "cloudevents")]
+  cloudevents: Option<CloudEventsFormat>,
+}
+
+impl Default for WebhookDispatcher {
+  fn default() -> WebhookDispatcher {
+    WebhookDispatcher::new()
+  }
+}
+
+impl WebhookDispatcher {
+  // Trusts the platform's root certificates. Plain http URLs are allowed for
+  // local receivers.
+  pub fn new() -> WebhookDispatcher {
+    let connector = HttpsConnectorBuilder::new()
+      .with_native_roots()
+      .https_or_http()
+      .enable_http1()
+      .build();
+    WebhookDispatcher {
+      client: Client::builder().build(connector),
+      targets: Vec::new(),
+      retry_policy: RetryPolicy::default(),
+      #[cfg(feature = "cloudevents")]
+      cloudevents: None,
+    }
+  }
+
+  pub fn with_endpoint(mut self, endpoint: WebhookEndpoint) -> WebhookDispatcher {
+    self.targets.push(Target {
+      endpoint,
+      state: Arc::default(),
+    });
+    self
+  }
+
+  // Only the backoff applies; each endpoint keeps retrying for as long as the
+  // surrounding dispatcher retries the commit.
+  pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> WebhookDispatcher {
+    self.retry_policy = retry_policy;
+    self
+  }
+
+  // Posts each commit as a structured-mode CloudEvent instead, for receivers
+  // such as a Knative broker or an EventBridge API destination.
+  #[cfg(feature = "cloudevents")]
+  pub fn with_cloudevents(mut self, format: CloudEventsFormat) -> WebhookDispatcher {
+    self.cloudevents = Some(format);
+    self
+  }
+
+  // The request body for a commit and its content type.
+  fn body(&self, commit: &Commit) -> Result<(Vec<u8>, &'static str), String> {
+    #[cfg(feature = "cloudevents")]
+    {
+      if let Some(ref format) = self.cloudevents {
+        return format
+          .to_json(commit)
+          .map(|body| (body, cloudevents::CONTENT_TYPE))
+          .map_err(|err| err.to_string());
+      }
+    }
+    let commit = commit.deserialize().map_err(|err| err.to_string())?;
+    serde_json::to_vec(&commit)
+      .map(|body| (body, "application/json"))
+      .map_err(|err| err.to_string())
+  }
+
+  // Consecutive failures of each endpoint, by URL.
+  pub fn endpoint_failures(&self) -> Vec<(&str, u32)> {
+    self
+      .targets
+      .iter()
+      .map(|target| {
+        let failures = target.state.lock().unwrap().consecutive_failures;
+        (target.endpoint.url.as_str(), failures)
+      })
+      .collect()
+  }
+
+  fn deliver(
+    &self,
+    target: &Target,
+    commit_id: Uuid,
+    body: &[u8],
+    content_type: &str,
+  ) -> DispatchFuture {
+    let url = target.endpoint.url.clone();
+    {
+      let state = target.state.lock().unwrap();
+      if state.delivered.contains(&commit_id) {
+        return Box::pin(future::ready(Ok(())));
+      }
+      if state
+        .retry_at
+        .is_some_and(|retry_at| retry_at > Instant::now())
+      {
+        return Box::pin(future::ready(Err(format!(
+          "{}: backing off after {} failures",
+          url, state.consecutive_failures
+        ))));
+      }
+    }
+    let request = Request::post(url.as_str())
+      .header("content-type", content_type)
+      .header(SIGNATURE_HEADER, sign(&target.endpoint.secret, body))
+      .header(COMMIT_ID_HEADER, commit_id.to_string())
+      .body(Body::from(body.to_vec()));
+    let request = match request {
+      Ok(request) => request,
+      Err(err) => return Box::pin(future::ready(Err(format!("{}: {}", url, err)))),
+    };
+    let response = self.client.request(request);
+    let timeout = target.endpoint.timeout;
+    let state = Arc::clone(&target.state);
+    let retry_policy = self.retry_policy.clone();
+    // The timer is created when first polled, inside the caller's runtime.
+    Box::pin(
+      future::lazy(move |_| tokio::time::timeout(timeout, response))
+        .flatten()
+        .map(move |result| {
+          let result = match result {
+            Ok(Ok(ref response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("{}: responded {}", url, response.status())),
+            Ok(Err(err)) => Err(format!("{}: {}", url, err)),
+            Err(_) => Err(format!("{}: timed out after {:?}", url, timeout)),
+          };
+          let mut state = state.lock().unwrap();
+          match result {
+            Ok(()) => {
+              state.consecutive_failures = 0;
+              state.retry_at = None;
+              state.delivered.insert(commit_id);
+            }
+            Err(_) => {
+              state.consecutive_failures += 1;
+              state.retry_at =
+                Some(Instant::now() + retry_policy.backoff(state.consecutive_failures));
+            }
+          }
+          result
+        }),
+    )
+  }
+}
+
+impl AsyncDispatchDelegate for WebhookDispatcher {
+  fn dispatch(&mut self, commit: &Commit) -> DispatchFuture {
+    let (body, content_type) = match self.body(commit) {
+      Ok(body) => body,
+      Err(err) => return Box::pin(future::ready(Err(err))),
+    };
+    let commit_id = commit.commit_id;
+    let deliveries: Vec<DispatchFuture> = self
+      .targets
+      .iter()
+      .map(|target| self.deliver(target, commit_id, &body, content_type))
+      .collect();
+    let states: Vec<Arc<Mutex<EndpointState>>> = self
+      .targets
+      .iter()
+      .map(|target| Arc::clone(&target.state))
+      .collect();
+    Box::pin(future::join_all(deliveries).map(move |results| {
+      let errors: Vec<String> = results.into_iter().filter_map(Result::err).collect();
+      if !errors.is_empty() {
+        return Err(errors.join("; "));
+      }
+      for state in states {
+        state.lock().unwrap().delivered.remove(&commit_id);
+      }
+      Ok(())
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+  use std::thread;
+  use tokio::runtime::Runtime;
+
+  struct ReceivedRequest {
+    signature: String,
+    body: Vec<u8>,
+  }
+
+  // Answers each connection with the next status in `statuses`.
+  fn serve(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<ReceivedRequest>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+      let mut received = Vec::new();
+      for status in statuses {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let (mut signature, mut content_length) = (String::new(), 0);
+        loop {
+          let mut line = String::new();
+          reader.read_line(&mut line).unwrap();
+          let line = line.trim_end();
+          if line.is_empty() {
+            break;
+          }
+          let (name, value) = line.split_once(": ").unwrap_or((line, ""));
+          match name.to_lowercase().as_str() {
+            SIGNATURE_HEADER => signature = String::from(value),
+            "content-length" => content_length = value.parse().unwrap(),
+            _ => (),
+          }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        received.push(ReceivedRequest { signature, body });
+        write!(
+          reader.get_mut(),
+          "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+          status
+        )
+        .unwrap();
+      }
+      received
+    });
+    (url, handle)
+  }
+
+  #[test]
+  fn it_posts_signed_commits_and_backs_off_failing_endpoints() {
+    let (url, server) = serve(vec![500, 200]);
+    let mut dispatcher = WebhookDispatcher::new()
+      .with_endpoint(WebhookEndpoint::new(&url, b"secret"))
+      .with_retry_policy(RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(50),
+      });
+    let commit = Commit {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 0,
+      commit_number: 1,
+      serialized_events: b"[]".to_vec(),
+      serialized_metadata: b"null".to_vec(),
+      events_count: 0,
+      dispatched: false,
+    };
+    let runtime = Runtime::new().unwrap();
+
+    assert!(runtime.block_on(dispatcher.dispatch(&commit)).is_err());
+    assert_eq!(dispatcher.endpoint_failures(), vec![(url.as_str(), 1)]);
+    let backing_off = runtime.block_on(dispatcher.dispatch(&commit)).unwrap_err();
+    assert!(backing_off.contains("backing off"));
+
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(runtime.block_on(dispatcher.dispatch(&commit)), Ok(()));
+    assert_eq!(dispatcher.endpoint_failures(), vec![(url.as_str(), 0)]);
+
+    let received = server.join().unwrap();
+    assert_eq!(received.len(), 2);
+    for request in received {
+      assert_eq!(request.signature, sign(b"secret", &request.body));
+      let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+      assert_eq!(body["commit_id"], commit.commit_id.to_string());
+    }
+  }
+  #[cfg(feature = "cloudevents")]
+  #[test]
+  fn it_posts_cloud_events() {
+    let (url, server) = serve(vec![200]);
+    let mut dispatcher = WebhookDispatcher::new()
+      .with_endpoint(WebhookEndpoint::new(&url, b"secret"))
+      .with_cloudevents(CloudEventsFormat::default().with_type_prefix("com.example"));
+    let commit = Commit {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::from("order"),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 1,
+      commit_number: 1,
+      serialized_events: b"[]".to_vec(),
+      serialized_metadata: b"null".to_vec(),
+      events_count: 0,
+      dispatched: false,
+    };
+    let runtime = Runtime::new().unwrap();
+    assert_eq!(runtime.block_on(dispatcher.dispatch(&commit)), Ok(()));
+
+    let received = server.join().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+    assert_eq!(body["id"], commit.commit_id.to_string());
+    assert_eq!(body["type"], "com.example.order.committed");
+    assert_eq!(body["data"]["commit_id"], commit.commit_id.to_string());
+  }
+}
@@ -1,5 +1,296 @@
+use crate::codec::{self, CodecError, Registries};
+#[cfg(feature = "json-schema")]
+use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde::Serialize;
-use std::fmt::Debug;
+use serde_json::{Map, Value};
+use std::fmt::{self, Debug};
 
-pub trait Event: Serialize + DeserializeOwned + Debug {}
+pub trait Event: Serialize + DeserializeOwned + Debug {
+  // The name subscriptions filter on. Filters read it from serialized commits
+  // without deserializing the events, so it must stay the tag serde writes: the
+  // variant name of an externally tagged enum, or the string of a unit variant.
+  fn event_type(&self) -> String {
+    match serde_json::to_value(self) {
+      Ok(Value::String(name)) => name,
+      Ok(Value::Object(map)) if map.len() == 1 => map.keys().next().cloned().unwrap_or_default(),
+      _ => String::new(),
+    }
+  }
+
+  // Bumped when the shape of an event type changes, so readers of older
+  // commits can tell which shape they hold.
+  fn schema_version(&self) -> u32 {
+    1
+  }
+}
+
+// How `Client` serializes each event: its payload beside the type and schema
+// version it was written with, so stores, subscribers and other languages can
+// tell events apart without the aggregate's event enum.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "json-schema", derive(JsonSchema))]
+pub struct EventEnvelope<E> {
+  pub event_type: String,
+  pub schema_version: u32,
+  pub data: E,
+  // Fields about this event alone, such as the actor or trace id of the
+  // sub-operation that caused it; see `Command::event_metadata`. Left out when
+  // empty, so envelopes without any read as they did before.
+  #[serde(default, skip_serializing_if = "Map::is_empty")]
+  pub metadata: Map<String, Value>,
+}
+
+impl<E: Event> EventEnvelope<E> {
+  pub fn new(event: E) -> EventEnvelope<E> {
+    EventEnvelope {
+      event_type: event.event_type(),
+      schema_version: event.schema_version(),
+      data: event,
+      metadata: Map::new(),
+    }
+  }
+}
+
+impl<E> EventEnvelope<E> {
+  pub fn with_metadata(mut self, metadata: Map<String, Value>) -> EventEnvelope<E> {
+    self.metadata = metadata;
+    self
+  }
+}
+
+impl<'a, E: Event> From<&'a E> for EventEnvelope<&'a E> {
+  fn from(event: &'a E) -> EventEnvelope<&'a E> {
+    EventEnvelope {
+      event_type: event.event_type(),
+      schema_version: event.schema_version(),
+      data: event,
+      metadata: Map::new(),
+    }
+  }
+}
+
+// Bincode reads fields by position, so it can't leave out empty metadata;
+// bincode commits are written and read without it.
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+struct BincodeEnvelope<E> {
+  event_type: String,
+  schema_version: u32,
+  data: E,
+}
+
+// Commits written before events were enveloped hold bare events.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEvent<E> {
+  Enveloped(EventEnvelope<E>),
+  Bare(E),
+}
+
+// Reads a commit's events whether or not they were enveloped.
+pub fn decode_events<E: Event>(serialized_events: &[u8]) -> Result<Vec<E>, CodecError> {
+  decode_events_with(serialized_events, &Registries::default())
+}
+
+// Like `decode_events`, reading codecs that need a schema registry from
+// `registries`.
+pub fn decode_events_with<E: Event>(
+  serialized_events: &[u8],
+  registries: &Registries,
+) -> Result<Vec<E>, CodecError> {
+  let envelopes = decode_envelopes_with(serialized_events, registries)?;
+  Ok(envelopes.into_iter().map(|envelope| envelope.data).collect())
+}
+
+// Like `decode_events`, enveloping bare events the way `Client` would now.
+// Bincode can't tell the two apart, but every bincode commit was written
+// enveloped.
+pub fn decode_envelopes<E: Event>(
+  serialized_events: &[u8],
+) -> Result<Vec<EventEnvelope<E>>, CodecError> {
+  decode_envelopes_with(serialized_events, &Registries::default())
+}
+
+pub fn decode_envelopes_with<E: Event>(
+  serialized_events: &[u8],
+  registries: &Registries,
+) -> Result<Vec<EventEnvelope<E>>, CodecError> {
+  let serialized_events = &*codec::decompress(serialized_events)?;
+  #[cfg(feature = "bincode")]
+  {
+    use crate::codec::EventCodec;
+    if codec::content_type(serialized_events) == codec::Bincode::CONTENT_TYPE {
+      let envelopes: Vec<BincodeEnvelope<E>> = codec::decode(serialized_events)?;
+      return Ok(
+        envelopes
+          .into_iter()
+          .map(|envelope| EventEnvelope {
+            event_type: envelope.event_type,
+            schema_version: envelope.schema_version,
+            data: envelope.data,
+            metadata: Map::new(),
+          })
+          .collect(),
+      );
+    }
+  }
+  let events: Vec<StoredEvent<E>> = codec::decode_with(serialized_events, registries)?;
+  Ok(
+    events
+      .into_iter()
+      .map(|event| match event {
+        StoredEvent::Enveloped(envelope) => envelope,
+        StoredEvent::Bare(event) => EventEnvelope::new(event),
+      })
+      .collect(),
+  )
+}
+
+// The event type of one serialized event, read from its envelope or, for a bare
+// event, its serde tag; its payload is skipped.
+struct EventTag(Option<String>);
+
+struct EventTagVisitor;
+
+impl<'de> Visitor<'de> for EventTagVisitor {
+  type Value = EventTag;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "a serialized event")
+  }
+
+  fn visit_str<E: de::Error>(self, value: &str) -> Result<EventTag, E> {
+    Ok(EventTag(Some(String::from(value))))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<EventTag, A::Error> {
+    let mut tag = None;
+    let mut envelope_tag = None;
+    let mut entries = 0;
+    while let Some(EventTag(key)) = map.next_key::<EventTag>()? {
+      entries += 1;
+      if key.as_deref() == Some("event_type") {
+        envelope_tag = map.next_value::<EventTag>()?.0;
+      } else {
+        map.next_value::<IgnoredAny>()?;
+      }
+      if entries == 1 {
+        tag = key;
+      }
+    }
+    Ok(EventTag(envelope_tag.or(if entries == 1 { tag } else { None })))
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<EventTag, A::Error> {
+    while seq.next_element::<IgnoredAny>()?.is_some() {}
+    Ok(EventTag(None))
+  }
+
+  fn visit_bool<E: de::Error>(self, _value: bool) -> Result<EventTag, E> {
+    Ok(EventTag(None))
+  }
+
+  fn visit_i64<E: de::Error>(self, _value: i64) -> Result<EventTag, E> {
+    Ok(EventTag(None))
+  }
+
+  fn visit_u64<E: de::Error>(self, _value: u64) -> Result<EventTag, E> {
+    Ok(EventTag(None))
+  }
+
+  fn visit_f64<E: de::Error>(self, _value: f64) -> Result<EventTag, E> {
+    Ok(EventTag(None))
+  }
+
+  fn visit_unit<E: de::Error>(self) -> Result<EventTag, E> {
+    Ok(EventTag(None))
+  }
+}
+
+impl<'de> Deserialize<'de> for EventTag {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<EventTag, D::Error> {
+    deserializer.deserialize_any(EventTagVisitor)
+  }
+}
+
+// The `Event::event_type` of each event in a commit's serialized events, read
+// without deserializing the events themselves. Events without a tag are `None`.
+pub fn serialized_event_types(
+  serialized_events: &[u8],
+) -> Result<Vec<Option<String>>, CodecError> {
+  let tags: Vec<EventTag> = codec::decode(serialized_events)?;
+  Ok(tags.into_iter().map(|tag| tag.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq)]
+  enum Account {
+    Opened,
+    Deposited { amount: i64 },
+    Renamed(String),
+  }
+
+  impl Event for Account {}
+
+  #[test]
+  fn it_reads_event_types_from_serialized_events() {
+    let events = vec![
+      Account::Opened,
+      Account::Deposited { amount: 3 },
+      Account::Renamed(String::from("savings")),
+    ];
+    let serialized = serde_json::to_vec(&events).unwrap();
+    let declared: Vec<Option<String>> = events.iter().map(|e| Some(e.event_type())).collect();
+    assert_eq!(serialized_event_types(&serialized).unwrap(), declared);
+    assert_eq!(
+      serialized_event_types(b"[1, {\"a\": 1, \"b\": 2}, [\"x\"]]").unwrap(),
+      vec![None, None, None]
+    );
+    let envelopes: Vec<EventEnvelope<&Account>> = events.iter().map(EventEnvelope::from).collect();
+    let serialized = serde_json::to_vec(&envelopes).unwrap();
+    assert_eq!(serialized_event_types(&serialized).unwrap(), declared);
+  }
+
+  #[test]
+  fn it_decodes_enveloped_and_bare_events() {
+    let event = Account::Deposited { amount: 3 };
+    let envelope = serde_json::to_value(EventEnvelope::from(&event)).unwrap();
+    assert_eq!(
+      envelope,
+      serde_json::json!({
+        "event_type": "Deposited",
+        "schema_version": 1,
+        "data": {"Deposited": {"amount": 3}}
+      })
+    );
+    let serialized = serde_json::to_vec(&serde_json::json!([envelope, "Opened"])).unwrap();
+    let events: Vec<Account> = decode_events(&serialized).unwrap();
+    assert_eq!(events, vec![event, Account::Opened]);
+  }
+
+  #[test]
+  fn it_keeps_metadata_with_each_event() {
+    let mut metadata = Map::new();
+    metadata.insert(String::from("actor"), Value::from("teller-7"));
+    let envelopes = vec![
+      EventEnvelope::new(Account::Opened),
+      EventEnvelope::new(Account::Deposited { amount: 3 }).with_metadata(metadata.clone()),
+    ];
+    let serialized = serde_json::to_vec(&envelopes).unwrap();
+    let stored: Value = serde_json::from_slice(&serialized).unwrap();
+    assert!(stored[0].get("metadata").is_none());
+    assert_eq!(stored[1]["metadata"]["actor"], "teller-7");
+    assert_eq!(
+      serialized_event_types(&serialized).unwrap(),
+      vec![Some(String::from("Opened")), Some(String::from("Deposited"))]
+    );
+    let decoded: Vec<EventEnvelope<Account>> = decode_envelopes(&serialized).unwrap();
+    assert_eq!(decoded, envelopes);
+    assert_eq!(decoded[1].metadata, metadata);
+  }
+}
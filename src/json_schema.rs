@@ -0,0 +1,411 @@
+use crate::command::ValidationErrors;
+use crate::events::{Event, EventEnvelope};
+use schemars::gen::SchemaSettings;
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+
+// `T`'s JSON Schema (draft 7), with the types it refers to under `definitions`.
+pub fn schema_for<T: JsonSchema>() -> Value {
+  let schema = SchemaSettings::draft07()
+    .into_generator()
+    .into_root_schema_for::<T>();
+  serde_json::to_value(schema).unwrap_or_default()
+}
+
+// The schema of the envelopes `Client` writes `E`'s events in, as commits,
+// subscriptions and exports carry them.
+pub fn event_schema<E: Event + JsonSchema>() -> Value {
+  schema_for::<EventEnvelope<E>>()
+}
+
+// Checks values against a schema. It understands the parts of draft 7 schemars
+// generates, which covers the types it describes; formats aren't checked.
+#[derive(Clone, Debug)]
+pub struct SchemaValidator {
+  schema: Value,
+}
+
+fn describe(values: &[Value]) -> String {
+  values
+    .iter()
+    .map(Value::to_string)
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+// Bounds as written in messages; schemars writes whole numbers as floats.
+fn bound_text(bound: &Value) -> String {
+  match bound.as_f64() {
+    Some(bound) if bound.fract() == 0.0 => format!("{}", bound as i64),
+    _ => bound.to_string(),
+  }
+}
+
+fn field_path(path: &str, name: &str) -> String {
+  match path {
+    "" => String::from(name),
+    path => format!("{}.{}", path, name),
+  }
+}
+
+fn has_type(value: &Value, type_name: &str) -> bool {
+  match (type_name, value) {
+    ("null", Value::Null)
+    | ("boolean", Value::Bool(_))
+    | ("number", Value::Number(_))
+    | ("string", Value::String(_))
+    | ("array", Value::Array(_))
+    | ("object", Value::Object(_)) => true,
+    ("integer", Value::Number(number)) => {
+      number.is_i64() || number.is_u64() || number.as_f64().is_some_and(|n| n.fract() == 0.0)
+    }
+    _ => false,
+  }
+}
+
+impl SchemaValidator {
+  pub fn new(schema: Value) -> SchemaValidator {
+    SchemaValidator { schema }
+  }
+
+  pub fn for_type<T: JsonSchema>() -> SchemaValidator {
+    SchemaValidator::new(schema_for::<T>())
+  }
+
+  pub fn schema(&self) -> &Value {
+    &self.schema
+  }
+
+  // Everywhere `value` doesn't match, by the path to it, such as
+  // `Deposit.lines[0].amount`; the whole value's path is empty.
+  pub fn validate(&self, value: &Value) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    self.check(&self.schema, value, "", &mut errors);
+    errors.into_result()
+  }
+
+  fn check(&self, schema: &Value, value: &Value, path: &str, errors: &mut ValidationErrors) {
+    let schema = match *schema {
+      Value::Bool(true) => return,
+      Value::Object(ref schema) => schema,
+      _ => return errors.add(path, "is not allowed"),
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+      return match self.schema.pointer(reference.trim_start_matches('#')) {
+        Some(referenced) => self.check(referenced, value, path, errors),
+        None => errors.add(
+          path,
+          &format!("refers to {}, which is not defined", reference),
+        ),
+      };
+    }
+    if let Some(types) = schema.get("type") {
+      let types: Vec<&str> = match *types {
+        Value::String(ref type_name) => vec![type_name],
+        Value::Array(ref types) => types.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+      };
+      if !types.iter().any(|type_name| has_type(value, type_name)) {
+        return errors.add(path, &format!("must be of type {}", types.join(" or ")));
+      }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+      if !allowed.contains(value) {
+        errors.add(path, &format!("must be one of {}", describe(allowed)));
+      }
+    }
+    if let Some(constant) = schema.get("const") {
+      if constant != value {
+        errors.add(path, &format!("must be {}", constant));
+      }
+    }
+    match *value {
+      Value::Number(ref number) => self.check_number(schema, number.as_f64(), path, errors),
+      Value::String(ref string) => {
+        let len = string.chars().count() as u64;
+        if schema
+          .get("minLength")
+          .and_then(Value::as_u64)
+          .is_some_and(|min| len < min)
+        {
+          errors.add(
+            path,
+            &format!("must be at least {} characters", schema["minLength"]),
+          );
+        }
+        if schema
+          .get("maxLength")
+          .and_then(Value::as_u64)
+          .is_some_and(|max| len > max)
+        {
+          errors.add(
+            path,
+            &format!("must be at most {} characters", schema["maxLength"]),
+          );
+        }
+      }
+      Value::Array(ref items) => self.check_array(schema, items, path, errors),
+      Value::Object(ref object) => self.check_object(schema, object, path, errors),
+      _ => (),
+    }
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+      for subschema in all {
+        self.check(subschema, value, path, errors);
+      }
+    }
+    if let Some(any) = schema.get("anyOf").and_then(Value::as_array) {
+      if let Err(closest) = self.check_any(any, value, path) {
+        errors.fields.extend(closest.fields);
+      }
+    }
+    if let Some(one) = schema.get("oneOf").and_then(Value::as_array) {
+      let matching = one
+        .iter()
+        .filter(|subschema| self.matches(subschema, value, path))
+        .count();
+      if matching > 1 {
+        errors.add(path, "matches more than one of the allowed shapes");
+      } else if let Err(closest) = self.check_any(one, value, path) {
+        errors.fields.extend(closest.fields);
+      }
+    }
+    if let Some(not) = schema.get("not") {
+      if self.matches(not, value, path) {
+        errors.add(path, "matches a shape that is not allowed");
+      }
+    }
+  }
+
+  fn matches(&self, schema: &Value, value: &Value, path: &str) -> bool {
+    let mut errors = ValidationErrors::new();
+    self.check(schema, value, path, &mut errors);
+    errors.is_empty()
+  }
+
+  // Passes if `value` matches any of `schemas`. Otherwise it fails with the
+  // errors of the closest: the one with the fewest errors about the value
+  // itself rather than what's inside it, as a variant whose fields are wrong is
+  // closer than one of another type.
+  fn check_any(
+    &self,
+    schemas: &[Value],
+    value: &Value,
+    path: &str,
+  ) -> Result<(), ValidationErrors> {
+    let mut closest: Option<(usize, ValidationErrors)> = None;
+    for subschema in schemas {
+      let mut errors = ValidationErrors::new();
+      self.check(subschema, value, path, &mut errors);
+      if errors.is_empty() {
+        return Ok(());
+      }
+      let shallow = errors.fields.iter().filter(|err| err.field == path).count();
+      let closer = closest.as_ref().is_none_or(|(closest_shallow, closest)| {
+        (shallow, errors.fields.len()) < (*closest_shallow, closest.fields.len())
+      });
+      if closer {
+        closest = Some((shallow, errors));
+      }
+    }
+    match closest {
+      Some((_, errors)) => Err(errors),
+      None => Ok(()),
+    }
+  }
+
+  fn check_number(
+    &self,
+    schema: &Map<String, Value>,
+    number: Option<f64>,
+    path: &str,
+    errors: &mut ValidationErrors,
+  ) {
+    let number = match number {
+      Some(number) => number,
+      None => return,
+    };
+    let bound = |name: &str| schema.get(name).and_then(Value::as_f64);
+    if bound("minimum").is_some_and(|min| number < min) {
+      errors.add(
+        path,
+        &format!("must be at least {}", bound_text(&schema["minimum"])),
+      );
+    }
+    if bound("maximum").is_some_and(|max| number > max) {
+      errors.add(
+        path,
+        &format!("must be at most {}", bound_text(&schema["maximum"])),
+      );
+    }
+    if bound("exclusiveMinimum").is_some_and(|min| number <= min) {
+      errors.add(
+        path,
+        &format!(
+          "must be more than {}",
+          bound_text(&schema["exclusiveMinimum"])
+        ),
+      );
+    }
+    if bound("exclusiveMaximum").is_some_and(|max| number >= max) {
+      errors.add(
+        path,
+        &format!(
+          "must be less than {}",
+          bound_text(&schema["exclusiveMaximum"])
+        ),
+      );
+    }
+  }
+
+  fn check_array(
+    &self,
+    schema: &Map<String, Value>,
+    items: &[Value],
+    path: &str,
+    errors: &mut ValidationErrors,
+  ) {
+    let len = items.len() as u64;
+    if schema
+      .get("minItems")
+      .and_then(Value::as_u64)
+      .is_some_and(|min| len < min)
+    {
+      errors.add(
+        path,
+        &format!("must have at least {} items", schema["minItems"]),
+      );
+    }
+    if schema
+      .get("maxItems")
+      .and_then(Value::as_u64)
+      .is_some_and(|max| len > max)
+    {
+      errors.add(
+        path,
+        &format!("must have at most {} items", schema["maxItems"]),
+      );
+    }
+    if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+      let repeated = items
+        .iter()
+        .enumerate()
+        .any(|(index, item)| items[..index].contains(item));
+      if repeated {
+        errors.add(path, "must not repeat items");
+      }
+    }
+    let item_path = |index: usize| format!("{}[{}]", path, index);
+    match schema.get("items") {
+      // A tuple, with `additionalItems` for what follows it.
+      Some(Value::Array(tuple)) => {
+        for (index, item) in items.iter().enumerate() {
+          if let Some(item_schema) = tuple.get(index).or_else(|| schema.get("additionalItems")) {
+            self.check(item_schema, item, &item_path(index), errors);
+          }
+        }
+      }
+      Some(item_schema) => {
+        for (index, item) in items.iter().enumerate() {
+          self.check(item_schema, item, &item_path(index), errors);
+        }
+      }
+      None => (),
+    }
+  }
+
+  fn check_object(
+    &self,
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+    errors: &mut ValidationErrors,
+  ) {
+    let required = schema.get("required").and_then(Value::as_array);
+    for name in required.into_iter().flatten().filter_map(Value::as_str) {
+      if !object.contains_key(name) {
+        errors.add(&field_path(path, name), "is required");
+      }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+      let property = properties.and_then(|properties| properties.get(name));
+      if let Some(property_schema) = property.or_else(|| schema.get("additionalProperties")) {
+        self.check(property_schema, value, &field_path(path, name), errors);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Debug, JsonSchema)]
+  struct Line {
+    sku: String,
+    quantity: u32,
+  }
+
+  #[derive(Serialize, Deserialize, Debug, JsonSchema)]
+  enum OrderEvent {
+    Placed {
+      lines: Vec<Line>,
+      note: Option<String>,
+    },
+    Cancelled,
+  }
+
+  impl Event for OrderEvent {}
+
+  #[test]
+  fn it_generates_the_schema_of_event_envelopes() {
+    let schema = event_schema::<OrderEvent>();
+    assert_eq!(schema["$schema"], "http://json-schema.org/draft-07/schema#");
+    assert_eq!(
+      schema["required"],
+      serde_json::json!(["data", "event_type", "schema_version"])
+    );
+    assert_eq!(
+      schema["properties"]["data"]["$ref"],
+      "#/definitions/OrderEvent"
+    );
+    assert!(schema["definitions"]["Line"].is_object());
+
+    let validator = SchemaValidator::new(schema);
+    let placed = OrderEvent::Placed {
+      lines: vec![Line {
+        sku: String::from("tea"),
+        quantity: 2,
+      }],
+      note: None,
+    };
+    let envelope = serde_json::to_value(EventEnvelope::new(placed)).unwrap();
+    assert_eq!(validator.validate(&envelope), Ok(()));
+    let envelope = serde_json::to_value(EventEnvelope::new(OrderEvent::Cancelled)).unwrap();
+    assert_eq!(validator.validate(&envelope), Ok(()));
+  }
+
+  #[test]
+  fn it_reports_where_values_do_not_match() {
+    let validator = SchemaValidator::for_type::<OrderEvent>();
+    let placed = serde_json::json!({
+      "Placed": {"lines": [{"sku": "tea", "quantity": 1}, {"sku": 7, "quantity": -1}]}
+    });
+    assert_eq!(
+      validator.validate(&placed),
+      Err(
+        ValidationErrors::new()
+          .with("Placed.lines[1].quantity", "must be at least 0")
+          .with("Placed.lines[1].sku", "must be of type string")
+      )
+    );
+    assert_eq!(
+      validator.validate(&serde_json::json!({"Placed": {"note": "soon"}})),
+      Err(ValidationErrors::new().with("Placed.lines", "is required"))
+    );
+    assert_eq!(
+      validator.validate(&serde_json::json!("Shipped")),
+      Err(ValidationErrors::new().with("", "must be one of \"Cancelled\""))
+    );
+  }
+}
@@ -9,34 +9,98 @@ extern crate serde_json;
 extern crate uuid;
 extern crate pretty_env_logger;
 extern crate chashmap;
+extern crate flate2;
+extern crate zstd;
+extern crate base64;
+extern crate xxhash_rust;
 
 #[macro_use]
 extern crate serde_derive;
-#[cfg(feature = "httpd")]
+#[macro_use]
+extern crate tracing;
+#[cfg(test)]
+extern crate tracing_subscriber;
+#[cfg(any(
+  feature = "httpd",
+  feature = "webhook",
+  feature = "http-client",
+  feature = "schema-registry"
+))]
 extern crate hyper;
+#[cfg(feature = "webhook")]
+extern crate hex;
+#[cfg(any(feature = "webhook", feature = "jwt"))]
+extern crate hmac;
+#[cfg(any(feature = "webhook", feature = "http-client", feature = "schema-registry"))]
+extern crate hyper_rustls;
+#[cfg(any(feature = "webhook", feature = "jwt"))]
+extern crate sha2;
+#[cfg(any(feature = "openapi", feature = "json-schema"))]
+extern crate schemars;
 #[cfg(feature = "dynamo")]
-extern crate rusoto_core;
+extern crate aws_config;
 #[cfg(feature = "dynamo")]
-extern crate rusoto_dynamodb;
-#[cfg(feature = "httpd")]
-extern crate tokio_timer;
+extern crate aws_sdk_dynamodb;
+#[cfg(any(
+  feature = "httpd",
+  feature = "dynamo",
+  feature = "outbox",
+  feature = "webhook",
+  feature = "http-client",
+  feature = "async-client",
+  feature = "schema-registry"
+))]
+extern crate tokio;
+#[cfg(feature = "http-client")]
+extern crate tokio_tungstenite;
 #[cfg(feature = "httpd")]
 extern crate warp;
+#[cfg(feature = "grpc")]
+extern crate prost;
+#[cfg(feature = "grpc")]
+extern crate tonic;
 
-#[cfg(any(feature = "httpd", feature = "dynamo"))]
+#[cfg(any(
+  feature = "httpd",
+  feature = "dynamo",
+  feature = "outbox",
+  feature = "webhook",
+  feature = "http-client",
+  feature = "async-client",
+  feature = "schema-registry"
+))]
 extern crate futures;
-#[cfg(feature = "httpd")]
-extern crate log;
 
 #[cfg(feature = "sqlite")]
 extern crate rusqlite;
 
+#[cfg(feature = "encryption")]
+extern crate aes_gcm;
+#[cfg(feature = "bincode")]
+extern crate bincode;
+#[cfg(feature = "cbor")]
+extern crate ciborium;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+
 pub mod aggregate;
+#[cfg(feature = "avro")]
+pub mod avro;
 pub mod client;
+pub mod clock;
+pub mod codec;
 pub mod command;
 pub mod commit;
 pub mod dispatch;
 pub mod events;
+#[cfg(feature = "json-schema")]
+pub mod json_schema;
+pub mod projection;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod readmodel;
+pub mod snapshot;
+pub mod subscription;
 
 pub mod store;
 
@@ -0,0 +1,445 @@
+use crate::commit::Commit;
+use crate::store::Store;
+use crate::subscription::EventTypeFilter;
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_BATCH_SIZE: i64 = 100;
+
+// Builds a read model from the global commit stream. `checkpoint_name` must be
+// stable across restarts, since it keys the projection's persisted checkpoint.
+pub trait Projection {
+  fn checkpoint_name(&self) -> String;
+  fn handle(&mut self, commit: &Commit) -> Result<(), String>;
+  // Clears everything the projection has built, ahead of a rebuild.
+  fn reset(&mut self) -> Result<(), String>;
+  // Narrows the commits passed to `handle`; the checkpoint still advances past
+  // the ones that are skipped.
+  fn event_types(&self) -> Option<EventTypeFilter> {
+    None
+  }
+  // Bump when the read model changes shape and build the new version side by
+  // side with `ProjectionRunner::build_next_version`. Each version keeps its own
+  // checkpoint, so it should also write to its own tables, for instance named
+  // with `versioned_name`.
+  fn version(&self) -> i64 {
+    1
+  }
+}
+
+// `name` for version 1, which predates versioning, and `name@v<version>` after.
+pub fn versioned_name(name: &str, version: i64) -> String {
+  if version <= 1 {
+    String::from(name)
+  } else {
+    format!("{}@v{}", name, version)
+  }
+}
+
+fn checkpoint_key(projection: &dyn Projection) -> String {
+  versioned_name(&projection.checkpoint_name(), projection.version())
+}
+
+fn active_version_key(name: &str) -> String {
+  format!("{}:active_version", name)
+}
+
+// The version of the named projection that readers should use.
+pub fn active_version<S: Store>(store: &S, name: &str) -> Result<i64, String> {
+  let version = store
+    .load_checkpoint(&active_version_key(name))
+    .map_err(|err| err.to_string())?;
+  Ok(version.unwrap_or(1))
+}
+
+// Reported after every batch a projection handles.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProjectionProgress {
+  pub projection: String,
+  pub handled: usize,
+  pub checkpoint: i64,
+}
+
+// Feeds every commit to each projection in commit_number order, saving each
+// projection's checkpoint after every commit it handles. A projection that fails
+// stops at the failing commit and resumes there on the next run, so handlers
+// should tolerate seeing a commit again after a crash.
+pub struct ProjectionRunner {
+  pub projections: Vec<Box<dyn Projection>>,
+  pub batch_size: i64,
+}
+
+impl Default for ProjectionRunner {
+  fn default() -> ProjectionRunner {
+    ProjectionRunner {
+      projections: Vec::new(),
+      batch_size: DEFAULT_BATCH_SIZE,
+    }
+  }
+}
+
+impl ProjectionRunner {
+  pub fn with_projection<P: Projection + 'static>(mut self, projection: P) -> ProjectionRunner {
+    self.projections.push(Box::new(projection));
+    self
+  }
+
+  pub fn with_batch_size(mut self, batch_size: i64) -> ProjectionRunner {
+    self.batch_size = batch_size;
+    self
+  }
+
+  // Runs every projection up to the end of the store and returns how many
+  // commits were handled in total.
+  pub fn catch_up<S: Store>(&mut self, store: &mut S) -> Result<usize, String> {
+    let mut handled = 0;
+    for projection in self.projections.iter_mut() {
+      handled += catch_up_projection(projection.as_mut(), store, self.batch_size, &mut |_| ())?;
+    }
+    Ok(handled)
+  }
+
+  pub fn projection_names(&self) -> Vec<String> {
+    self
+      .projections
+      .iter()
+      .map(|projection| projection.checkpoint_name())
+      .collect()
+  }
+
+  // Resets the named projection and its checkpoint, then replays the entire
+  // global stream into it. Refuses, before resetting anything, when a retention
+  // policy has truncated the stream; see `replayable_from_start`.
+  pub fn rebuild<S: Store>(
+    &mut self,
+    store: &mut S,
+    projection_name: &str,
+  ) -> Result<usize, String> {
+    self.rebuild_with_progress(store, projection_name, |_| ())
+  }
+
+  pub fn rebuild_with_progress<S: Store, F: FnMut(&ProjectionProgress)>(
+    &mut self,
+    store: &mut S,
+    projection_name: &str,
+    mut on_progress: F,
+  ) -> Result<usize, String> {
+    let batch_size = self.batch_size;
+    let projection = self
+      .projections
+      .iter_mut()
+      .find(|projection| projection.checkpoint_name() == projection_name)
+      .ok_or_else(|| format!("no projection named {}", projection_name))?;
+    replayable_from_start(store, batch_size)?;
+    projection.reset()?;
+    store
+      .save_checkpoint(&checkpoint_key(projection.as_ref()), 0)
+      .map_err(|err| err.to_string())?;
+    catch_up_projection(projection.as_mut(), store, batch_size, &mut on_progress)
+  }
+
+  // Builds `next` from the start of the stream while the current version of the
+  // projection stays registered and serving, then switches readers over by
+  // recording `next`'s version as active and replaces the current version with
+  // it. If the build fails, or the stream was truncated, the current version is
+  // left in place.
+  pub fn build_next_version<S: Store, P: Projection + 'static>(
+    &mut self,
+    store: &mut S,
+    mut next: P,
+  ) -> Result<usize, String> {
+    let name = next.checkpoint_name();
+    let current = self
+      .projections
+      .iter()
+      .position(|projection| projection.checkpoint_name() == name);
+    if let Some(index) = current {
+      let current_version = self.projections[index].version();
+      if next.version() <= current_version {
+        return Err(format!(
+          "projection {} is already at version {}",
+          name, current_version
+        ));
+      }
+    }
+    replayable_from_start(store, self.batch_size)?;
+    let handled = catch_up_projection(&mut next, store, self.batch_size, &mut |_| ())?;
+    store
+      .save_checkpoint(&active_version_key(&name), next.version())
+      .map_err(|err| err.to_string())?;
+    match current {
+      Some(index) => self.projections[index] = Box::new(next),
+      None => self.projections.push(Box::new(next)),
+    }
+    Ok(handled)
+  }
+
+  // Tails the store, sleeping for `poll_interval` whenever every projection has
+  // caught up. Only returns on error.
+  pub fn run<S: Store>(&mut self, store: &mut S, poll_interval: Duration) -> Result<(), String> {
+    loop {
+      if self.catch_up(store)? == 0 {
+        thread::sleep(poll_interval);
+      }
+    }
+  }
+}
+
+// Reads the whole stream to check each aggregate's first commit is its first
+// ever, so that projections built from the start of it see every commit. A
+// retention policy truncating an aggregate's history leaves a later one first.
+fn replayable_from_start<S: Store>(store: &S, batch_size: i64) -> Result<(), String> {
+  let mut seen = HashSet::new();
+  let mut after = 0;
+  loop {
+    let commits = store
+      .get_commits_after(after, batch_size)
+      .map_err(|err| err.to_string())?;
+    for commit in &commits {
+      if seen.insert(commit.aggregate_id) && commit.commit_sequence > 1 {
+        return Err(format!(
+          "the history of aggregate {} was truncated before commit_sequence {}",
+          commit.aggregate_id, commit.commit_sequence
+        ));
+      }
+    }
+    match commits.last() {
+      Some(last) if commits.len() as i64 == batch_size => after = last.commit_number,
+      _ => return Ok(()),
+    }
+  }
+}
+
+fn catch_up_projection<S: Store>(
+  projection: &mut dyn Projection,
+  store: &mut S,
+  batch_size: i64,
+  on_progress: &mut dyn FnMut(&ProjectionProgress),
+) -> Result<usize, String> {
+  let name = checkpoint_key(projection);
+  let mut checkpoint = store
+    .load_checkpoint(&name)
+    .map_err(|err| err.to_string())?
+    .unwrap_or(0);
+  let event_types = projection.event_types();
+  let mut handled = 0;
+  loop {
+    let commits = store
+      .get_commits_after(checkpoint, batch_size)
+      .map_err(|err| err.to_string())?;
+    let batch_len = commits.len();
+    for commit in commits {
+      let wanted = event_types
+        .as_ref()
+        .is_none_or(|event_types| event_types.matches(&commit));
+      if wanted {
+        projection.handle(&commit).map_err(|err| {
+          format!(
+            "projection {} failed on commit {}: {}",
+            name, commit.commit_number, err
+          )
+        })?;
+      }
+      checkpoint = commit.commit_number;
+      store
+        .save_checkpoint(&name, checkpoint)
+        .map_err(|err| err.to_string())?;
+      handled += 1;
+    }
+    on_progress(&ProjectionProgress {
+      projection: name.clone(),
+      handled,
+      checkpoint,
+    });
+    if (batch_len as i64) < batch_size {
+      return Ok(handled);
+    }
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::commit::CommitAttempt;
+  use crate::store::sqlite::SqliteStore;
+  use chrono::Utc;
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use uuid::Uuid;
+
+  struct RecordingProjection {
+    name: &'static str,
+    seen: Rc<RefCell<Vec<i64>>>,
+    fail_on: Option<i64>,
+  }
+
+  impl Projection for RecordingProjection {
+    fn checkpoint_name(&self) -> String {
+      String::from(self.name)
+    }
+
+    fn handle(&mut self, commit: &Commit) -> Result<(), String> {
+      if self.fail_on == Some(commit.commit_number) {
+        return Err(String::from("boom"));
+      }
+      self.seen.borrow_mut().push(commit.commit_number);
+      Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+      self.seen.borrow_mut().clear();
+      Ok(())
+    }
+  }
+
+  fn commit_to(store: &mut SqliteStore, aggregate_id: Uuid, aggregate_version: i64) {
+    store
+      .commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_timestamp: Utc::now(),
+        commit_sequence: aggregate_version,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: b"[]".to_vec(),
+        events_count: 0,
+      })
+      .unwrap();
+  }
+
+  #[test]
+  fn it_resumes_projections_from_their_checkpoints() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..5 {
+      commit_to(&mut store, aggregate_id, version);
+    }
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = ProjectionRunner::default()
+      .with_batch_size(2)
+      .with_projection(RecordingProjection {
+        name: "recording",
+        seen: Rc::clone(&seen),
+        fail_on: Some(4),
+      });
+
+    assert!(runner.catch_up(&mut store).is_err());
+    assert_eq!(*seen.borrow(), vec![1, 2, 3]);
+    assert_eq!(store.load_checkpoint("recording").unwrap(), Some(3));
+
+    let mut runner = ProjectionRunner::default().with_projection(RecordingProjection {
+      name: "recording",
+      seen: Rc::clone(&seen),
+      fail_on: None,
+    });
+    commit_to(&mut store, aggregate_id, 5);
+    assert_eq!(runner.catch_up(&mut store), Ok(3));
+    assert_eq!(*seen.borrow(), vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(runner.catch_up(&mut store), Ok(0));
+  }
+
+  #[test]
+  fn it_rebuilds_a_projection_from_the_start() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..3 {
+      commit_to(&mut store, aggregate_id, version);
+    }
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = ProjectionRunner::default()
+      .with_batch_size(2)
+      .with_projection(RecordingProjection {
+        name: "recording",
+        seen: Rc::clone(&seen),
+        fail_on: None,
+      });
+    assert_eq!(runner.catch_up(&mut store), Ok(3));
+
+    let mut progress = Vec::new();
+    let rebuilt =
+      runner.rebuild_with_progress(&mut store, "recording", |p| progress.push(p.checkpoint));
+    assert_eq!(rebuilt, Ok(3));
+    assert_eq!(progress, vec![2, 3]);
+    assert_eq!(*seen.borrow(), vec![1, 2, 3]);
+    assert!(runner.rebuild(&mut store, "missing").is_err());
+
+    // Once a retention policy truncates an aggregate, the projection is kept
+    // as it is rather than rebuilt without the truncated commits.
+    let truncated = Uuid::new_v4();
+    for version in 1..4 {
+      commit_to(&mut store, truncated, version);
+    }
+    store.truncate_through(truncated, 2).unwrap();
+    assert_eq!(runner.catch_up(&mut store), Ok(1));
+    assert!(runner.rebuild(&mut store, "recording").is_err());
+    assert_eq!(*seen.borrow(), vec![1, 2, 3, 6]);
+  }
+
+  struct VersionedProjection {
+    version: i64,
+    seen: Rc<RefCell<Vec<(i64, i64)>>>,
+  }
+
+  impl Projection for VersionedProjection {
+    fn checkpoint_name(&self) -> String {
+      String::from("versioned")
+    }
+
+    fn handle(&mut self, commit: &Commit) -> Result<(), String> {
+      self
+        .seen
+        .borrow_mut()
+        .push((self.version, commit.commit_number));
+      Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+      Ok(())
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+  }
+
+  #[test]
+  fn it_builds_the_next_version_side_by_side() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..2 {
+      commit_to(&mut store, aggregate_id, version);
+    }
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut runner = ProjectionRunner::default().with_projection(VersionedProjection {
+      version: 1,
+      seen: Rc::clone(&seen),
+    });
+    assert_eq!(runner.catch_up(&mut store), Ok(2));
+    assert_eq!(active_version(&store, "versioned"), Ok(1));
+
+    let next = VersionedProjection {
+      version: 2,
+      seen: Rc::clone(&seen),
+    };
+    assert_eq!(runner.build_next_version(&mut store, next), Ok(2));
+    assert_eq!(active_version(&store, "versioned"), Ok(2));
+    assert_eq!(store.load_checkpoint("versioned").unwrap(), Some(2));
+    assert_eq!(store.load_checkpoint("versioned@v2").unwrap(), Some(2));
+
+    commit_to(&mut store, aggregate_id, 2);
+    assert_eq!(runner.catch_up(&mut store), Ok(1));
+    assert_eq!(*seen.borrow(), vec![(1, 1), (1, 2), (2, 1), (2, 2), (2, 3)]);
+    let stale = VersionedProjection {
+      version: 2,
+      seen: Rc::clone(&seen),
+    };
+    assert!(runner.build_next_version(&mut store, stale).is_err());
+  }
+}
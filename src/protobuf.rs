@@ -0,0 +1,204 @@
+use crate::codec::CodecError;
+use crate::commit::Commit;
+use chrono::{DateTime, Utc};
+use prost::Message;
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+// The messages of `proto/commit.proto`, as prost generates them.
+pub mod storage {
+  include!(concat!(env!("OUT_DIR"), "/event_source.storage.rs"));
+}
+
+use self::storage::payload::Payload as Contents;
+
+// The messages `encode_commit` and the protobuf codec write, for generating
+// readers in other languages.
+pub const COMMIT_SCHEMA: &str = include_str!("../proto/commit.proto");
+
+fn error(message: &str) -> CodecError {
+  CodecError::EncodingError(format!("protobuf: {}", message))
+}
+
+// `value` as a `Payload`. An array of event envelopes, as the client writes a
+// commit's events, becomes `Events`, with each event's data as JSON; anything
+// else is JSON whole.
+pub fn encode_payload(value: &Value) -> Result<Vec<u8>, CodecError> {
+  let contents = match envelopes(value) {
+    Some(events) => Contents::Events(storage::Events { events }),
+    None => Contents::Json(serde_json::to_vec(value)?),
+  };
+  let payload = storage::Payload {
+    payload: Some(contents),
+  };
+  Ok(payload.encode_to_vec())
+}
+
+fn envelopes(value: &Value) -> Option<Vec<storage::Event>> {
+  value.as_array()?.iter().map(envelope).collect()
+}
+
+// The `Event` for an object with the fields of an `EventEnvelope` and no others,
+// so that decoding it gives the same object back.
+fn envelope(value: &Value) -> Option<storage::Event> {
+  let fields = value.as_object()?;
+  let metadata = match fields.get("metadata") {
+    Some(metadata @ Value::Object(_)) => Some(serde_json::to_vec(metadata).ok()?),
+    Some(_) => return None,
+    None => None,
+  };
+  if fields.len() != 3 + usize::from(metadata.is_some()) {
+    return None;
+  }
+  Some(storage::Event {
+    event_type: String::from(fields.get("event_type")?.as_str()?),
+    schema_version: u32::try_from(fields.get("schema_version")?.as_u64()?).ok()?,
+    data: serde_json::to_vec(fields.get("data")?).ok()?,
+    metadata,
+  })
+}
+
+pub fn decode_payload(bytes: &[u8]) -> Result<Value, CodecError> {
+  let payload = storage::Payload::decode(bytes).map_err(|err| error(&err.to_string()))?;
+  match payload.payload {
+    Some(Contents::Events(events)) => events
+      .events
+      .into_iter()
+      .map(event_value)
+      .collect::<Result<_, _>>()
+      .map(Value::Array),
+    Some(Contents::Json(json)) => Ok(serde_json::from_slice(&json)?),
+    None => Err(error("empty payload")),
+  }
+}
+
+fn event_value(event: storage::Event) -> Result<Value, CodecError> {
+  let mut fields = Map::new();
+  fields.insert(String::from("event_type"), Value::String(event.event_type));
+  fields.insert(String::from("schema_version"), Value::from(event.schema_version));
+  fields.insert(String::from("data"), serde_json::from_slice(&event.data)?);
+  if let Some(metadata) = event.metadata {
+    fields.insert(String::from("metadata"), serde_json::from_slice(&metadata)?);
+  }
+  Ok(Value::Object(fields))
+}
+
+// `commit` as an `event_source.storage.Commit`; see `COMMIT_SCHEMA`.
+pub fn encode_commit(commit: &Commit) -> Vec<u8> {
+  storage::Commit {
+    aggregate_id: commit.aggregate_id.to_string(),
+    aggregate_version: commit.aggregate_version,
+    category: commit.category.clone(),
+    tenant_id: commit.tenant_id.clone(),
+    parent_commit_id: commit
+      .parent_commit_id
+      .map(|commit_id| commit_id.to_string())
+      .unwrap_or_default(),
+    commit_id: commit.commit_id.to_string(),
+    commit_timestamp: commit.commit_timestamp.to_rfc3339(),
+    commit_sequence: commit.commit_sequence,
+    commit_number: commit.commit_number,
+    events: commit.serialized_events.clone(),
+    metadata: commit.serialized_metadata.clone(),
+    events_count: commit.events_count,
+    dispatched: commit.dispatched,
+  }
+  .encode_to_vec()
+}
+
+pub fn decode_commit(bytes: &[u8]) -> Result<Commit, CodecError> {
+  let commit = storage::Commit::decode(bytes).map_err(|err| error(&err.to_string()))?;
+  let uuid = |value: &str| {
+    Uuid::parse_str(value).map_err(|_| error(&format!("{:?} is not a uuid", value)))
+  };
+  let commit_timestamp = DateTime::parse_from_rfc3339(&commit.commit_timestamp)
+    .map_err(|_| error(&format!("commit_timestamp {:?} is not rfc3339", commit.commit_timestamp)))?
+    .with_timezone(&Utc);
+  Ok(Commit {
+    aggregate_id: uuid(&commit.aggregate_id)?,
+    aggregate_version: commit.aggregate_version,
+    category: commit.category,
+    tenant_id: commit.tenant_id,
+    parent_commit_id: match commit.parent_commit_id.as_str() {
+      "" => None,
+      parent_commit_id => Some(uuid(parent_commit_id)?),
+    },
+    commit_id: uuid(&commit.commit_id)?,
+    commit_timestamp,
+    commit_sequence: commit.commit_sequence,
+    commit_number: commit.commit_number,
+    serialized_events: commit.events,
+    serialized_metadata: commit.metadata,
+    events_count: commit.events_count,
+    dispatched: commit.dispatched,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codec::{self, Codec, EventCodec, Protobuf};
+
+  #[test]
+  fn it_round_trips_payloads() {
+    let events = serde_json::json!([
+      {"event_type": "Deposited", "schema_version": 2,
+        "data": {"Deposited": 9_007_199_254_740_993u64}},
+      {"event_type": "Noted", "schema_version": 1, "data": "Noted", "metadata": {"actor": "alice"}},
+    ]);
+    let bytes = encode_payload(&events).unwrap();
+    assert_eq!(decode_payload(&bytes).unwrap(), events);
+    // Envelopes are readable as messages; their data is JSON.
+    let payload = storage::Payload::decode(bytes.as_slice()).unwrap();
+    match payload.payload {
+      Some(Contents::Events(storage::Events { events })) => {
+        assert_eq!(events[0].event_type, "Deposited");
+        assert_eq!(events[0].data, br#"{"Deposited":9007199254740993}"#.to_vec());
+        assert_eq!(events[0].metadata, None);
+        assert_eq!(events[1].metadata, Some(br#"{"actor":"alice"}"#.to_vec()));
+      }
+      other => panic!("expected events, got {:?}", other),
+    }
+
+    for value in &[
+      serde_json::json!({"owner": "alice", "amounts": [10, -3, 2.5, null]}),
+      serde_json::json!(["Opened", {"Deposited": 10}]),
+      serde_json::json!([{"event_type": "Opened", "data": "Opened", "schema_version": -1}]),
+      serde_json::json!([]),
+    ] {
+      assert_eq!(&decode_payload(&encode_payload(value).unwrap()).unwrap(), value);
+    }
+    assert!(decode_payload(&[0x0a, 0x05, 0x0a]).is_err());
+  }
+
+  #[test]
+  fn it_round_trips_commits() {
+    let serialized_events = Codec::Protobuf.encode(&vec!["Opened"]).unwrap();
+    assert_eq!(codec::content_type(&serialized_events), Protobuf::CONTENT_TYPE);
+    let commit = Commit {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 3,
+      category: String::from("account"),
+      tenant_id: String::new(),
+      parent_commit_id: Some(Uuid::new_v4()),
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 4,
+      commit_number: 19,
+      serialized_events,
+      serialized_metadata: b"{}".to_vec(),
+      events_count: 1,
+      dispatched: true,
+    };
+
+    let decoded = decode_commit(&encode_commit(&commit)).unwrap();
+    assert_eq!(decoded.aggregate_id, commit.aggregate_id);
+    assert_eq!(decoded.parent_commit_id, commit.parent_commit_id);
+    assert_eq!(decoded.commit_timestamp, commit.commit_timestamp);
+    assert_eq!(decoded.commit_number, 19);
+    assert!(decoded.dispatched);
+    assert_eq!(codec::decode::<Vec<String>>(&decoded.serialized_events).unwrap(), vec!["Opened"]);
+    assert!(COMMIT_SCHEMA.contains("message Commit"));
+  }
+}
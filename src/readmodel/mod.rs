@@ -0,0 +1,47 @@
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use crate::store::StoreError;
+use serde_json::Value;
+
+// A JSON document in a read model collection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+  pub id: String,
+  pub body: Value,
+}
+
+// Matches documents whose value at each JSON path (such as `$.status`) equals the
+// given value, ordered by id.
+#[derive(Clone, Debug, Default)]
+pub struct DocumentQuery {
+  pub conditions: Vec<(String, Value)>,
+  pub limit: Option<i64>,
+}
+
+impl DocumentQuery {
+  pub fn field_equals(mut self, json_path: &str, value: Value) -> DocumentQuery {
+    self.conditions.push((String::from(json_path), value));
+    self
+  }
+
+  pub fn limit(mut self, limit: i64) -> DocumentQuery {
+    self.limit = Some(limit);
+    self
+  }
+}
+
+// Where projections keep the documents they build. Documents are grouped into
+// named collections and keyed by id within each one.
+pub trait ReadModelStore {
+  fn upsert(&mut self, collection: &str, id: &str, body: &Value)
+    -> Result<(), Box<dyn StoreError>>;
+  fn get(&self, collection: &str, id: &str) -> Result<Option<Value>, Box<dyn StoreError>>;
+  fn query(
+    &self,
+    collection: &str,
+    query: &DocumentQuery,
+  ) -> Result<Vec<Document>, Box<dyn StoreError>>;
+  // Returns whether a document was removed.
+  fn delete(&mut self, collection: &str, id: &str) -> Result<bool, Box<dyn StoreError>>;
+}
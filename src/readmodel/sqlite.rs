@@ -0,0 +1,196 @@
+use super::{Document, DocumentQuery, ReadModelStore};
+use crate::store::sqlite::SqliteStoreError;
+use crate::store::StoreError;
+use chrono::Utc;
+use rusqlite::types::Type;
+use rusqlite::{
+  Connection as RusqliteConnection, Error as RusqliteError, OptionalExtension, ToSql,
+};
+use serde_json::Value;
+use std::path::Path;
+
+// Stores every collection in one `documents` table as JSON text, so queries can
+// use the JSON1 functions.
+pub struct SqliteReadModelStore {
+  conn: RusqliteConnection,
+}
+
+impl SqliteReadModelStore {
+  pub fn with_connection(conn: RusqliteConnection) -> Self {
+    SqliteReadModelStore { conn }
+  }
+
+  pub fn with_new_in_memory_connection() -> Self {
+    Self::with_connection(RusqliteConnection::open_in_memory().unwrap())
+  }
+
+  pub fn with_new_connection_at_path(path: &Path) -> Self {
+    Self::with_connection(RusqliteConnection::open(path).unwrap())
+  }
+
+  pub fn initialize(&self) -> Result<(), SqliteStoreError> {
+    self.conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS documents (
+        collection TEXT NOT NULL,
+        id         TEXT NOT NULL,
+        body       TEXT NOT NULL,
+        updated_at DATETIME NOT NULL,
+        PRIMARY KEY (collection, id)
+      );",
+    )?;
+    Ok(())
+  }
+}
+
+fn parse_body(column: usize, body: String) -> Result<Value, RusqliteError> {
+  serde_json::from_str(&body)
+    .map_err(|err| RusqliteError::FromSqlConversionFailure(column, Type::Text, Box::new(err)))
+}
+
+impl ReadModelStore for SqliteReadModelStore {
+  fn upsert(
+    &mut self,
+    collection: &str,
+    id: &str,
+    body: &Value,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self
+      .conn
+      .execute(
+        "INSERT OR REPLACE INTO documents (collection, id, body, updated_at) VALUES (?, ?, ?, ?)",
+        [
+          &collection as &dyn ToSql,
+          &id,
+          &body.to_string(),
+          &Utc::now(),
+        ],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(())
+  }
+
+  fn get(&self, collection: &str, id: &str) -> Result<Option<Value>, Box<dyn StoreError>> {
+    let body = self
+      .conn
+      .query_row(
+        "SELECT body FROM documents WHERE collection = ? AND id = ?",
+        [collection, id],
+        |row| parse_body(0, row.get(0)?),
+      )
+      .optional()
+      .map_err(SqliteStoreError::from)?;
+    Ok(body)
+  }
+
+  fn query(
+    &self,
+    collection: &str,
+    query: &DocumentQuery,
+  ) -> Result<Vec<Document>, Box<dyn StoreError>> {
+    // Both sides go through json_extract so strings, numbers, booleans and null
+    // compare the way they are stored.
+    let mut sql = String::from("SELECT id, body FROM documents WHERE collection = ?");
+    let mut params: Vec<String> = vec![String::from(collection)];
+    for (json_path, value) in &query.conditions {
+      sql.push_str(" AND json_extract(body, ?) IS json_extract(?, '$')");
+      params.push(json_path.clone());
+      params.push(value.to_string());
+    }
+    sql.push_str(" ORDER BY id");
+    if let Some(limit) = query.limit {
+      sql.push_str(&format!(" LIMIT {}", limit));
+    }
+    let mut statement = self.conn.prepare(&sql).map_err(SqliteStoreError::from)?;
+    let rows = statement
+      .query_map(rusqlite::params_from_iter(params.iter()), |row| {
+        Ok(Document {
+          id: row.get(0)?,
+          body: parse_body(1, row.get(1)?)?,
+        })
+      })
+      .map_err(SqliteStoreError::from)?;
+    let documents = rows
+      .collect::<Result<Vec<Document>, RusqliteError>>()
+      .map_err(SqliteStoreError::from)?;
+    Ok(documents)
+  }
+
+  fn delete(&mut self, collection: &str, id: &str) -> Result<bool, Box<dyn StoreError>> {
+    let removed = self
+      .conn
+      .execute(
+        "DELETE FROM documents WHERE collection = ? AND id = ?",
+        [collection, id],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(removed > 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn it_upserts_gets_and_queries_documents() {
+    let mut store = SqliteReadModelStore::with_new_in_memory_connection();
+    store.initialize().unwrap();
+    store
+      .upsert("orders", "a", &json!({"status": "open", "total": 3}))
+      .unwrap();
+    store
+      .upsert("orders", "b", &json!({"status": "open", "total": 5}))
+      .unwrap();
+    store
+      .upsert("orders", "c", &json!({"status": "closed", "total": 5}))
+      .unwrap();
+    store
+      .upsert("carts", "a", &json!({"status": "open"}))
+      .unwrap();
+    store
+      .upsert("orders", "a", &json!({"status": "open", "total": 4}))
+      .unwrap();
+
+    assert_eq!(
+      store.get("orders", "a").unwrap(),
+      Some(json!({"status": "open", "total": 4}))
+    );
+    assert_eq!(store.get("orders", "z").unwrap(), None);
+
+    let open = store
+      .query(
+        "orders",
+        &DocumentQuery::default().field_equals("$.status", json!("open")),
+      )
+      .unwrap();
+    assert_eq!(
+      open.iter().map(|d| d.id.as_str()).collect::<Vec<&str>>(),
+      vec!["a", "b"]
+    );
+    let open_fives = store
+      .query(
+        "orders",
+        &DocumentQuery::default()
+          .field_equals("$.status", json!("open"))
+          .field_equals("$.total", json!(5)),
+      )
+      .unwrap();
+    assert_eq!(open_fives.len(), 1);
+    assert_eq!(open_fives[0].id, "b");
+    assert_eq!(
+      store
+        .query("orders", &DocumentQuery::default().limit(1))
+        .unwrap()
+        .len(),
+      1
+    );
+
+    assert!(store.delete("orders", "a").unwrap());
+    assert!(!store.delete("orders", "a").unwrap());
+    assert_eq!(
+      store.get("carts", "a").unwrap(),
+      Some(json!({"status": "open"}))
+    );
+  }
+}
@@ -0,0 +1,808 @@
+use warp::http::header::RETRY_AFTER;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{path, Filter, Rejection, Reply};
+
+use crate::commit::DeserializedCommit;
+use crate::dispatch::{DispatchDelegate, Dispatcher};
+use crate::projection::{ProjectionProgress, ProjectionRunner};
+use crate::server::auth::Forbidden;
+use crate::server::rate_limit::TooManyRequests;
+use crate::store::retention::RetentionPolicy;
+use crate::store::{PoisonedCommit, Store, StoreConfig, StoreError, StoreErrorType};
+use chrono::Utc;
+use futures::future;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+  pub token: String,
+  pub backup_directory: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Serialize)]
+struct BackupResponse {
+  path: String,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+  error: String,
+}
+
+pub type ProjectionRunnerFactory = Arc<dyn Fn() -> ProjectionRunner + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebuildState {
+  Running,
+  Completed,
+  Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RebuildStatus {
+  pub state: RebuildState,
+  pub progress: ProjectionProgress,
+  pub error: Option<String>,
+}
+
+// The latest rebuild of each projection, by name.
+pub type ProjectionRebuilds = Arc<Mutex<HashMap<String, RebuildStatus>>>;
+
+// Requires `Authorization: Bearer <token>`. An empty token authorizes nothing,
+// and the comparison takes as long however much of a guess is right.
+pub fn authorized(
+  config: &AdminConfig,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  let token = config.token.clone();
+  warp::header::optional::<String>("authorization")
+    .and_then(move |authorization: Option<String>| {
+      let presented = authorization
+        .as_ref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+      match presented {
+        Some(presented) if !token.is_empty() && constant_time_eq(presented, &token) => {
+          future::ok(())
+        }
+        _ => future::err(warp::reject::custom(Unauthorized)),
+      }
+    })
+    .untuple_one()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+  a.len() == b.len()
+    && a
+      .bytes()
+      .zip(b.bytes())
+      .fold(0, |difference, (a, b)| difference | (a ^ b))
+      == 0
+}
+
+pub fn backup<S: Store + Send, St>(
+  store: St,
+  config: &AdminConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  let backup_directory = config.backup_directory.clone();
+  path!("admin" / "backup")
+    .and(warp::post())
+    .and(authorized(config))
+    .and(store)
+    .map(move |store: S| {
+      let path = backup_directory.join(format!(
+        "backup-{}.sqlite",
+        Utc::now().format("%Y%m%dT%H%M%S%.fZ")
+      ));
+      match store.backup_to(&path) {
+        Ok(()) => warp::reply::with_status(
+          warp::reply::json(&BackupResponse {
+            path: path.to_string_lossy().into_owned(),
+          }),
+          StatusCode::CREATED,
+        ),
+        Err(err) => {
+          let status = match err.error_type() {
+            StoreErrorType::Unsupported => StatusCode::NOT_IMPLEMENTED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+          };
+          error!("backup to {:?} failed: {}", path, err);
+          warp::reply::with_status(
+            warp::reply::json(&ErrorResponse {
+              error: err.to_string(),
+            }),
+            status,
+          )
+        }
+      }
+    })
+}
+
+// Starts rebuilding a projection on a background thread and replies 202 at once;
+// progress is polled with `GET /admin/projections/{name}/rebuild`.
+pub fn rebuild_projection<S: Store + Send + 'static, St>(
+  store: St,
+  config: &AdminConfig,
+  runner_factory: &ProjectionRunnerFactory,
+  rebuilds: &ProjectionRebuilds,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  let runner_factory = Arc::clone(runner_factory);
+  let rebuilds = Arc::clone(rebuilds);
+  path!("admin" / "projections" / String / "rebuild")
+    .and(warp::post())
+    .and(authorized(config))
+    .and(store)
+    .map(move |name: String, mut store: S| {
+      if !runner_factory().projection_names().contains(&name) {
+        return error_reply(format!("no projection named {}", name), StatusCode::NOT_FOUND);
+      }
+      let status = {
+        let mut statuses = rebuilds.lock().unwrap();
+        let running = statuses
+          .get(&name)
+          .is_some_and(|status| status.state == RebuildState::Running);
+        if running {
+          return error_reply(
+            format!("projection {} is already rebuilding", name),
+            StatusCode::CONFLICT,
+          );
+        }
+        let status = RebuildStatus {
+          state: RebuildState::Running,
+          progress: ProjectionProgress {
+            projection: name.clone(),
+            handled: 0,
+            checkpoint: 0,
+          },
+          error: None,
+        };
+        statuses.insert(name.clone(), status.clone());
+        status
+      };
+      let runner_factory = Arc::clone(&runner_factory);
+      let rebuilds = Arc::clone(&rebuilds);
+      thread::spawn(move || {
+        let result = runner_factory().rebuild_with_progress(&mut store, &name, |progress| {
+          if let Some(status) = rebuilds.lock().unwrap().get_mut(&name) {
+            status.progress = progress.clone();
+          }
+        });
+        let mut statuses = rebuilds.lock().unwrap();
+        if let Some(status) = statuses.get_mut(&name) {
+          match result {
+            Ok(_) => status.state = RebuildState::Completed,
+            Err(err) => {
+              error!("rebuilding projection {} failed: {}", name, err);
+              status.state = RebuildState::Failed;
+              status.error = Some(err);
+            }
+          }
+        }
+      });
+      warp::reply::with_status(warp::reply::json(&status), StatusCode::ACCEPTED)
+    })
+}
+
+pub fn rebuild_status(
+  config: &AdminConfig,
+  rebuilds: &ProjectionRebuilds,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let rebuilds = Arc::clone(rebuilds);
+  path!("admin" / "projections" / String / "rebuild")
+    .and(warp::get())
+    .and(authorized(config))
+    .map(move |name: String| match rebuilds.lock().unwrap().get(&name) {
+      Some(status) => warp::reply::with_status(warp::reply::json(status), StatusCode::OK),
+      None => error_reply(
+        format!("projection {} has not been rebuilt", name),
+        StatusCode::NOT_FOUND,
+      ),
+    })
+}
+
+// Commits poisoned by the dispatcher after running out of retries.
+pub fn dispatch_failures<S: Store + Send, St>(
+  store: St,
+  config: &AdminConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("admin" / "dispatch" / "failures")
+    .and(warp::get())
+    .and(authorized(config))
+    .and(store)
+    .map(|mut store: S| match store.get_poisoned_commits() {
+      Ok(poisoned) => warp::reply::with_status(warp::reply::json(&poisoned), StatusCode::OK),
+      Err(err) => store_error_reply(err),
+    })
+}
+
+#[derive(Serialize)]
+struct DispatchFailure {
+  #[serde(flatten)]
+  failure: PoisonedCommit,
+  commit: DeserializedCommit,
+}
+
+// One poisoned commit along with its events and metadata.
+pub fn dispatch_failure<S: Store + Send, St>(
+  store: St,
+  config: &AdminConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("admin" / "dispatch" / "failures" / Uuid)
+    .and(warp::get())
+    .and(authorized(config))
+    .and(store)
+    .map(|commit_id: Uuid, mut store: S| {
+      let failure = match store.get_poisoned_commit(commit_id) {
+        Ok(Some(failure)) => failure,
+        Ok(None) => return not_a_failure(commit_id),
+        Err(err) => return store_error_reply(err),
+      };
+      let commit = match store.get_commit(&commit_id) {
+        Ok(commit) => commit,
+        Err(err) => return store_error_reply(err),
+      };
+      match commit.deserialize() {
+        Ok(commit) => warp::reply::with_status(
+          warp::reply::json(&DispatchFailure { failure, commit }),
+          StatusCode::OK,
+        ),
+        Err(err) => error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+      }
+    })
+}
+
+// Puts a poisoned commit back in line to be dispatched.
+pub fn retry_dispatch_failure<S: Store + Send, St>(
+  store: St,
+  config: &AdminConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("admin" / "dispatch" / "failures" / Uuid / "retry")
+    .and(warp::post())
+    .and(authorized(config))
+    .and(store)
+    .map(
+      |commit_id: Uuid, mut store: S| match store.requeue_poisoned_commit(commit_id) {
+        Ok(true) => warp::reply::with_status(warp::reply::json(&commit_id), StatusCode::OK),
+        Ok(false) => not_a_failure(commit_id),
+        Err(err) => store_error_reply(err),
+      },
+    )
+}
+
+#[derive(Deserialize)]
+struct UndispatchedQuery {
+  limit: Option<i64>,
+}
+
+const DEFAULT_UNDISPATCHED_LIMIT: i64 = 100;
+
+// The oldest undispatched commits, up to `?limit=` (100 by default).
+pub fn undispatched_commits<S: Store + Send, St>(
+  store: St,
+  config: &AdminConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("admin" / "undispatched")
+    .and(warp::get())
+    .and(authorized(config))
+    .and(warp::query::<UndispatchedQuery>())
+    .and(store)
+    .map(|query: UndispatchedQuery, mut store: S| {
+      let limit = query.limit.unwrap_or(DEFAULT_UNDISPATCHED_LIMIT);
+      match store.get_undispatched_commits_up_to(limit) {
+        Ok(commits) => {
+          let commits: Result<Vec<DeserializedCommit>, _> =
+            commits.iter().map(|commit| commit.deserialize()).collect();
+          match commits {
+            Ok(commits) => warp::reply::with_status(warp::reply::json(&commits), StatusCode::OK),
+            Err(err) => error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+          }
+        }
+        Err(err) => store_error_reply(err),
+      }
+    })
+}
+
+#[derive(Serialize)]
+struct DispatchResponse {
+  dispatched: usize,
+  poisoned: usize,
+  remaining: usize,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+// Runs one dispatch cycle, e.g. to clear the backlog after the dispatch target
+// was down. Commits that fail are retried by later cycles as usual.
+pub fn force_dispatch<S: Store + Send, D: DispatchDelegate, St, Fd>(
+  store: St,
+  dispatch_factory: &Fd,
+  config: &AdminConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+  Fd: Fn() -> D + Clone + Send + Sync,
+{
+  let owned_dispatch_factory = dispatch_factory.clone();
+  path!("admin" / "dispatch")
+    .and(warp::post())
+    .and(authorized(config))
+    .and(store)
+    .map(move |mut store: S| {
+      let (report, result) =
+        Dispatcher::new(owned_dispatch_factory()).dispatch_with_report(&mut store);
+      let remaining = match store.get_undispatched_commits() {
+        Ok(commits) => commits.len(),
+        Err(err) => return store_error_reply(err),
+      };
+      let status = match result {
+        Ok(()) => StatusCode::OK,
+        Err(_) => StatusCode::BAD_GATEWAY,
+      };
+      let response = DispatchResponse {
+        dispatched: report.dispatched,
+        poisoned: report.poisoned,
+        remaining,
+        error: result.err(),
+      };
+      warp::reply::with_status(warp::reply::json(&response), status)
+    })
+}
+
+// Each category's retention policy, as applied by a `RetentionTask` sharing
+// `store_config`.
+pub fn retention_policies(
+  config: &AdminConfig,
+  store_config: &StoreConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let store_config = store_config.clone();
+  path!("admin" / "retention")
+    .and(warp::get())
+    .and(authorized(config))
+    .map(move || {
+      warp::reply::with_status(
+        warp::reply::json(&store_config.retention_policies()),
+        StatusCode::OK,
+      )
+    })
+}
+
+// Sets a category's retention policy, which applies from the task's next run.
+pub fn set_retention_policy(
+  config: &AdminConfig,
+  store_config: &StoreConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let store_config = store_config.clone();
+  path!("admin" / "retention" / String)
+    .and(warp::put())
+    .and(authorized(config))
+    .and(warp::body::json())
+    .map(move |category: String, policy: RetentionPolicy| {
+      store_config.set_retention(&category, policy);
+      warp::reply::with_status(warp::reply::json(&policy), StatusCode::OK)
+    })
+}
+
+pub fn remove_retention_policy(
+  config: &AdminConfig,
+  store_config: &StoreConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let store_config = store_config.clone();
+  path!("admin" / "retention" / String)
+    .and(warp::delete())
+    .and(authorized(config))
+    .map(move |category: String| {
+      if store_config.remove_retention(&category) {
+        warp::reply::with_status(warp::reply::json(&category), StatusCode::OK)
+      } else {
+        error_reply(
+          format!("category {} has no retention policy", category),
+          StatusCode::NOT_FOUND,
+        )
+      }
+    })
+}
+
+fn not_a_failure(commit_id: Uuid) -> warp::reply::WithStatus<warp::reply::Json> {
+  error_reply(
+    format!("commit {} has not failed to dispatch", commit_id),
+    StatusCode::NOT_FOUND,
+  )
+}
+
+fn store_error_reply(err: Box<dyn StoreError>) -> warp::reply::WithStatus<warp::reply::Json> {
+  let status = match err.error_type() {
+    StoreErrorType::Unsupported => StatusCode::NOT_IMPLEMENTED,
+    _ => StatusCode::INTERNAL_SERVER_ERROR,
+  };
+  error_reply(err.to_string(), status)
+}
+
+fn error_reply(error: String, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+  warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status)
+}
+
+pub fn handle_rejection(rejection: Rejection) -> future::Ready<Result<Response, Rejection>> {
+  let reply = if rejection.find::<Unauthorized>().is_some() {
+    error_reply(String::from("unauthorized"), StatusCode::UNAUTHORIZED)
+  } else if rejection.find::<Forbidden>().is_some() {
+    error_reply(String::from("forbidden"), StatusCode::FORBIDDEN)
+  } else if let Some(too_many_requests) = rejection.find::<TooManyRequests>() {
+    let retry_after = too_many_requests.retry_after.as_secs_f64().ceil() as u64;
+    return future::ok(
+      warp::reply::with_header(
+        error_reply(
+          String::from("too many requests"),
+          StatusCode::TOO_MANY_REQUESTS,
+        ),
+        RETRY_AFTER,
+        retry_after.to_string(),
+      )
+      .into_response(),
+    );
+  } else if let Some(err) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+    error_reply(err.to_string(), StatusCode::BAD_REQUEST)
+  } else if let Some(err) = rejection.find::<warp::reject::PayloadTooLarge>() {
+    error_reply(err.to_string(), StatusCode::PAYLOAD_TOO_LARGE)
+  } else if let Some(err) = rejection.find::<warp::reject::LengthRequired>() {
+    error_reply(err.to_string(), StatusCode::LENGTH_REQUIRED)
+  } else {
+    return future::err(rejection);
+  };
+  future::ok(reply.into_response())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::commit::{Commit, CommitAttempt};
+  use crate::dispatch::NullDispatcher;
+  use crate::projection::Projection;
+  use crate::server::state::with_store;
+  use crate::store::shared::SharedStore;
+  use crate::store::sqlite::SqliteStore;
+  use std::env;
+  use std::fs;
+  use std::time::Duration;
+  use tokio::runtime::Runtime;
+  use uuid::Uuid;
+
+  #[test]
+  fn it_requires_the_admin_token_to_back_up() {
+    let backup_directory = env::temp_dir().join(format!("event_source_admin_{}", Uuid::new_v4()));
+    fs::create_dir_all(&backup_directory).unwrap();
+    let config = AdminConfig {
+      token: String::from("secret"),
+      backup_directory: backup_directory.clone(),
+    };
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let route = backup(with_store(SharedStore::new(store)), &config).recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path("/admin/backup")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path("/admin/backup")
+        .header("authorization", "Bearer secret")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(fs::read_dir(&backup_directory).unwrap().count(), 1);
+    fs::remove_dir_all(&backup_directory).unwrap();
+  }
+
+  #[test]
+  fn it_authorizes_nothing_with_an_empty_token() {
+    let config = AdminConfig {
+      token: String::new(),
+      backup_directory: env::temp_dir(),
+    };
+    let route = authorized(&config).map(warp::reply).recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+
+    for authorization in ["Bearer ", "Bearer", ""] {
+      let response = runtime.block_on(
+        warp::test::request()
+          .header("authorization", authorization)
+          .reply(&route),
+      );
+      assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+  }
+
+  #[test]
+  fn it_adjusts_retention_policies() {
+    let config = AdminConfig {
+      token: String::from("secret"),
+      backup_directory: env::temp_dir(),
+    };
+    let store_config =
+      StoreConfig::default().with_retention("order", RetentionPolicy::MaxCount(10));
+    let route = retention_policies(&config, &store_config)
+      .or(set_retention_policy(&config, &store_config))
+      .or(remove_retention_policy(&config, &store_config))
+      .recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+    let request = |method: &str, path: &str, body: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .method(method)
+          .path(path)
+          .header("authorization", "Bearer secret")
+          .body(body)
+          .reply(&route),
+      )
+    };
+
+    let response = request("PUT", "/admin/retention/user", r#"{"max_age": 86400}"#);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+      store_config.retention_policies()["user"],
+      RetentionPolicy::MaxAge(Duration::from_secs(86400))
+    );
+    assert_eq!(request("DELETE", "/admin/retention/order", "").status(), StatusCode::OK);
+    assert_eq!(
+      request("DELETE", "/admin/retention/order", "").status(),
+      StatusCode::NOT_FOUND
+    );
+    let response = request("GET", "/admin/retention", "");
+    let policies: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(policies, serde_json::json!({ "user": { "max_age": 86400 } }));
+  }
+
+  #[test]
+  fn it_inspects_and_retries_dispatch_failures() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let commit_id = Uuid::new_v4();
+    store
+      .commit(&CommitAttempt {
+        aggregate_id: Uuid::new_v4(),
+        aggregate_version: 0,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id,
+        commit_timestamp: Utc::now(),
+        commit_sequence: 0,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: b"[]".to_vec(),
+        events_count: 0,
+      })
+      .unwrap();
+    store.mark_commit_as_poisoned(commit_id, 5, "broker down").unwrap();
+    let config = AdminConfig {
+      token: String::from("secret"),
+      backup_directory: env::temp_dir(),
+    };
+    let route = dispatch_failures(with_store(store.clone()), &config)
+      .or(dispatch_failure(with_store(store.clone()), &config))
+      .or(retry_dispatch_failure(with_store(store.clone()), &config))
+      .recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+    let request = |method: &str, path: String| {
+      runtime.block_on(
+        warp::test::request()
+          .method(method)
+          .path(&path)
+          .header("authorization", "Bearer secret")
+          .reply(&route),
+      )
+    };
+
+    let response = request("GET", String::from("/admin/dispatch/failures"));
+    assert_eq!(response.status(), StatusCode::OK);
+    let poisoned: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(poisoned[0]["commit_id"], commit_id.to_string());
+    assert_eq!(poisoned[0]["error"], "broker down");
+
+    let response = request("GET", format!("/admin/dispatch/failures/{}", commit_id));
+    assert_eq!(response.status(), StatusCode::OK);
+    let failure: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(failure["attempts"], 5);
+    assert_eq!(failure["commit"]["events"], serde_json::json!([]));
+
+    let retry = format!("/admin/dispatch/failures/{}/retry", commit_id);
+    assert_eq!(request("POST", retry.clone()).status(), StatusCode::OK);
+    assert_eq!(request("POST", retry).status(), StatusCode::NOT_FOUND);
+    let response = request("GET", format!("/admin/dispatch/failures/{}", commit_id));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(store.get_undispatched_commits().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn it_lists_and_dispatches_undispatched_commits() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    for _ in 0..3 {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id: Uuid::new_v4(),
+          aggregate_version: 0,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: 0,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: b"[]".to_vec(),
+          events_count: 0,
+        })
+        .unwrap();
+    }
+    let config = AdminConfig {
+      token: String::from("secret"),
+      backup_directory: env::temp_dir(),
+    };
+    let route = undispatched_commits(with_store(store.clone()), &config)
+      .or(force_dispatch(with_store(store.clone()), &|| NullDispatcher {}, &config))
+      .recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+    let request = |method: &str, path: &str| {
+      let response = runtime.block_on(
+        warp::test::request()
+          .method(method)
+          .path(path)
+          .header("authorization", "Bearer secret")
+          .reply(&route),
+      );
+      assert_eq!(response.status(), StatusCode::OK);
+      serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+    };
+
+    assert_eq!(request("GET", "/admin/undispatched").as_array().unwrap().len(), 3);
+    assert_eq!(request("GET", "/admin/undispatched?limit=2").as_array().unwrap().len(), 2);
+    assert_eq!(
+      request("POST", "/admin/dispatch"),
+      serde_json::json!({"dispatched": 3, "poisoned": 0, "remaining": 0})
+    );
+    assert_eq!(request("GET", "/admin/undispatched"), serde_json::json!([]));
+
+    // A commit in a content type this build can't read.
+    store
+      .commit(&CommitAttempt {
+        aggregate_id: Uuid::new_v4(),
+        aggregate_version: 0,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_timestamp: Utc::now(),
+        commit_sequence: 0,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: vec![0x1f],
+        events_count: 1,
+      })
+      .unwrap();
+    let response = runtime.block_on(
+      warp::test::request()
+        .path("/admin/undispatched")
+        .header("authorization", "Bearer secret")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert!(body["error"].as_str().unwrap().contains("content type"));
+  }
+
+  struct CountingProjection(Arc<Mutex<usize>>);
+
+  impl Projection for CountingProjection {
+    fn checkpoint_name(&self) -> String {
+      String::from("counting")
+    }
+
+    fn handle(&mut self, _commit: &Commit) -> Result<(), String> {
+      *self.0.lock().unwrap() += 1;
+      Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+      *self.0.lock().unwrap() = 0;
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn it_rebuilds_projections_in_the_background() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..3 {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id,
+          aggregate_version: version,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: version,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: b"[]".to_vec(),
+          events_count: 0,
+        })
+        .unwrap();
+    }
+    let count = Arc::new(Mutex::new(7));
+    let projection_count = Arc::clone(&count);
+    let runner_factory: ProjectionRunnerFactory = Arc::new(move || {
+      ProjectionRunner::default().with_projection(CountingProjection(Arc::clone(&projection_count)))
+    });
+    let config = AdminConfig {
+      token: String::from("secret"),
+      backup_directory: env::temp_dir(),
+    };
+    let rebuilds = ProjectionRebuilds::default();
+    let route = rebuild_projection(with_store(store.clone()), &config, &runner_factory, &rebuilds)
+      .or(rebuild_status(&config, &rebuilds))
+      .recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+    let request = |method: &str, name: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .method(method)
+          .path(&format!("/admin/projections/{}/rebuild", name))
+          .header("authorization", "Bearer secret")
+          .reply(&route),
+      )
+    };
+
+    assert_eq!(request("POST", "missing").status(), StatusCode::NOT_FOUND);
+    assert_eq!(request("POST", "counting").status(), StatusCode::ACCEPTED);
+    let mut status = serde_json::Value::Null;
+    for _ in 0..100 {
+      let response = request("GET", "counting");
+      assert_eq!(response.status(), StatusCode::OK);
+      status = serde_json::from_slice(response.body()).unwrap();
+      if status["state"] != "running" {
+        break;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(status["state"], "completed");
+    assert_eq!(status["progress"]["handled"], 3);
+    assert_eq!(*count.lock().unwrap(), 3);
+  }
+}
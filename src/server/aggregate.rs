@@ -1,66 +1,1274 @@
+use warp::http::StatusCode;
 use warp::{path, Filter};
 
-use aggregate::Aggregate;
-use client::ClientBuilder;
-use command::Command;
-use dispatch::{DispatchDelegate, NullDispatcher};
+use crate::aggregate::{Aggregate, StreamState};
+use crate::client::{idempotent_commit_id, Client, ClientBuilder, ClientError};
+use crate::commit::Commit;
+use crate::command::{AsyncCommand, Command, FieldError, StreamPrecondition, ValidationErrors};
+use crate::dispatch::{DispatchDelegate, NullDispatcher};
+use crate::server::negotiation::MediaType;
+use crate::snapshot::{SharedSnapshotStore, SnapshotStore};
+use crate::store::{StorageCommitConflict, Store, StoreErrorType};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use either::Either;
+use futures::future::{self, FutureExt};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use store::Store;
+use serde_json::Value;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 use uuid::Uuid;
 
-pub fn get_latest<S: Store, A: Aggregate + Serialize, Fs: Fn() -> S>(
-  store_factory: &Fs,
+#[derive(Serialize)]
+struct ErrorResponse {
+  error: String,
+}
+
+fn error_reply(error: String, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+  warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status)
+}
+
+// Why a request can't be answered as asked: the status and JSON body the HTTP
+// routes reply with. The gRPC service maps these onto its own status codes.
+#[derive(Debug)]
+pub struct HandlerError {
+  pub status: StatusCode,
+  pub body: Value,
+}
+
+impl HandlerError {
+  fn new(error: String, status: StatusCode) -> HandlerError {
+    HandlerError::with_body(&ErrorResponse { error }, status)
+  }
+
+  fn with_body<T: Serialize>(body: &T, status: StatusCode) -> HandlerError {
+    HandlerError {
+      status,
+      body: serde_json::to_value(body).unwrap_or(Value::Null),
+    }
+  }
+
+  // The `error` message of the body.
+  pub fn message(&self) -> &str {
+    self.body["error"].as_str().unwrap_or_default()
+  }
+
+  fn reply(&self) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&self.body), self.status)
+  }
+}
+
+fn commit_reply(
+  result: Result<Commit, HandlerError>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+  match result {
+    Ok(commit) => match commit.deserialize() {
+      Ok(commit) => warp::reply::with_status(warp::reply::json(&commit), StatusCode::OK),
+      Err(err) => error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    },
+    Err(err) => err.reply(),
+  }
+}
+
+// Concurrent writes to the same aggregate conflict; everything else the client
+// can fail with is the server's problem.
+#[derive(Serialize)]
+struct VersionConflictResponse {
+  error: String,
+  current_version: i64,
+}
+
+#[derive(Serialize)]
+struct ValidationResponse<'a> {
+  error: String,
+  fields: &'a [FieldError],
+}
+
+#[derive(Deserialize)]
+struct ExpectedVersionQuery {
+  expected_version: Option<i64>,
+}
+
+// The version a commit expects its aggregate to be at, from `If-Match: "<n>"` or
+// `?expected_version=<n>`; the header wins. `If-Match: *` expects nothing.
+fn expected_version(
+) -> impl Filter<Extract = (Result<Option<i64>, String>,), Error = warp::Rejection> + Clone {
+  warp::header::optional::<String>("if-match")
+    .and(warp::query::<ExpectedVersionQuery>())
+    .map(|if_match: Option<String>, query: ExpectedVersionQuery| match if_match {
+      Some(ref if_match) if if_match.trim() == "*" => Ok(None),
+      Some(if_match) => if_match
+        .trim()
+        .trim_matches('"')
+        .parse::<i64>()
+        .map(Some)
+        .map_err(|_| format!("If-Match must be an aggregate version, not {}", if_match)),
+      None => Ok(query.expected_version),
+    })
+}
+
+// `Idempotency-Key: <key>` names the commit a request makes, so a retried request
+// answers with the commit the first attempt made instead of committing again.
+// Keys are scoped to the aggregate.
+fn commit_id() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+  warp::header::optional::<String>("idempotency-key")
+}
+
+// Checks a command's JSON before it's deserialized; see
+// `Server::with_schema_validation`.
+pub type CommandSchema = Arc<dyn Fn(&Value) -> Result<(), ValidationErrors> + Send + Sync>;
+
+// The schemas the commit routes check commands against, by command type.
+#[derive(Clone, Default)]
+pub struct CommandSchemas {
+  schemas: HashMap<TypeId, CommandSchema>,
+}
+
+impl CommandSchemas {
+  pub fn insert<C: 'static>(&mut self, schema: CommandSchema) {
+    self.schemas.insert(TypeId::of::<C>(), schema);
+  }
+
+  fn get<C: 'static>(&self) -> Option<CommandSchema> {
+    self.schemas.get(&TypeId::of::<C>()).cloned()
+  }
+}
+
+// The command in the request body, or the error to answer with: 415 for a
+// `Content-Type` other than JSON or CBOR, otherwise 400 for where the body
+// doesn't match `C`'s schema, if it has one, or why it isn't a `C`.
+fn command_body<C: DeserializeOwned + 'static>(
+  schemas: &CommandSchemas,
+) -> impl Filter<
+  Extract = (Result<C, warp::reply::WithStatus<warp::reply::Json>>,),
+  Error = warp::Rejection,
+> + Clone {
+  let schema = schemas.get::<C>();
+  warp::header::optional::<String>("content-type")
+    .and(warp::body::bytes())
+    .map(move |content_type: Option<String>, body: Bytes| {
+      let media_type = match content_type {
+        Some(ref content_type) => MediaType::parse(content_type),
+        None => Some(MediaType::Json),
+      };
+      let body = match media_type {
+        Some(MediaType::NdJson) | None => {
+          let content_type = content_type.unwrap_or_default();
+          let err = format!("commands can't be sent as {}", content_type);
+          return Err(error_reply(err, StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+        Some(media_type) => media_type.decode(&body),
+      };
+      let body = body.map_err(|err| error_reply(err.to_string(), StatusCode::BAD_REQUEST))?;
+      decode_command(schema.as_ref(), body).map_err(|err| err.reply())
+    })
+}
+
+// `body` as a `C`, once it matches `C`'s schema if it has one.
+fn decode_command<C: DeserializeOwned>(
+  schema: Option<&CommandSchema>,
+  body: Value,
+) -> Result<C, HandlerError> {
+  if let Some(Err(errors)) = schema.map(|schema| schema(&body)) {
+    let invalid = ValidationResponse {
+      error: String::from("the command does not match its schema"),
+      fields: &errors.fields,
+    };
+    return Err(HandlerError::with_body(&invalid, StatusCode::BAD_REQUEST));
+  }
+  serde_json::from_value(body)
+    .map_err(|err| HandlerError::new(err.to_string(), StatusCode::BAD_REQUEST))
+}
+
+fn client_error_reply(err: ClientError) -> warp::reply::WithStatus<warp::reply::Json> {
+  client_error(err).reply()
+}
+
+fn client_error(err: ClientError) -> HandlerError {
+  if let ClientError::Invalid(ref errors) = err {
+    let invalid = ValidationResponse {
+      error: err.to_string(),
+      fields: &errors.fields,
+    };
+    return HandlerError::with_body(&invalid, StatusCode::UNPROCESSABLE_ENTITY);
+  }
+  let status = match err {
+    ClientError::StoreError(ref err) => match err.error_type() {
+      StoreErrorType::DuplicateWriteError(_) => StatusCode::CONFLICT,
+      StoreErrorType::Unsupported => StatusCode::NOT_IMPLEMENTED,
+      StoreErrorType::UnknownError | StoreErrorType::ChecksumMismatch => {
+        StatusCode::INTERNAL_SERVER_ERROR
+      }
+      StoreErrorType::Rejected(_) => StatusCode::FORBIDDEN,
+    },
+    ClientError::Rejected(_) => StatusCode::FORBIDDEN,
+    ClientError::Deleted(_) => StatusCode::GONE,
+    ClientError::PreconditionFailed {
+      precondition: StreamPrecondition::MustExist,
+      actual: StreamState::NotCreated,
+      ..
+    } => StatusCode::NOT_FOUND,
+    ClientError::PreconditionFailed { .. } => StatusCode::CONFLICT,
+    _ => StatusCode::INTERNAL_SERVER_ERROR,
+  };
+  HandlerError::new(err.to_string(), status)
+}
+
+#[derive(Deserialize)]
+struct LatestQuery {
+  max_version: Option<i64>,
+  as_of: Option<DateTime<Utc>>,
+}
+
+// `?max_version=<n>` and `?as_of=<RFC 3339 timestamp>` bound the commits
+// applied, like `/aggregate/{id}/at/{version}` and `/aggregate/{id}/at?as_of=`
+// but together. Bounded reads skip snapshots.
+pub fn get_latest<S: Store + Send, A: Aggregate + Serialize, St>(
+  store: St,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
-  Fs: Clone + Send,
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
 {
-  let owned_factory = store_factory.clone();
-  path!("aggregate" / Uuid / "latest").map(move |aggregate_id: Uuid| {
-    let store = owned_factory();
-    let dispatcher = NullDispatcher {};
-    let mut client = ClientBuilder::default()
-      .with_store(store)
-      .with_dispatch_delegate(dispatcher)
-      .finish()
-      .unwrap();
-    let aggregate: A = client.fetch_latest(aggregate_id).unwrap();
-    warp::reply::json(&aggregate)
-  })
+  path!("aggregate" / Uuid / "latest")
+    .and(warp::query::<LatestQuery>())
+    .and(store)
+    .map(|aggregate_id: Uuid, query: LatestQuery, store: S| {
+      let client = ClientBuilder::default()
+        .with_store(store)
+        .with_dispatch_delegate(NullDispatcher {})
+        .finish();
+      let mut client = match client {
+        Ok(client) => client,
+        Err(err) => return error_reply(String::from(err), StatusCode::INTERNAL_SERVER_ERROR),
+      };
+      if query.max_version.is_some() || query.as_of.is_some() {
+        return match client.fetch_until::<A>(aggregate_id, query.max_version, query.as_of) {
+          Ok(Some(aggregate)) => {
+            warp::reply::with_status(warp::reply::json(&aggregate), StatusCode::OK)
+          }
+          Ok(None) => error_reply(
+            format!("aggregate {} had no commits within the bounds", aggregate_id),
+            StatusCode::NOT_FOUND,
+          ),
+          Err(err) => client_error_reply(err),
+        };
+      }
+      match latest::<_, _, A>(&mut client, aggregate_id) {
+        Ok(aggregate) => warp::reply::with_status(warp::reply::json(&aggregate), StatusCode::OK),
+        Err(err) => err.reply(),
+      }
+    })
+}
+
+// The aggregate as `/aggregate/{id}/latest` serves it without bounds: 404 if it
+// was never created and 410 once it is deleted.
+fn latest<D: DispatchDelegate, S: Store, A: Aggregate>(
+  client: &mut Client<D, S>,
+  aggregate_id: Uuid,
+) -> Result<A, HandlerError> {
+  match client.fetch_latest::<A>(aggregate_id) {
+    // No commits were read, so the aggregate was never created.
+    Ok(_) if client.commit_sequence(aggregate_id) == 0 => Err(HandlerError::new(
+      format!("no aggregate {}", aggregate_id),
+      StatusCode::NOT_FOUND,
+    )),
+    Ok(ref aggregate) if aggregate.is_deleted() => Err(HandlerError::new(
+      format!("aggregate {} was deleted", aggregate_id),
+      StatusCode::GONE,
+    )),
+    Ok(aggregate) => Ok(aggregate),
+    Err(err) => Err(client_error(err)),
+  }
+}
+
+// Like `/aggregate/{id}/latest` without bounds, for callers outside a route.
+pub fn fetch_latest<S: Store, A: Aggregate>(
+  store: S,
+  aggregate_id: Uuid,
+) -> Result<A, HandlerError> {
+  let client = ClientBuilder::default()
+    .with_store(store)
+    .with_dispatch_delegate(NullDispatcher {})
+    .finish();
+  let mut client =
+    client.map_err(|err| HandlerError::new(String::from(err), StatusCode::INTERNAL_SERVER_ERROR))?;
+  latest::<_, _, A>(&mut client, aggregate_id)
+}
+
+#[derive(Deserialize)]
+struct AsOfQuery {
+  as_of: DateTime<Utc>,
+}
+
+// `/aggregate/{id}/at/{version}`: the aggregate as it was when it reached
+// `version`, folded from its commits alone.
+pub fn get_at_version<S: Store + Send, A: Aggregate + Serialize, St>(
+  store: St,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("aggregate" / Uuid / "at" / i64)
+    .and(store)
+    .map(|aggregate_id: Uuid, version: i64, store: S| {
+      let client = ClientBuilder::default()
+        .with_store(store)
+        .with_dispatch_delegate(NullDispatcher {})
+        .finish();
+      let client = match client {
+        Ok(client) => client,
+        Err(err) => return error_reply(String::from(err), StatusCode::INTERNAL_SERVER_ERROR),
+      };
+      match client.fetch_at_version::<A>(aggregate_id, version) {
+        Ok(Some(ref aggregate)) if aggregate.version() >= version => {
+          warp::reply::with_status(warp::reply::json(aggregate), StatusCode::OK)
+        }
+        Ok(_) => error_reply(
+          format!("aggregate {} never reached version {}", aggregate_id, version),
+          StatusCode::NOT_FOUND,
+        ),
+        Err(err) => client_error_reply(err),
+      }
+    })
+}
+
+// `/aggregate/{id}/at?as_of=<RFC 3339 timestamp>`: the aggregate with only the
+// commits made by then applied.
+pub fn get_as_of<S: Store + Send, A: Aggregate + Serialize, St>(
+  store: St,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("aggregate" / Uuid / "at")
+    .and(warp::query::<AsOfQuery>())
+    .and(store)
+    .map(|aggregate_id: Uuid, query: AsOfQuery, store: S| {
+      let client = ClientBuilder::default()
+        .with_store(store)
+        .with_dispatch_delegate(NullDispatcher {})
+        .finish();
+      let client = match client {
+        Ok(client) => client,
+        Err(err) => return error_reply(String::from(err), StatusCode::INTERNAL_SERVER_ERROR),
+      };
+      match client.fetch_as_of::<A>(aggregate_id, query.as_of) {
+        Ok(Some(aggregate)) => {
+          warp::reply::with_status(warp::reply::json(&aggregate), StatusCode::OK)
+        }
+        Ok(None) => error_reply(
+          format!("aggregate {} had no commits at {}", aggregate_id, query.as_of),
+          StatusCode::NOT_FOUND,
+        ),
+        Err(err) => client_error_reply(err),
+      }
+    })
 }
 
 pub fn commit<
-  S: Store,
+  S: Store + Send,
   D: DispatchDelegate,
-  C: Command + Serialize + DeserializeOwned,
-  Fs: Fn() -> S,
-  Fd: Fn() -> D,
+  C: Command + Serialize + DeserializeOwned + 'static,
+  St,
+  Fd,
 >(
-  store_factory: &Fs,
+  store: St,
   dispatch_factory: &Fd,
+  schemas: &CommandSchemas,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
-  Fs: Clone + Send,
-  Fd: Clone + Send,
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+  Fd: Fn() -> D + Clone + Send + Sync,
   C::Aggregate: Serialize,
 {
-  let owned_store_factory = store_factory.clone();
   let owned_dispatch_factory = dispatch_factory.clone();
   path!("commit" / Uuid)
-    .and(warp::body::json())
-    .map(move |aggregate_id: Uuid, command: C| {
-      let store = owned_store_factory();
-      let dispatch = owned_dispatch_factory();
-      let mut client = ClientBuilder::default()
+    .and(expected_version())
+    .and(commit_id())
+    .and(command_body::<C>(schemas))
+    .and(store.clone())
+    .map(
+      move |aggregate_id: Uuid,
+            expected_version: Result<Option<i64>, String>,
+            idempotency_key: Option<String>,
+            command: Result<C, _>,
+            store: S| {
+        let expected_version = match expected_version {
+          Ok(expected_version) => expected_version,
+          Err(err) => return error_reply(err, StatusCode::BAD_REQUEST),
+        };
+        let command = match command {
+          Ok(command) => command,
+          Err(reply) => return reply,
+        };
+        commit_reply(issue_command(
+          store,
+          owned_dispatch_factory(),
+          aggregate_id,
+          expected_version,
+          idempotency_key.map(|key| idempotent_commit_id(aggregate_id, &key)),
+          &command,
+        ))
+      },
+    )
+}
+
+// `/commit/{aggregate_type}/{id}`, for servers that accept several command types.
+pub fn typed_commit<
+  S: Store + Send,
+  D: DispatchDelegate,
+  C: Command + Serialize + DeserializeOwned + 'static,
+  St,
+  Fd,
+>(
+  aggregate_type: &str,
+  store: St,
+  dispatch_factory: &Fd,
+  schemas: &CommandSchemas,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+  Fd: Fn() -> D + Clone + Send + Sync,
+  C::Aggregate: Serialize,
+{
+  let owned_dispatch_factory = dispatch_factory.clone();
+  warp::path("commit")
+    .and(warp::path(String::from(aggregate_type)))
+    .and(warp::path::param::<Uuid>())
+    .and(warp::path::end())
+    .and(expected_version())
+    .and(commit_id())
+    .and(command_body::<C>(schemas))
+    .and(store.clone())
+    .map(
+      move |aggregate_id: Uuid,
+            expected_version: Result<Option<i64>, String>,
+            idempotency_key: Option<String>,
+            command: Result<C, _>,
+            store: S| {
+        let expected_version = match expected_version {
+          Ok(expected_version) => expected_version,
+          Err(err) => return error_reply(err, StatusCode::BAD_REQUEST),
+        };
+        let command = match command {
+          Ok(command) => command,
+          Err(reply) => return reply,
+        };
+        commit_reply(issue_command(
+          store,
+          owned_dispatch_factory(),
+          aggregate_id,
+          expected_version,
+          idempotency_key.map(|key| idempotent_commit_id(aggregate_id, &key)),
+          &command,
+        ))
+      },
+    )
+}
+
+// `/commit/{aggregate_type}/{id}` for an `AsyncCommand`. Only its `apply` is
+// awaited; the aggregate is fetched and committed to as for other commands.
+pub fn async_typed_commit<
+  S: Store + Send + 'static,
+  D: DispatchDelegate + Send + 'static,
+  C: AsyncCommand + Serialize + DeserializeOwned + 'static,
+  St,
+  Fd,
+>(
+  aggregate_type: &str,
+  store: St,
+  dispatch_factory: &Fd,
+  schemas: &CommandSchemas,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+  Fd: Fn() -> D + Clone + Send + Sync,
+  C::Aggregate: Serialize + Send,
+  <C::Aggregate as Aggregate>::Event: Send,
+{
+  let owned_dispatch_factory = dispatch_factory.clone();
+  warp::path("commit")
+    .and(warp::path(String::from(aggregate_type)))
+    .and(warp::path::param::<Uuid>())
+    .and(warp::path::end())
+    .and(expected_version())
+    .and(commit_id())
+    .and(command_body::<C>(schemas))
+    .and(store.clone())
+    .and_then(
+      move |aggregate_id: Uuid,
+            expected_version: Result<Option<i64>, String>,
+            idempotency_key: Option<String>,
+            command: Result<C, _>,
+            store: S| {
+        let reply = match (expected_version, command) {
+          (Ok(expected_version), Ok(command)) => issue_async_command(
+            store,
+            owned_dispatch_factory(),
+            aggregate_id,
+            expected_version,
+            idempotency_key.map(|key| idempotent_commit_id(aggregate_id, &key)),
+            command,
+          )
+          .map(commit_reply)
+          .left_future(),
+          (Err(err), _) => future::ready(error_reply(err, StatusCode::BAD_REQUEST)).right_future(),
+          (_, Err(reply)) => future::ready(reply).right_future(),
+        };
+        reply.map(Ok::<_, warp::Rejection>)
+      },
+    )
+}
+
+// The client and aggregate a command is issued with, unless a retried request
+// already made its commit.
+enum Prepared<D: DispatchDelegate, S: Store, A> {
+  Committed(Commit),
+  Ready(Client<D, S>, A),
+}
+
+// What a command is issued with, or why the aggregate can't be committed to.
+fn prepare_command<S: Store, D: DispatchDelegate, A: Aggregate>(
+  store: S,
+  dispatch: D,
+  aggregate_id: Uuid,
+  expected_version: Option<i64>,
+  commit_id: Option<Uuid>,
+) -> Result<Prepared<D, S, A>, HandlerError> {
+  let client = ClientBuilder::default()
+    .with_store(store)
+    .with_dispatch_delegate(dispatch)
+    .finish();
+  let mut client =
+    client.map_err(|err| HandlerError::new(String::from(err), StatusCode::INTERNAL_SERVER_ERROR))?;
+  // A retry of a request that already committed gets the same reply, even if the
+  // aggregate has moved on since.
+  if let Some(commit_id) = commit_id {
+    if let Ok(commit) = client.store.get_commit(&commit_id) {
+      return Ok(Prepared::Committed(commit));
+    }
+  }
+  let aggregate = client.fetch_latest::<A>(aggregate_id).map_err(client_error)?;
+  if let Some(expected_version) = expected_version {
+    if aggregate.version() != expected_version {
+      let conflict = VersionConflictResponse {
+        error: format!("expected aggregate {} at version {}", aggregate_id, expected_version),
+        current_version: aggregate.version(),
+      };
+      return Err(HandlerError::with_body(&conflict, StatusCode::CONFLICT));
+    }
+  }
+  Ok(Prepared::Ready(client, aggregate))
+}
+
+// A command the aggregate rejects is the caller's fault, so it answers 400 with
+// the command's error. An aggregate that has moved past `expected_version`
+// answers 409 with the version it is at.
+fn issue_command<S: Store, D: DispatchDelegate, C: Command + Serialize>(
+  store: S,
+  dispatch: D,
+  aggregate_id: Uuid,
+  expected_version: Option<i64>,
+  commit_id: Option<Uuid>,
+  command: &C,
+) -> Result<Commit, HandlerError> {
+  let prepared = prepare_command(store, dispatch, aggregate_id, expected_version, commit_id)?;
+  let (mut client, aggregate) = match prepared {
+    Prepared::Committed(commit) => return Ok(commit),
+    Prepared::Ready(client, aggregate) => (client, aggregate),
+  };
+  // A concurrent retry that commits first is answered with its commit.
+  let result = client.issue_command_once(
+    &aggregate,
+    command,
+    command,
+    commit_id.unwrap_or_else(Uuid::new_v4),
+  );
+  match result {
+    Ok(commit) => Ok(commit),
+    Err(Either::Left(err)) => Err(client_error(err)),
+    Err(Either::Right(err)) => Err(HandlerError::new(err.to_string(), StatusCode::BAD_REQUEST)),
+  }
+}
+
+// Answers like `issue_command` once the command's events are in.
+fn issue_async_command<S: Store, D: DispatchDelegate, C: AsyncCommand + Serialize>(
+  store: S,
+  dispatch: D,
+  aggregate_id: Uuid,
+  expected_version: Option<i64>,
+  commit_id: Option<Uuid>,
+  command: C,
+) -> impl Future<Output = Result<Commit, HandlerError>> {
+  let prepared = prepare_command(store, dispatch, aggregate_id, expected_version, commit_id);
+  let (mut client, aggregate) = match prepared {
+    Ok(Prepared::Ready(client, aggregate)) => (client, aggregate),
+    Ok(Prepared::Committed(commit)) => return future::ready(Ok(commit)).left_future(),
+    Err(err) => return future::ready(Err(err)).left_future(),
+  };
+  if let Err(errors) = command.validate(&aggregate) {
+    return future::ready(Err(client_error(ClientError::Invalid(errors)))).left_future();
+  }
+  let commit_id = commit_id.unwrap_or_else(Uuid::new_v4);
+  command
+    .apply(&aggregate)
+    .map(move |events| {
+      let events =
+        events.map_err(|err| HandlerError::new(err.to_string(), StatusCode::BAD_REQUEST))?;
+      let result = client.issue_applied_command(&aggregate, &command, events, &command, commit_id);
+      let result = match result {
+        // A concurrent retry that commits first is answered with its commit.
+        Err(ClientError::StoreError(ref err))
+          if err.error_type()
+            == StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitIdConflict) =>
+        {
+          client.store.get_commit(&commit_id).map_err(ClientError::StoreError)
+        }
+        result => result,
+      };
+      result.map_err(client_error)
+    })
+    .right_future()
+}
+
+// A command issued outside a warp route, such as over gRPC, as the JSON the
+// commit routes take.
+pub struct CommandRequest {
+  pub aggregate_id: Uuid,
+  pub expected_version: Option<i64>,
+  pub command: Value,
+}
+
+pub type CommitFuture = Pin<Box<dyn Future<Output = Result<Commit, HandlerError>> + Send>>;
+
+// Checks, decodes and issues the command as `/commit/{id}` would.
+pub fn issue_command_request<S, D, C>(
+  store: S,
+  dispatch: D,
+  schemas: &CommandSchemas,
+  request: CommandRequest,
+) -> Result<Commit, HandlerError>
+where
+  S: Store,
+  D: DispatchDelegate,
+  C: Command + Serialize + DeserializeOwned + 'static,
+{
+  let command = decode_command::<C>(schemas.get::<C>().as_ref(), request.command)?;
+  issue_command(store, dispatch, request.aggregate_id, request.expected_version, None, &command)
+}
+
+// Like `issue_command_request`, for an `AsyncCommand`.
+pub fn issue_async_command_request<S, D, C>(
+  store: S,
+  dispatch: D,
+  schemas: &CommandSchemas,
+  request: CommandRequest,
+) -> CommitFuture
+where
+  S: Store + Send + 'static,
+  D: DispatchDelegate + Send + 'static,
+  C: AsyncCommand + Serialize + DeserializeOwned + 'static,
+  C::Aggregate: Send,
+  <C::Aggregate as Aggregate>::Event: Send,
+{
+  let command = match decode_command::<C>(schemas.get::<C>().as_ref(), request.command) {
+    Ok(command) => command,
+    Err(err) => return Box::pin(future::ready(Err(err))),
+  };
+  Box::pin(issue_async_command(
+    store,
+    dispatch,
+    request.aggregate_id,
+    request.expected_version,
+    None,
+    command,
+  ))
+}
+
+pub fn get_snapshot(
+  snapshot_store: &SharedSnapshotStore,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+  let snapshot_store = snapshot_store.clone();
+  path!("aggregate" / Uuid / "snapshot")
+    .and(warp::get())
+    .map(move |aggregate_id: Uuid| match snapshot_store.get_latest_snapshot(aggregate_id) {
+      Ok(Some(snapshot)) => match snapshot.deserialize() {
+        Ok(snapshot) => warp::reply::with_status(warp::reply::json(&snapshot), StatusCode::OK),
+        Err(err) => error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+      },
+      Ok(None) => error_reply(
+        format!("no snapshot of aggregate {}", aggregate_id),
+        StatusCode::NOT_FOUND,
+      ),
+      Err(err) => error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    })
+}
+
+// Folds the aggregate's latest state and saves it as a new snapshot, so hot
+// aggregates can be warmed before traffic reaches them.
+pub fn force_snapshot<S: Store + Send, A: Aggregate, St>(
+  store: St,
+  snapshot_store: &SharedSnapshotStore,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  let snapshot_store = snapshot_store.clone();
+  path!("aggregate" / Uuid / "snapshot")
+    .and(warp::post())
+    .and(store)
+    .map(move |aggregate_id: Uuid, store: S| {
+      let client = ClientBuilder::default()
         .with_store(store)
-        .with_dispatch_delegate(dispatch)
-        .finish()
-        .unwrap();
-      let aggregate = client.fetch_latest(aggregate_id).unwrap();
-      let commit = client
-        .issue_command(&aggregate, &command, &command)
-        .unwrap()
-        .deserialize();
-      warp::reply::json(&commit)
+        .with_dispatch_delegate(NullDispatcher {})
+        .with_snapshot_store(snapshot_store.clone())
+        .finish();
+      let mut client = match client {
+        Ok(client) => client,
+        Err(err) => return error_reply(String::from(err), StatusCode::INTERNAL_SERVER_ERROR),
+      };
+      let snapshot = client
+        .fetch_latest::<A>(aggregate_id)
+        .and_then(|aggregate| client.save_snapshot(&aggregate));
+      match snapshot.map(|snapshot| snapshot.deserialize()) {
+        Ok(Ok(snapshot)) => {
+          warp::reply::with_status(warp::reply::json(&snapshot), StatusCode::CREATED)
+        }
+        Ok(Err(err)) => error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) => client_error_reply(err),
+      }
     })
 }
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::command::ValidationErrors;
+  use crate::commit::CommitAttempt;
+  use crate::events::Event;
+  use crate::server::state::{with_store, with_tenant_store};
+  use crate::snapshot::compression::Compression;
+  use crate::snapshot::memory::InMemorySnapshotStore;
+  use crate::snapshot::Snapshot;
+  use crate::store::shared::SharedStore;
+  use crate::store::sqlite::SqliteStore;
+  use std::error::Error;
+  use std::fmt;
+  use tokio::runtime::Runtime;
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum Incremented {
+    Incremented,
+  }
+
+  impl Event for Incremented {}
+
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct Counter {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for Counter {
+    type Event = Incremented;
+
+    fn with_id(id: Uuid) -> Self {
+      Counter { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &Incremented) -> Counter {
+      Counter {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    // A counter is retired once it reaches 100.
+    fn is_deleted(&self) -> bool {
+      self.version >= 100
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[test]
+  fn it_forces_and_fetches_snapshots() {
+    let snapshot_store = SharedSnapshotStore::new(InMemorySnapshotStore::default());
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let route = force_snapshot::<_, Counter, _>(with_store(SharedStore::new(store)), &snapshot_store)
+      .or(get_snapshot(&snapshot_store));
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let path = format!("/aggregate/{}/snapshot", aggregate_id);
+
+    let response = runtime.block_on(warp::test::request().path(&path).reply(&route));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path(&path)
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = runtime.block_on(warp::test::request().path(&path).reply(&route));
+    assert_eq!(response.status(), StatusCode::OK);
+    let snapshot: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(snapshot["aggregate_version"], 0);
+    assert_eq!(snapshot["state"]["id"], aggregate_id.to_string());
+
+    let mut corrupt_store = snapshot_store.clone();
+    corrupt_store
+      .save_snapshot(&Snapshot {
+        aggregate_id,
+        aggregate_version: 1,
+        commit_sequence: 1,
+        schema_version: Counter::SNAPSHOT_SCHEMA_VERSION,
+        snapshot_timestamp: Utc::now(),
+        compression: Compression::Gzip,
+        serialized_state: b"not gzip".to_vec(),
+      })
+      .unwrap();
+    let response = runtime.block_on(warp::test::request().path(&path).reply(&route));
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+  }
+
+  #[derive(Debug)]
+  struct NotPositive;
+
+  impl fmt::Display for NotPositive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "increments must be positive")
+    }
+  }
+
+  impl Error for NotPositive {}
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  struct IncrementBy(i64);
+
+  impl Command for IncrementBy {
+    type Aggregate = Counter;
+    type Error = NotPositive;
+
+    fn validate(&self, _counter: &Counter) -> Result<(), ValidationErrors> {
+      let mut errors = ValidationErrors::new();
+      if self.0 > 100 {
+        errors.add("by", "must be at most 100");
+      }
+      errors.into_result()
+    }
+
+    fn apply(&self, _counter: &Counter) -> Result<Vec<Incremented>, NotPositive> {
+      if self.0 <= 0 {
+        return Err(NotPositive);
+      }
+      Ok((0..self.0).map(|_| Incremented::Incremented).collect())
+    }
+  }
+
+  #[test]
+  fn it_answers_failures_with_json_errors() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let route = get_latest::<_, Counter, _>(with_store(store.clone()))
+      .or(commit::<_, _, IncrementBy, _, _>(
+        with_store(store),
+        &|| NullDispatcher {},
+        &CommandSchemas::default(),
+      ));
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let latest = format!("/aggregate/{}/latest", aggregate_id);
+    let commit_path = format!("/commit/{}", aggregate_id);
+    let error = |response: &warp::http::Response<bytes::Bytes>| {
+      let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+      body["error"].as_str().unwrap().to_string()
+    };
+
+    let response = runtime.block_on(warp::test::request().path(&latest).reply(&route));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(error(&response).contains(&aggregate_id.to_string()));
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path(&commit_path)
+        .body("0")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error(&response), "increments must be positive");
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path(&commit_path)
+        .body("1000")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["error"], "invalid command: by must be at most 100");
+    assert_eq!(
+      body["fields"],
+      serde_json::json!([{ "field": "by", "message": "must be at most 100" }])
+    );
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path(&commit_path)
+        .body("2")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = runtime.block_on(warp::test::request().path(&latest).reply(&route));
+    assert_eq!(response.status(), StatusCode::OK);
+    let counter: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(counter["version"], 2);
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path(&commit_path)
+        .body("98")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = runtime.block_on(warp::test::request().path(&latest).reply(&route));
+    assert_eq!(response.status(), StatusCode::GONE);
+    let response = runtime.block_on(
+      warp::test::request()
+        .method("POST")
+        .path(&commit_path)
+        .body("1")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::GONE);
+    assert_eq!(error(&response), format!("aggregate {} was deleted", aggregate_id));
+
+  }
+
+  #[test]
+  fn it_answers_undecodable_commits_with_500() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let aggregate_id = Uuid::new_v4();
+    // A content type this build can't read.
+    store
+      .commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version: 0,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_sequence: 1,
+        commit_timestamp: ::chrono::Utc::now(),
+        events_count: 1,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: vec![0x1f],
+      })
+      .unwrap();
+    let route = get_latest::<_, Counter, _>(with_store(store.clone()))
+      .or(crate::server::store::commit_list(with_store(store)));
+    let runtime = Runtime::new().unwrap();
+
+    for path in &[
+      format!("/aggregate/{}/latest", aggregate_id),
+      format!("/aggregate/{}/latest?max_version=1", aggregate_id),
+      format!("/store/{}/commits", aggregate_id),
+    ] {
+      let response = runtime.block_on(warp::test::request().path(path).reply(&route));
+      assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR, "{}", path);
+      let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+      assert!(body["error"].as_str().unwrap().contains("content type"), "{}", path);
+    }
+  }
+
+  #[test]
+  fn it_answers_conflicting_writes_with_409() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 1,
+      commit_timestamp: ::chrono::Utc::now(),
+      events_count: 0,
+      serialized_metadata: b"null".to_vec(),
+      serialized_events: b"[]".to_vec(),
+    };
+    store.commit(&attempt).unwrap();
+    let conflict = store.commit(&attempt).unwrap_err();
+
+    let reply = client_error_reply(ClientError::StoreError(conflict));
+    let response = warp::reply::Reply::into_response(reply);
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+  }
+
+  #[test]
+  fn it_only_commits_at_the_expected_version() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let route = commit::<_, _, IncrementBy, _, _>(
+      with_store(store),
+      &|| NullDispatcher {},
+      &CommandSchemas::default(),
+    );
+    let runtime = Runtime::new().unwrap();
+    let commit_path = format!("/commit/{}", Uuid::new_v4());
+    let post = |path: &str, if_match: &str| {
+      let mut request = warp::test::request().method("POST").path(path).body("1");
+      if !if_match.is_empty() {
+        request = request.header("if-match", if_match);
+      }
+      runtime.block_on(request.reply(&route))
+    };
+
+    assert_eq!(post(&commit_path, "\"0\"").status(), StatusCode::OK);
+    let response = post(&commit_path, "\"0\"");
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let conflict: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(conflict["current_version"], 1);
+
+    assert_eq!(post(&format!("{}?expected_version=1", commit_path), "").status(), StatusCode::OK);
+    assert_eq!(post(&commit_path, "*").status(), StatusCode::OK);
+    assert_eq!(post(&commit_path, "latest").status(), StatusCode::BAD_REQUEST);
+
+  }
+
+  #[test]
+  fn it_reads_commands_in_their_content_type() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let route = commit::<_, _, IncrementBy, _, _>(
+      with_store(store),
+      &|| NullDispatcher {},
+      &CommandSchemas::default(),
+    );
+    let runtime = Runtime::new().unwrap();
+    let post = |content_type: &str, body: Vec<u8>| {
+      runtime.block_on(
+        warp::test::request()
+          .method("POST")
+          .path(&format!("/commit/{}", Uuid::new_v4()))
+          .header("content-type", content_type)
+          .body(body)
+          .reply(&route),
+      )
+    };
+
+    let response = post("application/json; charset=utf-8", b"2".to_vec());
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = post("application/x-ndjson", b"2\n".to_vec());
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let response = post("text/plain", b"2".to_vec());
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    #[cfg(feature = "cbor")]
+    {
+      let response = post("application/cbor", crate::codec::Cbor::to_vec(&2).unwrap());
+      assert_eq!(response.status(), StatusCode::OK);
+      let commit: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+      assert_eq!(commit["events_count"], 2);
+      let response = post("application/cbor", b"2".to_vec());
+      assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+  }
+
+  #[cfg(feature = "json-schema")]
+  #[test]
+  fn it_answers_commands_not_matching_their_schema_with_400() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let validator = crate::json_schema::SchemaValidator::new(serde_json::json!({
+      "type": "integer",
+      "minimum": 1,
+    }));
+    let mut schemas = CommandSchemas::default();
+    schemas.insert::<IncrementBy>(Arc::new(move |command| validator.validate(command)));
+    let route = commit::<_, _, IncrementBy, _, _>(
+      with_store(store),
+      &|| NullDispatcher {},
+      &schemas,
+    );
+    let runtime = Runtime::new().unwrap();
+    let post = |body: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .method("POST")
+          .path(&format!("/commit/{}", Uuid::new_v4()))
+          .body(String::from(body))
+          .reply(&route),
+      )
+    };
+
+    assert_eq!(post("2").status(), StatusCode::OK);
+    let response = post("0");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let invalid: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(
+      invalid["fields"],
+      serde_json::json!([{"field": "", "message": "must be at least 1"}])
+    );
+    let response = post("\"two\"");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let invalid: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(invalid["fields"][0]["message"], "must be of type integer");
+  }
+
+  #[test]
+  fn it_replays_commits_for_repeated_idempotency_keys() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let route = commit::<_, _, IncrementBy, _, _>(
+      with_store(store),
+      &|| NullDispatcher {},
+      &CommandSchemas::default(),
+    );
+    let runtime = Runtime::new().unwrap();
+    let commit_path = format!("/commit/{}", Uuid::new_v4());
+    let post = |idempotency_key: &str| {
+      let response = runtime.block_on(
+        warp::test::request()
+          .method("POST")
+          .path(&commit_path)
+          .header("idempotency-key", idempotency_key)
+          .body("1")
+          .reply(&route),
+      );
+      assert_eq!(response.status(), StatusCode::OK);
+      serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+    };
+
+    let first = post("a");
+    let retried = post("a");
+    assert_eq!(retried["commit_id"], first["commit_id"]);
+    assert_eq!(retried["aggregate_version"], 0);
+    let other = post("b");
+    assert_ne!(other["commit_id"], first["commit_id"]);
+    assert_eq!(other["aggregate_version"], 1);
+
+  }
+
+  #[test]
+  fn it_serves_each_tenant_only_its_own_aggregates() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let get_route = get_latest::<_, Counter, _>(with_tenant_store(store.clone()));
+    let commit_route = commit::<_, _, IncrementBy, _, _>(
+      with_tenant_store(store),
+      &|| NullDispatcher {},
+      &CommandSchemas::default(),
+    );
+    let route = warp::path("t")
+      .and(warp::path::param::<String>())
+      .map(|_tenant: String| ())
+      .untuple_one()
+      .and(get_route.or(commit_route));
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let request = |method: &str, path: String| {
+      runtime
+        .block_on(
+          warp::test::request()
+            .method(method)
+            .path(&path)
+            .body("1")
+            .reply(&route),
+        )
+        .status()
+    };
+
+    let commit_as = |tenant: &str| format!("/t/{}/commit/{}", tenant, aggregate_id);
+    let latest_as = |tenant: &str| format!("/t/{}/aggregate/{}/latest", tenant, aggregate_id);
+    assert_eq!(request("POST", commit_as("acme")), StatusCode::OK);
+    assert_eq!(request("GET", latest_as("acme")), StatusCode::OK);
+    assert_eq!(request("GET", latest_as("globex")), StatusCode::NOT_FOUND);
+    assert_eq!(request("POST", commit_as("globex")), StatusCode::FORBIDDEN);
+
+  }
+
+  #[test]
+  fn it_folds_aggregates_up_to_a_version_or_time() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let route = get_at_version::<_, Counter, _>(with_store(store.clone()))
+      .or(get_as_of::<_, Counter, _>(with_store(store.clone())))
+      .or(get_latest::<_, Counter, _>(with_store(store.clone())))
+      .or(commit::<_, _, IncrementBy, _, _>(
+        with_store(store),
+        &|| NullDispatcher {},
+        &CommandSchemas::default(),
+      ));
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let get = |path: String| runtime.block_on(warp::test::request().path(&path).reply(&route));
+    let rfc3339 = |timestamp: DateTime<Utc>| {
+      timestamp.to_rfc3339_opts(::chrono::SecondsFormat::Nanos, true)
+    };
+
+    let before = Utc::now();
+    for _ in 0..3 {
+      let response = runtime.block_on(
+        warp::test::request()
+          .method("POST")
+          .path(&format!("/commit/{}", aggregate_id))
+          .body("1")
+          .reply(&route),
+      );
+      assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let response = get(format!("/aggregate/{}/at/2", aggregate_id));
+    assert_eq!(response.status(), StatusCode::OK);
+    let counter: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(counter["version"], 2);
+    let response = get(format!("/aggregate/{}/at/4", aggregate_id));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = get(format!("/aggregate/{}/at?as_of={}", aggregate_id, rfc3339(Utc::now())));
+    let counter: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(counter["version"], 3);
+    let response = get(format!("/aggregate/{}/at?as_of={}", aggregate_id, rfc3339(before)));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let latest = format!("/aggregate/{}/latest", aggregate_id);
+    let response = get(format!("{}?max_version=2", latest));
+    let counter: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(counter["version"], 2);
+    let response = get(format!("{}?max_version=2&as_of={}", latest, rfc3339(Utc::now())));
+    let counter: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(counter["version"], 2);
+    let response = get(format!("{}?max_version=2&as_of={}", latest, rfc3339(before)));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+  }
+}
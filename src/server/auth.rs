@@ -0,0 +1,402 @@
+use warp::http::HeaderMap;
+use warp::path::FullPath;
+use warp::{Filter, Rejection};
+
+use crate::server::admin::Unauthorized;
+use crate::server::state::requested_tenant;
+#[cfg(feature = "jwt")]
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+#[cfg(feature = "jwt")]
+use base64::Engine;
+#[cfg(feature = "jwt")]
+use chrono::Utc;
+use futures::future;
+#[cfg(feature = "jwt")]
+use hmac::{Hmac, KeyInit, Mac};
+#[cfg(feature = "jwt")]
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregateAccess {
+  All,
+  Only(HashSet<Uuid>),
+}
+
+// Who made a request, and which aggregates they may read and write. A principal
+// bound to a tenant may only make requests under `/t/{tenant}/`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+  pub subject: String,
+  pub aggregates: AggregateAccess,
+  pub tenant: Option<String>,
+}
+
+impl Principal {
+  pub fn with_full_access(subject: &str) -> Principal {
+    Principal {
+      subject: String::from(subject),
+      aggregates: AggregateAccess::All,
+      tenant: None,
+    }
+  }
+
+  pub fn with_aggregates<I: IntoIterator<Item = Uuid>>(
+    subject: &str,
+    aggregate_ids: I,
+  ) -> Principal {
+    Principal {
+      subject: String::from(subject),
+      aggregates: AggregateAccess::Only(aggregate_ids.into_iter().collect()),
+      tenant: None,
+    }
+  }
+
+  pub fn with_tenant(mut self, tenant: &str) -> Principal {
+    self.tenant = Some(String::from(tenant));
+    self
+  }
+
+  // `None` is a request spanning aggregates, such as a category or `_all`
+  // subscription, which needs access to every aggregate.
+  pub fn can_access(&self, aggregate_id: Option<Uuid>) -> bool {
+    match self.aggregates {
+      AggregateAccess::All => true,
+      AggregateAccess::Only(ref aggregate_ids) => {
+        aggregate_id.is_some_and(|aggregate_id| aggregate_ids.contains(&aggregate_id))
+      }
+    }
+  }
+
+  // `None` is a request outside `/t/{tenant}/`, which principals bound to a
+  // tenant may not make.
+  pub fn can_access_tenant(&self, tenant: Option<&str>) -> bool {
+    match self.tenant {
+      Some(ref own) => tenant == Some(own.as_str()),
+      None => true,
+    }
+  }
+}
+
+pub trait Authenticator: Send + Sync {
+  // Errs with the reason when the request carries no acceptable credentials.
+  fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, String>;
+}
+
+// Static keys sent in the `x-api-key` header.
+#[derive(Clone, Default)]
+pub struct ApiKeyAuthenticator {
+  keys: HashMap<String, Principal>,
+}
+
+impl ApiKeyAuthenticator {
+  pub fn with_key(mut self, key: &str, principal: Principal) -> Self {
+    self.keys.insert(String::from(key), principal);
+    self
+  }
+}
+
+impl Authenticator for ApiKeyAuthenticator {
+  fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, String> {
+    let key = headers
+      .get(API_KEY_HEADER)
+      .and_then(|key| key.to_str().ok())
+      .ok_or_else(|| String::from("missing api key"))?;
+    self
+      .keys
+      .get(key)
+      .cloned()
+      .ok_or_else(|| String::from("unknown api key"))
+  }
+}
+
+#[cfg(feature = "jwt")]
+#[derive(Deserialize)]
+struct JwtHeader {
+  alg: String,
+}
+
+// `aggregates` lists the aggregate ids the bearer may access, or is `["*"]` for
+// all of them. `tenant` binds the bearer to one tenant's routes.
+#[cfg(feature = "jwt")]
+#[derive(Deserialize)]
+struct JwtClaims {
+  sub: String,
+  exp: Option<i64>,
+  #[serde(default)]
+  aggregates: Vec<String>,
+  tenant: Option<String>,
+}
+
+// HS256-signed JWTs sent as `Authorization: Bearer <token>`.
+#[cfg(feature = "jwt")]
+#[derive(Clone)]
+pub struct JwtAuthenticator {
+  secret: Vec<u8>,
+}
+
+#[cfg(feature = "jwt")]
+impl JwtAuthenticator {
+  pub fn new(secret: &[u8]) -> JwtAuthenticator {
+    JwtAuthenticator {
+      secret: secret.to_vec(),
+    }
+  }
+
+  fn verify(&self, token: &str) -> Result<JwtClaims, String> {
+    let mut parts = token.split('.');
+    let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next())
+    {
+      (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+      _ => return Err(String::from("malformed token")),
+    };
+    let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).map_err(|err| err.to_string());
+    let jwt_header: JwtHeader =
+      serde_json::from_slice(&decode(header)?).map_err(|err| err.to_string())?;
+    if jwt_header.alg != "HS256" {
+      return Err(format!("unsupported algorithm {}", jwt_header.alg));
+    }
+    let mut mac =
+      Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts keys of any length");
+    mac.update(header.as_bytes());
+    mac.update(b".");
+    mac.update(claims.as_bytes());
+    mac
+      .verify_slice(&decode(signature)?)
+      .map_err(|_| String::from("invalid signature"))?;
+    let claims: JwtClaims =
+      serde_json::from_slice(&decode(claims)?).map_err(|err| err.to_string())?;
+    if claims.exp.is_some_and(|exp| exp <= Utc::now().timestamp()) {
+      return Err(String::from("token expired"));
+    }
+    Ok(claims)
+  }
+}
+
+#[cfg(feature = "jwt")]
+impl Authenticator for JwtAuthenticator {
+  fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, String> {
+    let token = headers
+      .get("authorization")
+      .and_then(|authorization| authorization.to_str().ok())
+      .and_then(|authorization| authorization.strip_prefix("Bearer "))
+      .ok_or_else(|| String::from("missing bearer token"))?;
+    let claims = self.verify(token)?;
+    let principal = if claims.aggregates.iter().any(|aggregate| aggregate == "*") {
+      Principal::with_full_access(&claims.sub)
+    } else {
+      let aggregate_ids = claims
+        .aggregates
+        .iter()
+        .map(|aggregate| Uuid::parse_str(aggregate).map_err(|err| err.to_string()))
+        .collect::<Result<HashSet<Uuid>, String>>()?;
+      Principal::with_aggregates(&claims.sub, aggregate_ids)
+    };
+    Ok(match claims.tenant {
+      Some(ref tenant) => principal.with_tenant(tenant),
+      None => principal,
+    })
+  }
+}
+
+// The aggregate a request path is about, taken from its first uuid segment after
+// any `/t/{tenant}` prefix.
+fn requested_aggregate(path: &str) -> Option<Uuid> {
+  let prefix = if requested_tenant(path).is_some() { 3 } else { 0 };
+  path
+    .split('/')
+    .skip(prefix)
+    .find_map(|segment| Uuid::parse_str(segment).ok())
+}
+
+// Rejects requests the authenticator does not accept with `Unauthorized`, and
+// those for aggregates or tenants outside the principal's access with `Forbidden`. Without
+// an authenticator every request passes.
+pub fn authorize(
+  authenticator: Option<Arc<dyn Authenticator>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  warp::path::full()
+    .and(warp::header::headers_cloned())
+    .and_then(move |path: FullPath, headers: HeaderMap| {
+      let authenticator = match authenticator {
+        Some(ref authenticator) => authenticator,
+        None => return future::ok(()),
+      };
+      match authenticator.authenticate(&headers) {
+        Ok(ref principal)
+          if principal.can_access(requested_aggregate(path.as_str()))
+            && principal.can_access_tenant(requested_tenant(path.as_str())) =>
+        {
+          future::ok(())
+        }
+        Ok(principal) => {
+          debug!("{} may not access {}", principal.subject, path.as_str());
+          future::err(warp::reject::custom(Forbidden))
+        }
+        Err(reason) => {
+          debug!("rejected credentials for {}: {}", path.as_str(), reason);
+          future::err(warp::reject::custom(Unauthorized))
+        }
+      }
+    })
+    .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::admin::handle_rejection;
+  use tokio::runtime::Runtime;
+  use warp::http::StatusCode;
+
+  fn status<A: Authenticator + 'static>(
+    authenticator: A,
+    path: &str,
+    headers: &[(&str, &str)],
+  ) -> StatusCode {
+    let route = authorize(Some(Arc::new(authenticator)))
+      .map(warp::reply)
+      .recover(handle_rejection);
+    let mut request = warp::test::request().path(path);
+    for &(name, value) in headers {
+      request = request.header(name, value);
+    }
+    Runtime::new()
+      .unwrap()
+      .block_on(request.reply(&route))
+      .status()
+  }
+
+  #[test]
+  fn it_authorizes_api_keys_per_aggregate() {
+    let aggregate_id = Uuid::new_v4();
+    let authenticator = ApiKeyAuthenticator::default()
+      .with_key("admin", Principal::with_full_access("admin"))
+      .with_key(
+        "user",
+        Principal::with_aggregates("user", vec![aggregate_id]),
+      );
+    let owned = format!("/commits/{}", aggregate_id);
+    let other = format!("/commits/{}", Uuid::new_v4());
+
+    assert_eq!(
+      status(authenticator.clone(), &owned, &[]),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+      status(authenticator.clone(), &owned, &[(API_KEY_HEADER, "wrong")]),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+      status(authenticator.clone(), &owned, &[(API_KEY_HEADER, "user")]),
+      StatusCode::OK
+    );
+    assert_eq!(
+      status(authenticator.clone(), &other, &[(API_KEY_HEADER, "user")]),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status(
+        authenticator.clone(),
+        "/commits/_all",
+        &[(API_KEY_HEADER, "user")]
+      ),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status(authenticator, "/commits/_all", &[(API_KEY_HEADER, "admin")]),
+      StatusCode::OK
+    );
+  }
+
+  #[test]
+  fn it_keeps_tenant_principals_to_their_tenant() {
+    let aggregate_id = Uuid::new_v4();
+    let authenticator = ApiKeyAuthenticator::default()
+      .with_key("operator", Principal::with_full_access("operator"))
+      .with_key(
+        "acme",
+        Principal::with_aggregates("acme", vec![aggregate_id]).with_tenant("acme"),
+      );
+    let ours = format!("/t/acme/aggregate/{}/latest", aggregate_id);
+    let theirs = format!("/t/globex/aggregate/{}/latest", aggregate_id);
+    let untenanted = format!("/aggregate/{}/latest", aggregate_id);
+
+    assert_eq!(
+      status(authenticator.clone(), &ours, &[(API_KEY_HEADER, "acme")]),
+      StatusCode::OK
+    );
+    assert_eq!(
+      status(authenticator.clone(), &theirs, &[(API_KEY_HEADER, "acme")]),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status(authenticator.clone(), &untenanted, &[(API_KEY_HEADER, "acme")]),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status(authenticator, &theirs, &[(API_KEY_HEADER, "operator")]),
+      StatusCode::OK
+    );
+  }
+
+  #[cfg(feature = "jwt")]
+  fn token(secret: &[u8], claims: serde_json::Value) -> String {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(format!("{}.{}", header, claims).as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    format!("Bearer {}.{}.{}", header, claims, signature)
+  }
+
+  #[cfg(feature = "jwt")]
+  #[test]
+  fn it_authorizes_jwt_claims() {
+    let aggregate_id = Uuid::new_v4();
+    let path = format!("/aggregate/{}/latest", aggregate_id);
+    let valid = token(
+      b"secret",
+      serde_json::json!({"sub": "user", "exp": Utc::now().timestamp() + 60, "aggregates": [aggregate_id]}),
+    );
+    let expired = token(
+      b"secret",
+      serde_json::json!({"sub": "user", "exp": Utc::now().timestamp() - 60, "aggregates": ["*"]}),
+    );
+    let forged = token(
+      b"guess",
+      serde_json::json!({"sub": "user", "aggregates": ["*"]}),
+    );
+    let authenticator = JwtAuthenticator::new(b"secret");
+
+    assert_eq!(
+      status(authenticator.clone(), &path, &[("authorization", &valid)]),
+      StatusCode::OK
+    );
+    assert_eq!(
+      status(
+        authenticator.clone(),
+        "/commits/_all",
+        &[("authorization", &valid)]
+      ),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status(authenticator.clone(), &path, &[("authorization", &expired)]),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+      status(authenticator, &path, &[("authorization", &forged)]),
+      StatusCode::UNAUTHORIZED
+    );
+  }
+}
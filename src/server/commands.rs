@@ -0,0 +1,486 @@
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+
+use crate::aggregate::Aggregate;
+use crate::command::{AsyncCommand, Command};
+#[cfg(feature = "grpc")]
+use crate::server::aggregate::{
+  issue_async_command_request, issue_command_request, CommandRequest, CommitFuture,
+};
+use crate::server::aggregate::{async_typed_commit, typed_commit, CommandSchemas};
+use crate::server::dispatch::WebSocketSubscriptions;
+use crate::store::Store;
+use futures::future;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::marker::PhantomData;
+
+// The command types a server accepts at `/commit/{aggregate_type}/{id}`, built
+// up with `Server::register`. Each entry is a type, so the routes can only be
+// built once `serve` knows the store type.
+pub trait CommandRegistry: Clone + Send + Sync + 'static {
+  fn routes<S, St>(
+    &self,
+    store: &St,
+    subscriptions: &WebSocketSubscriptions,
+    schemas: &CommandSchemas,
+  ) -> BoxedFilter<(Box<dyn Reply>,)>
+  where
+    S: Store + Send + 'static,
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync + 'static;
+
+  fn aggregate_types(&self) -> Vec<&str>;
+
+  // Issues a command for `aggregate_type` as its route would, or `None` when no
+  // command is registered for it.
+  #[cfg(feature = "grpc")]
+  fn issue<S: Store + Send + 'static>(
+    &self,
+    aggregate_type: &str,
+    store: S,
+    subscriptions: &WebSocketSubscriptions,
+    schemas: &CommandSchemas,
+    request: CommandRequest,
+  ) -> Option<CommitFuture>;
+}
+
+impl CommandRegistry for () {
+  fn routes<S, St>(
+    &self,
+    _: &St,
+    _: &WebSocketSubscriptions,
+    _: &CommandSchemas,
+  ) -> BoxedFilter<(Box<dyn Reply>,)>
+  where
+    S: Store + Send + 'static,
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync + 'static,
+  {
+    warp::any()
+      .and_then(|| future::err::<Box<dyn Reply>, _>(warp::reject::not_found()))
+      .boxed()
+  }
+
+  fn aggregate_types(&self) -> Vec<&str> {
+    Vec::new()
+  }
+
+  #[cfg(feature = "grpc")]
+  fn issue<S: Store + Send + 'static>(
+    &self,
+    _: &str,
+    _: S,
+    _: &WebSocketSubscriptions,
+    _: &CommandSchemas,
+    _: CommandRequest,
+  ) -> Option<CommitFuture> {
+    None
+  }
+}
+
+pub struct Registered<C, R> {
+  aggregate_type: String,
+  rest: R,
+  command: PhantomData<fn() -> C>,
+}
+
+impl<C, R> Registered<C, R> {
+  pub fn new(aggregate_type: &str, rest: R) -> Registered<C, R> {
+    Registered {
+      aggregate_type: String::from(aggregate_type),
+      rest,
+      command: PhantomData,
+    }
+  }
+}
+
+impl<C, R: Clone> Clone for Registered<C, R> {
+  fn clone(&self) -> Self {
+    Registered {
+      aggregate_type: self.aggregate_type.clone(),
+      rest: self.rest.clone(),
+      command: PhantomData,
+    }
+  }
+}
+
+impl<C, R> CommandRegistry for Registered<C, R>
+where
+  C: Command + Serialize + DeserializeOwned + 'static,
+  C::Aggregate: Serialize,
+  R: CommandRegistry,
+{
+  fn routes<S, St>(
+    &self,
+    store: &St,
+    subscriptions: &WebSocketSubscriptions,
+    schemas: &CommandSchemas,
+  ) -> BoxedFilter<(Box<dyn Reply>,)>
+  where
+    S: Store + Send + 'static,
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync + 'static,
+  {
+    let subscriptions = subscriptions.clone();
+    let dispatch_factory = move || subscriptions.clone();
+    typed_commit::<_, _, C, _, _>(
+      &self.aggregate_type,
+      store.clone(),
+      &dispatch_factory,
+      schemas,
+    )
+    .map(|reply| Box::new(reply) as Box<dyn Reply>)
+    .or(self.rest.routes(store, &dispatch_factory(), schemas))
+    .unify()
+    .boxed()
+  }
+
+  fn aggregate_types(&self) -> Vec<&str> {
+    let mut aggregate_types = self.rest.aggregate_types();
+    aggregate_types.push(&self.aggregate_type);
+    aggregate_types
+  }
+
+  #[cfg(feature = "grpc")]
+  fn issue<S: Store + Send + 'static>(
+    &self,
+    aggregate_type: &str,
+    store: S,
+    subscriptions: &WebSocketSubscriptions,
+    schemas: &CommandSchemas,
+    request: CommandRequest,
+  ) -> Option<CommitFuture> {
+    if aggregate_type != self.aggregate_type {
+      return self
+        .rest
+        .issue(aggregate_type, store, subscriptions, schemas, request);
+    }
+    let commit = issue_command_request::<_, _, C>(store, subscriptions.clone(), schemas, request);
+    Some(Box::pin(future::ready(commit)))
+  }
+}
+
+// Like `Registered`, for an `AsyncCommand`.
+pub struct RegisteredAsync<C, R> {
+  aggregate_type: String,
+  rest: R,
+  command: PhantomData<fn() -> C>,
+}
+
+impl<C, R> RegisteredAsync<C, R> {
+  pub fn new(aggregate_type: &str, rest: R) -> RegisteredAsync<C, R> {
+    RegisteredAsync {
+      aggregate_type: String::from(aggregate_type),
+      rest,
+      command: PhantomData,
+    }
+  }
+}
+
+impl<C, R: Clone> Clone for RegisteredAsync<C, R> {
+  fn clone(&self) -> Self {
+    RegisteredAsync {
+      aggregate_type: self.aggregate_type.clone(),
+      rest: self.rest.clone(),
+      command: PhantomData,
+    }
+  }
+}
+
+impl<C, R> CommandRegistry for RegisteredAsync<C, R>
+where
+  C: AsyncCommand + Serialize + DeserializeOwned + 'static,
+  C::Aggregate: Serialize + Send,
+  <C::Aggregate as Aggregate>::Event: Send,
+  R: CommandRegistry,
+{
+  fn routes<S, St>(
+    &self,
+    store: &St,
+    subscriptions: &WebSocketSubscriptions,
+    schemas: &CommandSchemas,
+  ) -> BoxedFilter<(Box<dyn Reply>,)>
+  where
+    S: Store + Send + 'static,
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync + 'static,
+  {
+    let subscriptions = subscriptions.clone();
+    let dispatch_factory = move || subscriptions.clone();
+    async_typed_commit::<_, _, C, _, _>(
+      &self.aggregate_type,
+      store.clone(),
+      &dispatch_factory,
+      schemas,
+    )
+    .map(|reply| Box::new(reply) as Box<dyn Reply>)
+    .or(self.rest.routes(store, &dispatch_factory(), schemas))
+    .unify()
+    .boxed()
+  }
+
+  fn aggregate_types(&self) -> Vec<&str> {
+    let mut aggregate_types = self.rest.aggregate_types();
+    aggregate_types.push(&self.aggregate_type);
+    aggregate_types
+  }
+
+  #[cfg(feature = "grpc")]
+  fn issue<S: Store + Send + 'static>(
+    &self,
+    aggregate_type: &str,
+    store: S,
+    subscriptions: &WebSocketSubscriptions,
+    schemas: &CommandSchemas,
+    request: CommandRequest,
+  ) -> Option<CommitFuture> {
+    if aggregate_type != self.aggregate_type {
+      return self
+        .rest
+        .issue(aggregate_type, store, subscriptions, schemas, request);
+    }
+    Some(issue_async_command_request::<_, _, C>(
+      store,
+      subscriptions.clone(),
+      schemas,
+      request,
+    ))
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::command::{CommandFuture, ValidationErrors};
+  use crate::events::Event;
+  use crate::server::state::with_store;
+  use crate::store::shared::SharedStore;
+  use crate::store::sqlite::SqliteStore;
+  use futures::FutureExt;
+  use std::error::Error;
+  use std::fmt;
+  use tokio::runtime::Runtime;
+  use uuid::Uuid;
+  use warp::http::StatusCode;
+
+  #[derive(Debug)]
+  struct NeverFails;
+
+  impl fmt::Display for NeverFails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "never fails")
+    }
+  }
+
+  impl Error for NeverFails {}
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum CounterEvent {
+    Incremented,
+  }
+
+  impl Event for CounterEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct Counter {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for Counter {
+    type Event = CounterEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Counter { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &CounterEvent) -> Counter {
+      Counter {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  enum CounterCommand {
+    Increment,
+  }
+
+  impl Command for CounterCommand {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn apply(&self, _counter: &Counter) -> Result<Vec<CounterEvent>, NeverFails> {
+      Ok(vec![CounterEvent::Incremented])
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum LightEvent {
+    Toggled,
+  }
+
+  impl Event for LightEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct Light {
+    id: Uuid,
+    version: i64,
+    on: bool,
+  }
+
+  impl Aggregate for Light {
+    type Event = LightEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Light {
+        id,
+        ..Light::default()
+      }
+    }
+
+    fn apply(&self, _event: &LightEvent) -> Light {
+      Light {
+        id: self.id,
+        version: self.version + 1,
+        on: !self.on,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  enum LightCommand {
+    Toggle,
+  }
+
+  impl Command for LightCommand {
+    type Aggregate = Light;
+    type Error = NeverFails;
+
+    fn apply(&self, _light: &Light) -> Result<Vec<LightEvent>, NeverFails> {
+      Ok(vec![LightEvent::Toggled])
+    }
+  }
+
+  #[test]
+  fn it_routes_commits_to_the_registered_command_type() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let registry = Registered::<LightCommand, _>::new(
+      "light",
+      Registered::<CounterCommand, _>::new("counter", ()),
+    );
+    assert_eq!(registry.aggregate_types(), vec!["counter", "light"]);
+    let route = registry.routes(
+      &with_store(store.clone()),
+      &WebSocketSubscriptions::default(),
+      &CommandSchemas::default(),
+    );
+    let runtime = Runtime::new().unwrap();
+    let post = |path: String, command: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .method("POST")
+          .path(&path)
+          .body(format!("\"{}\"", command))
+          .reply(&route),
+      )
+    };
+
+    let response = post(format!("/commit/light/{}", Uuid::new_v4()), "Toggle");
+    assert_eq!(response.status(), StatusCode::OK);
+    let commit: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(
+      commit["events"],
+      serde_json::json!([{"event_type": "Toggled", "schema_version": 1, "data": "Toggled"}])
+    );
+
+    let response = post(format!("/commit/counter/{}", Uuid::new_v4()), "Increment");
+    let commit: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(
+      commit["events"],
+      serde_json::json!([{"event_type": "Incremented", "schema_version": 1, "data": "Incremented"}])
+    );
+
+    let response = post(format!("/commit/counter/{}", Uuid::new_v4()), "Toggle");
+    assert!(response.status().is_client_error());
+    let response = post(format!("/commit/thermostat/{}", Uuid::new_v4()), "Toggle");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+  }
+  // Asks a pricing service how far to count.
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  struct IncrementByPrice {
+    item: String,
+  }
+
+  impl AsyncCommand for IncrementByPrice {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn validate(&self, _counter: &Counter) -> Result<(), ValidationErrors> {
+      let mut errors = ValidationErrors::new();
+      if self.item.is_empty() {
+        errors.add("item", "must not be empty");
+      }
+      errors.into_result()
+    }
+
+    fn apply(&self, _counter: &Counter) -> CommandFuture<Self> {
+      let price = self.item.len();
+      let lookup = tokio::time::sleep(::std::time::Duration::from_millis(10));
+      Box::pin(lookup.map(move |()| Ok((0..price).map(|_| CounterEvent::Incremented).collect())))
+    }
+  }
+
+  #[test]
+  fn it_routes_commits_to_registered_async_commands() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let registry = RegisteredAsync::<IncrementByPrice, _>::new(
+      "priced",
+      Registered::<LightCommand, _>::new("light", ()),
+    );
+    assert_eq!(registry.aggregate_types(), vec!["light", "priced"]);
+    let route = registry.routes(
+      &with_store(store.clone()),
+      &WebSocketSubscriptions::default(),
+      &CommandSchemas::default(),
+    );
+    let runtime = Runtime::new().unwrap();
+    let post = |item: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .method("POST")
+          .path(&format!("/commit/priced/{}", Uuid::new_v4()))
+          .json(&serde_json::json!({ "item": item }))
+          .reply(&route),
+      )
+    };
+
+    let response = post("tea");
+    assert_eq!(response.status(), StatusCode::OK);
+    let commit: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(commit["events_count"], 3);
+    assert_eq!(commit["metadata"], serde_json::json!({ "item": "tea" }));
+
+    let response = post("");
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+  }
+}
@@ -0,0 +1,122 @@
+use warp::http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{self, Either, FutureExt};
+use hyper::body::{to_bytes, Body};
+use std::io::Write;
+
+// Bodies smaller than this are not worth compressing.
+const MIN_COMPRESSED_BYTES: usize = 1024;
+
+// True when an `Accept-Encoding` header accepts gzip, i.e. names `gzip` or `*`
+// without `q=0`.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+  accept_encoding.split(',').any(|coding| {
+    let mut parts = coding.split(';').map(str::trim);
+    let name = parts.next().unwrap_or("");
+    let refused = parts.any(|param| {
+      param
+        .strip_prefix("q=")
+        .and_then(|q| q.parse::<f32>().ok())
+        .is_some_and(|q| q == 0.0)
+    });
+    (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+  })
+}
+
+fn gzip(body: &[u8]) -> Option<Vec<u8>> {
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(body).ok()?;
+  encoder.finish().ok()
+}
+
+// Gzips the replies of `filter` for clients that accept it. The whole reply is
+// buffered, so this suits JSON replies rather than streams.
+pub fn gzipped<F, R>(filter: F) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+  F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+  R: Reply,
+{
+  warp::header::optional::<String>(ACCEPT_ENCODING.as_str())
+    .and(filter)
+    .and_then(|accept_encoding: Option<String>, reply: R| {
+      let response = reply.into_response();
+      let wanted = accept_encoding.as_deref().is_some_and(accepts_gzip)
+        && !response.headers().contains_key(CONTENT_ENCODING);
+      if !wanted {
+        return Either::Left(future::ok::<_, Rejection>(response));
+      }
+      let (mut parts, body) = response.into_parts();
+      Either::Right(to_bytes(body).map(move |bytes| {
+        let bytes = match bytes {
+          Ok(bytes) => bytes,
+          Err(err) => {
+            error!("could not buffer a reply to compress it: {}", err);
+            return Ok(Response::from_parts(parts, Body::empty()));
+          }
+        };
+        parts
+          .headers
+          .append(VARY, HeaderValue::from_static("accept-encoding"));
+        let compressed = if bytes.len() < MIN_COMPRESSED_BYTES {
+          None
+        } else {
+          gzip(&bytes)
+        };
+        match compressed {
+          Some(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+              .headers
+              .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            Ok(Response::from_parts(parts, Body::from(compressed)))
+          }
+          None => Ok(Response::from_parts(parts, Body::from(bytes))),
+        }
+      }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flate2::read::GzDecoder;
+  use std::io::Read;
+  use tokio::runtime::Runtime;
+
+  #[test]
+  fn it_reads_accept_encoding() {
+    assert!(accepts_gzip("gzip"));
+    assert!(accepts_gzip("br, GZIP;q=0.5"));
+    assert!(accepts_gzip("*"));
+    assert!(!accepts_gzip("gzip;q=0"));
+    assert!(!accepts_gzip("br, deflate"));
+  }
+
+  #[test]
+  fn it_gzips_large_replies_for_clients_that_accept_it() {
+    let events = vec!["Incremented"; 500];
+    let route = gzipped(warp::path("events").map(move || warp::reply::json(&events)));
+    let runtime = Runtime::new().unwrap();
+
+    let plain = runtime.block_on(warp::test::request().path("/events").reply(&route));
+    assert!(plain.headers().get(CONTENT_ENCODING).is_none());
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .path("/events")
+        .header("accept-encoding", "gzip, br")
+        .reply(&route),
+    );
+    assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+    assert!(response.body().len() < plain.body().len() / 10);
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&response.body()[..])
+      .read_to_end(&mut decompressed)
+      .unwrap();
+    assert_eq!(&decompressed[..], &plain.body()[..]);
+  }
+}
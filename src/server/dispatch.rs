@@ -1,132 +1,860 @@
-use warp::{self, Filter, Future};
+use warp::{self, Filter};
 
+use crate::codec::CodecError;
+use crate::commit::Commit;
+#[cfg(feature = "cloudevents")]
+use crate::dispatch::cloudevents::CloudEventsFormat;
+use crate::dispatch::{AsyncDispatchDelegate, DispatchDelegate, DispatchFuture};
+use crate::store::{Store, StoreError};
+use crate::subscription::EventTypeFilter;
 use chashmap::CHashMap;
-use commit::Commit;
-use dispatch::DispatchDelegate;
+use futures::channel::mpsc;
+use futures::future::{self, Either, Future};
+use futures::{stream, FutureExt, Stream, StreamExt};
 use serde::Serialize;
-use serde_json::Serializer as JsonSerializer;
-use std::str::from_utf8;
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::pin::Pin;
 use std::sync::{
   atomic::{AtomicUsize, Ordering},
   Arc,
 };
+use std::time::Duration;
 use uuid::Uuid;
+use warp::filters::sse;
 use warp::filters::ws::{Message, WebSocket};
-use warp::Stream;
+use warp::http::StatusCode;
 
 static SUBSCRIBER_ID: AtomicUsize = AtomicUsize::new(1);
+const REPLAY_BATCH_SIZE: i64 = 500;
 
-type AggregateMap = Arc<CHashMap<Uuid, CHashMap<usize, mpsc::Sender<Message>>>>;
+// WebSocket subscribers are pinged every `ping_interval`, and dropped once
+// nothing, not even a pong, has come back from them for `idle_timeout`. This
+// clears out connections that died behind a NAT or proxy without closing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heartbeat {
+  pub ping_interval: Duration,
+  pub idle_timeout: Duration,
+}
+
+impl Default for Heartbeat {
+  fn default() -> Heartbeat {
+    Heartbeat {
+      ping_interval: Duration::from_secs(30),
+      idle_timeout: Duration::from_secs(90),
+    }
+  }
+}
 
+// Each commit is sent as its number and its JSON, so a resumed subscription can
+// drop live commits it already replayed. WebSocket and SSE subscribers share
+// the same registry.
 #[derive(Clone)]
+pub struct Subscriber {
+  pub sender: mpsc::UnboundedSender<(i64, String)>,
+  pub event_types: Option<EventTypeFilter>,
+  pub format: CommitFormat,
+}
+
+// How a subscriber is sent each commit: as the commit JSON the HTTP API serves,
+// or, for `?format=cloudevents`, as a CloudEvent. gRPC subscribers are sent the
+// stored commit's JSON, which they convert to their own message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommitFormat {
+  Json,
+  #[cfg(feature = "cloudevents")]
+  CloudEvents(CloudEventsFormat),
+  #[cfg(feature = "grpc")]
+  Stored,
+}
+
+impl CommitFormat {
+  // Fails for commits this server can't decode, such as Avro ones without a
+  // schema registry.
+  fn encode(&self, commit: &Commit) -> Result<String, CodecError> {
+    match *self {
+      CommitFormat::Json => Ok(serde_json::to_string(&commit.deserialize()?)?),
+      #[cfg(feature = "cloudevents")]
+      CommitFormat::CloudEvents(ref format) => {
+        Ok(serde_json::to_string(&format.cloud_event(commit)?)?)
+      }
+      #[cfg(feature = "grpc")]
+      CommitFormat::Stored => Ok(serde_json::to_string(commit)?),
+    }
+  }
+}
+
+// What a subscriber subscribed to: one aggregate, an aggregate category, or
+// every commit.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum SubscriptionKey {
+  Aggregate(Uuid),
+  Category(String),
+  All,
+}
+
+impl SubscriptionKey {
+  // The keys whose subscribers should receive a commit.
+  fn for_commit(commit: &Commit) -> Vec<SubscriptionKey> {
+    let mut keys = vec![SubscriptionKey::Aggregate(commit.aggregate_id)];
+    if !commit.category.is_empty() {
+      keys.push(SubscriptionKey::Category(commit.category.clone()));
+    }
+    keys.push(SubscriptionKey::All);
+    keys
+  }
+
+  // The key's commits numbered after `commit_number`, in commit order.
+  fn commits_after<S: Store>(
+    &self,
+    store: &S,
+    commit_number: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut commits = match *self {
+      SubscriptionKey::Aggregate(aggregate_id) => {
+        let mut commits = store.get_range(aggregate_id, 0, i64::MAX)?;
+        commits.retain(|commit| commit.commit_number > commit_number);
+        commits.sort_by_key(|commit| commit.commit_number);
+        return Ok(commits);
+      }
+      _ => Vec::new(),
+    };
+    let mut after = commit_number;
+    loop {
+      let batch = match *self {
+        SubscriptionKey::Category(ref category) => {
+          store.get_category_range(category, after, REPLAY_BATCH_SIZE)?
+        }
+        _ => store.get_commits_after(after, REPLAY_BATCH_SIZE)?,
+      };
+      match batch.last() {
+        Some(last) => after = last.commit_number,
+        None => return Ok(commits),
+      }
+      commits.extend(batch);
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+  error: String,
+}
+
+// Commit numbers and the commits encoded for a subscriber.
+pub type CommitMessages = Pin<Box<dyn Stream<Item = (i64, String)> + Send>>;
+
+type SubscriptionMap = Arc<CHashMap<SubscriptionKey, CHashMap<usize, Subscriber>>>;
+
+#[derive(Clone, Default)]
 pub struct WebSocketSubscriptions {
-  pub aggregate_map: AggregateMap,
+  pub subscription_map: SubscriptionMap,
+  pub heartbeat: Heartbeat,
+  // Used for subscribers asking for `?format=cloudevents`.
+  #[cfg(feature = "cloudevents")]
+  pub cloudevents: CloudEventsFormat,
 }
 
 impl DispatchDelegate for WebSocketSubscriptions {
   fn dispatch(&mut self, commit: &Commit) -> Result<(), String> {
-    self.publish(commit.clone());
-    Ok(())
+    self.publish(commit.clone())
   }
 }
 
-impl Default for WebSocketSubscriptions {
-  fn default() -> Self {
-    WebSocketSubscriptions {
-      aggregate_map: Default::default(),
-    }
+// Publishing only queues messages on unbounded channels, so it never waits.
+impl AsyncDispatchDelegate for WebSocketSubscriptions {
+  fn dispatch(&mut self, commit: &Commit) -> DispatchFuture {
+    Box::pin(future::ready(self.publish(commit.clone())))
   }
 }
 
 impl WebSocketSubscriptions {
-  pub fn commit_subscription(
+  // `/commits/{aggregate_id}`, `/commits/category/{name}` or `/commits/_all`.
+  // `?event_types=A,B`, or `?event_type=`, narrows a subscription to commits with
+  // those event types.
+  // `?from_commit_number=N` first replays the stored commits numbered after N,
+  // so a client that reconnects with the last number it saw misses nothing.
+  // `?format=cloudevents` sends CloudEvents, with the `cloudevents` feature.
+  pub fn commit_subscription<S: Store + Send + 'static, St>(
     &self,
-  ) -> impl Filter<Error = warp::Rejection, Extract = (impl warp::Reply,)> {
-    let state_handle = Arc::clone(&self.aggregate_map);
-    warp::path("commits")
-      .and(warp::path::param())
+    store: St,
+  ) -> impl Filter<Error = warp::Rejection, Extract = (impl warp::Reply,)> + Clone
+  where
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+  {
+    let state_handle = Arc::clone(&self.subscription_map);
+    let heartbeat = self.heartbeat.clone();
+    let subscriptions = self.clone();
+    let all = warp::path!("commits" / "_all").map(|| SubscriptionKey::All);
+    let category = warp::path!("commits" / "category" / String).map(SubscriptionKey::Category);
+    let aggregate = warp::path!("commits" / Uuid).map(SubscriptionKey::Aggregate);
+    all
+      .or(category)
+      .unify()
+      .or(aggregate)
+      .unify()
+      .and(warp::query::<HashMap<String, String>>())
       .and(warp::ws())
-      .map(move |aggregate_id: Uuid, ws: warp::ws::Ws2| {
-        let state_handle = Arc::clone(&state_handle);
-        ws.on_upgrade(move |websocket| subscribe(aggregate_id, state_handle, websocket))
-      })
+      .and(store)
+      .map(
+        move |key: SubscriptionKey, query: HashMap<String, String>, ws: warp::ws::Ws, store: S| {
+          let state_handle = Arc::clone(&state_handle);
+          let heartbeat = heartbeat.clone();
+          let event_types = event_type_filter(&query);
+          let format = subscriptions.commit_format(&query);
+          let from_commit_number = query
+            .get("from_commit_number")
+            .and_then(|number| number.parse::<i64>().ok());
+          ws.on_upgrade(move |websocket| {
+            let replay_key = key.clone();
+            let subscription = Subscription {
+              key,
+              event_types,
+              format,
+              subscription_map: state_handle,
+              heartbeat,
+            };
+            subscribe(subscription, websocket, move || match from_commit_number {
+              Some(commit_number) => replay_key
+                .commits_after(&store, commit_number)
+                .map_err(|err| err.to_string()),
+              None => Ok(Vec::new()),
+            })
+          })
+        },
+      )
   }
 
-  fn publish(&self, commit: Commit) {
-    let option = self.aggregate_map.get(&commit.aggregate_id);
-    let mut serialized_buffer = Vec::<u8>::new();
-    {
-      let mut buffer_serializer = JsonSerializer::new(&mut serialized_buffer);
+  // `GET /commits/{aggregate_id}/stream` sends the same commits as Server-Sent
+  // Events, each with its commit number as the event id. A reconnecting client's
+  // `Last-Event-ID` header resumes like `?from_commit_number`.
+  pub fn commit_stream<S: Store + Send, St>(
+    &self,
+    store: St,
+  ) -> impl Filter<Error = warp::Rejection, Extract = (Box<dyn warp::Reply>,)> + Clone
+  where
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+  {
+    let subscriptions = self.clone();
+    warp::path!("commits" / Uuid / "stream")
+      .and(warp::get())
+      .and(warp::query::<HashMap<String, String>>())
+      .and(warp::header::optional::<i64>("last-event-id"))
+      .and(store)
+      .map(
+        move |aggregate_id: Uuid,
+              query: HashMap<String, String>,
+              last_event_id: Option<i64>,
+              store: S| {
+          let key = SubscriptionKey::Aggregate(aggregate_id);
+          let event_types = event_type_filter(&query);
+          let format = subscriptions.commit_format(&query);
+          let from_commit_number = last_event_id.or_else(|| {
+            query
+              .get("from_commit_number")
+              .and_then(|number| number.parse::<i64>().ok())
+          });
+          let commits =
+            match subscriptions.subscribe(key, event_types, format, from_commit_number, &store) {
+              Ok(commits) => commits,
+              Err(err) => {
+                return Box::new(warp::reply::with_status(
+                  warp::reply::json(&ErrorResponse {
+                    error: err.to_string(),
+                  }),
+                  StatusCode::INTERNAL_SERVER_ERROR,
+                )) as Box<dyn warp::Reply>;
+              }
+            };
+          let events = commits.map(|(commit_number, json)| {
+            Ok::<_, Infallible>(sse::Event::default().id(commit_number.to_string()).data(json))
+          });
+          Box::new(sse::reply(sse::keep_alive().stream(events))) as Box<dyn warp::Reply>
+        },
+      )
+  }
+
+  // Registers a subscriber to `key` and streams the commits it is sent, after the
+  // stored commits numbered past `from_commit_number` when given. The stream owns
+  // the registration, so the subscriber is removed when the stream is dropped.
+  pub fn subscribe<S: Store>(
+    &self,
+    key: SubscriptionKey,
+    event_types: Option<EventTypeFilter>,
+    format: CommitFormat,
+    from_commit_number: Option<i64>,
+    store: &S,
+  ) -> Result<CommitMessages, Box<dyn StoreError>> {
+    let (subscriber_id, live) =
+      register(&key, event_types.clone(), format.clone(), &self.subscription_map);
+    let registration = Registration {
+      key: key.clone(),
+      subscription_map: Arc::clone(&self.subscription_map),
+      subscriber_id,
+    };
+    let replayed = match from_commit_number {
+      Some(commit_number) => key.commits_after(store, commit_number)?,
+      None => Vec::new(),
+    };
+    let commits = replay_then_live(replayed, event_types.as_ref(), &format, live);
+    Ok(Box::pin(commits.map(move |commit| {
+      let _registration = &registration;
       commit
-        .deserialize()
-        .serialize(&mut buffer_serializer)
-        .unwrap();
+    })))
+  }
+
+  // Drops every subscriber. Each WebSocket is sent a close frame once its queued
+  // commits are out, and each SSE stream ends.
+  pub fn close_all(&self) {
+    let subscribers = self.subscription_map.clear();
+    info!("closed subscriptions to {} keys", subscribers.len());
+  }
+
+  // `?format=cloudevents` is the only other format; anything else gets JSON.
+  fn commit_format(&self, query: &HashMap<String, String>) -> CommitFormat {
+    match query.get("format").map(String::as_str) {
+      #[cfg(feature = "cloudevents")]
+      Some("cloudevents") => CommitFormat::CloudEvents(self.cloudevents.clone()),
+      _ => CommitFormat::Json,
     }
-    match option {
-      Some(ref subscriber_map_guard) => {
-        let subscriber_map = (*subscriber_map_guard).clone();
-        for (_, subscriber) in subscriber_map.into_iter() {
-          subscriber
-            .send(Message::text(
-              from_utf8(serialized_buffer.as_slice()).unwrap(),
-            ))
-            .unwrap();
+  }
+
+  // Subscribers whose format the commit can't be encoded in are skipped, and
+  // the dispatch fails so the commit is retried.
+  fn publish(&self, commit: Commit) -> Result<(), String> {
+    // Each format is encoded once, for the first subscriber that wants it.
+    let mut encoded: Vec<(CommitFormat, String)> = Vec::new();
+    let mut failure = None;
+    let mut published = false;
+    for key in SubscriptionKey::for_commit(&commit) {
+      let subscriber_map = match self.subscription_map.get(&key) {
+        Some(ref subscriber_map_guard) => (*subscriber_map_guard).clone(),
+        None => continue,
+      };
+      for (_, subscriber) in subscriber_map.into_iter() {
+        let wanted = subscriber
+          .event_types
+          .as_ref()
+          .is_none_or(|event_types| event_types.matches(&commit));
+        if !wanted {
+          continue;
+        }
+        published = true;
+        let message = match encoded.iter().find(|(format, _)| *format == subscriber.format) {
+          Some((_, message)) => message.clone(),
+          None => match subscriber.format.encode(&commit) {
+            Ok(message) => {
+              encoded.push((subscriber.format.clone(), message.clone()));
+              message
+            }
+            Err(err) => {
+              failure = Some(format!("could not encode commit {}: {}", commit.commit_id, err));
+              continue;
+            }
+          },
+        };
+        // The receiving half goes away when the socket closes, just before the
+        // subscriber is removed.
+        if let Err(err) = subscriber
+          .sender
+          .unbounded_send((commit.commit_number, message))
+        {
+          debug!("could not publish to a closed subscriber: {}", err);
         }
       }
-      None => unreachable!("No subscriber to contact!"),
-    };
+    }
+    if !published {
+      debug!("no subscribers to aggregate {}", commit.aggregate_id);
+    }
+    failure.map_or(Ok(()), Err)
   }
 }
 
-fn disconnect(aggregate_id: Uuid, aggregate_map: &AggregateMap, subscriber_id: usize) {
+fn disconnect(key: SubscriptionKey, subscription_map: &SubscriptionMap, subscriber_id: usize) {
   info!("disconnecting subscriber {}", subscriber_id);
-  aggregate_map.alter(aggregate_id, |subscriber_by_id_map| {
-    subscriber_by_id_map.map(|subscriber_by_id| {
+  subscription_map.alter(key, |subscriber_by_id_map| {
+    subscriber_by_id_map.inspect(|subscriber_by_id| {
       subscriber_by_id.remove(&subscriber_id);
-      subscriber_by_id
     })
   });
 }
 
-fn subscribe(
-  aggregate_id: Uuid,
-  aggregate_map: AggregateMap,
-  websocket: WebSocket,
-) -> impl Future<Item = (), Error = ()> {
+fn register(
+  key: &SubscriptionKey,
+  event_types: Option<EventTypeFilter>,
+  format: CommitFormat,
+  subscription_map: &SubscriptionMap,
+) -> (usize, mpsc::UnboundedReceiver<(i64, String)>) {
   let subscriber_id = SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+  let (tx, rx) = mpsc::unbounded();
+  let subscriber = Subscriber {
+    sender: tx,
+    event_types,
+    format,
+  };
+  let subscriber_clone = subscriber.clone();
+  subscription_map.upsert(
+    key.clone(),
+    || {
+      let new_map = CHashMap::new();
+      new_map.insert_new(subscriber_id, subscriber);
+      new_map
+    },
+    |hash_map| {
+      hash_map.insert(subscriber_id, subscriber_clone);
+    },
+  );
+  info!("new subscriber: {}", subscriber_id);
+  (subscriber_id, rx)
+}
+
+struct Registration {
+  key: SubscriptionKey,
+  subscription_map: SubscriptionMap,
+  subscriber_id: usize,
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    disconnect(self.key.clone(), &self.subscription_map, self.subscriber_id);
+  }
+}
+
+// Replayed commits followed by live ones. The subscriber must be registered
+// before the replay is read, so commits published meanwhile are queued rather
+// than lost; any the replay already covered are dropped.
+fn replay_then_live(
+  replayed: Vec<Commit>,
+  event_types: Option<&EventTypeFilter>,
+  format: &CommitFormat,
+  live: mpsc::UnboundedReceiver<(i64, String)>,
+) -> impl Stream<Item = (i64, String)> {
+  let replayed_through = replayed.last().map_or(i64::MIN, |commit| commit.commit_number);
+  let replayed: Vec<(i64, String)> = replayed
+    .iter()
+    .filter(|commit| event_types.is_none_or(|event_types| event_types.matches(commit)))
+    .filter_map(|commit| match format.encode(commit) {
+      Ok(message) => Some((commit.commit_number, message)),
+      Err(err) => {
+        error!("could not replay commit {}: {}", commit.commit_id, err);
+        None
+      }
+    })
+    .collect();
+  stream::iter(replayed).chain(live.filter(move |&(commit_number, _)| {
+    future::ready(commit_number > replayed_through)
+  }))
+}
+
+// Ends `received` once nothing has arrived on it for `idle_timeout`.
+fn until_idle<St>(received: St, idle_timeout: Duration) -> impl Stream<Item = St::Item>
+where
+  St: Stream + Unpin,
+{
+  stream::unfold(received, move |received| {
+    tokio::time::timeout(idle_timeout, received.into_future()).map(move |next| match next {
+      Ok((Some(item), received)) => Some((item, received)),
+      Ok((None, _)) => None,
+      Err(_) => {
+        info!("no messages for {:?}, disconnecting", idle_timeout);
+        None
+      }
+    })
+  })
+}
+
+// `?event_types=A,B`, or `?event_type=A,B` as the commit list spells it.
+fn event_type_filter(query: &HashMap<String, String>) -> Option<EventTypeFilter> {
+  query
+    .get("event_types")
+    .or_else(|| query.get("event_type"))
+    .map(|event_types| EventTypeFilter::new(event_types.split(',')))
+}
+
+struct Subscription {
+  key: SubscriptionKey,
+  event_types: Option<EventTypeFilter>,
+  format: CommitFormat,
+  subscription_map: SubscriptionMap,
+  heartbeat: Heartbeat,
+}
+
+fn subscribe<F>(
+  subscription: Subscription,
+  websocket: WebSocket,
+  replay: F,
+) -> impl Future<Output = ()>
+where
+  F: FnOnce() -> Result<Vec<Commit>, String>,
+{
+  let Subscription {
+    key,
+    event_types,
+    format,
+    subscription_map,
+    heartbeat,
+  } = subscription;
   let (subscriber_ws_tx, subscriber_ws_rx) = websocket.split();
-  let (tx, rx) = mpsc::channel();
-  let tx_clone = tx.clone();
-  let after_clone = tx.clone();
-  let state_handle = Arc::clone(&aggregate_map);
-  warp::spawn(
-    rx.forward(subscriber_ws_tx)
-      .map(|_tx_rx| ())
-      .map_err(|ws_err| error!("websocket send error: {}", ws_err)),
+  let (subscriber_id, live) =
+    register(&key, event_types.clone(), format.clone(), &subscription_map);
+  let registration = Registration {
+    key,
+    subscription_map,
+    subscriber_id,
+  };
+  let replayed = match replay() {
+    Ok(commits) => commits,
+    Err(err) => {
+      error!("could not replay commits to subscriber {}: {}", subscriber_id, err);
+      return Either::Left(future::ready(()));
+    }
+  };
+  // Commits end with `None` once the subscriber is removed, which ends the
+  // pings too and closes the socket.
+  let commits = replay_then_live(replayed, event_types.as_ref(), &format, live)
+    .map(|(_, json)| Some(Message::text(json)))
+    .chain(stream::once(future::ready(None)));
+  let ping_interval = heartbeat.ping_interval;
+  let pings = stream::repeat(())
+    .then(move |_| tokio::time::sleep(ping_interval))
+    .map(|_| Some(Message::ping(Vec::new())));
+  tokio::spawn(
+    stream::select(commits, pings)
+      .take_while(|message| future::ready(message.is_some()))
+      .filter_map(future::ready)
+      .map(Ok)
+      .forward(subscriber_ws_tx)
+      .map(|result| {
+        if let Err(ws_err) = result {
+          error!("websocket send error: {}", ws_err);
+        }
+      }),
   );
-  {
-    state_handle.upsert(
+
+  Either::Right(
+    until_idle(subscriber_ws_rx, heartbeat.idle_timeout)
+      .for_each(|result| {
+        if let Err(err) = result {
+          error!("websocket error: {:?}", err);
+        }
+        future::ready(())
+      })
+      .map(move |_| drop(registration)),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[cfg(feature = "sqlite")]
+  use crate::commit::CommitAttempt;
+  #[cfg(feature = "sqlite")]
+  use crate::server::state::with_store;
+  #[cfg(feature = "sqlite")]
+  use crate::store::shared::SharedStore;
+  #[cfg(feature = "sqlite")]
+  use crate::store::sqlite::SqliteStore;
+  use chrono::Utc;
+
+  fn commit(aggregate_id: Uuid) -> Commit {
+    Commit {
       aggregate_id,
-      || {
-        let new_map = CHashMap::new();
-        new_map.insert_new(subscriber_id, after_clone);
-        new_map
-      },
-      |hash_map| {
-        hash_map.insert(subscriber_id, tx_clone);
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 0,
+      commit_number: 1,
+      serialized_events: br#"[{"type":"Created"}]"#.to_vec(),
+      serialized_metadata: b"null".to_vec(),
+      events_count: 1,
+      dispatched: false,
+    }
+  }
+
+  fn subscribe(
+    subscriptions: &WebSocketSubscriptions,
+    key: SubscriptionKey,
+  ) -> mpsc::UnboundedReceiver<(i64, String)> {
+    let (sender, receiver) = mpsc::unbounded();
+    let subscribers = CHashMap::new();
+    subscribers.insert(
+      0,
+      Subscriber {
+        sender,
+        event_types: None,
+        format: CommitFormat::Json,
       },
     );
+    subscriptions.subscription_map.insert(key, subscribers);
+    receiver
   }
 
-  info!("new subscriber: {}", subscriber_id);
-  subscriber_ws_rx
-    .for_each(|_| Ok(()))
-    .map_err(move |err| {
-      error!("websocket error: {:?}", err);
-    })
-    .then(move |_: Result<(), ()>| {
-      disconnect(aggregate_id, &state_handle, subscriber_id);
+  #[test]
+  fn it_ignores_commits_to_aggregates_without_subscribers() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let _receiver = subscribe(&subscriptions, SubscriptionKey::Aggregate(Uuid::new_v4()));
+
+    assert_eq!(
+      DispatchDelegate::dispatch(&mut subscriptions, &commit(Uuid::new_v4())),
       Ok(())
-    })
+    );
+  }
+
+  #[test]
+  fn it_ignores_subscribers_that_have_gone_away() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let aggregate_id = Uuid::new_v4();
+    drop(subscribe(&subscriptions, SubscriptionKey::Aggregate(aggregate_id)));
+
+    assert_eq!(
+      DispatchDelegate::dispatch(&mut subscriptions, &commit(aggregate_id)),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn it_publishes_commits_to_subscribers() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let aggregate_id = Uuid::new_v4();
+    let mut receiver = subscribe(&subscriptions, SubscriptionKey::Aggregate(aggregate_id));
+
+    assert_eq!(
+      DispatchDelegate::dispatch(&mut subscriptions, &commit(aggregate_id)),
+      Ok(())
+    );
+    let (_, json) = receiver.try_recv().unwrap();
+    let published: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(published["aggregate_id"], aggregate_id.to_string());
+  }
+
+  #[test]
+  fn it_fails_to_dispatch_commits_it_cannot_decode() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let aggregate_id = Uuid::new_v4();
+    let mut receiver = subscribe(&subscriptions, SubscriptionKey::Aggregate(aggregate_id));
+    let undecodable = Commit {
+      serialized_events: vec![0x1f],
+      ..commit(aggregate_id)
+    };
+
+    assert!(DispatchDelegate::dispatch(&mut subscriptions, &undecodable).is_err());
+    assert!(receiver.try_recv().is_err());
+  }
+
+  #[test]
+  fn it_ends_subscriptions_after_queued_commits_when_closed() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let aggregate_id = Uuid::new_v4();
+    let mut receiver = subscribe(&subscriptions, SubscriptionKey::Aggregate(aggregate_id));
+    DispatchDelegate::dispatch(&mut subscriptions, &commit(aggregate_id)).unwrap();
+
+    subscriptions.close_all();
+    assert!(receiver.try_recv().is_ok());
+    assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Closed));
+  }
+
+  #[test]
+  fn it_publishes_to_category_and_wildcard_subscribers() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let mut everything = subscribe(&subscriptions, SubscriptionKey::All);
+    let mut orders = subscribe(&subscriptions, SubscriptionKey::Category(String::from("order")));
+    let mut users = subscribe(&subscriptions, SubscriptionKey::Category(String::from("user")));
+    let order = Commit {
+      category: String::from("order"),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      ..commit(Uuid::new_v4())
+    };
+
+    assert_eq!(DispatchDelegate::dispatch(&mut subscriptions, &order), Ok(()));
+    assert!(everything.try_recv().is_ok());
+    assert!(orders.try_recv().is_ok());
+    assert!(users.try_recv().is_err());
+  }
+
+  #[cfg(feature = "cloudevents")]
+  #[test]
+  fn it_publishes_cloud_events_to_subscribers_that_ask_for_them() {
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let aggregate_id = Uuid::new_v4();
+    let mut plain = subscribe(&subscriptions, SubscriptionKey::Aggregate(aggregate_id));
+    let query = [(String::from("format"), String::from("cloudevents"))];
+    let format = subscriptions.commit_format(&query.iter().cloned().collect());
+    let key = SubscriptionKey::Aggregate(aggregate_id);
+    let (_, mut cloud) = register(&key, None, format, &subscriptions.subscription_map);
+    let order = commit(aggregate_id);
+
+    DispatchDelegate::dispatch(&mut subscriptions, &order).unwrap();
+    let (_, json) = plain.try_recv().unwrap();
+    let published: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(published["commit_id"], order.commit_id.to_string());
+    let (_, json) = cloud.try_recv().unwrap();
+    let published: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(published["id"], order.commit_id.to_string());
+    assert_eq!(published["type"], "event_source.committed");
+    assert_eq!(published["data"]["aggregate_id"], aggregate_id.to_string());
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[test]
+  fn it_routes_wildcard_and_category_subscriptions() {
+    let store = SharedStore::new(SqliteStore::with_new_in_memory_connection());
+    let route = WebSocketSubscriptions::default().commit_subscription(with_store(store));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for path in &["/commits/_all", "/commits/category/order"] {
+      let connected = runtime.block_on(warp::test::ws().path(path).handshake(route.clone()));
+      assert!(connected.is_ok(), "{} did not upgrade", path);
+    }
+    let connected = runtime.block_on(
+      warp::test::ws()
+        .path("/commits/not-a-uuid")
+        .handshake(route),
+    );
+    assert!(connected.is_err());
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[test]
+  fn it_replays_missed_commits_before_live_ones() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let aggregate_id = Uuid::new_v4();
+    let mut commits = Vec::new();
+    for version in 0..3 {
+      let attempt = CommitAttempt::from(&Commit {
+        aggregate_version: version,
+        commit_sequence: version,
+        ..commit(aggregate_id)
+      });
+      store.commit(&attempt).unwrap();
+      commits.push(store.get_commit(&attempt.commit_id).unwrap());
+    }
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let route = subscriptions.commit_subscription(with_store(store.clone()));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut client = runtime
+      .block_on(
+        warp::test::ws()
+          .path(&format!("/commits/_all?from_commit_number={}", commits[0].commit_number))
+          .handshake(route),
+      )
+      .unwrap();
+    let mut next_commit_number = || {
+      let message = runtime.block_on(client.recv()).unwrap();
+      let published: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+      published["commit_number"].as_i64().unwrap()
+    };
+
+    assert_eq!(next_commit_number(), commits[1].commit_number);
+    assert_eq!(next_commit_number(), commits[2].commit_number);
+    // A commit dispatched while replaying is not sent twice.
+    DispatchDelegate::dispatch(&mut subscriptions, &commits[2]).unwrap();
+    let live = Commit {
+      aggregate_version: 3,
+      commit_number: commits[2].commit_number + 1,
+      ..commit(aggregate_id)
+    };
+    DispatchDelegate::dispatch(&mut subscriptions, &live).unwrap();
+    assert_eq!(next_commit_number(), live.commit_number);
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[test]
+  fn it_pings_subscribers() {
+    let store = SharedStore::new(SqliteStore::with_new_in_memory_connection());
+    let subscriptions = WebSocketSubscriptions {
+      heartbeat: Heartbeat {
+        ping_interval: Duration::from_millis(10),
+        ..Heartbeat::default()
+      },
+      ..WebSocketSubscriptions::default()
+    };
+    let route = subscriptions.commit_subscription(with_store(store));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut client = runtime
+      .block_on(
+        warp::test::ws()
+          .path(&format!("/commits/{}", Uuid::new_v4()))
+          .handshake(route),
+      )
+      .unwrap();
+
+    assert!(runtime.block_on(client.recv()).unwrap().is_ping());
+    assert!(runtime.block_on(client.recv()).unwrap().is_ping());
+  }
+
+  #[test]
+  fn it_ends_received_messages_once_idle() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (sender, receiver) = mpsc::unbounded::<i32>();
+    sender.unbounded_send(1).unwrap();
+
+    let received =
+      runtime.block_on(until_idle(receiver, Duration::from_millis(20)).collect::<Vec<_>>());
+    assert_eq!(received, vec![1]);
+    assert!(sender.is_closed());
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[test]
+  fn it_streams_commits_as_server_sent_events() {
+    use hyper::body::HttpBody;
+    use warp::Reply;
+
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let aggregate_id = Uuid::new_v4();
+    let mut commits = Vec::new();
+    for version in 0..2 {
+      let attempt = CommitAttempt::from(&Commit {
+        aggregate_version: version,
+        commit_sequence: version,
+        ..commit(aggregate_id)
+      });
+      store.commit(&attempt).unwrap();
+      commits.push(store.get_commit(&attempt.commit_id).unwrap());
+    }
+    let mut subscriptions = WebSocketSubscriptions::default();
+    let route = subscriptions.commit_stream(with_store(store.clone()));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let reply = runtime
+      .block_on(
+        warp::test::request()
+          .path(&format!("/commits/{}/stream", aggregate_id))
+          .header("last-event-id", commits[0].commit_number)
+          .filter(&route),
+      )
+      .unwrap();
+    let mut body = reply.into_response().into_body();
+    let mut next_event = || {
+      let chunk = runtime.block_on(body.data()).unwrap().unwrap();
+      String::from_utf8(chunk.to_vec()).unwrap()
+    };
+
+    let replayed = next_event();
+    assert!(replayed.contains(&format!("id:{}", commits[1].commit_number)));
+    assert!(replayed.contains(&commits[1].commit_id.to_string()));
+    let live = Commit {
+      aggregate_version: 2,
+      commit_number: commits[1].commit_number + 1,
+      ..commit(aggregate_id)
+    };
+    DispatchDelegate::dispatch(&mut subscriptions, &live).unwrap();
+    assert!(next_event().contains(&live.commit_id.to_string()));
+
+    drop(body);
+    assert_eq!(
+      subscriptions
+        .subscription_map
+        .get(&SubscriptionKey::Aggregate(aggregate_id))
+        .unwrap()
+        .len(),
+      0
+    );
+  }
 }
@@ -0,0 +1,507 @@
+// Every RPC fails with tonic's `Status`, however large clippy finds it.
+#![allow(clippy::result_large_err)]
+
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+
+use crate::aggregate::Aggregate;
+use crate::command::Command;
+use crate::commit::Commit;
+use crate::server::aggregate::{
+  fetch_latest, issue_command_request, CommandRequest, CommandSchemas, HandlerError,
+};
+use crate::server::auth::Authenticator;
+use crate::server::commands::CommandRegistry;
+use crate::server::dispatch::{CommitFormat, SubscriptionKey, WebSocketSubscriptions};
+use crate::server::store::commit_page;
+use crate::store::shared::SharedStore;
+use crate::store::Store;
+use crate::subscription::EventTypeFilter;
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+// The messages and service generated from `proto/event_source.proto`.
+pub mod proto {
+  tonic::include_proto!("event_source");
+}
+
+use self::proto::event_source_server::{EventSource, EventSourceServer};
+use self::proto::subscribe_request::Key;
+
+// Serves `proto/event_source.proto` from the same store, subscribers, command
+// registry and authenticator as the HTTP routes, so a commit made over either
+// reaches the subscribers of both. `C` is the command type committed when a
+// request names no aggregate type, as at `/commit/{id}`.
+pub struct GrpcService<S: Store, C, R> {
+  store: SharedStore<S>,
+  subscriptions: WebSocketSubscriptions,
+  commands: R,
+  schemas: CommandSchemas,
+  authenticator: Option<Arc<dyn Authenticator>>,
+  command: PhantomData<fn() -> C>,
+}
+
+impl<S, C, R> GrpcService<S, C, R>
+where
+  S: Store + Send + 'static,
+  C: Command + Serialize + DeserializeOwned + 'static,
+  C::Aggregate: Serialize,
+  R: CommandRegistry,
+{
+  pub fn new(
+    store: SharedStore<S>,
+    subscriptions: WebSocketSubscriptions,
+    commands: R,
+    schemas: CommandSchemas,
+    authenticator: Option<Arc<dyn Authenticator>>,
+  ) -> GrpcService<S, C, R> {
+    GrpcService {
+      store,
+      subscriptions,
+      commands,
+      schemas,
+      authenticator,
+      command: PhantomData,
+    }
+  }
+
+  pub fn into_server(self) -> EventSourceServer<Self> {
+    EventSourceServer::new(self)
+  }
+
+  // Authenticates requests as the HTTP routes do, from their metadata instead of
+  // headers. Principals bound to a tenant are refused, since the service reads
+  // and writes outside any tenant.
+  fn authorize(&self, metadata: &MetadataMap, aggregate_id: Option<Uuid>) -> Result<(), Status> {
+    let authenticator = match self.authenticator {
+      Some(ref authenticator) => authenticator,
+      None => return Ok(()),
+    };
+    match authenticator.authenticate(&metadata.clone().into_headers()) {
+      Ok(ref principal)
+        if principal.can_access(aggregate_id) && principal.can_access_tenant(None) =>
+      {
+        Ok(())
+      }
+      Ok(principal) => {
+        debug!("{} may not make this gRPC request", principal.subject);
+        Err(Status::permission_denied("forbidden"))
+      }
+      Err(reason) => {
+        debug!("rejected gRPC credentials: {}", reason);
+        Err(Status::unauthenticated("unauthorized"))
+      }
+    }
+  }
+}
+
+type CommitStream = Pin<Box<dyn Stream<Item = Result<proto::StoredCommit, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<S, C, R> EventSource for GrpcService<S, C, R>
+where
+  S: Store + Send + 'static,
+  C: Command + Serialize + DeserializeOwned + 'static,
+  C::Aggregate: Serialize,
+  R: CommandRegistry,
+{
+  async fn commit(
+    &self,
+    request: Request<proto::CommitRequest>,
+  ) -> Result<Response<proto::StoredCommit>, Status> {
+    let aggregate_id = parse_uuid(&request.get_ref().aggregate_id)?;
+    self.authorize(request.metadata(), Some(aggregate_id))?;
+    let request = request.into_inner();
+    let command = serde_json::from_slice(&request.command)
+      .map_err(|err| Status::invalid_argument(err.to_string()))?;
+    let command_request = CommandRequest {
+      aggregate_id,
+      expected_version: request.expected_version,
+      command,
+    };
+    let commit = if request.aggregate_type.is_empty() {
+      issue_command_request::<_, _, C>(
+        self.store.clone(),
+        self.subscriptions.clone(),
+        &self.schemas,
+        command_request,
+      )
+    } else {
+      let issued = self.commands.issue(
+        &request.aggregate_type,
+        self.store.clone(),
+        &self.subscriptions,
+        &self.schemas,
+        command_request,
+      );
+      match issued {
+        Some(commit) => commit.await,
+        None => {
+          let unknown = format!("no command is registered for {}", request.aggregate_type);
+          return Err(Status::not_found(unknown));
+        }
+      }
+    };
+    let commit = commit.map_err(handler_status)?;
+    Ok(Response::new(stored_commit(&commit)?))
+  }
+
+  async fn get_range(
+    &self,
+    request: Request<proto::GetRangeRequest>,
+  ) -> Result<Response<proto::GetRangeResponse>, Status> {
+    let aggregate_id = parse_uuid(&request.get_ref().aggregate_id)?;
+    self.authorize(request.metadata(), Some(aggregate_id))?;
+    let request = request.into_inner();
+    let page = commit_page(
+      &self.store,
+      aggregate_id,
+      request.page_token.or(request.from_version).unwrap_or(0),
+      request.to_version.unwrap_or(i64::MAX),
+      request.limit.map(i64::from),
+      None,
+    );
+    let (commits, next_version) = page.map_err(|err| Status::internal(err.to_string()))?;
+    Ok(Response::new(proto::GetRangeResponse {
+      commits: commits.iter().map(stored_commit).collect::<Result<_, _>>()?,
+      next_page_token: next_version,
+    }))
+  }
+
+  async fn get_latest(
+    &self,
+    request: Request<proto::GetLatestRequest>,
+  ) -> Result<Response<proto::Aggregate>, Status> {
+    let aggregate_id = parse_uuid(&request.get_ref().aggregate_id)?;
+    self.authorize(request.metadata(), Some(aggregate_id))?;
+    let aggregate =
+      fetch_latest::<_, C::Aggregate>(self.store.clone(), aggregate_id).map_err(handler_status)?;
+    let state = serde_json::to_vec(&aggregate).map_err(|err| Status::internal(err.to_string()))?;
+    Ok(Response::new(proto::Aggregate {
+      version: aggregate.version(),
+      state,
+    }))
+  }
+
+  type SubscribeStream = CommitStream;
+
+  async fn subscribe(
+    &self,
+    request: Request<proto::SubscribeRequest>,
+  ) -> Result<Response<CommitStream>, Status> {
+    let key = match request.get_ref().key {
+      Some(Key::AggregateId(ref aggregate_id)) => {
+        SubscriptionKey::Aggregate(parse_uuid(aggregate_id)?)
+      }
+      Some(Key::Category(ref category)) => SubscriptionKey::Category(category.clone()),
+      Some(Key::All(_)) | None => SubscriptionKey::All,
+    };
+    let aggregate_id = match key {
+      SubscriptionKey::Aggregate(aggregate_id) => Some(aggregate_id),
+      _ => None,
+    };
+    self.authorize(request.metadata(), aggregate_id)?;
+    let request = request.into_inner();
+    let event_types = if request.event_types.is_empty() {
+      None
+    } else {
+      Some(EventTypeFilter::new(request.event_types))
+    };
+    let commits = self
+      .subscriptions
+      .subscribe(
+        key,
+        event_types,
+        CommitFormat::Stored,
+        request.from_commit_number,
+        &self.store,
+      )
+      .map_err(|err| Status::internal(err.to_string()))?;
+    let commits = commits.map(|(_, json)| {
+      serde_json::from_str::<Commit>(&json)
+        .map_err(|err| Status::internal(err.to_string()))
+        .and_then(|commit| stored_commit(&commit))
+    });
+    Ok(Response::new(Box::pin(commits) as CommitStream))
+  }
+}
+
+fn parse_uuid(aggregate_id: &str) -> Result<Uuid, Status> {
+  Uuid::parse_str(aggregate_id)
+    .map_err(|err| Status::invalid_argument(format!("invalid aggregate_id: {}", err)))
+}
+
+// The gRPC status for what the HTTP routes would answer, with their JSON body,
+// such as the fields of a 422 or the current version of a 409, as its details.
+fn handler_status(err: HandlerError) -> Status {
+  let code = match err.status {
+    StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+    StatusCode::NOT_FOUND | StatusCode::GONE => Code::NotFound,
+    StatusCode::CONFLICT => Code::Aborted,
+    StatusCode::FORBIDDEN => Code::PermissionDenied,
+    StatusCode::NOT_IMPLEMENTED => Code::Unimplemented,
+    _ => Code::Internal,
+  };
+  let details = serde_json::to_vec(&err.body).unwrap_or_default();
+  Status::with_details(code, err.message(), details.into())
+}
+
+// Events and metadata are sent as the JSON the HTTP API serves them as.
+fn stored_commit(commit: &Commit) -> Result<proto::StoredCommit, Status> {
+  let deserialized = commit
+    .deserialize()
+    .map_err(|err| Status::internal(err.to_string()))?;
+  Ok(proto::StoredCommit {
+    aggregate_id: commit.aggregate_id.to_string(),
+    aggregate_version: commit.aggregate_version,
+    category: commit.category.clone(),
+    commit_id: commit.commit_id.to_string(),
+    commit_timestamp: commit.commit_timestamp.to_rfc3339(),
+    commit_sequence: commit.commit_sequence,
+    commit_number: commit.commit_number,
+    events: serde_json::to_vec(&deserialized.events).unwrap_or_default(),
+    metadata: serde_json::to_vec(&deserialized.metadata).unwrap_or_default(),
+    events_count: commit.events_count,
+    tenant_id: commit.tenant_id.clone(),
+    parent_commit_id: commit
+      .parent_commit_id
+      .map(|commit_id| commit_id.to_string()),
+    checksum: commit.checksum(),
+  })
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::events::Event;
+  use crate::server::auth::{ApiKeyAuthenticator, Principal, API_KEY_HEADER};
+  use crate::server::commands::Registered;
+  use crate::store::sqlite::SqliteStore;
+  use std::error::Error;
+  use std::fmt;
+  use tokio::runtime::Runtime;
+
+  #[derive(Debug)]
+  struct NeverFails;
+
+  impl fmt::Display for NeverFails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "never fails")
+    }
+  }
+
+  impl Error for NeverFails {}
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum CounterEvent {
+    Incremented,
+  }
+
+  impl Event for CounterEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone)]
+  struct Counter {
+    id: Uuid,
+    version: i64,
+  }
+
+  impl Aggregate for Counter {
+    type Event = CounterEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Counter { id, version: 0 }
+    }
+
+    fn apply(&self, _event: &CounterEvent) -> Counter {
+      Counter {
+        id: self.id,
+        version: self.version + 1,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.version
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone)]
+  enum CounterCommand {
+    Increment,
+  }
+
+  impl Command for CounterCommand {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn apply(&self, _counter: &Counter) -> Result<Vec<CounterEvent>, NeverFails> {
+      Ok(vec![CounterEvent::Incremented])
+    }
+  }
+
+  type CounterService = GrpcService<SqliteStore, CounterCommand, Registered<CounterCommand, ()>>;
+
+  fn service(
+    authenticator: Option<Arc<dyn Authenticator>>,
+  ) -> (CounterService, SharedStore<SqliteStore>) {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let store = SharedStore::new(store);
+    let service = GrpcService::new(
+      store.clone(),
+      WebSocketSubscriptions::default(),
+      Registered::new("counter", ()),
+      CommandSchemas::default(),
+      authenticator,
+    );
+    (service, store)
+  }
+
+  fn increment(aggregate_type: &str, aggregate_id: Uuid) -> proto::CommitRequest {
+    proto::CommitRequest {
+      aggregate_type: String::from(aggregate_type),
+      aggregate_id: aggregate_id.to_string(),
+      expected_version: None,
+      command: b"\"Increment\"".to_vec(),
+    }
+  }
+
+  #[test]
+  fn it_commits_and_reads_back_through_the_http_handlers() {
+    let (service, store) = service(None);
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+
+    let first = runtime
+      .block_on(service.commit(Request::new(increment("", aggregate_id))))
+      .unwrap()
+      .into_inner();
+    let second = runtime
+      .block_on(service.commit(Request::new(increment("counter", aggregate_id))))
+      .unwrap()
+      .into_inner();
+    let stored = store.get_range(aggregate_id, 0, i64::MAX).unwrap();
+    assert_eq!(first.commit_id, stored[0].commit_id.to_string());
+    assert_eq!(first.checksum, stored[0].checksum());
+    assert_eq!(first.parent_commit_id, None);
+    assert_eq!(second.parent_commit_id, Some(first.commit_id.clone()));
+    assert_eq!(
+      serde_json::from_slice::<serde_json::Value>(&second.events).unwrap(),
+      serde_json::json!([{"event_type": "Incremented", "schema_version": 1, "data": "Incremented"}])
+    );
+
+    let page = runtime
+      .block_on(service.get_range(Request::new(proto::GetRangeRequest {
+        aggregate_id: aggregate_id.to_string(),
+        limit: Some(1),
+        ..proto::GetRangeRequest::default()
+      })))
+      .unwrap()
+      .into_inner();
+    assert_eq!(page.commits, vec![first]);
+    assert_eq!(page.next_page_token, Some(1));
+
+    let latest = runtime
+      .block_on(service.get_latest(Request::new(proto::GetLatestRequest {
+        aggregate_id: aggregate_id.to_string(),
+      })))
+      .unwrap()
+      .into_inner();
+    assert_eq!(latest.version, 2);
+  }
+
+  #[test]
+  fn it_maps_handler_errors_onto_status_codes() {
+    let (service, _) = service(None);
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+
+    let conflict = proto::CommitRequest {
+      expected_version: Some(3),
+      ..increment("", aggregate_id)
+    };
+    let status = runtime
+      .block_on(service.commit(Request::new(conflict)))
+      .unwrap_err();
+    assert_eq!(status.code(), Code::Aborted);
+    let details: serde_json::Value = serde_json::from_slice(status.details()).unwrap();
+    assert_eq!(details["current_version"], 0);
+
+    let status = runtime
+      .block_on(service.commit(Request::new(increment("light", aggregate_id))))
+      .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let status = runtime
+      .block_on(service.get_latest(Request::new(proto::GetLatestRequest {
+        aggregate_id: aggregate_id.to_string(),
+      })))
+      .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+  }
+
+  #[test]
+  fn it_replays_then_streams_commits_to_subscribers() {
+    let (service, _) = service(None);
+    let runtime = Runtime::new().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    let replayed = runtime
+      .block_on(service.commit(Request::new(increment("", aggregate_id))))
+      .unwrap()
+      .into_inner();
+
+    let mut commits = runtime
+      .block_on(service.subscribe(Request::new(proto::SubscribeRequest {
+        key: Some(Key::AggregateId(aggregate_id.to_string())),
+        event_types: Vec::new(),
+        from_commit_number: Some(0),
+      })))
+      .unwrap()
+      .into_inner();
+    let live = runtime
+      .block_on(service.commit(Request::new(increment("", aggregate_id))))
+      .unwrap()
+      .into_inner();
+
+    assert_eq!(runtime.block_on(commits.next()).unwrap().unwrap(), replayed);
+    assert_eq!(runtime.block_on(commits.next()).unwrap().unwrap(), live);
+  }
+
+  #[test]
+  fn it_authenticates_requests_from_their_metadata() {
+    let aggregate_id = Uuid::new_v4();
+    let authenticator = ApiKeyAuthenticator::default()
+      .with_key("admin", Principal::with_full_access("admin"))
+      .with_key(
+        "other",
+        Principal::with_aggregates("other", vec![Uuid::new_v4()]),
+      );
+    let (service, _) = service(Some(Arc::new(authenticator)));
+    let runtime = Runtime::new().unwrap();
+    let commit = |key: Option<&str>| {
+      let mut request = Request::new(increment("", aggregate_id));
+      if let Some(key) = key {
+        request
+          .metadata_mut()
+          .insert(API_KEY_HEADER, key.parse().unwrap());
+      }
+      runtime.block_on(service.commit(request))
+    };
+
+    assert_eq!(commit(None).unwrap_err().code(), Code::Unauthenticated);
+    assert_eq!(
+      commit(Some("other")).unwrap_err().code(),
+      Code::PermissionDenied
+    );
+    assert!(commit(Some("admin")).is_ok());
+  }
+}
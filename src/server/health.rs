@@ -0,0 +1,90 @@
+use warp::http::StatusCode;
+use warp::{path, Filter, Rejection, Reply};
+
+use crate::store::Store;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct HealthResponse {
+  status: &'static str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+fn health_reply(error: Option<String>) -> warp::reply::WithStatus<warp::reply::Json> {
+  let (status, code) = match error {
+    None => ("ok", StatusCode::OK),
+    Some(_) => ("unavailable", StatusCode::SERVICE_UNAVAILABLE),
+  };
+  warp::reply::with_status(warp::reply::json(&HealthResponse { status, error }), code)
+}
+
+// Liveness: answers as long as the server can handle requests at all.
+pub fn healthz() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  path!("healthz").and(warp::get()).map(|| health_reply(None))
+}
+
+// Readiness: fails while the store is unhealthy, and once the server has begun
+// shutting down so load balancers stop sending it traffic.
+pub fn readyz<S: Store + Send, St>(
+  store: St,
+  shutting_down: Arc<AtomicBool>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("readyz")
+    .and(warp::get())
+    .and(store)
+    .map(move |store: S| {
+      if shutting_down.load(Ordering::SeqCst) {
+        return health_reply(Some(String::from("shutting down")));
+      }
+      health_reply(store.health_check().err().map(|err| err.to_string()))
+    })
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::server::state::with_store;
+  use crate::store::shared::SharedStore;
+  use crate::store::sqlite::SqliteStore;
+  use tokio::runtime::Runtime;
+
+  #[test]
+  fn it_is_ready_while_the_store_is_healthy_and_not_shutting_down() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let readyz_route = readyz(with_store(SharedStore::new(store)), Arc::clone(&shutting_down));
+    let runtime = Runtime::new().unwrap();
+    let get = |path: &str| warp::test::request().path(path);
+
+    assert_eq!(
+      runtime.block_on(get("/healthz").reply(&healthz())).status(),
+      StatusCode::OK
+    );
+    assert_eq!(
+      runtime
+        .block_on(get("/readyz").reply(&readyz_route))
+        .status(),
+      StatusCode::OK
+    );
+
+    shutting_down.store(true, Ordering::SeqCst);
+    let response = runtime.block_on(get("/readyz").reply(&readyz_route));
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+      runtime.block_on(get("/healthz").reply(&healthz())).status(),
+      StatusCode::OK
+    );
+
+    let uninitialized_store = SharedStore::new(SqliteStore::with_new_in_memory_connection());
+    let readyz_route = readyz(with_store(uninitialized_store), Arc::new(AtomicBool::new(false)));
+    let response = runtime.block_on(get("/readyz").reply(&readyz_route));
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+  }
+}
@@ -1,61 +1,971 @@
+pub mod admin;
 pub mod aggregate;
+pub mod auth;
+pub mod commands;
+pub mod compression;
 pub mod dispatch;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod negotiation;
+#[cfg(feature = "openapi")]
+pub mod openapi;
+pub mod rate_limit;
+pub mod state;
 pub mod store;
 
-use command::Command;
+use crate::aggregate::Aggregate;
+use crate::command::{AsyncCommand, Command};
+#[cfg(feature = "cloudevents")]
+use crate::dispatch::cloudevents::CloudEventsFormat;
+#[cfg(feature = "outbox")]
+use crate::dispatch::outbox::OutboxHandle;
+#[cfg(feature = "json-schema")]
+use crate::json_schema::SchemaValidator;
+use crate::server::admin::{
+  backup, dispatch_failure, dispatch_failures, force_dispatch, handle_rejection,
+  rebuild_projection, rebuild_status, remove_retention_policy, retention_policies,
+  retry_dispatch_failure, set_retention_policy, undispatched_commits, AdminConfig,
+  ProjectionRebuilds, ProjectionRunnerFactory,
+};
+use crate::server::aggregate::commit;
+use crate::server::aggregate::CommandSchemas;
+use crate::server::aggregate::{force_snapshot, get_snapshot};
+use crate::server::aggregate::{get_as_of, get_at_version, get_latest};
+use crate::server::auth::{authorize, Authenticator};
+use crate::server::commands::{CommandRegistry, Registered, RegisteredAsync};
+use crate::server::compression::gzipped;
+use crate::server::dispatch::{Heartbeat, WebSocketSubscriptions};
+#[cfg(feature = "grpc")]
+use crate::server::grpc::GrpcService;
+use crate::server::health::{healthz, readyz};
+use crate::server::negotiation::negotiated;
+#[cfg(feature = "openapi")]
+use crate::server::openapi::{openapi_document, OpenApi};
+use crate::server::rate_limit::{rate_limited, RateLimitConfig, RateLimiter};
+use crate::server::state::{with_store, with_tenant_store};
+use crate::server::store::{category_commit_list, commit_export, commit_list};
+use crate::snapshot::{SharedSnapshotStore, SnapshotStore};
+use crate::store::shared::SharedStore;
+use crate::store::tenant::TenantStore;
+use crate::store::{Store, StoreConfig};
+use futures::future::{self, FutureExt};
+#[cfg(feature = "json-schema")]
+use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use server::aggregate::commit;
-use server::aggregate::get_latest;
-use server::dispatch::WebSocketSubscriptions;
-use server::store::commit_list;
-use store::Store;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "outbox")]
+use std::sync::Mutex;
+#[cfg(feature = "grpc")]
+use tonic::transport::server::TcpIncoming;
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
 use warp::Filter;
 
-pub struct Server {
-  subscriptions_state: WebSocketSubscriptions,
+pub type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+  pub cert_path: PathBuf,
+  pub key_path: PathBuf,
 }
 
-impl Clone for Server {
-  fn clone(&self) -> Self {
-    Server {
-      subscriptions_state: self.subscriptions_state.clone(),
+// Where and how `Server::serve` listens. Defaults to plain HTTP on
+// 127.0.0.1:4321, running until the process exits.
+pub struct ServerConfig {
+  pub bind_address: IpAddr,
+  pub port: u16,
+  #[cfg(feature = "tls")]
+  pub tls: Option<TlsConfig>,
+  // Also serves the gRPC service, over plain HTTP/2, on this port.
+  #[cfg(feature = "grpc")]
+  pub grpc_port: Option<u16>,
+  // Once this resolves the server stops accepting connections and returns
+  // after in-flight requests finish.
+  pub shutdown: Option<ShutdownSignal>,
+}
+
+impl Default for ServerConfig {
+  fn default() -> ServerConfig {
+    ServerConfig {
+      bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+      port: 4321,
+      #[cfg(feature = "tls")]
+      tls: None,
+      #[cfg(feature = "grpc")]
+      grpc_port: None,
+      shutdown: None,
+    }
+  }
+}
+
+impl ServerConfig {
+  pub fn with_bind_address(mut self, bind_address: IpAddr) -> Self {
+    self.bind_address = bind_address;
+    self
+  }
+
+  pub fn with_port(mut self, port: u16) -> Self {
+    self.port = port;
+    self
+  }
+
+  // Serves HTTPS (and WSS) using the PEM certificate chain and private key at
+  // these paths.
+  #[cfg(feature = "tls")]
+  pub fn with_tls<P: Into<PathBuf>>(mut self, cert_path: P, key_path: P) -> Self {
+    self.tls = Some(TlsConfig {
+      cert_path: cert_path.into(),
+      key_path: key_path.into(),
+    });
+    self
+  }
+
+  #[cfg(feature = "grpc")]
+  pub fn with_grpc_port(mut self, grpc_port: u16) -> Self {
+    self.grpc_port = Some(grpc_port);
+    self
+  }
+
+  pub fn with_shutdown_signal<F>(mut self, signal: F) -> Self
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    self.shutdown = Some(Box::pin(signal));
+    self
+  }
+
+  pub fn socket_address(&self) -> SocketAddr {
+    SocketAddr::new(self.bind_address, self.port)
+  }
+}
+
+pub const DEFAULT_MAX_COMMIT_BYTES: u64 = 1024 * 1024;
+
+// Cross-origin access for browser clients. No origins allows any origin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsConfig {
+  pub allowed_origins: Vec<String>,
+  pub allowed_headers: Vec<String>,
+  pub allowed_methods: Vec<String>,
+}
+
+impl Default for CorsConfig {
+  fn default() -> CorsConfig {
+    CorsConfig {
+      allowed_origins: Vec::new(),
+      allowed_headers: ["authorization", "content-type", "if-match", "x-api-key"]
+        .iter()
+        .map(|header| String::from(*header))
+        .collect(),
+      allowed_methods: vec![String::from("GET"), String::from("POST")],
     }
   }
 }
 
+impl CorsConfig {
+  pub fn with_allowed_origin(mut self, origin: &str) -> Self {
+    self.allowed_origins.push(String::from(origin));
+    self
+  }
+
+  pub fn with_allowed_header(mut self, header: &str) -> Self {
+    self.allowed_headers.push(String::from(header));
+    self
+  }
+
+  pub fn with_allowed_method(mut self, method: &str) -> Self {
+    self.allowed_methods.push(String::from(method));
+    self
+  }
+
+  // Panics on an origin, header or method that is not valid HTTP.
+  pub fn to_cors(&self) -> warp::cors::Cors {
+    let cors = warp::cors()
+      .allow_headers(self.allowed_headers.iter().map(String::as_str))
+      .allow_methods(self.allowed_methods.iter().map(String::as_str))
+      .expose_headers(vec!["link"]);
+    if self.allowed_origins.is_empty() {
+      cors.allow_any_origin().build()
+    } else {
+      cors
+        .allow_origins(self.allowed_origins.iter().map(String::as_str))
+        .build()
+    }
+  }
+}
+
+// `R` is the registry of command types accepted at
+// `/commit/{aggregate_type}/{id}`; see `register`.
+#[derive(Clone)]
+pub struct Server<R = ()> {
+  commands: R,
+  subscriptions_state: WebSocketSubscriptions,
+  admin_config: Option<AdminConfig>,
+  snapshot_store: Option<SharedSnapshotStore>,
+  projection_runner_factory: Option<ProjectionRunnerFactory>,
+  projection_rebuilds: ProjectionRebuilds,
+  shutting_down: Arc<AtomicBool>,
+  authenticator: Option<Arc<dyn Authenticator>>,
+  cors: Option<CorsConfig>,
+  max_commit_bytes: u64,
+  rate_limiter: Option<RateLimiter>,
+  tenants: bool,
+  store_config: Option<StoreConfig>,
+  command_schemas: CommandSchemas,
+  #[cfg(feature = "openapi")]
+  openapi: Option<OpenApi>,
+  #[cfg(feature = "outbox")]
+  outbox: Arc<Mutex<Option<OutboxHandle>>>,
+}
+
 impl Default for Server {
-  fn default() -> Self {
+  fn default() -> Server {
     Server {
-      subscriptions_state: Default::default(),
+      commands: (),
+      subscriptions_state: WebSocketSubscriptions::default(),
+      admin_config: None,
+      snapshot_store: None,
+      projection_runner_factory: None,
+      projection_rebuilds: ProjectionRebuilds::default(),
+      shutting_down: Arc::default(),
+      authenticator: None,
+      cors: None,
+      max_commit_bytes: DEFAULT_MAX_COMMIT_BYTES,
+      rate_limiter: None,
+      tenants: false,
+      store_config: None,
+      command_schemas: CommandSchemas::default(),
+      #[cfg(feature = "openapi")]
+      openapi: None,
+      #[cfg(feature = "outbox")]
+      outbox: Arc::default(),
     }
   }
 }
 
-impl Server {
-  pub fn serve<
-    S: Store + 'static,
+impl<R: CommandRegistry> Server<R> {
+  // Accepts `C` at `POST /commit/{aggregate_type}/{id}`, alongside the command
+  // type `serve` is called with at `POST /commit/{id}`.
+  pub fn register<C>(self, aggregate_type: &str) -> Server<Registered<C, R>>
+  where
+    C: Command + Serialize + DeserializeOwned + 'static,
+    C::Aggregate: Serialize,
+  {
+    self.assert_unregistered(aggregate_type);
+    self.with_commands(|commands| Registered::new(aggregate_type, commands))
+  }
+
+  // Like `register`, for a command whose `apply` is awaited.
+  pub fn register_async<C>(self, aggregate_type: &str) -> Server<RegisteredAsync<C, R>>
+  where
+    C: AsyncCommand + Serialize + DeserializeOwned + 'static,
+    C::Aggregate: Serialize + Send,
+    <C::Aggregate as Aggregate>::Event: Send,
+  {
+    self.assert_unregistered(aggregate_type);
+    self.with_commands(|commands| RegisteredAsync::new(aggregate_type, commands))
+  }
+
+  fn assert_unregistered(&self, aggregate_type: &str) {
+    assert!(
+      !self.commands.aggregate_types().contains(&aggregate_type),
+      "a command is already registered for {}",
+      aggregate_type
+    );
+  }
+
+  fn with_commands<C, F: FnOnce(R) -> C>(self, add: F) -> Server<C> {
+    Server {
+      commands: add(self.commands),
+      subscriptions_state: self.subscriptions_state,
+      admin_config: self.admin_config,
+      snapshot_store: self.snapshot_store,
+      projection_runner_factory: self.projection_runner_factory,
+      projection_rebuilds: self.projection_rebuilds,
+      shutting_down: self.shutting_down,
+      authenticator: self.authenticator,
+      cors: self.cors,
+      max_commit_bytes: self.max_commit_bytes,
+      rate_limiter: self.rate_limiter,
+      tenants: self.tenants,
+      store_config: self.store_config,
+      command_schemas: self.command_schemas,
+      #[cfg(feature = "openapi")]
+      openapi: self.openapi,
+      #[cfg(feature = "outbox")]
+      outbox: self.outbox,
+    }
+  }
+
+  // Panics on an empty token, which would leave the admin routes unusable.
+  pub fn with_admin_config(mut self, admin_config: AdminConfig) -> Self {
+    assert!(!admin_config.token.is_empty(), "the admin token is empty");
+    self.admin_config = Some(admin_config);
+    self
+  }
+
+  // Enables the `/aggregate/{id}/snapshot` routes.
+  pub fn with_snapshot_store<T: SnapshotStore + Send + 'static>(mut self, snapshot_store: T) -> Self {
+    self.snapshot_store = Some(SharedSnapshotStore::new(snapshot_store));
+    self
+  }
+
+  // Enables the `/admin/projections/{name}/rebuild` routes when an admin config is
+  // also set.
+  pub fn with_projection_runner_factory(
+    mut self,
+    projection_runner_factory: ProjectionRunnerFactory,
+  ) -> Self {
+    self.projection_runner_factory = Some(projection_runner_factory);
+    self
+  }
+
+  // Guards the commit, query, snapshot and subscription routes. Without one they
+  // are open to anyone who can reach the server.
+  pub fn with_authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+    self.authenticator = Some(Arc::new(authenticator));
+    self
+  }
+
+  // Without a CORS config, browsers only allow same-origin requests.
+  pub fn with_cors(mut self, cors: CorsConfig) -> Self {
+    self.cors = Some(cors);
+    self
+  }
+
+  // Larger commit requests, and those without a content-length, are refused
+  // before their body is read.
+  pub fn with_max_commit_bytes(mut self, max_commit_bytes: u64) -> Self {
+    self.max_commit_bytes = max_commit_bytes;
+    self
+  }
+
+  // Limits how often each client may commit or open a subscription, replying
+  // 429 with `Retry-After` once it is over the limit.
+  pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+    self.rate_limiter = Some(RateLimiter::new(rate_limit));
+    self
+  }
+
+  // How often subscribers are pinged, and how long an unresponsive one is kept.
+  // Also serves the aggregate and commit routes under `/t/{tenant}/`, where each
+  // request reads and writes only its tenant's commits through a `TenantStore`.
+  pub fn with_tenants(mut self) -> Self {
+    self.tenants = true;
+    self
+  }
+
+  // Serves the retention policies of `store_config` under `/admin/retention`, so
+  // they can be changed while a `RetentionTask` sharing it runs. Needs an admin
+  // config as well.
+  pub fn with_store_config(mut self, store_config: StoreConfig) -> Self {
+    self.store_config = Some(store_config);
+    self
+  }
+
+  pub fn with_subscription_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+    self.subscriptions_state.heartbeat = heartbeat;
+    self
+  }
+
+  // How commits are wrapped for subscribers asking for `?format=cloudevents`.
+  #[cfg(feature = "cloudevents")]
+  pub fn with_cloudevents(mut self, format: CloudEventsFormat) -> Self {
+    self.subscriptions_state.cloudevents = format;
+    self
+  }
+
+  // Checks `C` commands against their JSON Schema before they're deserialized,
+  // answering 400 with the fields that don't match, as 422s list invalid ones.
+  // `C` is the command type `serve` is called with or one that's registered.
+  #[cfg(feature = "json-schema")]
+  pub fn with_schema_validation<C: JsonSchema + 'static>(mut self) -> Self {
+    let validator = SchemaValidator::for_type::<C>();
+    self
+      .command_schemas
+      .insert::<C>(Arc::new(move |command| validator.validate(command)));
+    self
+  }
+
+  // Serves the document at `/openapi.json`, without authentication.
+  #[cfg(feature = "openapi")]
+  pub fn with_openapi(mut self, openapi: OpenApi) -> Self {
+    self.openapi = Some(openapi);
+    self
+  }
+
+  // Stopped once the server has drained, so commits accepted while shutting down
+  // are still dispatched.
+  #[cfg(feature = "outbox")]
+  pub fn with_outbox(self, outbox: OutboxHandle) -> Self {
+    *self.outbox.lock().unwrap() = Some(outbox);
+    self
+  }
+
+  // Serves until `shutdown` resolves, then fails readiness checks, stops
+  // accepting connections, closes subscriptions, waits for in-flight requests and
+  // stops the outbox dispatcher. Overrides any shutdown signal in `config`.
+  pub fn serve_until<
+    S: Store + Send + 'static,
     C: Command + Serialize + DeserializeOwned + 'static,
-    Fs: Fn() -> S + Sync,
+    F,
   >(
-    &'static self,
-    store_factory: &'static Fs,
+    &self,
+    store: S,
+    config: ServerConfig,
+    shutdown: F,
   ) -> Result<(), String>
   where
-    Fs: Clone + Send + Sync,
     C::Aggregate: Serialize,
+    F: Future<Output = ()> + Send + 'static,
   {
-    let get_latest_route = get_latest::<S, C::Aggregate, Fs>(&store_factory);
-    let commit_list_route = commit_list(&store_factory);
-    let commit_subscription_route = self.subscriptions_state.commit_subscription();
-    let f = move || self.subscriptions_state.clone();
-    let commit_route = commit::<_, _, C, _, _>(&store_factory, &f);
-    let get_routes = warp::get2().and(commit_list_route.or(get_latest_route));
-    let post_routes = warp::post2().and(commit_route);
-    let routes = commit_subscription_route.or(get_routes).or(post_routes);
-    info!("Starting server at 127.0.0.1:4321");
-    warp::serve(routes).run(([127, 0, 0, 1], 4321));
+    self.serve::<S, C>(store, config.with_shutdown_signal(shutdown))
+  }
+
+  // Every request shares `store`, which should already be initialized.
+  pub fn serve<S: Store + Send + 'static, C: Command + Serialize + DeserializeOwned + 'static>(
+    &self,
+    store: S,
+    config: ServerConfig,
+  ) -> Result<(), String>
+  where
+    C::Aggregate: Serialize,
+  {
+    let store = SharedStore::new(store);
+    let store_routes = self.store_routes::<SharedStore<S>, C, _>(with_store(store.clone()));
+    let tenant_routes = if self.tenants {
+      let tenant_store = with_tenant_store(store.clone());
+      warp::path("t")
+        .and(warp::path::param::<String>())
+        .map(|_tenant: String| ())
+        .untuple_one()
+        .and(self.store_routes::<TenantStore<SharedStore<S>>, C, _>(tenant_store))
+        .boxed()
+    } else {
+      warp::any()
+        .and_then(|| future::err::<Box<dyn warp::Reply>, _>(warp::reject::not_found()))
+        .boxed()
+    };
+    // Only websocket upgrades are subscriptions, so other requests don't spend
+    // tokens on the way past this route.
+    let commit_subscription_route = warp::header::exact_ignore_case("upgrade", "websocket")
+      .and(rate_limited(self.rate_limiter.clone(), self.authenticator.clone()))
+      .and(self.subscriptions_state.commit_subscription(with_store(store.clone())));
+    let commit_stream_route = self.subscriptions_state.commit_stream(with_store(store.clone()));
+    let subscriptions_state = self.subscriptions_state.clone();
+    let f = move || subscriptions_state.clone();
+    let health_routes = healthz().or(readyz(with_store(store.clone()), Arc::clone(&self.shutting_down)));
+    #[cfg(feature = "openapi")]
+    let health_routes = {
+      let openapi_route = match self.openapi {
+        Some(ref openapi) => openapi_document(openapi)
+          .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+          .boxed(),
+        None => warp::any()
+          .and_then(|| future::err::<Box<dyn warp::Reply>, _>(warp::reject::not_found()))
+          .boxed(),
+      };
+      health_routes.or(openapi_route)
+    };
+    let admin_routes = match self.admin_config {
+      Some(ref admin_config) => {
+        let backup_route = backup(with_store(store.clone()), admin_config)
+          .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+          .or(
+            dispatch_failures(with_store(store.clone()), admin_config)
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+          )
+          .unify()
+          .or(
+            dispatch_failure(with_store(store.clone()), admin_config)
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+          )
+          .unify()
+          .or(
+            retry_dispatch_failure(with_store(store.clone()), admin_config)
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+          )
+          .unify()
+          .or(
+            undispatched_commits(with_store(store.clone()), admin_config)
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+          )
+          .unify()
+          .or(
+            force_dispatch(with_store(store.clone()), &f, admin_config)
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+          )
+          .unify()
+          .boxed();
+        let backup_route = match self.store_config {
+          Some(ref store_config) => backup_route
+            .or(
+              retention_policies(admin_config, store_config)
+                .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+            )
+            .unify()
+            .or(
+              set_retention_policy(admin_config, store_config)
+                .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+            )
+            .unify()
+            .or(
+              remove_retention_policy(admin_config, store_config)
+                .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+            )
+            .unify()
+            .boxed(),
+          None => backup_route,
+        };
+        match self.projection_runner_factory {
+          Some(ref runner_factory) => backup_route
+            .or(
+              rebuild_projection(
+                with_store(store.clone()),
+                admin_config,
+                runner_factory,
+                &self.projection_rebuilds,
+              )
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+            )
+            .unify()
+            .or(
+              rebuild_status(admin_config, &self.projection_rebuilds)
+                .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+            )
+            .unify()
+            .boxed(),
+          None => backup_route,
+        }
+      }
+      None => warp::any()
+        .and_then(|| future::err::<Box<dyn warp::Reply>, _>(warp::reject::not_found()))
+        .boxed(),
+    };
+    let snapshot_routes = match self.snapshot_store {
+      Some(ref snapshot_store) => {
+        force_snapshot::<SharedStore<S>, C::Aggregate, _>(with_store(store.clone()), snapshot_store)
+          .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+          .or(
+            get_snapshot(snapshot_store)
+              .map(|reply| Box::new(reply) as Box<dyn warp::Reply>),
+          )
+          .unify()
+          .boxed()
+      }
+      None => warp::any()
+        .and_then(|| future::err::<Box<dyn warp::Reply>, _>(warp::reject::not_found()))
+        .boxed(),
+    };
+    let api_routes = authorize(self.authenticator.clone()).and(
+      snapshot_routes
+        .or(commit_subscription_route)
+        .or(warp::get().and(commit_stream_route))
+        .or(store_routes)
+        .or(tenant_routes),
+    );
+    let routes = health_routes
+      .or(admin_routes)
+      .or(api_routes)
+      .recover(handle_rejection)
+      .with(warp::trace::request());
+    let routes = match self.cors {
+      Some(ref cors) => routes
+        .with(cors.to_cors())
+        .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+        .boxed(),
+      None => routes
+        .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+        .boxed(),
+    };
+    let runtime = tokio::runtime::Runtime::new().map_err(|err| err.to_string())?;
+    let address = config.socket_address();
+    let shutdown = config
+      .shutdown
+      .unwrap_or_else(|| Box::pin(future::pending()))
+      .map({
+        let shutting_down = Arc::clone(&self.shutting_down);
+        let subscriptions_state = self.subscriptions_state.clone();
+        move |()| {
+          info!("Shutting down server");
+          shutting_down.store(true, Ordering::SeqCst);
+          subscriptions_state.close_all();
+        }
+      })
+      .shared();
+    // Binding registers the listener with the runtime's reactor.
+    let _guard = runtime.enter();
+    #[cfg(feature = "grpc")]
+    let grpc_server = match config.grpc_port {
+      Some(grpc_port) => self.grpc_server::<S, C>(
+        store,
+        SocketAddr::new(config.bind_address, grpc_port),
+        shutdown.clone(),
+      )?,
+      None => future::ready(()).boxed(),
+    };
+    #[cfg(not(feature = "grpc"))]
+    let grpc_server = future::ready(());
+    #[cfg(feature = "tls")]
+    {
+      if let Some(tls) = config.tls {
+        let (address, server) = warp::serve(routes)
+          .tls()
+          .cert_path(tls.cert_path)
+          .key_path(tls.key_path)
+          .bind_with_graceful_shutdown(address, shutdown);
+        info!("Starting server at https://{}", address);
+        runtime.block_on(future::join(server, grpc_server));
+        self.stop_outbox();
+        info!("Server shut down, exiting cleanly....");
+        return Ok(());
+      }
+    }
+    let (address, server) = warp::serve(routes)
+      .try_bind_with_graceful_shutdown(address, shutdown)
+      .map_err(|err| err.to_string())?;
+    info!("Starting server at http://{}", address);
+    runtime.block_on(future::join(server, grpc_server));
+    self.stop_outbox();
     info!("Server shut down, exiting cleanly....");
     Ok(())
   }
+
+  // Serves `GrpcService` at `address` until `shutdown` resolves.
+  #[cfg(feature = "grpc")]
+  fn grpc_server<S, C>(
+    &self,
+    store: SharedStore<S>,
+    address: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+  ) -> Result<Pin<Box<dyn Future<Output = ()> + Send>>, String>
+  where
+    S: Store + Send + 'static,
+    C: Command + Serialize + DeserializeOwned + 'static,
+    C::Aggregate: Serialize,
+  {
+    let service = GrpcService::<S, C, R>::new(
+      store,
+      self.subscriptions_state.clone(),
+      self.commands.clone(),
+      self.command_schemas.clone(),
+      self.authenticator.clone(),
+    );
+    let incoming = TcpIncoming::new(address, true, None).map_err(|err| err.to_string())?;
+    info!("Starting gRPC server at http://{}", address);
+    let server = tonic::transport::Server::builder()
+      .add_service(service.into_server())
+      .serve_with_incoming_shutdown(incoming, shutdown);
+    Ok(Box::pin(server.map(|served| {
+      if let Err(err) = served {
+        error!("gRPC server failed: {}", err);
+      }
+    })))
+  }
+
+  // The aggregate and commit routes, reading and writing through `store`.
+  fn store_routes<S, C, St>(&self, store: St) -> BoxedFilter<(Box<dyn warp::Reply>,)>
+  where
+    S: Store + Send + 'static,
+    C: Command + Serialize + DeserializeOwned + 'static,
+    C::Aggregate: Serialize,
+    St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync + 'static,
+  {
+    let subscriptions_state = self.subscriptions_state.clone();
+    let f = move || subscriptions_state.clone();
+    let get_routes = warp::get().and(
+      gzipped(negotiated(category_commit_list(store.clone())))
+        .or(gzipped(negotiated(commit_list(store.clone()))))
+        .or(commit_export(store.clone()))
+        .or(gzipped(negotiated(get_latest::<S, C::Aggregate, _>(store.clone()))))
+        .or(gzipped(negotiated(get_at_version::<S, C::Aggregate, _>(store.clone()))))
+        .or(gzipped(negotiated(get_as_of::<S, C::Aggregate, _>(store.clone())))),
+    );
+    let registered_commit_routes =
+      self
+        .commands
+        .routes(&store, &self.subscriptions_state, &self.command_schemas);
+    let post_routes = warp::post()
+      .and(commit_path())
+      .and(rate_limited(self.rate_limiter.clone(), self.authenticator.clone()))
+      .and(warp::body::content_length_limit(self.max_commit_bytes))
+      .and(negotiated(registered_commit_routes.or(commit::<_, _, C, _, _>(
+        store,
+        &f,
+        &self.command_schemas,
+      ))));
+    get_routes
+      .or(post_routes)
+      .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+      .boxed()
+  }
+
+  fn stop_outbox(&self) {
+    #[cfg(feature = "outbox")]
+    {
+      if let Some(outbox) = self.outbox.lock().unwrap().take() {
+        info!("Stopping outbox dispatcher");
+        outbox.stop();
+      }
+    }
+  }
+}
+
+// Matches `/commit/{id}` and `/commit/{aggregate_type}/{id}` without consuming
+// them, so requests for other paths are rejected before they spend a rate
+// limit token.
+fn commit_path() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+  warp::path::peek()
+    .and_then(|peek: warp::path::Peek| {
+      let segments: Vec<&str> = peek.segments().collect();
+      let matches = match segments.as_slice() {
+        ["commit", id] | ["commit", _, id] => id.parse::<Uuid>().is_ok(),
+        _ => false,
+      };
+      if matches {
+        future::ok(())
+      } else {
+        future::err(warp::reject::not_found())
+      }
+    })
+    .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::Ipv6Addr;
+
+  #[test]
+  fn it_defaults_to_localhost_4321() {
+    let config = ServerConfig::default();
+    assert_eq!(config.socket_address(), "127.0.0.1:4321".parse().unwrap());
+    assert!(config.shutdown.is_none());
+  }
+
+  #[test]
+  fn it_answers_preflights_from_allowed_origins() {
+    let cors = CorsConfig::default()
+      .with_allowed_origin("https://app.example.com")
+      .to_cors();
+    let route = warp::any().map(warp::reply).with(cors);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let preflight = |origin: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .method("OPTIONS")
+          .header("origin", origin)
+          .header("access-control-request-method", "POST")
+          .header("access-control-request-headers", "if-match")
+          .reply(&route),
+      )
+    };
+
+    let response = preflight("https://app.example.com");
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+    assert_eq!(
+      response.headers()["access-control-allow-origin"],
+      "https://app.example.com"
+    );
+    assert_eq!(
+      preflight("https://evil.example.com").status(),
+      warp::http::StatusCode::FORBIDDEN
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "the admin token is empty")]
+  fn it_refuses_an_empty_admin_token() {
+    Server::default().with_admin_config(AdminConfig {
+      token: String::new(),
+      backup_directory: std::path::PathBuf::new(),
+    });
+  }
+
+  #[test]
+  fn it_builds_the_socket_address() {
+    let config = ServerConfig::default()
+      .with_bind_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+      .with_port(8443);
+    assert_eq!(config.socket_address(), "[::]:8443".parse().unwrap());
+  }
+
+  #[cfg(feature = "sqlite")]
+  mod serving {
+    use super::*;
+    use crate::aggregate::Aggregate;
+    use crate::events::Event;
+    use crate::store::sqlite::SqliteStore;
+    use futures::channel::oneshot;
+    use std::error::Error;
+    use std::fmt;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Incremented;
+
+    impl Event for Incremented {}
+
+    #[derive(Serialize, Deserialize, Default, Clone)]
+    struct Counter {
+      id: Uuid,
+      version: i64,
+    }
+
+    impl Aggregate for Counter {
+      type Event = Incremented;
+
+      fn with_id(id: Uuid) -> Self {
+        Counter { id, version: 0 }
+      }
+
+      fn apply(&self, _event: &Incremented) -> Counter {
+        Counter {
+          id: self.id,
+          version: self.version + 1,
+        }
+      }
+
+      fn version(&self) -> i64 {
+        self.version
+      }
+
+      fn id(&self) -> Uuid {
+        self.id
+      }
+    }
+
+    #[derive(Debug)]
+    struct NeverFails;
+
+    impl fmt::Display for NeverFails {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "never fails")
+      }
+    }
+
+    impl Error for NeverFails {}
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Increment;
+
+    impl Command for Increment {
+      type Aggregate = Counter;
+      type Error = NeverFails;
+
+      fn apply(&self, _counter: &Counter) -> Result<Vec<Incremented>, NeverFails> {
+        Ok(vec![Incremented])
+      }
+    }
+
+    fn free_port() -> u16 {
+      TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+    }
+
+    fn get_healthz(port: u16) -> String {
+      for _ in 0..50 {
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+          write!(
+            stream,
+            "GET /healthz HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n"
+          )
+          .unwrap();
+          let mut response = String::new();
+          stream.read_to_string(&mut response).unwrap();
+          return response;
+        }
+        thread::sleep(Duration::from_millis(20));
+      }
+      panic!("server on port {} never started", port);
+    }
+
+    fn post(port: u16, path: &str, body: &str) -> String {
+      let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+      write!(
+        stream,
+        "POST {} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json\r\n\
+         content-length: {}\r\nconnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+      )
+      .unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    }
+
+    #[test]
+    fn it_runs_independent_servers_in_one_process() {
+      let servers: Vec<_> = (0..2)
+        .map(|_| {
+          let port = free_port();
+          let (stop, stopped) = oneshot::channel::<()>();
+          let handle = thread::spawn(move || {
+            let store = SqliteStore::with_new_in_memory_connection();
+            store.initialize();
+            Server::default().serve_until::<SqliteStore, Increment, _>(
+              store,
+              ServerConfig::default().with_port(port),
+              stopped.map(|_| ()),
+            )
+          });
+          (port, stop, handle)
+        })
+        .collect();
+
+      for (port, stop, handle) in servers {
+        assert!(get_healthz(port).starts_with("HTTP/1.1 200"));
+        stop.send(()).unwrap();
+        assert_eq!(handle.join().unwrap(), Ok(()));
+      }
+    }
+
+    #[test]
+    fn it_spends_one_token_per_tenant_commit() {
+      let port = free_port();
+      let (stop, stopped) = oneshot::channel::<()>();
+      let handle = thread::spawn(move || {
+        let store = SqliteStore::with_new_in_memory_connection();
+        store.initialize();
+        Server::default()
+          .with_tenants()
+          .with_rate_limit(RateLimitConfig::new(2, 0.001))
+          .serve_until::<SqliteStore, Increment, _>(
+            store,
+            ServerConfig::default().with_port(port),
+            stopped.map(|_| ()),
+          )
+      });
+      assert!(get_healthz(port).starts_with("HTTP/1.1 200"));
+
+      // Requests for other paths are rejected before they reach the limiter,
+      // so the burst of two is left for the commits.
+      post(port, "/nothing/here", "null");
+      let path = format!("/t/acme/commit/{}", Uuid::new_v4());
+      assert!(post(port, &path, "null").starts_with("HTTP/1.1 200"));
+      assert!(post(port, &path, "null").starts_with("HTTP/1.1 200"));
+      assert!(post(port, &path, "null").starts_with("HTTP/1.1 429"));
+
+      stop.send(()).unwrap();
+      assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+  }
 }
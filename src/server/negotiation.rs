@@ -0,0 +1,271 @@
+use warp::http::header::{
+  HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "cbor")]
+use crate::codec::Cbor;
+use crate::codec::CodecError;
+use futures::future::{self, Either, FutureExt};
+use hyper::body::{to_bytes, Body};
+use serde_json::Value;
+
+// The media types the store routes read and write bodies as. JSON is the
+// default; CBOR needs the `cbor` feature, and NDJSON is only for lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaType {
+  Json,
+  #[cfg(feature = "cbor")]
+  Cbor,
+  NdJson,
+}
+
+impl MediaType {
+  pub fn essence(self) -> &'static str {
+    match self {
+      MediaType::Json => "application/json",
+      #[cfg(feature = "cbor")]
+      MediaType::Cbor => "application/cbor",
+      MediaType::NdJson => "application/x-ndjson",
+    }
+  }
+
+  // The media type a `Content-Type` header names, ignoring its parameters.
+  pub fn parse(content_type: &str) -> Option<MediaType> {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    [
+      MediaType::Json,
+      #[cfg(feature = "cbor")]
+      MediaType::Cbor,
+      MediaType::NdJson,
+    ]
+    .iter()
+    .find(|media_type| essence.eq_ignore_ascii_case(media_type.essence()))
+    .cloned()
+  }
+
+  // The media type to reply with for an `Accept` header: the supported one it
+  // gives the highest quality, the earliest on a tie. Wildcards, and headers
+  // naming nothing supported, get JSON, as browsers expect.
+  pub fn preferred(accept: &str) -> MediaType {
+    let mut preferred = (MediaType::Json, 0.0);
+    for range in accept.split(',') {
+      let mut parts = range.split(';').map(str::trim);
+      let media_type = match parts.next().unwrap_or("") {
+        "*/*" | "application/*" => MediaType::Json,
+        range => match MediaType::parse(range) {
+          Some(media_type) => media_type,
+          None => continue,
+        },
+      };
+      let quality = parts
+        .filter_map(|param| param.strip_prefix("q="))
+        .find_map(|q| q.parse::<f32>().ok())
+        .unwrap_or(1.0);
+      if quality > preferred.1 {
+        preferred = (media_type, quality);
+      }
+    }
+    preferred.0
+  }
+
+  // `json` in this media type, along with the media type it ended up in. NDJSON
+  // writes each item of a list on a line of its own, and leaves anything else
+  // as JSON.
+  pub fn transcode(self, json: &[u8]) -> Result<(MediaType, Vec<u8>), CodecError> {
+    match self {
+      MediaType::Json => Ok((MediaType::Json, json.to_vec())),
+      #[cfg(feature = "cbor")]
+      MediaType::Cbor => Ok((
+        MediaType::Cbor,
+        Cbor::to_vec(&serde_json::from_slice::<Value>(json)?)?,
+      )),
+      MediaType::NdJson => match serde_json::from_slice(json)? {
+        Value::Array(items) => {
+          let mut lines = Vec::new();
+          for item in items {
+            serde_json::to_writer(&mut lines, &item)?;
+            lines.push(b'\n');
+          }
+          Ok((MediaType::NdJson, lines))
+        }
+        _ => Ok((MediaType::Json, json.to_vec())),
+      },
+    }
+  }
+
+  // A request body in this media type, as JSON.
+  pub fn decode(self, body: &[u8]) -> Result<Value, CodecError> {
+    match self {
+      #[cfg(feature = "cbor")]
+      MediaType::Cbor => Cbor::from_slice(body),
+      _ => Ok(serde_json::from_slice(body)?),
+    }
+  }
+}
+
+fn is_json(response: &Response) -> bool {
+  let content_type = response.headers().get(CONTENT_TYPE);
+  let content_type = content_type.and_then(|content_type| content_type.to_str().ok());
+  content_type.and_then(MediaType::parse) == Some(MediaType::Json)
+    && !response.headers().contains_key(CONTENT_ENCODING)
+}
+
+// Re-encodes the JSON replies of `filter` in the media type the request's
+// `Accept` header prefers. Like `gzipped`, the whole reply is buffered, and it
+// must run before it, on the uncompressed reply.
+pub fn negotiated<F, R>(filter: F) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+  F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+  R: Reply,
+{
+  warp::header::optional::<String>(ACCEPT.as_str())
+    .and(filter)
+    .and_then(|accept: Option<String>, reply: R| {
+      let mut response = reply.into_response();
+      response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+      let media_type = accept
+        .as_deref()
+        .map_or(MediaType::Json, MediaType::preferred);
+      if media_type == MediaType::Json || !is_json(&response) {
+        return Either::Left(future::ok::<_, Rejection>(response));
+      }
+      let (mut parts, body) = response.into_parts();
+      Either::Right(to_bytes(body).map(move |bytes| {
+        let bytes = match bytes {
+          Ok(bytes) => bytes,
+          Err(err) => {
+            error!("could not read a reply to re-encode: {}", err);
+            parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.remove(CONTENT_TYPE);
+            return Ok(Response::from_parts(parts, Body::empty()));
+          }
+        };
+        match media_type.transcode(&bytes) {
+          Ok((media_type, transcoded)) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+              .headers
+              .insert(CONTENT_TYPE, HeaderValue::from_static(media_type.essence()));
+            Ok(Response::from_parts(parts, Body::from(transcoded)))
+          }
+          // The reply goes out as the JSON it was.
+          Err(err) => {
+            error!(
+              "could not re-encode a reply as {}: {}",
+              media_type.essence(),
+              err
+            );
+            Ok(Response::from_parts(parts, Body::from(bytes)))
+          }
+        }
+      }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::runtime::Runtime;
+
+  #[test]
+  fn it_prefers_the_highest_quality_supported_media_type() {
+    assert_eq!(
+      MediaType::preferred("application/x-ndjson"),
+      MediaType::NdJson
+    );
+    assert_eq!(
+      MediaType::preferred("application/x-ndjson;q=0.5, application/json"),
+      MediaType::Json
+    );
+    assert_eq!(
+      MediaType::preferred("text/html,application/xhtml+xml,*/*;q=0.8"),
+      MediaType::Json
+    );
+    assert_eq!(
+      MediaType::preferred("application/x-ndjson;q=0"),
+      MediaType::Json
+    );
+    assert_eq!(
+      MediaType::preferred("application/x-ndjson;q=0.5, */*"),
+      MediaType::Json
+    );
+    #[cfg(feature = "cbor")]
+    assert_eq!(
+      MediaType::preferred("application/json;q=0.9, application/cbor"),
+      MediaType::Cbor
+    );
+  }
+
+  #[test]
+  fn it_re_encodes_replies_in_the_accepted_media_type() {
+    let route = negotiated(warp::path("events").map(|| {
+      warp::reply::json(&serde_json::json!([{"event_type": "Opened"}, {"event_type": "Closed"}]))
+    }));
+    let runtime = Runtime::new().unwrap();
+    let get = |accept: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .path("/events")
+          .header("accept", accept)
+          .reply(&route),
+      )
+    };
+
+    let response = get("application/json");
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    assert_eq!(response.headers()[VARY], "accept");
+
+    let response = get("application/x-ndjson");
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/x-ndjson");
+    assert_eq!(
+      &response.body()[..],
+      &b"{\"event_type\":\"Opened\"}\n{\"event_type\":\"Closed\"}\n"[..]
+    );
+
+    #[cfg(feature = "cbor")]
+    {
+      let response = get("application/cbor");
+      assert_eq!(response.headers()[CONTENT_TYPE], "application/cbor");
+      let events: Value = Cbor::from_slice(response.body()).unwrap();
+      assert_eq!(events[1]["event_type"], "Closed");
+    }
+  }
+
+  #[test]
+  fn it_keeps_json_replies_it_cannot_re_encode() {
+    let route = negotiated(
+      warp::path("latest")
+        .map(|| warp::reply::json(&serde_json::json!({"version": 3})).into_response())
+        .or(warp::path("broken").map(|| {
+          warp::reply::with_header("{\"version\":", "content-type", "application/json")
+            .into_response()
+        }))
+        .unify(),
+    );
+    let runtime = Runtime::new().unwrap();
+    let get = |path: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .path(path)
+          .header("accept", "application/x-ndjson")
+          .reply(&route),
+      )
+    };
+
+    let response = get("/latest");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    assert_eq!(&response.body()[..], &b"{\"version\":3}"[..]);
+
+    let response = get("/broken");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    assert_eq!(&response.body()[..], &b"{\"version\":"[..]);
+  }
+}
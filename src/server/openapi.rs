@@ -0,0 +1,526 @@
+use warp::{Filter, Rejection, Reply};
+
+use crate::command::Command;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+const SCHEMAS_PATH: &str = "#/components/schemas/";
+
+// An OpenAPI 3 description of the server's routes, with request and response
+// bodies taken from the command and aggregate types' JSON schemas.
+#[derive(Clone, Debug)]
+pub struct OpenApi {
+  title: String,
+  version: String,
+  paths: Map<String, Value>,
+  schemas: Map<String, Value>,
+}
+
+fn schema_ref(name: &str) -> Value {
+  json!({ "$ref": format!("{}{}", SCHEMAS_PATH, name) })
+}
+
+fn json_content(schema: Value) -> Value {
+  json!({ "application/json": { "schema": schema } })
+}
+
+fn response(description: &str, schema: Value) -> Value {
+  json!({ "description": description, "content": json_content(schema) })
+}
+
+fn error_response(description: &str) -> Value {
+  response(description, schema_ref("Error"))
+}
+
+fn path_parameter(name: &str, schema: Value) -> Value {
+  json!({ "name": name, "in": "path", "required": true, "schema": schema })
+}
+
+fn query_parameter(name: &str, schema: Value) -> Value {
+  json!({ "name": name, "in": "query", "required": false, "schema": schema })
+}
+
+fn uuid() -> Value {
+  json!({ "type": "string", "format": "uuid" })
+}
+
+fn integer() -> Value {
+  json!({ "type": "integer", "format": "int64" })
+}
+
+fn aggregate_id() -> Value {
+  path_parameter("id", uuid())
+}
+
+fn expected_version() -> Vec<Value> {
+  vec![
+    json!({
+      "name": "If-Match",
+      "in": "header",
+      "required": false,
+      "schema": { "type": "string" },
+    }),
+    query_parameter("expected_version", integer()),
+  ]
+}
+
+fn commit_list_parameters() -> Vec<Value> {
+  vec![
+    query_parameter("from_version", integer()),
+    query_parameter("to_version", integer()),
+    query_parameter("limit", integer()),
+    query_parameter("page_token", integer()),
+    query_parameter("event_type", json!({ "type": "string" })),
+  ]
+}
+
+fn commit_operation(operation_id: &str, mut parameters: Vec<Value>, command: Value) -> Value {
+  parameters.push(json!({
+    "name": "Idempotency-Key",
+    "in": "header",
+    "required": false,
+    "schema": { "type": "string" },
+  }));
+  json!({
+    "post": {
+      "operationId": operation_id,
+      "parameters": parameters,
+      "requestBody": { "required": true, "content": json_content(command) },
+      "responses": {
+        "200": response("The new commit", schema_ref("Commit")),
+        "400": response(
+          "The command was rejected, or does not match its schema",
+          json!({ "oneOf": [schema_ref("Error"), schema_ref("ValidationErrors")] }),
+        ),
+        "403": error_response("A middleware or commit interceptor refused the commit"),
+        "404": error_response("The command requires an aggregate that does not exist"),
+        "409": response(
+          "The aggregate is past the expected version, or exists when the command requires \
+           it not to",
+          json!({ "oneOf": [schema_ref("VersionConflict"), schema_ref("Error")] }),
+        ),
+        "410": error_response("The aggregate was deleted"),
+        "422": response("The command is invalid", schema_ref("ValidationErrors")),
+      },
+    }
+  })
+}
+
+impl OpenApi {
+  // Describes the server `serve::<_, C>` starts: `C` is accepted at
+  // `/commit/{id}` and its aggregate is served from the `/aggregate` routes.
+  pub fn new<C>(title: &str, version: &str) -> OpenApi
+  where
+    C: Command + JsonSchema,
+    C::Aggregate: JsonSchema,
+  {
+    let mut openapi = OpenApi {
+      title: String::from(title),
+      version: String::from(version),
+      paths: Map::new(),
+      schemas: Map::new(),
+    };
+    let command = openapi.schema_for::<C>();
+    let aggregate = openapi.schema_for::<C::Aggregate>();
+    openapi.add_common_schemas();
+
+    let mut parameters = vec![aggregate_id()];
+    parameters.extend(expected_version());
+    openapi.add_path(
+      "/commit/{id}",
+      commit_operation("commit", parameters, command),
+    );
+    let found = |description| response(description, aggregate.clone());
+    openapi.add_path(
+      "/aggregate/{id}/latest",
+      json!({ "get": {
+        "operationId": "getLatest",
+        "parameters": [
+          aggregate_id(),
+          query_parameter("max_version", integer()),
+          query_parameter("as_of", json!({ "type": "string", "format": "date-time" })),
+        ],
+        "responses": {
+          "200": found("The aggregate's latest state within the bounds"),
+          "404": error_response("The aggregate has no commits within the bounds"),
+          "410": error_response("The aggregate was deleted"),
+        },
+      }}),
+    );
+    openapi.add_path(
+      "/aggregate/{id}/at/{version}",
+      json!({ "get": {
+        "operationId": "getAtVersion",
+        "parameters": [aggregate_id(), path_parameter("version", integer())],
+        "responses": {
+          "200": found("The aggregate as it was at the version"),
+          "404": error_response("The aggregate never reached the version"),
+        },
+      }}),
+    );
+    openapi.add_path(
+      "/aggregate/{id}/at",
+      json!({ "get": {
+        "operationId": "getAsOf",
+        "parameters": [
+          aggregate_id(),
+          {
+            "name": "as_of",
+            "in": "query",
+            "required": true,
+            "schema": { "type": "string", "format": "date-time" },
+          },
+        ],
+        "responses": {
+          "200": found("The aggregate as it was at the time"),
+          "404": error_response("The aggregate did not exist yet"),
+        },
+      }}),
+    );
+    openapi.add_path(
+      "/aggregate/{id}/snapshot",
+      json!({
+        "get": {
+          "operationId": "getSnapshot",
+          "parameters": [aggregate_id()],
+          "responses": {
+            "200": response("The aggregate's latest snapshot", json!({})),
+            "404": error_response("The aggregate has no snapshot"),
+          },
+        },
+        "post": {
+          "operationId": "forceSnapshot",
+          "parameters": [aggregate_id()],
+          "responses": {
+            "201": response("The new snapshot", json!({})),
+          },
+        },
+      }),
+    );
+    let commits = response(
+      "Commits in version order; a `Link` header points at the next page",
+      json!({ "type": "array", "items": schema_ref("Commit") }),
+    );
+    let mut parameters = vec![aggregate_id()];
+    parameters.extend(commit_list_parameters());
+    openapi.add_path(
+      "/store/{id}/commits",
+      json!({ "get": {
+        "operationId": "getCommits",
+        "parameters": parameters,
+        "responses": { "200": commits.clone(), "400": error_response("Invalid paging") },
+      }}),
+    );
+    openapi.add_path(
+      "/store/category/{category}/commits",
+      json!({ "get": {
+        "operationId": "getCategoryCommits",
+        "parameters": [
+          path_parameter("category", json!({ "type": "string" })),
+          query_parameter("after", integer()),
+          query_parameter("limit", integer()),
+        ],
+        "responses": { "200": commits, "400": error_response("Invalid paging") },
+      }}),
+    );
+    openapi.add_path(
+      "/store/export",
+      json!({ "get": {
+        "operationId": "exportCommits",
+        "parameters": [query_parameter("from_commit_number", integer())],
+        "responses": {
+          "200": {
+            "description": "Commits in commit_number order, one JSON object per line",
+            "content": { "application/x-ndjson": { "schema": schema_ref("Commit") } },
+          },
+          "501": error_response("The store cannot list every commit"),
+        },
+      }}),
+    );
+    openapi.add_admin_paths();
+    openapi
+  }
+
+  // Documents `/commit/{aggregate_type}/{id}` for a type added with
+  // `Server::register`.
+  pub fn with_command<C: Command + JsonSchema>(mut self, aggregate_type: &str) -> Self {
+    let command = self.schema_for::<C>();
+    let mut parameters = vec![aggregate_id()];
+    parameters.extend(expected_version());
+    self.add_path(
+      &format!("/commit/{}/{{id}}", aggregate_type),
+      commit_operation(&format!("commit_{}", aggregate_type), parameters, command),
+    );
+    self
+  }
+
+  pub fn document(&self) -> Value {
+    json!({
+      "openapi": "3.0.3",
+      "info": { "title": self.title, "version": self.version },
+      "paths": self.paths,
+      "components": {
+        "schemas": self.schemas,
+        "securitySchemes": {
+          "apiKey": { "type": "apiKey", "in": "header", "name": "x-api-key" },
+          "bearer": { "type": "http", "scheme": "bearer" },
+        },
+      },
+    })
+  }
+
+  // A reference to `T`'s schema, which is added to the components.
+  fn schema_for<T: JsonSchema>(&mut self) -> Value {
+    let mut settings = SchemaSettings::openapi3();
+    settings.definitions_path = String::from(SCHEMAS_PATH);
+    let mut generator = SchemaGenerator::new(settings);
+    let schema = generator.subschema_for::<T>();
+    for (name, definition) in generator.take_definitions() {
+      self
+        .schemas
+        .insert(name, serde_json::to_value(definition).unwrap_or_default());
+    }
+    serde_json::to_value(schema).unwrap_or_default()
+  }
+
+  fn add_path(&mut self, path: &str, operations: Value) {
+    self.paths.insert(String::from(path), operations);
+  }
+
+  fn add_common_schemas(&mut self) {
+    self.schemas.insert(
+      String::from("Error"),
+      json!({
+        "type": "object",
+        "required": ["error"],
+        "properties": { "error": { "type": "string" } },
+      }),
+    );
+    self.schemas.insert(
+      String::from("VersionConflict"),
+      json!({
+        "type": "object",
+        "required": ["error", "current_version"],
+        "properties": { "error": { "type": "string" }, "current_version": integer() },
+      }),
+    );
+    self.schemas.insert(
+      String::from("ValidationErrors"),
+      json!({
+        "type": "object",
+        "required": ["error", "fields"],
+        "properties": {
+          "error": { "type": "string" },
+          "fields": {
+            "type": "array",
+            "items": {
+              "type": "object",
+              "required": ["field", "message"],
+              "properties": {
+                "field": { "type": "string" },
+                "message": { "type": "string" },
+              },
+            },
+          },
+        },
+      }),
+    );
+    self.schemas.insert(
+      String::from("Commit"),
+      json!({
+        "type": "object",
+        "properties": {
+          "aggregate_id": uuid(),
+          "aggregate_version": integer(),
+          "category": { "type": "string" },
+          "tenant_id": { "type": "string" },
+          "parent_commit_id": { "type": "string", "format": "uuid", "nullable": true },
+          "commit_id": uuid(),
+          "commit_timestamp": { "type": "string", "format": "date-time" },
+          "commit_sequence": integer(),
+          "commit_number": integer(),
+          "events": { "type": "array", "items": {} },
+          "metadata": {},
+          "events_count": integer(),
+          "dispatched": { "type": "boolean" },
+        },
+      }),
+    );
+  }
+
+  fn add_admin_paths(&mut self) {
+    let admin = |operation_id: &str, parameters: Value, description: &str| {
+      json!({
+        "operationId": operation_id,
+        "parameters": parameters,
+        "security": [{ "bearer": [] }],
+        "responses": {
+          "200": { "description": description },
+          "401": error_response("Missing or wrong admin token"),
+        },
+      })
+    };
+    let name = json!([path_parameter("name", json!({ "type": "string" }))]);
+    let failure = json!([path_parameter("commit_id", uuid())]);
+    self.add_path(
+      "/admin/backup",
+      json!({ "post": admin("backup", json!([]), "Where the backup was written") }),
+    );
+    self.add_path(
+      "/admin/projections/{name}/rebuild",
+      json!({
+        "post": admin("rebuildProjection", name.clone(), "The rebuild has started"),
+        "get": admin("rebuildStatus", name, "The latest rebuild's progress"),
+      }),
+    );
+    self.add_path(
+      "/admin/undispatched",
+      json!({
+        "get": admin(
+          "undispatchedCommits",
+          json!([query_parameter("limit", integer())]),
+          "The oldest undispatched commits",
+        ),
+      }),
+    );
+    self.add_path(
+      "/admin/dispatch",
+      json!({ "post": admin("forceDispatch", json!([]), "How many commits were dispatched") }),
+    );
+    self.add_path(
+      "/admin/dispatch/failures",
+      json!({ "get": admin("dispatchFailures", json!([]), "Commits that failed to dispatch") }),
+    );
+    self.add_path(
+      "/admin/dispatch/failures/{commit_id}",
+      json!({
+        "get": admin("dispatchFailure", failure.clone(), "A commit that failed to dispatch"),
+      }),
+    );
+    self.add_path(
+      "/admin/dispatch/failures/{commit_id}/retry",
+      json!({ "post": admin("retryDispatchFailure", failure, "The commit was dispatched") }),
+    );
+    self.add_path(
+      "/admin/retention",
+      json!({ "get": admin("retentionPolicies", json!([]), "Each category's retention policy") }),
+    );
+    let category = json!([path_parameter("category", json!({ "type": "string" }))]);
+    self.add_path(
+      "/admin/retention/{category}",
+      json!({
+        "put": admin("setRetentionPolicy", category.clone(), "The category's new policy"),
+        "delete": admin("removeRetentionPolicy", category, "The policy was removed"),
+      }),
+    );
+  }
+}
+
+pub fn openapi_document(
+  openapi: &OpenApi,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let document = openapi.document();
+  warp::path!("openapi.json")
+    .and(warp::get())
+    .map(move || warp::reply::json(&document))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::aggregate::Aggregate;
+  use crate::events::Event;
+  use std::error::Error;
+  use std::fmt;
+  use uuid::Uuid;
+
+  #[derive(Debug)]
+  struct NeverFails;
+
+  impl fmt::Display for NeverFails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "never fails")
+    }
+  }
+
+  impl Error for NeverFails {}
+
+  #[derive(Serialize, Deserialize, Debug)]
+  enum CounterEvent {
+    Incremented,
+  }
+
+  impl Event for CounterEvent {}
+
+  #[derive(Serialize, Deserialize, Default, Clone, JsonSchema)]
+  struct Counter {
+    id: Uuid,
+    count: i64,
+  }
+
+  impl Aggregate for Counter {
+    type Event = CounterEvent;
+
+    fn with_id(id: Uuid) -> Self {
+      Counter { id, count: 0 }
+    }
+
+    fn apply(&self, _event: &CounterEvent) -> Counter {
+      Counter {
+        id: self.id,
+        count: self.count + 1,
+      }
+    }
+
+    fn version(&self) -> i64 {
+      self.count
+    }
+
+    fn id(&self) -> Uuid {
+      self.id
+    }
+  }
+
+  #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+  enum CounterCommand {
+    IncrementBy(i64),
+  }
+
+  impl Command for CounterCommand {
+    type Aggregate = Counter;
+    type Error = NeverFails;
+
+    fn apply(&self, _counter: &Counter) -> Result<Vec<CounterEvent>, NeverFails> {
+      Ok(vec![CounterEvent::Incremented])
+    }
+  }
+
+  #[test]
+  fn it_describes_routes_with_the_types_schemas() {
+    let openapi =
+      OpenApi::new::<CounterCommand>("counters", "1.0.0").with_command::<CounterCommand>("counter");
+    let response = tokio::runtime::Runtime::new().unwrap().block_on(
+      warp::test::request()
+        .path("/openapi.json")
+        .reply(&openapi_document(&openapi)),
+    );
+    let document: Value = serde_json::from_slice(response.body()).unwrap();
+
+    let commit = &document["paths"]["/commit/{id}"]["post"];
+    assert_eq!(
+      commit["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+      "#/components/schemas/CounterCommand"
+    );
+    assert!(document["paths"]["/commit/counter/{id}"]["post"].is_object());
+    assert_eq!(
+      document["paths"]["/aggregate/{id}/latest"]["get"]["responses"]["200"]["content"]
+        ["application/json"]["schema"]["$ref"],
+      "#/components/schemas/Counter"
+    );
+    let counter = &document["components"]["schemas"]["Counter"];
+    assert_eq!(counter["properties"]["id"]["format"], "uuid");
+    assert!(document["components"]["schemas"]["CounterCommand"].is_object());
+    assert!(document["paths"]["/admin/backup"]["post"].is_object());
+  }
+}
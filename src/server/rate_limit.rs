@@ -0,0 +1,181 @@
+use warp::http::HeaderMap;
+use warp::{Filter, Rejection};
+
+use crate::server::auth::Authenticator;
+use futures::future;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Past this many clients, buckets that have refilled are forgotten.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+#[derive(Debug)]
+pub struct TooManyRequests {
+  pub retry_after: Duration,
+}
+
+impl warp::reject::Reject for TooManyRequests {}
+
+// A token bucket per client: up to `burst` requests at once, refilling at
+// `requests_per_second`. Clients the server's authenticator accepts are told
+// apart by their principal's subject, and all others by remote address, so
+// made-up credentials can't each get a bucket of their own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+  pub burst: u32,
+  pub requests_per_second: f64,
+}
+
+impl RateLimitConfig {
+  pub fn new(burst: u32, requests_per_second: f64) -> RateLimitConfig {
+    RateLimitConfig {
+      burst,
+      requests_per_second,
+    }
+  }
+}
+
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+  config: RateLimitConfig,
+  buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+  pub fn new(config: RateLimitConfig) -> RateLimiter {
+    RateLimiter {
+      config,
+      buckets: Arc::default(),
+    }
+  }
+
+  // Takes a token from `client`'s bucket, or errs with how long until one is
+  // available.
+  fn acquire(&self, client: String, now: Instant) -> Result<(), Duration> {
+    let burst = f64::from(self.config.burst);
+    let refill = |bucket: &Bucket| {
+      let elapsed = now.saturating_duration_since(bucket.updated_at);
+      (bucket.tokens + elapsed.as_secs_f64() * self.config.requests_per_second).min(burst)
+    };
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.len() >= MAX_IDLE_BUCKETS {
+      buckets.retain(|_, bucket| refill(bucket) < burst);
+    }
+    let bucket = buckets.entry(client).or_insert(Bucket {
+      tokens: burst,
+      updated_at: now,
+    });
+    bucket.tokens = refill(bucket);
+    bucket.updated_at = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      let wait = (1.0 - bucket.tokens) / self.config.requests_per_second;
+      Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+    }
+  }
+}
+
+fn client_key(
+  headers: &HeaderMap,
+  remote: Option<SocketAddr>,
+  authenticator: Option<&dyn Authenticator>,
+) -> String {
+  let principal = authenticator.and_then(|authenticator| authenticator.authenticate(headers).ok());
+  if let Some(principal) = principal {
+    return format!("principal:{}", principal.subject);
+  }
+  match remote {
+    Some(remote) => format!("ip:{}", remote.ip()),
+    None => String::from("ip:unknown"),
+  }
+}
+
+// Rejects with `TooManyRequests` once a client's bucket is empty. Without a
+// limiter every request passes.
+pub fn rate_limited(
+  limiter: Option<RateLimiter>,
+  authenticator: Option<Arc<dyn Authenticator>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  warp::header::headers_cloned()
+    .and(warp::addr::remote())
+    .and_then(move |headers: HeaderMap, remote: Option<SocketAddr>| {
+      let limiter = match limiter {
+        Some(ref limiter) => limiter,
+        None => return future::ok(()),
+      };
+      let client = client_key(&headers, remote, authenticator.as_deref());
+      match limiter.acquire(client.clone(), Instant::now()) {
+        Ok(()) => future::ok(()),
+        Err(retry_after) => {
+          debug!("rate limited {}", client);
+          future::err(warp::reject::custom(TooManyRequests { retry_after }))
+        }
+      }
+    })
+    .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::admin::handle_rejection;
+  use crate::server::auth::{ApiKeyAuthenticator, Principal, API_KEY_HEADER};
+  use tokio::runtime::Runtime;
+  use warp::http::StatusCode;
+
+  #[test]
+  fn it_refills_buckets_over_time() {
+    let limiter = RateLimiter::new(RateLimitConfig::new(2, 4.0));
+    let start = Instant::now();
+    assert!(limiter.acquire(String::from("a"), start).is_ok());
+    assert!(limiter.acquire(String::from("a"), start).is_ok());
+    assert_eq!(
+      limiter.acquire(String::from("a"), start),
+      Err(Duration::from_millis(250))
+    );
+    assert!(limiter.acquire(String::from("b"), start).is_ok());
+    let later = start + Duration::from_millis(250);
+    assert!(limiter.acquire(String::from("a"), later).is_ok());
+    assert!(limiter.acquire(String::from("a"), later).is_err());
+  }
+
+  #[test]
+  fn it_replies_429_with_retry_after() {
+    let limiter = RateLimiter::new(RateLimitConfig::new(1, 0.5));
+    let authenticator = ApiKeyAuthenticator::default()
+      .with_key("a", Principal::with_full_access("alice"))
+      .with_key("a2", Principal::with_full_access("alice"))
+      .with_key("b", Principal::with_full_access("bob"));
+    let route = rate_limited(Some(limiter), Some(Arc::new(authenticator)))
+      .map(warp::reply)
+      .recover(handle_rejection);
+    let runtime = Runtime::new().unwrap();
+    let request = |key: &str| {
+      runtime.block_on(
+        warp::test::request()
+          .header(API_KEY_HEADER, key)
+          .reply(&route),
+      )
+    };
+
+    assert_eq!(request("a").status(), StatusCode::OK);
+    let response = request("a");
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "2");
+    // Buckets belong to principals, not to the keys they use.
+    assert_eq!(request("a2").status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(request("b").status(), StatusCode::OK);
+    // Keys the authenticator doesn't know share their address's bucket.
+    assert_eq!(request("forged-1").status(), StatusCode::OK);
+    assert_eq!(request("forged-2").status(), StatusCode::TOO_MANY_REQUESTS);
+  }
+}
@@ -0,0 +1,36 @@
+use warp::path::FullPath;
+use warp::Filter;
+
+use crate::store::shared::SharedStore;
+use crate::store::tenant::TenantStore;
+use crate::store::Store;
+use std::convert::Infallible;
+
+// Hands each request a handle on the server's one store, as request state the
+// routes extract like a path or query parameter. The store is opened and
+// initialized once, before the server starts.
+pub fn with_store<S: Store + Send>(
+  store: SharedStore<S>,
+) -> impl Filter<Extract = (SharedStore<S>,), Error = Infallible> + Clone {
+  warp::any().map(move || store.clone())
+}
+
+// The tenant of a request under `/t/{tenant}/`.
+pub fn requested_tenant(path: &str) -> Option<&str> {
+  let mut segments = path.trim_start_matches('/').split('/');
+  match (segments.next(), segments.next()) {
+    (Some("t"), Some(tenant)) if !tenant.is_empty() => Some(tenant),
+    _ => None,
+  }
+}
+
+// Like `with_store`, wrapping the store in a `TenantStore` for the tenant the
+// request path is under. Routes using it are only mounted under `/t/{tenant}/`.
+pub fn with_tenant_store<S: Store + Send>(
+  store: SharedStore<S>,
+) -> impl Filter<Extract = (TenantStore<SharedStore<S>>,), Error = Infallible> + Clone {
+  warp::path::full().map(move |path: FullPath| {
+    let tenant = requested_tenant(path.as_str()).unwrap_or_default();
+    TenantStore::new(store.clone(), tenant)
+  })
+}
@@ -1,22 +1,479 @@
-use warp::{path, Filter};
+use warp::http::header::{HeaderValue, CONTENT_TYPE, LINK};
+use warp::http::StatusCode;
+use warp::{path, Filter, Reply};
 
-use commit::*;
-use store::*;
+use crate::codec::CodecError;
+use crate::commit::*;
+use crate::store::*;
+use crate::subscription::EventTypeFilter;
+use futures::{future, stream, StreamExt};
+use hyper::Body;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::convert::Infallible;
 use uuid::Uuid;
 
-pub fn commit_list<S: Store, Fs: Fn() -> S>(
-  store_factory: &Fs,
+const DEFAULT_CATEGORY_LIMIT: i64 = 100;
+// Escapes a query value but for URL-safe characters and the commas between
+// event types.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'.')
+  .remove(b'_')
+  .remove(b'~')
+  .remove(b',');
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+#[derive(Deserialize)]
+struct CategoryQuery {
+  after: Option<i64>,
+  limit: Option<i64>,
+}
+
+// `page_token` is opaque to clients; it is the aggregate version the next page
+// starts from.
+#[derive(Deserialize)]
+struct CommitListQuery {
+  from_version: Option<i64>,
+  to_version: Option<i64>,
+  limit: Option<i64>,
+  page_token: Option<String>,
+  event_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+  from_commit_number: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+  error: String,
+}
+
+// `?from_version=&to_version=` narrows the history to a version range,
+// `?event_type=A,B` to commits with those event types, and `?limit=` pages
+// through it. When more commits remain, a `Link: <...>;
+// rel="next"` header carries the `page_token` of the next page.
+pub fn commit_list<S: Store + Send, St>(
+  store: St,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
-  Fs: Clone + Send,
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
 {
-  let owned_store_factory = store_factory.clone();
-  path!("store" / Uuid / "commits").map(move |aggregate_id: Uuid| {
-    let store = owned_store_factory();
-    let commits = store.get_range(aggregate_id, 0, i64::max_value()).unwrap();
-
-    let deserialized_commits: Vec<DeserializedCommit> =
-      commits.into_iter().map(|c| c.deserialize()).collect();
-    warp::reply::json(&deserialized_commits)
-  })
+  path!("store" / Uuid / "commits")
+    .and(warp::query::<CommitListQuery>())
+    .and(store)
+    .map(|aggregate_id: Uuid, query: CommitListQuery, store: S| {
+      let from_version = match query.page_token {
+        Some(ref page_token) => match page_token.parse::<i64>() {
+          Ok(from_version) => from_version,
+          Err(_) => {
+            return error_reply(String::from("invalid page_token"), StatusCode::BAD_REQUEST)
+              .into_response()
+          }
+        },
+        None => query.from_version.unwrap_or(0),
+      };
+      let event_types = query
+        .event_type
+        .as_ref()
+        .map(|event_types| EventTypeFilter::new(event_types.split(',')));
+      let page = commit_page(
+        &store,
+        aggregate_id,
+        from_version,
+        query.to_version.unwrap_or(i64::MAX),
+        query.limit,
+        event_types.as_ref(),
+      );
+      let (commits, next_version) = match page {
+        Ok(page) => page,
+        Err(err) => return store_error_reply(err).into_response(),
+      };
+
+      let deserialized_commits = match deserialize_all(&commits) {
+        Ok(deserialized_commits) => deserialized_commits,
+        Err(err) => return codec_error_reply(err).into_response(),
+      };
+      let mut response = warp::reply::json(&deserialized_commits).into_response();
+      if let (Some(next_version), Some(limit)) = (next_version, query.limit) {
+        let mut next = format!(
+          "</store/{}/commits?limit={}&page_token={}",
+          aggregate_id, limit, next_version
+        );
+        if let Some(to_version) = query.to_version {
+          next.push_str(&format!("&to_version={}", to_version));
+        }
+        if let Some(ref event_type) = query.event_type {
+          let event_type = utf8_percent_encode(event_type, QUERY_VALUE);
+          next.push_str(&format!("&event_type={}", event_type));
+        }
+        next.push_str(">; rel=\"next\"");
+        if let Ok(link) = HeaderValue::from_str(&next) {
+          response.headers_mut().insert(LINK, link);
+        }
+      }
+      response
+    })
+}
+
+// Up to `limit` of an aggregate's commits between two versions, in version
+// order, and the version the next page starts from when more remain.
+pub fn commit_page<S: Store>(
+  store: &S,
+  aggregate_id: Uuid,
+  from_version: i64,
+  to_version: i64,
+  limit: Option<i64>,
+  event_types: Option<&EventTypeFilter>,
+) -> Result<(Vec<Commit>, Option<i64>), Box<dyn StoreError>> {
+  let mut commits = match event_types {
+    Some(event_types) => {
+      store.get_range_of_event_types(aggregate_id, from_version, to_version, event_types)?
+    }
+    None => store.get_range(aggregate_id, from_version, to_version)?,
+  };
+  commits.sort_by_key(|commit| commit.aggregate_version);
+  let next_version = match limit {
+    Some(limit) if commits.len() as i64 > limit.max(0) => {
+      commits.truncate(limit.max(0) as usize);
+      commits.last().map(|commit| commit.aggregate_version + 1)
+    }
+    _ => None,
+  };
+  Ok((commits, next_version))
+}
+
+// Pages through a category's commits with `?after=<commit_number>&limit=<n>`.
+pub fn category_commit_list<S: Store + Send, St>(
+  store: St,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("store" / "category" / String / "commits")
+    .and(warp::query::<CategoryQuery>())
+    .and(store)
+    .map(|category: String, query: CategoryQuery, store: S| {
+      let commits = store.get_category_range(
+        &category,
+        query.after.unwrap_or(0),
+        query.limit.unwrap_or(DEFAULT_CATEGORY_LIMIT),
+      );
+      match commits {
+        Ok(commits) => match deserialize_all(&commits) {
+          Ok(deserialized_commits) => {
+            warp::reply::with_status(warp::reply::json(&deserialized_commits), StatusCode::OK)
+          }
+          Err(err) => codec_error_reply(err),
+        },
+        Err(err) => store_error_reply(err),
+      }
+    })
+}
+
+// Streams every commit numbered after `?from_commit_number=`, or all of them, as
+// newline-delimited JSON in commit_number order. The store is read a batch at a
+// time as the client keeps up; a store or decoding error after the first batch
+// cuts the response short.
+pub fn commit_export<S: Store + Send + 'static, St>(
+  store: St,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+  St: Filter<Extract = (S,), Error = Infallible> + Clone + Send + Sync,
+{
+  path!("store" / "export")
+    .and(warp::query::<ExportQuery>())
+    .and(store)
+    .map(|query: ExportQuery, store: S| {
+      let after = query.from_commit_number.unwrap_or(0);
+      let first = match store.get_commits_after(after, EXPORT_BATCH_SIZE) {
+        Ok(commits) => commits,
+        Err(err) => return store_error_reply(err).into_response(),
+      };
+      let next = next_export_batch(&first);
+      let first = match ndjson(&first) {
+        Ok(lines) => lines,
+        Err(err) => return codec_error_reply(err).into_response(),
+      };
+      let rest = stream::unfold((store, next), |(store, after)| {
+        let after = match after {
+          Some(after) => after,
+          None => return future::ready(None),
+        };
+        let chunk = match store.get_commits_after(after, EXPORT_BATCH_SIZE) {
+          Ok(commits) => match ndjson(&commits) {
+            Ok(lines) => {
+              let next = next_export_batch(&commits);
+              return future::ready(Some((Ok(lines), (store, next))));
+            }
+            Err(err) => Err(err.to_string()),
+          },
+          Err(err) => Err(err.to_string()),
+        };
+        future::ready(Some((chunk, (store, None))))
+      });
+      let chunks = stream::once(future::ready(Ok(first))).chain(rest);
+      let mut response = warp::reply::Response::new(Body::wrap_stream(chunks));
+      response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+      );
+      response
+    })
+}
+
+// The commit_number to read the next export batch after, unless `batch` was the
+// last.
+fn next_export_batch(batch: &[Commit]) -> Option<i64> {
+  if (batch.len() as i64) < EXPORT_BATCH_SIZE {
+    return None;
+  }
+  batch.last().map(|commit| commit.commit_number)
+}
+
+fn ndjson(commits: &[Commit]) -> Result<Vec<u8>, CodecError> {
+  let mut lines = Vec::new();
+  for commit in commits {
+    lines.extend(serde_json::to_vec(&commit.deserialize()?)?);
+    lines.push(b'\n');
+  }
+  Ok(lines)
+}
+
+fn deserialize_all(commits: &[Commit]) -> Result<Vec<DeserializedCommit>, CodecError> {
+  commits.iter().map(Commit::deserialize).collect()
+}
+
+// Commits this server can't decode, such as Avro ones without a schema registry.
+fn codec_error_reply(err: CodecError) -> warp::reply::WithStatus<warp::reply::Json> {
+  error_reply(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn store_error_reply(err: Box<dyn StoreError>) -> warp::reply::WithStatus<warp::reply::Json> {
+  let status = match err.error_type() {
+    StoreErrorType::Unsupported => StatusCode::NOT_IMPLEMENTED,
+    _ => StatusCode::INTERNAL_SERVER_ERROR,
+  };
+  error_reply(err.to_string(), status)
+}
+
+fn error_reply(error: String, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+  warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::server::state::with_store;
+  use crate::store::shared::SharedStore;
+  use crate::store::sqlite::SqliteStore;
+  use chrono::Utc;
+  use tokio::runtime::Runtime;
+
+  #[test]
+  fn it_lists_the_commits_of_a_category() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    for category in &["order", "user", "order", "order"] {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id: Uuid::new_v4(),
+          aggregate_version: 0,
+          category: String::from(*category),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: 0,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: b"[]".to_vec(),
+          events_count: 0,
+        })
+        .unwrap();
+    }
+    let route = category_commit_list(with_store(store.clone()));
+    let runtime = Runtime::new().unwrap();
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .path("/store/category/order/commits?after=1&limit=1")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    let commits: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(commits.as_array().unwrap().len(), 1);
+    assert_eq!(commits[0]["commit_number"], 3);
+    assert_eq!(commits[0]["category"], "order");
+  }
+
+  #[test]
+  fn it_exports_commits_as_ndjson() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    for events in &[r#"["Opened"]"#, r#"["Renamed"]"#, r#"["Closed"]"#] {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id: Uuid::new_v4(),
+          aggregate_version: 0,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: 0,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: events.as_bytes().to_vec(),
+          events_count: 1,
+        })
+        .unwrap();
+    }
+    let route = commit_export(with_store(store.clone()));
+    let runtime = Runtime::new().unwrap();
+
+    let response = runtime.block_on(
+      warp::test::request()
+        .path("/store/export?from_commit_number=1")
+        .reply(&route),
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/x-ndjson");
+    let body = ::std::str::from_utf8(response.body()).unwrap();
+    assert!(body.ends_with('\n'));
+    let commits: Vec<serde_json::Value> = body
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    assert_eq!(commits.len(), 2);
+    assert_eq!(commits[0]["commit_number"], 2);
+    assert_eq!(commits[0]["events"], serde_json::json!(["Renamed"]));
+    assert_eq!(commits[1]["commit_number"], 3);
+  }
+
+  #[test]
+  fn it_pages_through_an_aggregates_commits() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..5 {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id,
+          aggregate_version: version,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: version + 1,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: b"[]".to_vec(),
+          events_count: 0,
+        })
+        .unwrap();
+    }
+    let route = commit_list(with_store(store.clone()));
+    let runtime = Runtime::new().unwrap();
+    let get = |path: &str| runtime.block_on(warp::test::request().path(path).reply(&route));
+    let versions = |body: &[u8]| -> Vec<i64> {
+      let commits: Vec<serde_json::Value> = serde_json::from_slice(body).unwrap();
+      commits
+        .iter()
+        .map(|commit| commit["aggregate_version"].as_i64().unwrap())
+        .collect()
+    };
+
+    let response = get(&format!("/store/{}/commits?from_version=1&limit=2", aggregate_id));
+    assert_eq!(versions(response.body()), vec![1, 2]);
+    let next = format!("/store/{}/commits?limit=2&page_token=3", aggregate_id);
+    assert_eq!(
+      response.headers()["link"],
+      format!("<{}>; rel=\"next\"", next).as_str()
+    );
+
+    let response = get(&next);
+    assert_eq!(versions(response.body()), vec![3, 4]);
+    assert!(response.headers().get("link").is_none());
+
+    let response = get(&format!("/store/{}/commits?to_version=1", aggregate_id));
+    assert_eq!(versions(response.body()), vec![0, 1]);
+    let response = get(&format!("/store/{}/commits?page_token=soon", aggregate_id));
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[test]
+  fn it_filters_an_aggregates_commits_by_event_type() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = SharedStore::new(store);
+    let aggregate_id = Uuid::new_v4();
+    let events = [
+      r#"["Placed"]"#,
+      r#"[{"Shipped": {"carrier": "post"}}]"#,
+      r#"["Paid", {"Shipped": {"carrier": "courier"}}]"#,
+      r#"["Paid"]"#,
+      r#"["Paid & Shipped"]"#,
+      r#"["Paid & Shipped"]"#,
+    ];
+    for (version, events) in events.iter().enumerate() {
+      store
+        .commit(&CommitAttempt {
+          aggregate_id,
+          aggregate_version: version as i64,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_timestamp: Utc::now(),
+          commit_sequence: version as i64 + 1,
+          serialized_metadata: b"null".to_vec(),
+          serialized_events: events.as_bytes().to_vec(),
+          events_count: 1,
+        })
+        .unwrap();
+    }
+    let route = commit_list(with_store(store.clone()));
+    let runtime = Runtime::new().unwrap();
+    let get = |path: &str| runtime.block_on(warp::test::request().path(path).reply(&route));
+    let versions = |body: &[u8]| -> Vec<i64> {
+      let commits: Vec<serde_json::Value> = serde_json::from_slice(body).unwrap();
+      commits
+        .iter()
+        .map(|commit| commit["aggregate_version"].as_i64().unwrap())
+        .collect()
+    };
+
+    let response = get(&format!("/store/{}/commits?event_type=Shipped", aggregate_id));
+    assert_eq!(versions(response.body()), vec![1, 2]);
+    let response = get(&format!("/store/{}/commits?event_type=Placed,Paid&limit=2", aggregate_id));
+    assert_eq!(versions(response.body()), vec![0, 2]);
+    let next = format!(
+      "/store/{}/commits?limit=2&page_token=3&event_type=Placed,Paid",
+      aggregate_id
+    );
+    assert_eq!(
+      response.headers()["link"],
+      format!("<{}>; rel=\"next\"", next).as_str()
+    );
+    assert_eq!(versions(get(&next).body()), vec![3]);
+    let response = get(&format!(
+      "/store/{}/commits?event_type=Paid%20%26%20Shipped&limit=1",
+      aggregate_id
+    ));
+    assert_eq!(versions(response.body()), vec![4]);
+    let next = format!(
+      "/store/{}/commits?limit=1&page_token=5&event_type=Paid%20%26%20Shipped",
+      aggregate_id
+    );
+    assert_eq!(
+      response.headers()["link"],
+      format!("<{}>; rel=\"next\"", next).as_str()
+    );
+    assert_eq!(versions(get(&next).body()), vec![5]);
+    let response = get(&format!("/store/{}/commits?event_type=Cancelled", aggregate_id));
+    assert!(versions(response.body()).is_empty());
+  }
 }
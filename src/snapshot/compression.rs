@@ -0,0 +1,74 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+
+// How a snapshot's `serialized_state` is encoded at rest. Large aggregates
+// serialize to several megabytes of JSON, which compresses well.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+  #[default]
+  None,
+  Gzip,
+  Zstd,
+}
+
+impl Compression {
+  pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    match self {
+      Compression::None => Ok(bytes.to_vec()),
+      Compression::Gzip => {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes)?;
+        encoder.finish()
+      }
+      Compression::Zstd => zstd::encode_all(bytes, 0),
+    }
+  }
+
+  pub fn decompress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    match self {
+      Compression::None => Ok(bytes.to_vec()),
+      Compression::Gzip => {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+      }
+      Compression::Zstd => zstd::decode_all(bytes),
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Compression::None => "none",
+      Compression::Gzip => "gzip",
+      Compression::Zstd => "zstd",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Compression> {
+    match name {
+      "none" => Some(Compression::None),
+      "gzip" => Some(Compression::Gzip),
+      "zstd" => Some(Compression::Zstd),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn it_round_trips_every_compression() {
+    let state = "{\"counter\":1}".repeat(1000).into_bytes();
+    for compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+      let compressed = compression.compress(&state).unwrap();
+      if *compression != Compression::None {
+        assert!(compressed.len() < state.len());
+      }
+      assert_eq!(compression.decompress(&compressed).unwrap(), state);
+      assert_eq!(Compression::from_name(compression.name()), Some(*compression));
+    }
+  }
+}
@@ -0,0 +1,100 @@
+use super::{Snapshot, SnapshotStore};
+use crate::store::StoreError;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Keeps only the newest snapshot of each aggregate.
+#[derive(Default)]
+pub struct InMemorySnapshotStore {
+  snapshots: HashMap<Uuid, Snapshot>,
+}
+
+impl SnapshotStore for InMemorySnapshotStore {
+  fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Box<dyn StoreError>> {
+    let newer = self
+      .snapshots
+      .get(&snapshot.aggregate_id)
+      .is_none_or(|latest| latest.aggregate_version <= snapshot.aggregate_version);
+    if newer {
+      self
+        .snapshots
+        .insert(snapshot.aggregate_id, snapshot.clone());
+    }
+    Ok(())
+  }
+
+  fn get_latest_snapshot(
+    &self,
+    aggregate_id: Uuid,
+  ) -> Result<Option<Snapshot>, Box<dyn StoreError>> {
+    Ok(self.snapshots.get(&aggregate_id).cloned())
+  }
+
+  fn invalidate_older_than(&mut self, schema_version: i64) -> Result<usize, Box<dyn StoreError>> {
+    let before = self.snapshots.len();
+    self
+      .snapshots
+      .retain(|_, snapshot| snapshot.schema_version >= schema_version);
+    Ok(before - self.snapshots.len())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::snapshot::compression::Compression;
+  use chrono::Utc;
+
+  #[test]
+  fn it_keeps_the_newest_snapshot() {
+    let mut store = InMemorySnapshotStore::default();
+    let aggregate_id = Uuid::new_v4();
+    let snapshot = |aggregate_version| Snapshot {
+      aggregate_id,
+      aggregate_version,
+      commit_sequence: aggregate_version,
+      schema_version: 0,
+      snapshot_timestamp: Utc::now(),
+      compression: Compression::None,
+      serialized_state: Vec::new(),
+    };
+    store.save_snapshot(&snapshot(2)).unwrap();
+    store.save_snapshot(&snapshot(1)).unwrap();
+    assert_eq!(
+      store
+        .get_latest_snapshot(aggregate_id)
+        .unwrap()
+        .unwrap()
+        .aggregate_version,
+      2
+    );
+    assert!(store.get_latest_snapshot(Uuid::new_v4()).unwrap().is_none());
+  }
+
+  #[test]
+  fn it_invalidates_snapshots_from_older_schemas() {
+    let mut store = InMemorySnapshotStore::default();
+    let snapshot = |schema_version| Snapshot {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 1,
+      commit_sequence: 1,
+      schema_version,
+      snapshot_timestamp: Utc::now(),
+      compression: Compression::None,
+      serialized_state: Vec::new(),
+    };
+    let stale = snapshot(1);
+    let current = snapshot(2);
+    store.save_snapshot(&stale).unwrap();
+    store.save_snapshot(&current).unwrap();
+    assert_eq!(store.invalidate_older_than(2).unwrap(), 1);
+    assert!(store
+      .get_latest_snapshot(stale.aggregate_id)
+      .unwrap()
+      .is_none());
+    assert_eq!(
+      store.get_latest_snapshot(current.aggregate_id).unwrap(),
+      Some(current)
+    );
+  }
+}
@@ -0,0 +1,157 @@
+pub mod compression;
+pub mod memory;
+pub mod policy;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use self::compression::Compression;
+use crate::store::StoreError;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde_json::Error as JsonError;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// The serialized state of an aggregate once every commit up to and including
+// `commit_sequence` has been applied. `aggregate_version` is the state's own
+// version, so replay resumes with the commits made against that version.
+// `schema_version` records the `Aggregate::SNAPSHOT_SCHEMA_VERSION` the state
+// was serialized with, and `serialized_state` is encoded with `compression`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+  pub aggregate_id: Uuid,
+  pub aggregate_version: i64,
+  pub commit_sequence: i64,
+  pub schema_version: i64,
+  pub snapshot_timestamp: DateTime<Utc>,
+  pub compression: Compression,
+  pub serialized_state: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeserializedSnapshot {
+  pub aggregate_id: Uuid,
+  pub aggregate_version: i64,
+  pub commit_sequence: i64,
+  pub schema_version: i64,
+  pub snapshot_timestamp: DateTime<Utc>,
+  pub state: serde_json::Value,
+}
+
+// Why a snapshot's state could not be read back.
+#[derive(Debug)]
+pub enum SnapshotError {
+  CompressionError(io::Error),
+  SerializationError(JsonError),
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      SnapshotError::CompressionError(ref err) => write!(f, "compression error: {}", err),
+      SnapshotError::SerializationError(ref err) => write!(f, "serialization error: {}", err),
+    }
+  }
+}
+
+impl ::std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+  fn from(error: io::Error) -> SnapshotError {
+    SnapshotError::CompressionError(error)
+  }
+}
+
+impl From<JsonError> for SnapshotError {
+  fn from(error: JsonError) -> SnapshotError {
+    SnapshotError::SerializationError(error)
+  }
+}
+
+impl Snapshot {
+  pub fn state(&self) -> io::Result<Vec<u8>> {
+    self.compression.decompress(&self.serialized_state)
+  }
+
+  // The state deserialized, such as into the aggregate it was taken of.
+  pub fn state_as<T: DeserializeOwned>(&self) -> Result<T, SnapshotError> {
+    Ok(serde_json::from_slice(&self.state()?)?)
+  }
+
+  pub fn deserialize(&self) -> Result<DeserializedSnapshot, SnapshotError> {
+    Ok(DeserializedSnapshot {
+      aggregate_id: self.aggregate_id,
+      aggregate_version: self.aggregate_version,
+      commit_sequence: self.commit_sequence,
+      schema_version: self.schema_version,
+      snapshot_timestamp: self.snapshot_timestamp,
+      state: self.state_as()?,
+    })
+  }
+}
+
+pub trait SnapshotStore {
+  fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Box<dyn StoreError>>;
+  fn get_latest_snapshot(
+    &self,
+    aggregate_id: Uuid,
+  ) -> Result<Option<Snapshot>, Box<dyn StoreError>>;
+  // Drops every snapshot written with a schema version below `schema_version`,
+  // returning how many were removed.
+  fn invalidate_older_than(&mut self, schema_version: i64) -> Result<usize, Box<dyn StoreError>>;
+}
+
+impl<T: SnapshotStore + ?Sized> SnapshotStore for Box<T> {
+  fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Box<dyn StoreError>> {
+    (**self).save_snapshot(snapshot)
+  }
+
+  fn get_latest_snapshot(
+    &self,
+    aggregate_id: Uuid,
+  ) -> Result<Option<Snapshot>, Box<dyn StoreError>> {
+    (**self).get_latest_snapshot(aggregate_id)
+  }
+
+  fn invalidate_older_than(&mut self, schema_version: i64) -> Result<usize, Box<dyn StoreError>> {
+    (**self).invalidate_older_than(schema_version)
+  }
+}
+
+// One snapshot store handed to everything that clones the handle, locked for
+// each call like a `SharedStore`.
+#[derive(Clone)]
+pub struct SharedSnapshotStore {
+  snapshot_store: Arc<Mutex<Box<dyn SnapshotStore + Send>>>,
+}
+
+impl SharedSnapshotStore {
+  pub fn new<T: SnapshotStore + Send + 'static>(snapshot_store: T) -> SharedSnapshotStore {
+    SharedSnapshotStore {
+      snapshot_store: Arc::new(Mutex::new(Box::new(snapshot_store))),
+    }
+  }
+}
+
+impl SnapshotStore for SharedSnapshotStore {
+  fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Box<dyn StoreError>> {
+    self.snapshot_store.lock().unwrap().save_snapshot(snapshot)
+  }
+
+  fn get_latest_snapshot(
+    &self,
+    aggregate_id: Uuid,
+  ) -> Result<Option<Snapshot>, Box<dyn StoreError>> {
+    self.snapshot_store.lock().unwrap().get_latest_snapshot(aggregate_id)
+  }
+
+  fn invalidate_older_than(&mut self, schema_version: i64) -> Result<usize, Box<dyn StoreError>> {
+    self
+      .snapshot_store
+      .lock()
+      .unwrap()
+      .invalidate_older_than(schema_version)
+  }
+}
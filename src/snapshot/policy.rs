@@ -0,0 +1,108 @@
+use super::Snapshot;
+use crate::commit::Commit;
+use chrono::{DateTime, Duration, Utc};
+
+// What a `SnapshotPolicy` sees after a command has been committed.
+pub struct SnapshotContext<'a> {
+  pub latest_snapshot: Option<&'a Snapshot>,
+  pub commit: &'a Commit,
+  // The version of the aggregate once the commit's events have been applied.
+  pub aggregate_version: i64,
+  // The time by the client's clock.
+  pub now: DateTime<Utc>,
+}
+
+// Decides when `Client::issue_command` persists a new snapshot on its own.
+// `EveryEvents` measures distance in aggregate versions, so it assumes the
+// aggregate's version counts the events applied to it.
+#[derive(Default)]
+pub enum SnapshotPolicy {
+  #[default]
+  Never,
+  EveryEvents(i64),
+  EveryCommits(i64),
+  Every(Duration),
+  Custom(Box<dyn Fn(&SnapshotContext) -> bool + Send>),
+}
+
+impl SnapshotPolicy {
+  pub fn should_snapshot(&self, context: &SnapshotContext) -> bool {
+    match *self {
+      SnapshotPolicy::Never => false,
+      SnapshotPolicy::EveryEvents(events) => {
+        let since = context.latest_snapshot.map_or(0, |s| s.aggregate_version);
+        context.aggregate_version - since >= events
+      }
+      SnapshotPolicy::EveryCommits(commits) => {
+        let since = context.latest_snapshot.map_or(0, |s| s.commit_sequence);
+        context.commit.commit_sequence - since >= commits
+      }
+      SnapshotPolicy::Every(interval) => context
+        .latest_snapshot
+        .is_none_or(|s| context.now - s.snapshot_timestamp >= interval),
+      SnapshotPolicy::Custom(ref decide) => decide(context),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::snapshot::compression::Compression;
+  use uuid::Uuid;
+
+  fn commit(commit_sequence: i64) -> Commit {
+    Commit {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence,
+      commit_number: commit_sequence,
+      serialized_events: Vec::new(),
+      serialized_metadata: Vec::new(),
+      events_count: 1,
+      dispatched: false,
+    }
+  }
+
+  #[test]
+  fn it_measures_distance_from_the_latest_snapshot() {
+    let snapshot = Snapshot {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 10,
+      commit_sequence: 4,
+      schema_version: 0,
+      snapshot_timestamp: Utc::now(),
+      compression: Compression::None,
+      serialized_state: Vec::new(),
+    };
+    let commit = commit(6);
+    let context = SnapshotContext {
+      latest_snapshot: Some(&snapshot),
+      commit: &commit,
+      aggregate_version: 14,
+      now: Utc::now(),
+    };
+    assert!(!SnapshotPolicy::Never.should_snapshot(&context));
+    assert!(SnapshotPolicy::EveryEvents(4).should_snapshot(&context));
+    assert!(!SnapshotPolicy::EveryEvents(5).should_snapshot(&context));
+    assert!(SnapshotPolicy::EveryCommits(2).should_snapshot(&context));
+    assert!(!SnapshotPolicy::EveryCommits(3).should_snapshot(&context));
+    assert!(!SnapshotPolicy::Every(Duration::hours(1)).should_snapshot(&context));
+    assert!(
+      SnapshotPolicy::Custom(Box::new(|c| c.commit.commit_sequence == 6)).should_snapshot(&context)
+    );
+
+    let first = SnapshotContext {
+      latest_snapshot: None,
+      commit: &commit,
+      aggregate_version: 14,
+      now: Utc::now(),
+    };
+    assert!(SnapshotPolicy::Every(Duration::hours(1)).should_snapshot(&first));
+  }
+}
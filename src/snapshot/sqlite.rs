@@ -0,0 +1,211 @@
+use super::compression::Compression;
+use super::{Snapshot, SnapshotStore};
+use crate::store::sqlite::SqliteStoreError;
+use crate::store::StoreError;
+use rusqlite::types::Type;
+use rusqlite::{Connection as RusqliteConnection, Error as RusqliteError, OptionalExtension, Row};
+use std::path::Path;
+use uuid::Uuid;
+
+// Snapshots live in their own table, so they can share a database file with a
+// `SqliteStore` or be kept apart from the commits entirely.
+pub struct SqliteSnapshotStore {
+  conn: RusqliteConnection,
+}
+
+impl SqliteSnapshotStore {
+  pub fn with_connection(conn: RusqliteConnection) -> Self {
+    SqliteSnapshotStore { conn }
+  }
+
+  pub fn with_new_in_memory_connection() -> Self {
+    Self::with_connection(RusqliteConnection::open_in_memory().unwrap())
+  }
+
+  pub fn with_new_connection_at_path(path: &Path) -> Self {
+    Self::with_connection(RusqliteConnection::open(path).unwrap())
+  }
+
+  pub fn initialize(&self) -> Result<(), SqliteStoreError> {
+    self.conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS snapshots (
+        aggregate_id       VARCHAR(36) NOT NULL,
+        aggregate_version  INTEGER NOT NULL,
+        commit_sequence    INTEGER NOT NULL,
+        snapshot_timestamp DATETIME NOT NULL,
+        state              BLOB NOT NULL,
+        schema_version     INTEGER NOT NULL DEFAULT 0,
+        compression        TEXT NOT NULL DEFAULT 'none',
+        PRIMARY KEY (aggregate_id, aggregate_version)
+      );",
+    )?;
+    // Tables created before snapshots carried these columns.
+    self.add_column_if_missing("schema_version", "INTEGER NOT NULL DEFAULT 0")?;
+    self.add_column_if_missing("compression", "TEXT NOT NULL DEFAULT 'none'")?;
+    Ok(())
+  }
+
+  fn add_column_if_missing(&self, column: &str, definition: &str) -> Result<(), SqliteStoreError> {
+    let exists = self
+      .conn
+      .prepare("SELECT 1 FROM pragma_table_info('snapshots') WHERE name = ?")?
+      .exists([column])?;
+    if !exists {
+      self.conn.execute_batch(&format!(
+        "ALTER TABLE snapshots ADD COLUMN {} {};",
+        column, definition
+      ))?;
+    }
+    Ok(())
+  }
+}
+
+fn snapshot_from_row(row: &Row) -> Result<Snapshot, RusqliteError> {
+  let aggregate_id: String = row.get(0)?;
+  let compression: String = row.get(6)?;
+  Ok(Snapshot {
+    aggregate_id: Uuid::parse_str(&aggregate_id)
+      .map_err(|err| RusqliteError::FromSqlConversionFailure(0, Type::Text, Box::new(err)))?,
+    aggregate_version: row.get(1)?,
+    commit_sequence: row.get(2)?,
+    snapshot_timestamp: row.get(3)?,
+    serialized_state: row.get(4)?,
+    schema_version: row.get(5)?,
+    compression: Compression::from_name(&compression).ok_or_else(|| {
+      RusqliteError::FromSqlConversionFailure(
+        6,
+        Type::Text,
+        format!("unknown snapshot compression {:?}", compression).into(),
+      )
+    })?,
+  })
+}
+
+impl SnapshotStore for SqliteSnapshotStore {
+  fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Box<dyn StoreError>> {
+    self
+      .conn
+      .execute(
+        "INSERT OR REPLACE INTO snapshots
+          (aggregate_id, aggregate_version, commit_sequence, snapshot_timestamp, state,
+            schema_version, compression)
+          VALUES (?, ?, ?, ?, ?, ?, ?)",
+        (
+          snapshot.aggregate_id.to_string(),
+          snapshot.aggregate_version,
+          snapshot.commit_sequence,
+          snapshot.snapshot_timestamp,
+          &snapshot.serialized_state,
+          snapshot.schema_version,
+          snapshot.compression.name(),
+        ),
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(())
+  }
+
+  fn get_latest_snapshot(
+    &self,
+    aggregate_id: Uuid,
+  ) -> Result<Option<Snapshot>, Box<dyn StoreError>> {
+    let snapshot = self
+      .conn
+      .query_row(
+        "SELECT aggregate_id, aggregate_version, commit_sequence, snapshot_timestamp, state,
+            schema_version, compression
+          FROM snapshots
+          WHERE aggregate_id = ?
+          ORDER BY aggregate_version DESC
+          LIMIT 1",
+        [aggregate_id.to_string()],
+        snapshot_from_row,
+      )
+      .optional()
+      .map_err(SqliteStoreError::from)?;
+    Ok(snapshot)
+  }
+
+  fn invalidate_older_than(&mut self, schema_version: i64) -> Result<usize, Box<dyn StoreError>> {
+    let removed = self
+      .conn
+      .execute(
+        "DELETE FROM snapshots WHERE schema_version < ?",
+        [schema_version],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(removed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+
+  #[test]
+  fn it_returns_the_newest_snapshot() {
+    let mut store = SqliteSnapshotStore::with_new_in_memory_connection();
+    store.initialize().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    for aggregate_version in &[3, 7, 5] {
+      store
+        .save_snapshot(&Snapshot {
+          aggregate_id,
+          aggregate_version: *aggregate_version,
+          commit_sequence: *aggregate_version,
+          schema_version: 0,
+          snapshot_timestamp: Utc::now(),
+          compression: Compression::None,
+          serialized_state: format!("{}", aggregate_version).into_bytes(),
+        })
+        .unwrap();
+    }
+    let latest = store.get_latest_snapshot(aggregate_id).unwrap().unwrap();
+    assert_eq!(latest.aggregate_version, 7);
+    assert_eq!(latest.serialized_state, b"7".to_vec());
+    assert!(store.get_latest_snapshot(Uuid::new_v4()).unwrap().is_none());
+  }
+
+  #[test]
+  fn it_adds_missing_columns_to_older_tables() {
+    let mut store = SqliteSnapshotStore::with_new_in_memory_connection();
+    store
+      .conn
+      .execute_batch(
+        "CREATE TABLE snapshots (
+          aggregate_id       VARCHAR(36) NOT NULL,
+          aggregate_version  INTEGER NOT NULL,
+          commit_sequence    INTEGER NOT NULL,
+          snapshot_timestamp DATETIME NOT NULL,
+          state              BLOB NOT NULL,
+          PRIMARY KEY (aggregate_id, aggregate_version)
+        );",
+      )
+      .unwrap();
+    store.initialize().unwrap();
+    store.initialize().unwrap();
+    let aggregate_id = Uuid::new_v4();
+    store
+      .save_snapshot(&Snapshot {
+        aggregate_id,
+        aggregate_version: 1,
+        commit_sequence: 1,
+        schema_version: 1,
+        snapshot_timestamp: Utc::now(),
+        compression: Compression::Gzip,
+        serialized_state: Vec::new(),
+      })
+      .unwrap();
+    assert_eq!(
+      store
+        .get_latest_snapshot(aggregate_id)
+        .unwrap()
+        .unwrap()
+        .compression,
+      Compression::Gzip
+    );
+    assert_eq!(store.invalidate_older_than(1).unwrap(), 0);
+    assert_eq!(store.invalidate_older_than(2).unwrap(), 1);
+    assert!(store.get_latest_snapshot(aggregate_id).unwrap().is_none());
+  }
+}
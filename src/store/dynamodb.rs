@@ -1,29 +1,83 @@
-extern crate tokio;
-
+use crate::commit::{Commit, CommitAttempt};
+use crate::store::{
+  ChecksumMismatchError, ChecksumVerification, StorageCommitConflict, Store, StoreError,
+  StoreErrorType,
+};
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::config::{Region, SharedCredentialsProvider};
+use aws_sdk_dynamodb::error::{BuildError, SdkError};
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::{
+  AttributeDefinition, AttributeValue, BillingMode as TableBillingMode, Delete,
+  GlobalSecondaryIndex, KeySchemaElement, KeyType, Projection, ProjectionType,
+  ProvisionedThroughput, Put, ScalarAttributeType, TimeToLiveSpecification, TransactWriteItem, Update,
+};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
 use chrono::{DateTime, Utc};
-use commit::{Commit, CommitAttempt};
-
-use futures::future::Future;
-use futures::{FutureExt, TryFutureExt};
-use rusoto_core::{RusotoError, Region};
+use futures::executor::block_on;
+use futures::future;
+use futures::FutureExt;
 use std::collections::HashMap;
-use uuid::Uuid;
-use rusoto_dynamodb::{DynamoDb, DynamoDbClient, AttributeDefinition,
-    KeySchemaElement, CreateTableInput, CreateTableError,
-    ProvisionedThroughput, PutItemInput, PutItemOutput, PutItemError,
-    GetItemInput, QueryInput, AttributeValue};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::slice;
 use std::str::FromStr;
-use bytes::Bytes;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+type Item = HashMap<String, AttributeValue>;
+
+const COMMIT_ID_INDEX: &str = "commit_id_index";
+
+// TransactWriteItems takes at most 100 items, and each commit writes three, so
+// `commit_all` is limited to this many commits.
+pub const MAX_COMMITS_PER_WRITE: usize = 100 / 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BillingMode {
+  OnDemand,
+  Provisioned {
+    read_capacity_units: i64,
+    write_capacity_units: i64,
+  },
+}
 
 #[derive(Debug, Clone)]
 pub struct DynamoDbConfig {
   pub table_name: String,
+  // Holds one item per commit that has not been dispatched yet, keyed by commit_id.
+  pub outbox_table_name: String,
+  // Anything left unset falls back to the SDK's environment and profile lookup.
+  pub region: Option<String>,
+  pub endpoint_url: Option<String>,
+  pub credentials_provider: Option<SharedCredentialsProvider>,
+  pub retry_config: Option<RetryConfig>,
+  // Only consulted by `initialize` when it creates the tables.
+  pub billing_mode: BillingMode,
+  // How long dispatched commits are kept once `expire_dispatched_through` has
+  // released them. Setting it makes `initialize` enable TTL on `expires_at`.
+  pub retention: Option<Duration>,
+  // How commits that fail their checksum are handled when read back.
+  pub checksum_verification: ChecksumVerification,
 }
 
 impl Default for DynamoDbConfig {
   fn default() -> Self {
     DynamoDbConfig {
       table_name: String::from("commits"),
+      outbox_table_name: String::from("commits_outbox"),
+      region: None,
+      endpoint_url: None,
+      credentials_provider: None,
+      retry_config: None,
+      billing_mode: BillingMode::OnDemand,
+      retention: None,
+      checksum_verification: ChecksumVerification::default(),
     }
   }
 }
@@ -35,17 +89,129 @@ pub struct DynamoDbStore {
 
 impl Default for DynamoDbStore {
   fn default() -> Self {
-    DynamoDbStore {
-      config: DynamoDbConfig::default(),
-      client: DynamoDbClient::new(Region::default()),
+    DynamoDbStore::with_new_connection(DynamoDbConfig::default())
+  }
+}
+
+// The `Store` trait is synchronous, so every request is driven to completion on a
+// runtime owned by the store module. Spawning onto it (rather than blocking on the
+// request directly) keeps this usable from inside the server's own tokio runtime.
+fn runtime() -> &'static Runtime {
+  static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+  RUNTIME.get_or_init(|| Runtime::new().expect("could not start the dynamodb runtime"))
+}
+
+fn run<F>(future: F) -> F::Output
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  block_on(runtime().spawn(future)).expect("dynamodb request panicked")
+}
+
+#[derive(Debug)]
+pub enum DynamoDbStoreError {
+  RequestError(Box<dyn Error + Send + Sync>),
+  MalformedItem(String),
+  CommitNotFound(Uuid),
+  Conflict(StorageCommitConflict),
+  ChecksumMismatch(ChecksumMismatchError),
+  // More commits than fit in one transaction; see `MAX_COMMITS_PER_WRITE`.
+  TooManyCommits(usize),
+}
+
+impl fmt::Display for DynamoDbStoreError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DynamoDbStoreError::RequestError(ref cause) => write!(f, "DynamoDbStoreError({})", cause),
+      DynamoDbStoreError::MalformedItem(ref detail) => {
+        write!(f, "DynamoDbStoreError(malformed item: {})", detail)
+      }
+      DynamoDbStoreError::CommitNotFound(ref commit_id) => {
+        write!(f, "DynamoDbStoreError(no commit {})", commit_id)
+      }
+      DynamoDbStoreError::Conflict(ref conflict) => write!(f, "DynamoDbStoreError({})", conflict),
+      DynamoDbStoreError::ChecksumMismatch(ref mismatch) => {
+        write!(f, "DynamoDbStoreError({})", mismatch)
+      }
+      DynamoDbStoreError::TooManyCommits(commits) => write!(
+        f,
+        "DynamoDbStoreError({} commits in one write; at most {} fit in a transaction)",
+        commits, MAX_COMMITS_PER_WRITE
+      ),
+    }
+  }
+}
+
+impl Error for DynamoDbStoreError {}
+
+impl StoreError for DynamoDbStoreError {
+  fn error_type(&self) -> StoreErrorType {
+    match *self {
+      DynamoDbStoreError::Conflict(ref conflict) => {
+        StoreErrorType::DuplicateWriteError(conflict.clone())
+      }
+      DynamoDbStoreError::ChecksumMismatch(_) => StoreErrorType::ChecksumMismatch,
+      _ => StoreErrorType::UnknownError,
+    }
+  }
+}
+
+impl<E, R> From<SdkError<E, R>> for DynamoDbStoreError
+where
+  E: Error + Send + Sync + 'static,
+  R: fmt::Debug + Send + Sync + 'static,
+{
+  fn from(error: SdkError<E, R>) -> Self {
+    DynamoDbStoreError::RequestError(Box::new(error))
+  }
+}
+
+// A cancelled transaction reports one reason per item, in request order, so a
+// failed condition can be traced back to the item that guards against each kind
+// of duplicate commit.
+fn conflict_or_error<R>(
+  error: SdkError<TransactWriteItemsError, R>,
+  conflicts: &[Option<StorageCommitConflict>],
+) -> DynamoDbStoreError
+where
+  R: fmt::Debug + Send + Sync + 'static,
+{
+  if let Some(TransactWriteItemsError::TransactionCanceledException(cancelled)) =
+    error.as_service_error()
+  {
+    let conflict = cancelled
+      .cancellation_reasons()
+      .iter()
+      .zip(conflicts)
+      .find(|&(reason, _)| reason.code() == Some("ConditionalCheckFailed"))
+      .and_then(|(_, conflict)| conflict.clone());
+    if let Some(conflict) = conflict {
+      return DynamoDbStoreError::Conflict(conflict);
     }
   }
+  DynamoDbStoreError::from(error)
+}
+
+impl From<BuildError> for DynamoDbStoreError {
+  fn from(error: BuildError) -> Self {
+    DynamoDbStoreError::RequestError(Box::new(error))
+  }
+}
+
+impl From<DynamoDbStoreError> for Box<dyn StoreError> {
+  fn from(error: DynamoDbStoreError) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
 }
 
 #[derive(Debug, Clone)]
 struct CommitDTO {
   pub aggregate_id: Uuid,
   pub aggregate_version: i64,
+  pub category: String,
+  pub tenant_id: String,
+  pub parent_commit_id: Option<Uuid>,
   pub commit_id: Uuid,
   pub commit_timestamp: String,
 
@@ -54,209 +220,822 @@ struct CommitDTO {
   pub serialized_events: Vec<u8>,
   pub serialized_metadata: Vec<u8>,
   pub events_count: i64,
+  pub dispatched: bool,
+  pub checksum: Option<u64>,
 }
 
-impl CommitDTO {
-  fn from_attrs(attrs: HashMap<String, AttributeValue>) -> Option<Self> {
-    let aggregate_id_str: String = attrs.get("aggregate_id").and_then(|av| av.s.as_ref()).expect("No string field aggregate_id").to_string();
-    let aggregate_id: Uuid = Uuid::parse_str(aggregate_id_str.as_str()).unwrap();
-    let aggregate_version: i64 = attrs.get("aggregate_version").and_then(|av|av.n.as_ref()).map(|s|i64::from_str(s.as_str()).unwrap()).expect("No number field aggregate_version");
-    let commit_id_str: String = attrs.get("commit_id").and_then(|av| av.s.as_ref()).expect("No string field commit_id").to_string();
-    let commit_id: Uuid = Uuid::parse_str(commit_id_str.as_str()).unwrap();
-    let commit_timestamp: String = attrs.get("commit_timestamp").and_then(|av| av.s.as_ref()).expect("No string field commit_timestamp").to_string();
-    let commit_sequence: i64 = attrs.get("commit_sequence").and_then(|av|av.n.as_ref()).map(|s|i64::from_str(s.as_str()).unwrap()).expect("No number field commit_sequence");
-    let events_count: i64 = attrs.get("events_count").and_then(|av|av.n.as_ref()).map(|s|i64::from_str(s.as_str()).unwrap()).expect("No number field events_count");
-    let serialized_events: Vec<u8> = attrs.get("serialized_events").and_then(|av| av.b.as_ref()).map(|b|b.into_iter().map(|b| *b).collect()).expect("No such bytes field serialized_events");
-    let serialized_metadata: Vec<u8> = attrs.get("serialized_metadata").and_then(|av| av.b.as_ref()).map(|b|b.into_iter().map(|b| *b).collect()).expect("No such bytes field serialized_metadata");
-    Some(CommitDTO{
-      aggregate_id,
-      aggregate_version,
-      commit_id,
-      commit_timestamp,
-      commit_sequence,
-      serialized_events,
-      serialized_metadata,
-      events_count
-    })
-  }
+fn string_attr<'a>(attrs: &'a Item, name: &str) -> Result<&'a String, DynamoDbStoreError> {
+  attrs
+    .get(name)
+    .and_then(|av| av.as_s().ok())
+    .ok_or_else(|| DynamoDbStoreError::MalformedItem(format!("no string field {}", name)))
+}
 
-  fn into(self: Self) -> HashMap<String, AttributeValue> {
-    let mut attr_map: HashMap<String, AttributeValue> = HashMap::new();
-    attr_map.insert(String::from("aggregate_id"), AttributeValue{s: Some(self.aggregate_id.to_string()), ..Default::default()});
-    attr_map.insert(String::from("aggregate_version"), AttributeValue{n: Some(self.aggregate_version.to_string()), ..Default::default()});
-    attr_map.insert(String::from("commit_id"), AttributeValue{s: Some(self.commit_id.to_string()), ..Default::default()});
-    attr_map.insert(String::from("commit_timestamp"), AttributeValue{s: Some(self.commit_timestamp), ..Default::default()});
-    attr_map.insert(String::from("commit_sequence"), AttributeValue{n: Some(self.commit_sequence.to_string()), ..Default::default()});
-    attr_map.insert(String::from("serialized_events"), AttributeValue{b: Some(Bytes::from(self.serialized_events)), ..Default::default()});
-    attr_map.insert(String::from("serialized_metadata"), AttributeValue{b: Some(Bytes::from(self.serialized_metadata)), ..Default::default()});
-    attr_map.insert(String::from("events_count"), AttributeValue{n: Some(self.events_count.to_string()), ..Default::default()});
-    attr_map
-  }
+fn uuid_attr(attrs: &Item, name: &str) -> Result<Uuid, DynamoDbStoreError> {
+  let value = string_attr(attrs, name)?;
+  Uuid::parse_str(value)
+    .map_err(|_| DynamoDbStoreError::MalformedItem(format!("{} {:?} is not a uuid", name, value)))
 }
 
-impl DynamoDbStore {
-  pub fn initialize(
-    &self,
-  ) -> impl Future<Output = Result<(), RusotoError<CreateTableError>>> + '_ {
-    let attribute_definitions = vec![
-      AttributeDefinition {
-        attribute_name: "aggregate_id".into(),
-        attribute_type: "S".into(),
-      },
-      AttributeDefinition {
-        attribute_name: "commit_sequence".into(),
-        attribute_type: "N".into(),
-      },
-    ];
-    let key_schema = vec![
-      KeySchemaElement {
-        attribute_name: String::from("aggregate_id"),
-        key_type: "HASH".into(),
-      },
-      KeySchemaElement {
-        attribute_name: String::from("commit_sequence"),
-        key_type: "RANGE".into(),
-      },
-    ];
-    self
-      .client
-      .create_table(CreateTableInput {
-        attribute_definitions,
-        provisioned_throughput: Some(ProvisionedThroughput {
-          read_capacity_units: 1,
-          write_capacity_units: 1,
-        }),
-        key_schema,
-        table_name: self.config.table_name.clone(),
-        ..CreateTableInput::default()
-      }).map(|_| Ok(()))
-  }
-
-  pub fn commit(
-    &mut self,
-    commit_attempt: &CommitAttempt,
-  ) -> impl Future<Output = Result<PutItemOutput, RusotoError<PutItemError>>> + '_ {
-    let commit_dto = CommitDTO {
+fn number_attr(attrs: &Item, name: &str) -> Result<i64, DynamoDbStoreError> {
+  attrs
+    .get(name)
+    .and_then(|av| av.as_n().ok())
+    .and_then(|n| i64::from_str(n).ok())
+    .ok_or_else(|| DynamoDbStoreError::MalformedItem(format!("no number field {}", name)))
+}
+
+fn bytes_attr(attrs: &Item, name: &str) -> Result<Vec<u8>, DynamoDbStoreError> {
+  attrs
+    .get(name)
+    .and_then(|av| av.as_b().ok())
+    .map(|b| b.as_ref().to_vec())
+    .ok_or_else(|| DynamoDbStoreError::MalformedItem(format!("no bytes field {}", name)))
+}
+
+impl CommitDTO {
+  fn from_attempt(commit_attempt: &CommitAttempt) -> Self {
+    CommitDTO {
       aggregate_id: commit_attempt.aggregate_id,
       aggregate_version: commit_attempt.aggregate_version,
+      category: commit_attempt.category.clone(),
+      tenant_id: commit_attempt.tenant_id.clone(),
+      parent_commit_id: commit_attempt.parent_commit_id,
       commit_id: commit_attempt.commit_id,
       commit_sequence: commit_attempt.commit_sequence,
       commit_timestamp: commit_attempt.commit_timestamp.to_rfc3339(),
       serialized_events: commit_attempt.serialized_events.clone(),
       serialized_metadata: commit_attempt.serialized_metadata.clone(),
       events_count: commit_attempt.events_count,
+      dispatched: false,
+      checksum: Some(commit_attempt.checksum()),
+    }
+  }
+
+  fn from_attrs(attrs: &Item) -> Result<Self, DynamoDbStoreError> {
+    Ok(CommitDTO {
+      aggregate_id: uuid_attr(attrs, "aggregate_id")?,
+      aggregate_version: number_attr(attrs, "aggregate_version")?,
+      // Commits written before categories existed have none.
+      category: attrs
+        .get("category")
+        .and_then(|av| av.as_s().ok())
+        .cloned()
+        .unwrap_or_default(),
+      tenant_id: attrs
+        .get("tenant_id")
+        .and_then(|av| av.as_s().ok())
+        .cloned()
+        .unwrap_or_default(),
+      // Absent for first commits and ones written before commits were linked.
+      parent_commit_id: match attrs.get("parent_commit_id") {
+        Some(_) => Some(uuid_attr(attrs, "parent_commit_id")?),
+        None => None,
+      },
+      commit_id: uuid_attr(attrs, "commit_id")?,
+      commit_timestamp: string_attr(attrs, "commit_timestamp")?.clone(),
+      commit_sequence: number_attr(attrs, "commit_sequence")?,
+      serialized_events: bytes_attr(attrs, "serialized_events")?,
+      serialized_metadata: bytes_attr(attrs, "serialized_metadata")?,
+      events_count: number_attr(attrs, "events_count")?,
+      dispatched: attrs
+        .get("dispatched")
+        .and_then(|av| av.as_bool().ok())
+        .cloned()
+        .unwrap_or(false),
+      // Absent for commits written before checksums were recorded.
+      checksum: match attrs.get("checksum") {
+        Some(av) => Some(av.as_n().ok().and_then(|n| u64::from_str(n).ok()).ok_or_else(|| {
+          DynamoDbStoreError::MalformedItem(String::from("checksum is not a u64"))
+        })?),
+        None => None,
+      },
+    })
+  }
+
+  fn into(self) -> Item {
+    let mut attr_map: Item = HashMap::new();
+    attr_map.insert(String::from("aggregate_id"), AttributeValue::S(self.aggregate_id.to_string()));
+    attr_map.insert(String::from("aggregate_version"), AttributeValue::N(self.aggregate_version.to_string()));
+    attr_map.insert(String::from("category"), AttributeValue::S(self.category));
+    attr_map.insert(String::from("tenant_id"), AttributeValue::S(self.tenant_id));
+    if let Some(parent_commit_id) = self.parent_commit_id {
+      attr_map.insert(String::from("parent_commit_id"), AttributeValue::S(parent_commit_id.to_string()));
+    }
+    attr_map.insert(String::from("commit_id"), AttributeValue::S(self.commit_id.to_string()));
+    attr_map.insert(String::from("commit_timestamp"), AttributeValue::S(self.commit_timestamp));
+    attr_map.insert(String::from("commit_sequence"), AttributeValue::N(self.commit_sequence.to_string()));
+    attr_map.insert(String::from("serialized_events"), AttributeValue::B(Blob::new(self.serialized_events)));
+    attr_map.insert(String::from("serialized_metadata"), AttributeValue::B(Blob::new(self.serialized_metadata)));
+    attr_map.insert(String::from("events_count"), AttributeValue::N(self.events_count.to_string()));
+    attr_map.insert(String::from("dispatched"), AttributeValue::Bool(self.dispatched));
+    if let Some(checksum) = self.checksum {
+      attr_map.insert(String::from("checksum"), AttributeValue::N(checksum.to_string()));
+    }
+    attr_map
+  }
+
+  fn into_checked_commit(
+    self,
+    checksum_verification: ChecksumVerification,
+  ) -> Result<Commit, DynamoDbStoreError> {
+    let checksum = self.checksum;
+    let commit = self.into_commit()?;
+    checksum_verification
+      .verify(&commit, checksum)
+      .map_err(DynamoDbStoreError::ChecksumMismatch)?;
+    Ok(commit)
+  }
+
+  fn into_commit(self) -> Result<Commit, DynamoDbStoreError> {
+    let commit_timestamp = DateTime::parse_from_rfc3339(&self.commit_timestamp)
+      .map_err(|_| {
+        DynamoDbStoreError::MalformedItem(format!(
+          "commit_timestamp {:?} is not rfc3339",
+          self.commit_timestamp
+        ))
+      })?
+      .with_timezone(&Utc);
+    Ok(Commit {
+      aggregate_id: self.aggregate_id,
+      aggregate_version: self.aggregate_version,
+      category: self.category,
+      tenant_id: self.tenant_id,
+      parent_commit_id: self.parent_commit_id,
+      commit_id: self.commit_id,
+      commit_timestamp,
+      commit_sequence: self.commit_sequence,
+      commit_number: self.commit_sequence, // this is intentional
+      serialized_events: self.serialized_events,
+      serialized_metadata: self.serialized_metadata,
+      events_count: self.events_count,
+      dispatched: self.dispatched,
+    })
+  }
+}
+
+// The outbox item carries the commit table's key, so a dispatcher can find each
+// undispatched commit without scanning the commit table.
+fn outbox_item(commit_attempt: &CommitAttempt) -> Item {
+  let mut attr_map: Item = HashMap::new();
+  attr_map.insert(String::from("commit_id"), AttributeValue::S(commit_attempt.commit_id.to_string()));
+  attr_map.insert(String::from("aggregate_id"), AttributeValue::S(commit_attempt.aggregate_id.to_string()));
+  attr_map.insert(String::from("commit_sequence"), AttributeValue::N(commit_attempt.commit_sequence.to_string()));
+  attr_map
+}
+
+// DynamoDB can only enforce uniqueness on a table's key, so each commit also
+// claims a marker item in the commit table whose hash key is derived from its
+// commit_id. Marker keys never collide with an aggregate's uuid.
+fn commit_id_marker_key(commit_id: &Uuid) -> Item {
+  commit_key(&format!("commit_id:{}", commit_id), "0")
+}
+
+// The marker also points at the commit it claims, which gives `get_commit` a
+// strongly consistent path for commits the commit_id index hasn't caught up with.
+fn commit_id_marker(commit_attempt: &CommitAttempt) -> Item {
+  let mut attr_map = commit_id_marker_key(&commit_attempt.commit_id);
+  attr_map.insert(String::from("target_aggregate_id"), AttributeValue::S(commit_attempt.aggregate_id.to_string()));
+  attr_map.insert(String::from("target_commit_sequence"), AttributeValue::N(commit_attempt.commit_sequence.to_string()));
+  attr_map
+}
+
+fn commit_key(aggregate_id: &str, commit_sequence: &str) -> Item {
+  let mut key: Item = HashMap::new();
+  key.insert(String::from("aggregate_id"), AttributeValue::S(aggregate_id.to_string()));
+  key.insert(String::from("commit_sequence"), AttributeValue::N(commit_sequence.to_string()));
+  key
+}
+
+impl DynamoDbStore {
+  pub fn with_config(client: DynamoDbClient, config: DynamoDbConfig) -> Self {
+    DynamoDbStore { client, config }
+  }
+
+  pub fn with_new_connection(config: DynamoDbConfig) -> Self {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(ref region) = config.region {
+      loader = loader.region(Region::new(region.clone()));
+    }
+    if let Some(ref endpoint_url) = config.endpoint_url {
+      loader = loader.endpoint_url(endpoint_url.clone());
+    }
+    if let Some(ref credentials_provider) = config.credentials_provider {
+      loader = loader.credentials_provider(credentials_provider.clone());
+    }
+    if let Some(ref retry_config) = config.retry_config {
+      loader = loader.retry_config(retry_config.clone());
+    }
+    let sdk_config = run(loader.load());
+    DynamoDbStore::with_config(DynamoDbClient::new(&sdk_config), config)
+  }
+
+  pub fn initialize(&self) -> Result<(), DynamoDbStoreError> {
+    self.create_table(
+      &self.config.table_name,
+      &[
+        ("aggregate_id", ScalarAttributeType::S, KeyType::Hash),
+        ("commit_sequence", ScalarAttributeType::N, KeyType::Range),
+      ],
+      Some((COMMIT_ID_INDEX, "commit_id")),
+    )?;
+    self.create_table(
+      &self.config.outbox_table_name,
+      &[("commit_id", ScalarAttributeType::S, KeyType::Hash)],
+      None,
+    )?;
+    if self.config.retention.is_some() {
+      let request = self
+        .client
+        .update_time_to_live()
+        .table_name(self.config.table_name.clone())
+        .time_to_live_specification(
+          TimeToLiveSpecification::builder()
+            .enabled(true)
+            .attribute_name("expires_at")
+            .build()?,
+        );
+      run(request.send())?;
+    }
+    Ok(())
+  }
+
+  fn create_table(
+    &self,
+    table_name: &str,
+    keys: &[(&str, ScalarAttributeType, KeyType)],
+    string_index: Option<(&str, &str)>,
+  ) -> Result<(), DynamoDbStoreError> {
+    let mut request = self.client.create_table().table_name(table_name);
+    for &(name, ref attribute_type, ref key_type) in keys {
+      request = request
+        .attribute_definitions(
+          AttributeDefinition::builder()
+            .attribute_name(name)
+            .attribute_type(attribute_type.clone())
+            .build()?,
+        )
+        .key_schema(
+          KeySchemaElement::builder()
+            .attribute_name(name)
+            .key_type(key_type.clone())
+            .build()?,
+        );
+    }
+    let throughput = match self.config.billing_mode {
+      BillingMode::OnDemand => {
+        request = request.billing_mode(TableBillingMode::PayPerRequest);
+        None
+      }
+      BillingMode::Provisioned {
+        read_capacity_units,
+        write_capacity_units,
+      } => {
+        let throughput = ProvisionedThroughput::builder()
+          .read_capacity_units(read_capacity_units)
+          .write_capacity_units(write_capacity_units)
+          .build()?;
+        request = request
+          .billing_mode(TableBillingMode::Provisioned)
+          .provisioned_throughput(throughput.clone());
+        Some(throughput)
+      }
     };
-    self.client.put_item(PutItemInput {
-      condition_expression: Some("attribute_not_exists(commit_sequence)".into()),
-      conditional_operator: None,
-      expected: None,
-      expression_attribute_names: None,
-      expression_attribute_values: None,
-      item: commit_dto.into(),
-      return_consumed_capacity: None,
-      return_item_collection_metrics: None,
-      return_values: None,
-      table_name: self.config.table_name.clone(),
-    }).into_future()
-  }
-
-  pub fn get_commit(
-    &mut self,
+    // Items without the index attribute are left out of the index, so it only
+    // ever holds commits.
+    if let Some((index_name, attribute_name)) = string_index {
+      request = request
+        .attribute_definitions(
+          AttributeDefinition::builder()
+            .attribute_name(attribute_name)
+            .attribute_type(ScalarAttributeType::S)
+            .build()?,
+        )
+        .global_secondary_indexes(
+          GlobalSecondaryIndex::builder()
+            .index_name(index_name)
+            .key_schema(
+              KeySchemaElement::builder()
+                .attribute_name(attribute_name)
+                .key_type(KeyType::Hash)
+                .build()?,
+            )
+            .projection(Projection::builder().projection_type(ProjectionType::KeysOnly).build())
+            .set_provisioned_throughput(throughput)
+            .build()?,
+        );
+    }
+    run(request.send())?;
+    Ok(())
+  }
+
+  pub fn get_commit_by_sequence(
+    &self,
     aggregate_id: Uuid,
     commit_sequence: i64,
-  ) -> impl Future<Output = Result<Option<Commit>, RusotoError<rusoto_dynamodb::GetItemError>>> + '_
-  {
-    let mut key: HashMap<String, AttributeValue> = Default::default();
-    let mut hash_value: AttributeValue = Default::default();
-    hash_value.s = Some(aggregate_id.to_string());
-    let mut range_value: AttributeValue = Default::default();
-    range_value.n = Some(commit_sequence.to_string());
-    key.insert("aggregate_id".into(), hash_value);
-    key.insert("commit_sequence".into(), range_value);
+  ) -> Result<Option<Commit>, DynamoDbStoreError> {
+    let request = self
+      .client
+      .get_item()
+      .table_name(self.config.table_name.clone())
+      .set_key(Some(commit_key(
+        &aggregate_id.to_string(),
+        &commit_sequence.to_string(),
+      )))
+      .consistent_read(true);
+    match run(request.send())?.item() {
+      Some(item) => Ok(Some(
+        CommitDTO::from_attrs(item)?.into_checked_commit(self.config.checksum_verification)?,
+      )),
+      None => Ok(None),
+    }
+  }
+
+  fn scan_all(&self, table_name: &str) -> Result<Vec<Item>, DynamoDbStoreError> {
+    let mut items = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+      let request = self
+        .client
+        .scan()
+        .table_name(table_name)
+        .consistent_read(true)
+        .set_exclusive_start_key(exclusive_start_key);
+      let output = run(request.send())?;
+      items.extend(output.items().iter().cloned());
+      exclusive_start_key = output.last_evaluated_key().cloned();
+      if exclusive_start_key.is_none() {
+        return Ok(items);
+      }
+    }
+  }
+
+  // The commit_id marker, the outbox entry and the commit itself are written in
+  // one transaction, so a commit is never stored without being queued for dispatch.
+  // Every commit is three items of one transaction, and a transaction holds at
+  // most 100 items, so at most 33 commits can be written together.
+  fn write_commits(&self, commit_attempts: &[CommitAttempt]) -> Result<(), DynamoDbStoreError> {
+    if commit_attempts.len() > MAX_COMMITS_PER_WRITE {
+      return Err(DynamoDbStoreError::TooManyCommits(commit_attempts.len()));
+    }
+    let mut request = self.client.transact_write_items();
+    let mut conflicts = Vec::with_capacity(commit_attempts.len() * 3);
+    for commit_attempt in commit_attempts {
+      let marker = Put::builder()
+        .table_name(self.config.table_name.clone())
+        .set_item(Some(commit_id_marker(commit_attempt)))
+        .condition_expression("attribute_not_exists(aggregate_id)")
+        .build()?;
+      let outbox = Put::builder()
+        .table_name(self.config.outbox_table_name.clone())
+        .set_item(Some(outbox_item(commit_attempt)))
+        .build()?;
+      let commit = Put::builder()
+        .table_name(self.config.table_name.clone())
+        .set_item(Some(CommitDTO::from_attempt(commit_attempt).into()))
+        .condition_expression("attribute_not_exists(commit_sequence)")
+        .build()?;
+      request = request
+        .transact_items(TransactWriteItem::builder().put(marker).build())
+        .transact_items(TransactWriteItem::builder().put(outbox).build())
+        .transact_items(TransactWriteItem::builder().put(commit).build());
+      conflicts.push(Some(StorageCommitConflict::CommitIdConflict));
+      conflicts.push(None);
+      conflicts.push(Some(StorageCommitConflict::CommitSequenceConflict));
+    }
+    run(request.send()).map_err(|err| conflict_or_error(err, &conflicts))?;
+    Ok(())
+  }
+
+  fn undispatched_commits(&self) -> Result<Vec<Commit>, DynamoDbStoreError> {
+    let mut commits = Vec::new();
+    for entry in self.scan_all(&self.config.outbox_table_name)? {
+      let aggregate_id = uuid_attr(&entry, "aggregate_id")?;
+      let commit_sequence = number_attr(&entry, "commit_sequence")?;
+      if let Some(commit) = self.get_commit_by_sequence(aggregate_id, commit_sequence)? {
+        commits.push(commit);
+      }
+    }
+    commits.sort_by(|a, b| {
+      (a.commit_timestamp, a.aggregate_id, a.commit_sequence).cmp(&(
+        b.commit_timestamp,
+        b.aggregate_id,
+        b.commit_sequence,
+      ))
+    });
+    Ok(commits)
+  }
+
+  fn mark_dispatched(&self, commit_id: Uuid) -> Result<(), DynamoDbStoreError> {
+    let mut outbox_key: Item = HashMap::new();
+    outbox_key.insert(String::from("commit_id"), AttributeValue::S(commit_id.to_string()));
+    let lookup = self
+      .client
+      .get_item()
+      .table_name(self.config.outbox_table_name.clone())
+      .set_key(Some(outbox_key.clone()))
+      .consistent_read(true);
+    let entry = match run(lookup.send())?.item() {
+      Some(entry) => entry.clone(),
+      None => return Ok(()),
+    };
+
+    let update = Update::builder()
+      .table_name(self.config.table_name.clone())
+      .set_key(Some(commit_key(
+        string_attr(&entry, "aggregate_id")?,
+        &number_attr(&entry, "commit_sequence")?.to_string(),
+      )))
+      .update_expression("SET dispatched = :dispatched")
+      .expression_attribute_values(":dispatched", AttributeValue::Bool(true))
+      .build()?;
+    let delete = Delete::builder()
+      .table_name(self.config.outbox_table_name.clone())
+      .set_key(Some(outbox_key))
+      .build()?;
+    let request = self
+      .client
+      .transact_write_items()
+      .transact_items(TransactWriteItem::builder().update(update).build())
+      .transact_items(TransactWriteItem::builder().delete(delete).build());
+    run(request.send())?;
+    Ok(())
+  }
+
+  // Looks the commit's key up on the commit_id index. Index reads are eventually
+  // consistent, so a commit that was only just written falls back to the key
+  // recorded on its commit_id marker.
+  fn find_commit(&self, commit_id: &Uuid) -> Result<Commit, DynamoDbStoreError> {
+    let request = self
+      .client
+      .query()
+      .table_name(self.config.table_name.clone())
+      .index_name(COMMIT_ID_INDEX)
+      .key_condition_expression("commit_id = :commit_id")
+      .expression_attribute_values(":commit_id", AttributeValue::S(commit_id.to_string()));
+    let output = run(request.send())?;
+    let (aggregate_id, commit_sequence) = match output.items().first() {
+      Some(keys) => (uuid_attr(keys, "aggregate_id")?, number_attr(keys, "commit_sequence")?),
+      None => {
+        let lookup = self
+          .client
+          .get_item()
+          .table_name(self.config.table_name.clone())
+          .set_key(Some(commit_id_marker_key(commit_id)))
+          .consistent_read(true);
+        match run(lookup.send())?.item() {
+          Some(marker) => (
+            uuid_attr(marker, "target_aggregate_id")?,
+            number_attr(marker, "target_commit_sequence")?,
+          ),
+          None => return Err(DynamoDbStoreError::CommitNotFound(*commit_id)),
+        }
+      }
+    };
+    self
+      .get_commit_by_sequence(aggregate_id, commit_sequence)?
+      .ok_or(DynamoDbStoreError::CommitNotFound(*commit_id))
+  }
+
+  // Each page holds at most 1MB of items, so long histories span several pages;
+  // the paginator keeps following LastEvaluatedKey until the query is exhausted.
+  fn query_all(
+    &self,
+    key_condition: &str,
+    filter: &str,
+    values: Item,
+  ) -> impl Future<Output = Result<Vec<Item>, DynamoDbStoreError>> + Send + 'static {
     self
       .client
-      .get_item(GetItemInput {
-        consistent_read: Some(true),
-        key,
-        table_name: self.config.table_name.clone(),
-        ..GetItemInput::default()
+      .query()
+      .table_name(self.config.table_name.clone())
+      .consistent_read(true)
+      .key_condition_expression(key_condition)
+      .filter_expression(filter)
+      .set_expression_attribute_values(Some(values))
+      .into_paginator()
+      .items()
+      .send()
+      .try_collect()
+      .map(|result| result.map_err(DynamoDbStoreError::from))
+  }
+
+  fn range_items(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+  ) -> impl Future<Output = Result<Vec<Item>, DynamoDbStoreError>> + Send + 'static {
+    let mut values: Item = HashMap::new();
+    values.insert(String::from(":aggregate_id"), AttributeValue::S(aggregate_id.to_string()));
+    values.insert(String::from(":min_version"), AttributeValue::N(min_version.to_string()));
+    values.insert(String::from(":max_version"), AttributeValue::N(max_version.to_string()));
+    self.query_all(
+      "aggregate_id = :aggregate_id",
+      "aggregate_version BETWEEN :min_version AND :max_version",
+      values,
+    )
+  }
+
+  fn query_range(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+  ) -> Result<Vec<Commit>, DynamoDbStoreError> {
+    run(self.range_items(aggregate_id, min_version, max_version))?
+      .iter()
+      .map(|item| {
+        CommitDTO::from_attrs(item)?.into_checked_commit(self.config.checksum_verification)
+      })
+      .collect()
+  }
+
+  // Runs one `get_range` query per `(aggregate_id, min_version, max_version)`
+  // concurrently and returns their results in the same order.
+  pub fn get_many_ranges(
+    &self,
+    ranges: &[(Uuid, i64, i64)],
+  ) -> Result<Vec<Vec<Commit>>, DynamoDbStoreError> {
+    let queries: Vec<_> = ranges
+      .iter()
+      .map(|&(aggregate_id, min_version, max_version)| {
+        self.range_items(aggregate_id, min_version, max_version)
       })
-      .map(|result| {
-        result.map(|get_item_output| {
-          get_item_output.item.map(|item| {
-            let commit_dto = CommitDTO::from_attrs(item).expect("could not parse dynamo db row");
-
-            Commit {
-              aggregate_id: commit_dto.aggregate_id,
-              aggregate_version: commit_dto.aggregate_version,
-              commit_id: commit_dto.commit_id,
-              commit_timestamp: DateTime::parse_from_rfc3339(&commit_dto.commit_timestamp)
-                .expect("could not parse timestamp")
-                .with_timezone(&Utc),
-              commit_sequence: commit_dto.commit_sequence,
-              commit_number: commit_dto.commit_sequence, // this is intentional
-              serialized_events: commit_dto.serialized_events,
-              serialized_metadata: commit_dto.serialized_metadata,
-              events_count: commit_dto.events_count,
-              dispatched: true,
-            }
+      .collect();
+    run(future::try_join_all(queries))?
+      .iter()
+      .map(|items| {
+        items
+          .iter()
+          .map(|item| {
+            CommitDTO::from_attrs(item)?.into_checked_commit(self.config.checksum_verification)
           })
-        })
+          .collect()
       })
+      .collect()
+  }
+
+  // Schedules the dispatched commits of an aggregate up to and including
+  // `commit_sequence` for deletion once `retention` has passed since they were
+  // committed. Callers pass the sequence covered by the aggregate's latest
+  // snapshot, so replay never needs an expired commit. Does nothing when no
+  // retention is configured.
+  pub fn expire_dispatched_through(
+    &self,
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  ) -> Result<usize, DynamoDbStoreError> {
+    let retention = match self.config.retention {
+      Some(retention) => retention,
+      None => return Ok(0),
+    };
+    let mut values: Item = HashMap::new();
+    values.insert(String::from(":aggregate_id"), AttributeValue::S(aggregate_id.to_string()));
+    values.insert(String::from(":commit_sequence"), AttributeValue::N(commit_sequence.to_string()));
+    values.insert(String::from(":dispatched"), AttributeValue::Bool(true));
+    let items = run(self.query_all(
+      "aggregate_id = :aggregate_id AND commit_sequence <= :commit_sequence",
+      "dispatched = :dispatched AND attribute_not_exists(expires_at)",
+      values,
+    ))?;
+    for item in &items {
+      let commit = CommitDTO::from_attrs(item)?.into_commit()?;
+      let expires_at = commit.commit_timestamp.timestamp() + retention.as_secs() as i64;
+      let request = self
+        .client
+        .update_item()
+        .table_name(self.config.table_name.clone())
+        .set_key(Some(commit_key(
+          &commit.aggregate_id.to_string(),
+          &commit.commit_sequence.to_string(),
+        )))
+        .update_expression("SET expires_at = :expires_at")
+        .expression_attribute_values(":expires_at", AttributeValue::N(expires_at.to_string()));
+      run(request.send())?;
+    }
+    Ok(items.len())
+  }
+}
+
+impl Store for DynamoDbStore {
+  type Connection = DynamoDbClient;
+
+  fn with_connection(connection: DynamoDbClient) -> Self {
+    DynamoDbStore::with_config(connection, DynamoDbConfig::default())
+  }
+
+  fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
+    self.write_commits(slice::from_ref(commit_attempt))?;
+    Ok(commit_attempt.commit_sequence)
+  }
+
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    self.write_commits(commit_attempts)?;
+    Ok(commit_attempts.iter().map(|attempt| attempt.commit_sequence).collect())
   }
 
-  pub fn get_range(
+  fn get_range(
     &self,
     aggregate_id: Uuid,
-    min_commit_sequence: i64,
-    max_commit_sequence: i64,
-  ) -> impl Future<Output = Result<Option<Vec<Commit>>, RusotoError<rusoto_dynamodb::QueryError>>> + '_ {
-    let mut expression_attribute_values: HashMap<String, AttributeValue> = Default::default();
+    min_version: i64,
+    max_version: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    Ok(self.query_range(aggregate_id, min_version, max_version)?)
+  }
 
-    let mut hash_value: AttributeValue = Default::default();
-    hash_value.s = Some(aggregate_id.to_string());
+  fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    Ok(self.undispatched_commits()?)
+  }
 
-    let mut min_range_value: AttributeValue = Default::default();
-    min_range_value.n = Some(min_commit_sequence.to_string());
+  fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    Ok(self.mark_dispatched(commit_id)?)
+  }
 
-    let mut max_range_value: AttributeValue = Default::default();
-    max_range_value.n = Some(max_commit_sequence.to_string());
+  fn get_commit(&mut self, commit_id: &Uuid) -> Result<Commit, Box<dyn StoreError>> {
+    Ok(self.find_commit(commit_id)?)
+  }
+}
 
-    expression_attribute_values.insert(":aggregate_id".into(), hash_value);
-    expression_attribute_values.insert(":commit_sequence_lower_bound".into(), min_range_value);
-    expression_attribute_values.insert(":commit_sequence_upper_bound".into(), max_range_value);
-    self
-      .client
-      .query(QueryInput {
-        consistent_read: Some(true),
-        key_condition_expression: Some("aggregate_id = :aggregate_id AND commit_sequence BETWEEN :commit_sequence_lower_bound AND :commit_sequence_upper_bound".into()),
-        expression_attribute_values: Some(expression_attribute_values),
-        table_name: self.config.table_name.clone(),
-        ..QueryInput::default()
-      }).into_future()
-      .map(|result| {
-        result.map(|query_output| {
-          query_output.items.map(|item_vec| {
-            item_vec.into_iter().map(|item| {
-              let commit_dto = CommitDTO::from_attrs(item).expect("could not parse dynamo db row");
-
-              Commit {
-                aggregate_id: commit_dto.aggregate_id,
-                aggregate_version: commit_dto.aggregate_version,
-                commit_id: commit_dto.commit_id,
-                commit_timestamp: DateTime::parse_from_rfc3339(&commit_dto.commit_timestamp)
-                  .expect("could not parse timestamp")
-                  .with_timezone(&Utc),
-                commit_sequence: commit_dto.commit_sequence,
-                commit_number: commit_dto.commit_sequence, // this is intentional
-                serialized_events: commit_dto.serialized_events,
-                serialized_metadata: commit_dto.serialized_metadata,
-                events_count: commit_dto.events_count,
-                dispatched: true,
-              }
-            }).collect()
-          })
-        })
-      })
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use aws_sdk_dynamodb::config::Credentials;
+  use std::env;
+
+  fn commit_attempt(aggregate_id: Uuid, aggregate_version: i64, commit_sequence: i64) -> CommitAttempt {
+    CommitAttempt {
+      aggregate_id,
+      aggregate_version,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    }
+  }
+
+  // The tests below need DynamoDB Local, e.g. `docker run -p 8000:8000
+  // amazon/dynamodb-local`, and run with `cargo test --features dynamo -- --ignored`.
+  // Each one works in its own pair of tables.
+  fn local_store() -> DynamoDbStore {
+    let table_name = format!("commits_{}", Uuid::new_v4().simple());
+    let store = DynamoDbStore::with_new_connection(DynamoDbConfig {
+      outbox_table_name: format!("{}_outbox", table_name),
+      table_name,
+      region: Some(String::from("us-east-1")),
+      endpoint_url: Some(
+        env::var("DYNAMODB_LOCAL_ENDPOINT").unwrap_or_else(|_| String::from("http://localhost:8000")),
+      ),
+      credentials_provider: Some(SharedCredentialsProvider::new(Credentials::new(
+        "local", "local", None, None, "dynamodb-local",
+      ))),
+      ..DynamoDbConfig::default()
+    });
+    store.initialize().unwrap();
+    store
+  }
+
+  #[test]
+  #[ignore]
+  fn it_stores_and_retrieves_commits_from_dynamodb_local() {
+    let mut store = local_store();
+    let aggregate_id = Uuid::new_v4();
+    let first = commit_attempt(aggregate_id, 0, 0);
+    let second = commit_attempt(aggregate_id, 1, 1);
+    assert_eq!(store.commit(&first).unwrap(), 0);
+    assert_eq!(store.commit(&second).unwrap(), 1);
+
+    let range = store.get_range(aggregate_id, 1, 1).unwrap();
+    assert_eq!(range.len(), 1);
+    assert_eq!(range[0].commit_id, second.commit_id);
+    assert_eq!(store.get_commit(&first.commit_id).unwrap().aggregate_version, 0);
+
+    let other_id = Uuid::new_v4();
+    store.commit(&commit_attempt(other_id, 0, 0)).unwrap();
+    let ranges = store
+      .get_many_ranges(&[(aggregate_id, 0, 1), (other_id, 0, 0)])
+      .unwrap();
+    assert_eq!(ranges.iter().map(|r| r.len()).collect::<Vec<_>>(), vec![2, 1]);
+  }
+
+  #[test]
+  #[ignore]
+  fn it_detects_conflicts_in_dynamodb_local() {
+    let mut store = local_store();
+    let aggregate_id = Uuid::new_v4();
+    let attempt = commit_attempt(aggregate_id, 0, 0);
+    store.commit(&attempt).unwrap();
+
+    let mut same_id = commit_attempt(aggregate_id, 1, 1);
+    same_id.commit_id = attempt.commit_id;
+    assert_eq!(
+      store.commit(&same_id).unwrap_err().error_type(),
+      StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitIdConflict)
+    );
+    assert_eq!(
+      store.commit(&commit_attempt(aggregate_id, 1, 0)).unwrap_err().error_type(),
+      StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitSequenceConflict)
+    );
+  }
+
+  #[test]
+  #[ignore]
+  fn it_tracks_dispatch_in_dynamodb_local() {
+    let mut store = local_store();
+    let attempt = commit_attempt(Uuid::new_v4(), 0, 0);
+    store.commit(&attempt).unwrap();
+    let undispatched = store.get_undispatched_commits().unwrap();
+    assert_eq!(undispatched.len(), 1);
+    assert_eq!(undispatched[0].commit_id, attempt.commit_id);
+
+    store.mark_commit_as_dispatched(attempt.commit_id).unwrap();
+    assert!(store.get_undispatched_commits().unwrap().is_empty());
+    assert!(store.get_commit(&attempt.commit_id).unwrap().dispatched);
+  }
+
+  #[test]
+  fn it_round_trips_commits_through_attributes() {
+    let commit_attempt = commit_attempt(Uuid::new_v4(), 3, 2);
+    let item = CommitDTO::from_attempt(&commit_attempt).into();
+    let commit = CommitDTO::from_attrs(&item).unwrap().into_commit().unwrap();
+    assert_eq!(commit.commit_id, commit_attempt.commit_id);
+    assert_eq!(commit.aggregate_version, 3);
+    assert_eq!(commit.commit_number, 2);
+    assert_eq!(commit.serialized_events, commit_attempt.serialized_events);
+    assert!(!commit.dispatched);
+
+    let mut malformed = item.clone();
+    malformed.remove("events_count");
+    assert!(CommitDTO::from_attrs(&malformed).is_err());
+  }
+
+  #[test]
+  fn it_checks_commits_against_their_checksum_attribute() {
+    let commit_attempt = commit_attempt(Uuid::new_v4(), 3, 2);
+    let mut item = CommitDTO::from_attempt(&commit_attempt).into();
+    let commit = CommitDTO::from_attrs(&item)
+      .unwrap()
+      .into_checked_commit(ChecksumVerification::Strict)
+      .unwrap();
+    assert_eq!(commit.checksum(), commit_attempt.checksum());
+
+    item.insert(String::from("events_count"), AttributeValue::N(String::from("2")));
+    let err = CommitDTO::from_attrs(&item)
+      .unwrap()
+      .into_checked_commit(ChecksumVerification::Strict)
+      .unwrap_err();
+    assert_eq!(err.error_type(), StoreErrorType::ChecksumMismatch);
+    assert!(CommitDTO::from_attrs(&item)
+      .unwrap()
+      .into_checked_commit(ChecksumVerification::LogOnly)
+      .is_ok());
+
+    item.remove("checksum");
+    assert!(CommitDTO::from_attrs(&item)
+      .unwrap()
+      .into_checked_commit(ChecksumVerification::Strict)
+      .is_ok());
+  }
+
+  #[test]
+  fn it_refuses_more_commits_than_fit_in_a_transaction() {
+    let mut store = DynamoDbStore::with_new_connection(DynamoDbConfig {
+      region: Some(String::from("us-east-1")),
+      endpoint_url: Some(String::from("http://localhost:1")),
+      credentials_provider: Some(SharedCredentialsProvider::new(Credentials::new(
+        "local", "local", None, None, "offline",
+      ))),
+      ..DynamoDbConfig::default()
+    });
+    let aggregate_id = Uuid::new_v4();
+    let attempts: Vec<CommitAttempt> = (0..=MAX_COMMITS_PER_WRITE as i64)
+      .map(|sequence| commit_attempt(aggregate_id, sequence, sequence))
+      .collect();
+    let error = store.commit_all(&attempts).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "DynamoDbStoreError(34 commits in one write; at most 33 fit in a transaction)"
+    );
+  }
+
+  #[test]
+  fn it_reports_conflicts_as_duplicate_writes() {
+    let error = DynamoDbStoreError::Conflict(StorageCommitConflict::CommitIdConflict);
+    assert_eq!(
+      error.error_type(),
+      StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitIdConflict)
+    );
+    assert_eq!(
+      DynamoDbStoreError::CommitNotFound(Uuid::new_v4()).error_type(),
+      StoreErrorType::UnknownError
+    );
   }
 }
@@ -0,0 +1,848 @@
+use super::{PoisonedCommit, Store, StoreError, StoreErrorType};
+use crate::codec::{ENCRYPTED, SHREDDABLE};
+use crate::commit::{Commit, CommitAttempt};
+#[cfg(feature = "sqlite")]
+use crate::store::sqlite::SqliteStoreError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+#[cfg(feature = "sqlite")]
+use rusqlite::types::Type;
+#[cfg(feature = "sqlite")]
+use rusqlite::{Connection as RusqliteConnection, Error as RusqliteError, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+const NONCE_LENGTH: usize = 12;
+const HEADER_LENGTH: usize = 1 + 4 + NONCE_LENGTH;
+const SHREDDABLE_HEADER_LENGTH: usize = 1 + 16 + NONCE_LENGTH;
+// What a payload reads as once its subject has been forgotten.
+const FORGOTTEN: &[u8] = b"null";
+
+// Supplies the AES-256 keys `EncryptionCodec` uses. The id of the key that
+// encrypted a payload is stored with it, so keys can be rotated: new payloads
+// use the current key and older ones are decrypted with the key they name.
+pub trait KeyProvider: Send + Sync {
+  fn current_key_id(&self) -> u32;
+  fn key(&self, key_id: u32) -> Option<[u8; 32]>;
+}
+
+// A fixed set of keys, for keys loaded from configuration at startup.
+pub struct StaticKeyProvider {
+  current_key_id: u32,
+  keys: HashMap<u32, [u8; 32]>,
+}
+
+impl StaticKeyProvider {
+  pub fn new(key_id: u32, key: [u8; 32]) -> StaticKeyProvider {
+    let mut keys = HashMap::new();
+    keys.insert(key_id, key);
+    StaticKeyProvider {
+      current_key_id: key_id,
+      keys,
+    }
+  }
+
+  // A key that only decrypts payloads written before it was rotated out.
+  pub fn with_retired_key(mut self, key_id: u32, key: [u8; 32]) -> StaticKeyProvider {
+    self.keys.entry(key_id).or_insert(key);
+    self
+  }
+}
+
+impl KeyProvider for StaticKeyProvider {
+  fn current_key_id(&self) -> u32 {
+    self.current_key_id
+  }
+
+  fn key(&self, key_id: u32) -> Option<[u8; 32]> {
+    self.keys.get(&key_id).cloned()
+  }
+}
+
+// A data key and its id.
+pub type DataKey = (Uuid, [u8; 32]);
+
+// Holds a data key per subject, such as a person, for `EncryptedStore` to
+// encrypt the subject's commits with. Forgetting a subject deletes its keys,
+// which leaves its payloads unrecoverable while its commits remain.
+pub trait KeyStore: Send + Sync {
+  // The id and key the subject's new commits are encrypted with, created the
+  // first time the subject is seen or after it has been forgotten.
+  fn current_key(&self, subject_id: Uuid) -> Result<DataKey, Box<dyn StoreError>>;
+  // `None` once the key's subject has been forgotten.
+  fn key(&self, key_id: Uuid) -> Result<Option<[u8; 32]>, Box<dyn StoreError>>;
+  fn forget(&self, subject_id: Uuid) -> Result<(), Box<dyn StoreError>>;
+}
+
+fn new_data_key() -> DataKey {
+  (Uuid::new_v4(), Aes256Gcm::generate_key(&mut OsRng).into())
+}
+
+#[derive(Default)]
+pub struct InMemoryKeyStore {
+  // Each subject's keys, newest last.
+  keys: Mutex<HashMap<Uuid, Vec<DataKey>>>,
+}
+
+impl KeyStore for InMemoryKeyStore {
+  fn current_key(&self, subject_id: Uuid) -> Result<DataKey, Box<dyn StoreError>> {
+    let mut keys = self.keys.lock().unwrap();
+    let subject_keys = keys.entry(subject_id).or_default();
+    if subject_keys.is_empty() {
+      subject_keys.push(new_data_key());
+    }
+    Ok(subject_keys[subject_keys.len() - 1])
+  }
+
+  fn key(&self, key_id: Uuid) -> Result<Option<[u8; 32]>, Box<dyn StoreError>> {
+    let keys = self.keys.lock().unwrap();
+    Ok(
+      keys
+        .values()
+        .flatten()
+        .find(|&&(id, _)| id == key_id)
+        .map(|&(_, key)| key),
+    )
+  }
+
+  fn forget(&self, subject_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    self.keys.lock().unwrap().remove(&subject_id);
+    Ok(())
+  }
+}
+
+// Data keys in their own table, which can share a database file with a
+// `SqliteStore`. Backups of it keep forgotten keys until they expire.
+#[cfg(feature = "sqlite")]
+pub struct SqliteKeyStore {
+  conn: Mutex<RusqliteConnection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteKeyStore {
+  pub fn with_connection(conn: RusqliteConnection) -> Self {
+    SqliteKeyStore {
+      conn: Mutex::new(conn),
+    }
+  }
+
+  pub fn with_new_in_memory_connection() -> Self {
+    Self::with_connection(RusqliteConnection::open_in_memory().unwrap())
+  }
+
+  pub fn with_new_connection_at_path(path: &Path) -> Self {
+    Self::with_connection(RusqliteConnection::open(path).unwrap())
+  }
+
+  pub fn initialize(&self) -> Result<(), SqliteStoreError> {
+    self.conn.lock().unwrap().execute_batch(
+      "CREATE TABLE IF NOT EXISTS data_keys (
+        key_id     VARCHAR(36) NOT NULL PRIMARY KEY,
+        subject_id VARCHAR(36) NOT NULL,
+        key        BLOB NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS data_keys_subject_idx ON data_keys (subject_id);",
+    )?;
+    Ok(())
+  }
+}
+
+#[cfg(feature = "sqlite")]
+fn data_key_from_row(row: &Row) -> Result<DataKey, RusqliteError> {
+  let key_id: String = row.get(0)?;
+  let key: Vec<u8> = row.get(1)?;
+  let key_id = Uuid::parse_str(&key_id)
+    .map_err(|err| RusqliteError::FromSqlConversionFailure(0, Type::Text, Box::new(err)))?;
+  let mut data_key = [0; 32];
+  if key.len() != data_key.len() {
+    return Err(RusqliteError::InvalidColumnType(
+      1,
+      String::from("key"),
+      Type::Blob,
+    ));
+  }
+  data_key.copy_from_slice(&key);
+  Ok((key_id, data_key))
+}
+
+#[cfg(feature = "sqlite")]
+impl KeyStore for SqliteKeyStore {
+  fn current_key(&self, subject_id: Uuid) -> Result<DataKey, Box<dyn StoreError>> {
+    let conn = self.conn.lock().unwrap();
+    let current = conn
+      .query_row(
+        "SELECT key_id, key FROM data_keys WHERE subject_id = ? ORDER BY rowid DESC LIMIT 1",
+        [subject_id.to_string()],
+        data_key_from_row,
+      )
+      .optional()
+      .map_err(SqliteStoreError::from)?;
+    if let Some(data_key) = current {
+      return Ok(data_key);
+    }
+    let (key_id, key) = new_data_key();
+    conn
+      .execute(
+        "INSERT INTO data_keys (key_id, subject_id, key) VALUES (?, ?, ?)",
+        (key_id.to_string(), subject_id.to_string(), &key[..]),
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok((key_id, key))
+  }
+
+  fn key(&self, key_id: Uuid) -> Result<Option<[u8; 32]>, Box<dyn StoreError>> {
+    let data_key = self
+      .conn
+      .lock()
+      .unwrap()
+      .query_row(
+        "SELECT key_id, key FROM data_keys WHERE key_id = ?",
+        [key_id.to_string()],
+        data_key_from_row,
+      )
+      .optional()
+      .map_err(SqliteStoreError::from)?;
+    Ok(data_key.map(|(_, key)| key))
+  }
+
+  fn forget(&self, subject_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    self
+      .conn
+      .lock()
+      .unwrap()
+      .execute(
+        "DELETE FROM data_keys WHERE subject_id = ?",
+        [subject_id.to_string()],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(())
+  }
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+  UnknownKey(u32),
+  // A payload was encrypted with a data key, but no `KeyStore` is configured.
+  MissingKeyStore,
+  // The payload was truncated, tampered with, or encrypted for another commit.
+  DecryptionFailed,
+  EncryptionFailed,
+}
+
+impl fmt::Display for EncryptionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      EncryptionError::UnknownKey(key_id) => write!(f, "no encryption key {}", key_id),
+      EncryptionError::MissingKeyStore => write!(f, "payload needs a key store to decrypt"),
+      EncryptionError::DecryptionFailed => write!(f, "payload could not be decrypted"),
+      EncryptionError::EncryptionFailed => write!(f, "payload could not be encrypted"),
+    }
+  }
+}
+
+impl error::Error for EncryptionError {}
+
+impl StoreError for EncryptionError {
+  fn error_type(&self) -> StoreErrorType {
+    StoreErrorType::UnknownError
+  }
+}
+
+impl From<EncryptionError> for Box<dyn StoreError> {
+  fn from(error: EncryptionError) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
+}
+
+// Encrypts payloads with AES-256-GCM under a random nonce. Each payload is bound
+// to the commit and field it was written for, so ciphertext copied elsewhere
+// fails to decrypt.
+#[derive(Clone)]
+pub struct EncryptionCodec {
+  key_provider: Arc<dyn KeyProvider>,
+}
+
+impl EncryptionCodec {
+  pub fn new<K: KeyProvider + 'static>(key_provider: K) -> EncryptionCodec {
+    EncryptionCodec {
+      key_provider: Arc::new(key_provider),
+    }
+  }
+
+  fn cipher(&self, key_id: u32) -> Result<Aes256Gcm, EncryptionError> {
+    let key = self
+      .key_provider
+      .key(key_id)
+      .ok_or(EncryptionError::UnknownKey(key_id))?;
+    Ok(Aes256Gcm::new(&key.into()))
+  }
+
+  pub fn encrypt(
+    &self,
+    plaintext: &[u8],
+    associated_data: &[u8],
+  ) -> Result<Vec<u8>, EncryptionError> {
+    let key_id = self.key_provider.current_key_id();
+    let mut header = vec![ENCRYPTED];
+    header.extend_from_slice(&key_id.to_be_bytes());
+    seal(&self.cipher(key_id)?, header, plaintext, associated_data)
+  }
+
+  // Payloads written before encryption was enabled are returned as they are.
+  pub fn decrypt(
+    &self,
+    payload: &[u8],
+    associated_data: &[u8],
+  ) -> Result<Vec<u8>, EncryptionError> {
+    if payload.first() != Some(&ENCRYPTED) {
+      return Ok(payload.to_vec());
+    }
+    if payload.len() < HEADER_LENGTH {
+      return Err(EncryptionError::DecryptionFailed);
+    }
+    let mut key_id = [0; 4];
+    key_id.copy_from_slice(&payload[1..5]);
+    let cipher = self.cipher(u32::from_be_bytes(key_id))?;
+    open(&cipher, &payload[5..], associated_data)
+  }
+}
+
+// Appends the nonce and ciphertext to `header`.
+fn seal(
+  cipher: &Aes256Gcm,
+  mut header: Vec<u8>,
+  plaintext: &[u8],
+  associated_data: &[u8],
+) -> Result<Vec<u8>, EncryptionError> {
+  let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+  let ciphertext = cipher
+    .encrypt(
+      &nonce,
+      Payload {
+        msg: plaintext,
+        aad: associated_data,
+      },
+    )
+    .map_err(|_| EncryptionError::EncryptionFailed)?;
+  header.extend_from_slice(&nonce);
+  header.extend_from_slice(&ciphertext);
+  Ok(header)
+}
+
+// Decrypts a nonce followed by its ciphertext.
+fn open(
+  cipher: &Aes256Gcm,
+  sealed: &[u8],
+  associated_data: &[u8],
+) -> Result<Vec<u8>, EncryptionError> {
+  let nonce = Nonce::from_slice(&sealed[..NONCE_LENGTH]);
+  cipher
+    .decrypt(
+      nonce,
+      Payload {
+        msg: &sealed[NONCE_LENGTH..],
+        aad: associated_data,
+      },
+    )
+    .map_err(|_| EncryptionError::DecryptionFailed)
+}
+
+fn associated_data(commit_id: Uuid, field: &str) -> Vec<u8> {
+  let mut data = commit_id.as_bytes().to_vec();
+  data.extend_from_slice(field.as_bytes());
+  data
+}
+
+// Encrypts the events and metadata of every commit before they reach `store`
+// and decrypts them on the way out. The wrapped store only ever sees
+// ciphertext, so it can't filter on event types itself; `EncryptedStore` filters
+// the decrypted commits instead.
+pub struct EncryptedStore<S: Store> {
+  store: S,
+  codec: EncryptionCodec,
+  key_store: Option<Box<dyn KeyStore>>,
+  subject: fn(&CommitAttempt) -> Uuid,
+}
+
+impl<S: Store> EncryptedStore<S> {
+  pub fn new(store: S, codec: EncryptionCodec) -> EncryptedStore<S> {
+    EncryptedStore {
+      store,
+      codec,
+      key_store: None,
+      subject: |commit_attempt| commit_attempt.aggregate_id,
+    }
+  }
+
+  // Encrypts new commits with their subject's data key, so `forget` can shred
+  // them. Commits encrypted by the codec's keys are still read with those.
+  pub fn with_key_store<K: KeyStore + 'static>(mut self, key_store: K) -> EncryptedStore<S> {
+    self.key_store = Some(Box::new(key_store));
+    self
+  }
+
+  // Whose data key encrypts a commit; the commit's aggregate by default.
+  pub fn with_subject(mut self, subject: fn(&CommitAttempt) -> Uuid) -> EncryptedStore<S> {
+    self.subject = subject;
+    self
+  }
+
+  pub fn store(&self) -> &S {
+    &self.store
+  }
+
+  // Deletes the subject's data keys. Its commits stay in the store, but their
+  // events and metadata read as `null` from then on.
+  pub fn forget(&self, subject_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    match self.key_store {
+      Some(ref key_store) => key_store.forget(subject_id),
+      None => Err(EncryptionError::MissingKeyStore.into()),
+    }
+  }
+
+  fn encrypt(
+    &self,
+    data_key: Option<DataKey>,
+    plaintext: &[u8],
+    associated_data: &[u8],
+  ) -> Result<Vec<u8>, EncryptionError> {
+    match data_key {
+      Some((key_id, key)) => {
+        let mut header = vec![SHREDDABLE];
+        header.extend_from_slice(key_id.as_bytes());
+        seal(
+          &Aes256Gcm::new(&key.into()),
+          header,
+          plaintext,
+          associated_data,
+        )
+      }
+      None => self.codec.encrypt(plaintext, associated_data),
+    }
+  }
+
+  fn decrypt(
+    &self,
+    payload: &[u8],
+    associated_data: &[u8],
+  ) -> Result<Vec<u8>, Box<dyn StoreError>> {
+    if payload.first() != Some(&SHREDDABLE) {
+      return Ok(self.codec.decrypt(payload, associated_data)?);
+    }
+    if payload.len() < SHREDDABLE_HEADER_LENGTH {
+      return Err(EncryptionError::DecryptionFailed.into());
+    }
+    let key_store = self
+      .key_store
+      .as_ref()
+      .ok_or(EncryptionError::MissingKeyStore)?;
+    let key_id =
+      Uuid::from_slice(&payload[1..17]).map_err(|_| EncryptionError::DecryptionFailed)?;
+    match key_store.key(key_id)? {
+      Some(key) => Ok(open(
+        &Aes256Gcm::new(&key.into()),
+        &payload[17..],
+        associated_data,
+      )?),
+      None => Ok(FORGOTTEN.to_vec()),
+    }
+  }
+
+  fn decrypt_commit(&self, mut commit: Commit) -> Result<Commit, Box<dyn StoreError>> {
+    commit.serialized_events = self.decrypt(
+      &commit.serialized_events,
+      &associated_data(commit.commit_id, "events"),
+    )?;
+    commit.serialized_metadata = self.decrypt(
+      &commit.serialized_metadata,
+      &associated_data(commit.commit_id, "metadata"),
+    )?;
+    Ok(commit)
+  }
+
+  fn encrypt_attempt(
+    &self,
+    commit_attempt: &CommitAttempt,
+  ) -> Result<CommitAttempt, Box<dyn StoreError>> {
+    let data_key = match self.key_store {
+      Some(ref key_store) => Some(key_store.current_key((self.subject)(commit_attempt))?),
+      None => None,
+    };
+    let mut encrypted = commit_attempt.clone();
+    encrypted.serialized_events = self.encrypt(
+      data_key,
+      &commit_attempt.serialized_events,
+      &associated_data(commit_attempt.commit_id, "events"),
+    )?;
+    encrypted.serialized_metadata = self.encrypt(
+      data_key,
+      &commit_attempt.serialized_metadata,
+      &associated_data(commit_attempt.commit_id, "metadata"),
+    )?;
+    Ok(encrypted)
+  }
+
+  fn decrypt_commits(&self, commits: Vec<Commit>) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    commits
+      .into_iter()
+      .map(|commit| self.decrypt_commit(commit))
+      .collect()
+  }
+}
+
+impl<S: Store> Store for EncryptedStore<S> {
+  type Connection = (S::Connection, EncryptionCodec);
+
+  fn with_connection((connection, codec): Self::Connection) -> Self {
+    EncryptedStore::new(S::with_connection(connection), codec)
+  }
+
+  fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
+    let encrypted = self.encrypt_attempt(commit_attempt)?;
+    self.store.commit(&encrypted)
+  }
+
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    let encrypted = commit_attempts
+      .iter()
+      .map(|commit_attempt| self.encrypt_attempt(commit_attempt))
+      .collect::<Result<Vec<_>, _>>()?;
+    self.store.commit_all(&encrypted)
+  }
+
+  fn get_range(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self
+      .store
+      .get_range(aggregate_id, min_version, max_version)?;
+    self.decrypt_commits(commits)
+  }
+
+  fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self.store.get_undispatched_commits()?;
+    self.decrypt_commits(commits)
+  }
+
+  fn get_undispatched_commits_up_to(
+    &mut self,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self.store.get_undispatched_commits_up_to(limit)?;
+    self.decrypt_commits(commits)
+  }
+
+  fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    self.store.mark_commit_as_dispatched(commit_id)
+  }
+
+  fn get_commit(&mut self, commit_id: &Uuid) -> Result<Commit, Box<dyn StoreError>> {
+    let commit = self.store.get_commit(commit_id)?;
+    self.decrypt_commit(commit)
+  }
+
+  fn health_check(&self) -> Result<(), Box<dyn StoreError>> {
+    self.store.health_check()
+  }
+
+  fn backup_to(&self, path: &Path) -> Result<(), Box<dyn StoreError>> {
+    self.store.backup_to(path)
+  }
+
+  fn get_commits_after(
+    &self,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self.store.get_commits_after(commit_number, limit)?;
+    self.decrypt_commits(commits)
+  }
+
+  fn get_category_range(
+    &self,
+    category: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self
+      .store
+      .get_category_range(category, commit_number, limit)?;
+    self.decrypt_commits(commits)
+  }
+
+  fn get_tenant_range(
+    &self,
+    tenant_id: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self
+      .store
+      .get_tenant_range(tenant_id, commit_number, limit)?;
+    self.decrypt_commits(commits)
+  }
+
+  fn load_checkpoint(&self, name: &str) -> Result<Option<i64>, Box<dyn StoreError>> {
+    self.store.load_checkpoint(name)
+  }
+
+  fn save_checkpoint(&mut self, name: &str, commit_number: i64) -> Result<(), Box<dyn StoreError>> {
+    self.store.save_checkpoint(name, commit_number)
+  }
+
+  fn mark_commit_as_poisoned(
+    &mut self,
+    commit_id: Uuid,
+    attempts: i64,
+    error: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self
+      .store
+      .mark_commit_as_poisoned(commit_id, attempts, error)
+  }
+
+  fn get_poisoned_commits(&mut self) -> Result<Vec<PoisonedCommit>, Box<dyn StoreError>> {
+    self.store.get_poisoned_commits()
+  }
+
+  fn get_poisoned_commit(
+    &mut self,
+    commit_id: Uuid,
+  ) -> Result<Option<PoisonedCommit>, Box<dyn StoreError>> {
+    self.store.get_poisoned_commit(commit_id)
+  }
+
+  fn requeue_poisoned_commit(&mut self, commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    self.store.requeue_poisoned_commit(commit_id)
+  }
+
+  fn get_unacknowledged_commits(
+    &mut self,
+    consumer: &str,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self.store.get_unacknowledged_commits(consumer, limit)?;
+    self.decrypt_commits(commits)
+  }
+
+  fn acknowledge_commit(
+    &mut self,
+    commit_id: Uuid,
+    consumer: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self.store.acknowledge_commit(commit_id, consumer)
+  }
+
+  fn get_acknowledgements(&mut self, commit_id: Uuid) -> Result<Vec<String>, Box<dyn StoreError>> {
+    self.store.get_acknowledgements(commit_id)
+  }
+
+  fn truncate_through(
+    &mut self,
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    self.store.truncate_through(aggregate_id, commit_sequence)
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::sqlite::SqliteStore;
+  use super::*;
+  use chrono::Utc;
+
+  fn commit_attempt(aggregate_id: Uuid, version: i64) -> CommitAttempt {
+    CommitAttempt {
+      aggregate_id,
+      aggregate_version: version,
+      category: String::from("account"),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: version + 1,
+      serialized_metadata: b"{\"user\":\"alice\"}".to_vec(),
+      serialized_events: b"[\"Opened\"]".to_vec(),
+      events_count: 1,
+    }
+  }
+
+  #[test]
+  fn it_encrypts_payloads_at_rest() {
+    let path =
+      ::std::env::temp_dir().join(format!("event_source_encrypted_{}.sqlite", Uuid::new_v4()));
+    let store = SqliteStore::with_new_connection_at_path(&path);
+    store.initialize();
+    let codec = EncryptionCodec::new(StaticKeyProvider::new(1, [7; 32]));
+    let mut store = EncryptedStore::new(store, codec);
+    let aggregate_id = Uuid::new_v4();
+    let attempt = commit_attempt(aggregate_id, 0);
+    store.commit(&attempt).unwrap();
+
+    let commit = store.get_commit(&attempt.commit_id).unwrap();
+    assert_eq!(commit.serialized_events, attempt.serialized_events);
+    assert_eq!(commit.serialized_metadata, attempt.serialized_metadata);
+    let at_rest = SqliteStore::with_new_connection_at_path(&path)
+      .get_range(aggregate_id, 0, 0)
+      .unwrap();
+    assert_eq!(at_rest[0].serialized_events[0], ENCRYPTED);
+    assert!(!at_rest[0]
+      .serialized_metadata
+      .windows(5)
+      .any(|w| w == b"alice"));
+
+    // After rotating, old commits still decrypt and new ones use the new key.
+    let rotated = StaticKeyProvider::new(2, [9; 32]).with_retired_key(1, [7; 32]);
+    let mut store = EncryptedStore::new(
+      SqliteStore::with_new_connection_at_path(&path),
+      EncryptionCodec::new(rotated),
+    );
+    store.commit(&commit_attempt(aggregate_id, 1)).unwrap();
+    let commits = store.get_range(aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits.len(), 2);
+    assert!(commits
+      .iter()
+      .all(|c| c.serialized_events == b"[\"Opened\"]".to_vec()));
+
+    let forgotten = EncryptedStore::new(
+      SqliteStore::with_new_connection_at_path(&path),
+      EncryptionCodec::new(StaticKeyProvider::new(2, [9; 32])),
+    );
+    assert!(forgotten.get_range(aggregate_id, 0, 1).is_err());
+    let _ = ::std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn it_shreds_forgotten_subjects() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let key_store = SqliteKeyStore::with_new_in_memory_connection();
+    key_store.initialize().unwrap();
+    let codec = EncryptionCodec::new(StaticKeyProvider::new(1, [7; 32]));
+    let mut store = EncryptedStore::new(store, codec).with_key_store(key_store);
+    let forgotten_id = Uuid::new_v4();
+    let kept_id = Uuid::new_v4();
+    store.commit(&commit_attempt(forgotten_id, 0)).unwrap();
+    store.commit(&commit_attempt(kept_id, 0)).unwrap();
+
+    store.forget(forgotten_id).unwrap();
+    let forgotten = store.get_range(forgotten_id, 0, 0).unwrap();
+    assert_eq!(forgotten.len(), 1);
+    assert_eq!(forgotten[0].serialized_events, FORGOTTEN.to_vec());
+    assert_eq!(forgotten[0].serialized_metadata, FORGOTTEN.to_vec());
+    let kept = store.get_range(kept_id, 0, 0).unwrap();
+    assert_eq!(kept[0].serialized_events, b"[\"Opened\"]".to_vec());
+
+    // The subject's later commits get a new key of their own.
+    store.commit(&commit_attempt(forgotten_id, 1)).unwrap();
+    let commits = store.get_range(forgotten_id, 0, 1).unwrap();
+    assert_eq!(commits[0].serialized_events, FORGOTTEN.to_vec());
+    assert_eq!(commits[1].serialized_events, b"[\"Opened\"]".to_vec());
+  }
+
+  #[cfg(feature = "avro")]
+  #[test]
+  fn it_reads_avro_commits_through_encrypted_stores() {
+    use crate::avro::{InMemorySchemaRegistry, Schemas};
+    use crate::codec::{self, Avro, Registries};
+    use serde_json::Value;
+
+    let schemas = Schemas::new(InMemorySchemaRegistry::default());
+    schemas
+      .register("account-events", r#"{"type": "array", "items": "string"}"#)
+      .unwrap();
+    let registries = Registries {
+      avro: Some(schemas.clone()),
+    };
+    let avro_attempt = |aggregate_id, version| {
+      let mut attempt = commit_attempt(aggregate_id, version);
+      attempt.serialized_events =
+        Avro::encode(&schemas, "account-events", &vec!["Opened"]).unwrap();
+      attempt
+    };
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let key_store = SqliteKeyStore::with_new_in_memory_connection();
+    key_store.initialize().unwrap();
+    // One commit from before encryption was enabled, and one after.
+    let aggregate_id = Uuid::new_v4();
+    store.commit(&avro_attempt(aggregate_id, 0)).unwrap();
+    let codec = EncryptionCodec::new(StaticKeyProvider::new(1, [7; 32]));
+    let mut store = EncryptedStore::new(store, codec).with_key_store(key_store);
+    store.commit(&avro_attempt(aggregate_id, 1)).unwrap();
+
+    let commits = store.get_range(aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits.len(), 2);
+    for commit in commits {
+      assert_eq!(
+        codec::decode_with::<Value>(&commit.serialized_events, &registries).unwrap(),
+        serde_json::json!(["Opened"])
+      );
+    }
+  }
+
+  #[cfg(feature = "protobuf")]
+  #[test]
+  fn it_reads_protobuf_commits_through_encrypted_stores() {
+    use crate::codec::{self, Codec};
+
+    let protobuf_attempt = |aggregate_id, version| {
+      let mut attempt = commit_attempt(aggregate_id, version);
+      attempt.serialized_events = Codec::Protobuf.encode(&vec!["Opened"]).unwrap();
+      attempt
+    };
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    // One commit from before encryption was enabled, and one after.
+    let aggregate_id = Uuid::new_v4();
+    store.commit(&protobuf_attempt(aggregate_id, 0)).unwrap();
+    let codec = EncryptionCodec::new(StaticKeyProvider::new(1, [7; 32]));
+    let mut store = EncryptedStore::new(store, codec);
+    store.commit(&protobuf_attempt(aggregate_id, 1)).unwrap();
+
+    let commits = store.get_range(aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits.len(), 2);
+    for commit in commits {
+      assert_eq!(
+        codec::decode::<Vec<String>>(&commit.serialized_events).unwrap(),
+        vec!["Opened"]
+      );
+    }
+  }
+
+  #[test]
+  fn it_binds_ciphertext_to_its_commit() {
+    let codec = EncryptionCodec::new(StaticKeyProvider::new(1, [7; 32]));
+    let commit_id = Uuid::new_v4();
+    let payload = codec
+      .encrypt(b"[]", &associated_data(commit_id, "events"))
+      .unwrap();
+    assert_eq!(
+      codec
+        .decrypt(&payload, &associated_data(commit_id, "events"))
+        .unwrap(),
+      b"[]".to_vec()
+    );
+    assert!(codec
+      .decrypt(&payload, &associated_data(commit_id, "metadata"))
+      .is_err());
+    assert!(codec
+      .decrypt(&payload, &associated_data(Uuid::new_v4(), "events"))
+      .is_err());
+    assert_eq!(
+      codec.decrypt(b"[\"plain\"]", &[]).unwrap(),
+      b"[\"plain\"]".to_vec()
+    );
+  }
+}
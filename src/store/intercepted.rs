@@ -0,0 +1,365 @@
+use super::{PoisonedCommit, Store, StoreError, StoreErrorType};
+use crate::commit::{Commit, CommitAttempt};
+use crate::events::serialized_event_types;
+use crate::subscription::EventTypeFilter;
+use std::error;
+use std::fmt;
+use std::path::Path;
+use uuid::Uuid;
+
+// Vetoes commits before they are written, such as ones over a size limit or
+// past a tenant's quota. The reason comes back in a `CommitRejected`.
+pub trait CommitInterceptor: Send {
+  fn intercept(&self, commit_attempt: &CommitAttempt) -> Result<(), String>;
+}
+
+impl<F: Fn(&CommitAttempt) -> Result<(), String> + Send> CommitInterceptor for F {
+  fn intercept(&self, commit_attempt: &CommitAttempt) -> Result<(), String> {
+    self(commit_attempt)
+  }
+}
+
+// Commits whose serialized events and metadata take more than this many bytes
+// together, as they reach the store.
+pub struct MaxCommitSize(pub usize);
+
+impl CommitInterceptor for MaxCommitSize {
+  fn intercept(&self, commit_attempt: &CommitAttempt) -> Result<(), String> {
+    let size = commit_attempt.serialized_events.len() + commit_attempt.serialized_metadata.len();
+    if size > self.0 {
+      return Err(format!("commit is {} bytes, over the limit of {}", size, self.0));
+    }
+    Ok(())
+  }
+}
+
+pub struct MaxEventsCount(pub i64);
+
+impl CommitInterceptor for MaxEventsCount {
+  fn intercept(&self, commit_attempt: &CommitAttempt) -> Result<(), String> {
+    if commit_attempt.events_count > self.0 {
+      return Err(format!(
+        "commit has {} events, over the limit of {}",
+        commit_attempt.events_count, self.0
+      ));
+    }
+    Ok(())
+  }
+}
+
+// Commits holding any of the event types. Commits whose event types can't be
+// read, such as bincode ones, are refused as well.
+pub struct BannedEventTypes(pub EventTypeFilter);
+
+impl CommitInterceptor for BannedEventTypes {
+  fn intercept(&self, commit_attempt: &CommitAttempt) -> Result<(), String> {
+    let event_types = serialized_event_types(&commit_attempt.serialized_events)
+      .map_err(|err| format!("commit's event types can't be read: {}", err))?;
+    let banned = self.0.event_types();
+    match event_types
+      .into_iter()
+      .flatten()
+      .find(|event_type| banned.contains(&event_type.as_str()))
+    {
+      Some(event_type) => Err(format!("{} events are not allowed", event_type)),
+      None => Ok(()),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct CommitRejected {
+  pub reason: String,
+}
+
+impl fmt::Display for CommitRejected {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "CommitRejected({})", self.reason)
+  }
+}
+
+impl error::Error for CommitRejected {}
+
+impl StoreError for CommitRejected {
+  fn error_type(&self) -> StoreErrorType {
+    StoreErrorType::Rejected(self.reason.clone())
+  }
+}
+
+impl From<CommitRejected> for Box<dyn StoreError> {
+  fn from(error: CommitRejected) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
+}
+
+// Runs every commit past its interceptors, in the order they were added, and
+// writes it only if none refuses it. Reads pass straight through. Wrap an
+// `EncryptedStore` rather than the other way round, so interceptors see the
+// plaintext.
+pub struct InterceptedStore<S: Store> {
+  store: S,
+  interceptors: Vec<Box<dyn CommitInterceptor>>,
+}
+
+impl<S: Store> InterceptedStore<S> {
+  pub fn new(store: S) -> InterceptedStore<S> {
+    InterceptedStore {
+      store,
+      interceptors: Vec::new(),
+    }
+  }
+
+  pub fn with_interceptor<I: CommitInterceptor + 'static>(
+    mut self,
+    interceptor: I,
+  ) -> InterceptedStore<S> {
+    self.interceptors.push(Box::new(interceptor));
+    self
+  }
+
+  pub fn store(&self) -> &S {
+    &self.store
+  }
+
+  fn intercept(&self, commit_attempt: &CommitAttempt) -> Result<(), Box<dyn StoreError>> {
+    for interceptor in &self.interceptors {
+      if let Err(reason) = interceptor.intercept(commit_attempt) {
+        return Err(CommitRejected { reason }.into());
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<S: Store> Store for InterceptedStore<S> {
+  type Connection = S::Connection;
+
+  fn with_connection(connection: Self::Connection) -> Self {
+    InterceptedStore::new(S::with_connection(connection))
+  }
+
+  fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
+    self.intercept(commit_attempt)?;
+    self.store.commit(commit_attempt)
+  }
+
+  // One refused commit refuses them all.
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    for commit_attempt in commit_attempts {
+      self.intercept(commit_attempt)?;
+    }
+    self.store.commit_all(commit_attempts)
+  }
+
+  fn get_range(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.get_range(aggregate_id, min_version, max_version)
+  }
+
+  fn get_range_of_event_types(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+    event_types: &EventTypeFilter,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .get_range_of_event_types(aggregate_id, min_version, max_version, event_types)
+  }
+
+  fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.get_undispatched_commits()
+  }
+
+  fn get_undispatched_commits_up_to(
+    &mut self,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.get_undispatched_commits_up_to(limit)
+  }
+
+  fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    self.store.mark_commit_as_dispatched(commit_id)
+  }
+
+  fn get_commit(&mut self, commit_id: &Uuid) -> Result<Commit, Box<dyn StoreError>> {
+    self.store.get_commit(commit_id)
+  }
+
+  fn health_check(&self) -> Result<(), Box<dyn StoreError>> {
+    self.store.health_check()
+  }
+
+  fn backup_to(&self, path: &Path) -> Result<(), Box<dyn StoreError>> {
+    self.store.backup_to(path)
+  }
+
+  fn get_commits_after(
+    &self,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.get_commits_after(commit_number, limit)
+  }
+
+  fn get_category_range(
+    &self,
+    category: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .get_category_range(category, commit_number, limit)
+  }
+
+  fn get_tenant_range(
+    &self,
+    tenant_id: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .get_tenant_range(tenant_id, commit_number, limit)
+  }
+
+  fn load_checkpoint(&self, name: &str) -> Result<Option<i64>, Box<dyn StoreError>> {
+    self.store.load_checkpoint(name)
+  }
+
+  fn save_checkpoint(&mut self, name: &str, commit_number: i64) -> Result<(), Box<dyn StoreError>> {
+    self.store.save_checkpoint(name, commit_number)
+  }
+
+  fn mark_commit_as_poisoned(
+    &mut self,
+    commit_id: Uuid,
+    attempts: i64,
+    error: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self
+      .store
+      .mark_commit_as_poisoned(commit_id, attempts, error)
+  }
+
+  fn get_poisoned_commits(&mut self) -> Result<Vec<PoisonedCommit>, Box<dyn StoreError>> {
+    self.store.get_poisoned_commits()
+  }
+
+  fn get_poisoned_commit(
+    &mut self,
+    commit_id: Uuid,
+  ) -> Result<Option<PoisonedCommit>, Box<dyn StoreError>> {
+    self.store.get_poisoned_commit(commit_id)
+  }
+
+  fn requeue_poisoned_commit(&mut self, commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    self.store.requeue_poisoned_commit(commit_id)
+  }
+
+  fn get_unacknowledged_commits(
+    &mut self,
+    consumer: &str,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.get_unacknowledged_commits(consumer, limit)
+  }
+
+  fn acknowledge_commit(
+    &mut self,
+    commit_id: Uuid,
+    consumer: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self.store.acknowledge_commit(commit_id, consumer)
+  }
+
+  fn get_acknowledgements(&mut self, commit_id: Uuid) -> Result<Vec<String>, Box<dyn StoreError>> {
+    self.store.get_acknowledgements(commit_id)
+  }
+
+  fn truncate_through(
+    &mut self,
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    self.store.truncate_through(aggregate_id, commit_sequence)
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::sqlite::SqliteStore;
+  use super::*;
+  use chrono::Utc;
+
+  fn commit_attempt(aggregate_id: Uuid, version: i64, events: &[u8]) -> CommitAttempt {
+    CommitAttempt {
+      aggregate_id,
+      aggregate_version: version,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: version + 1,
+      serialized_metadata: b"{}".to_vec(),
+      serialized_events: events.to_vec(),
+      events_count: 1,
+    }
+  }
+
+  #[test]
+  fn it_refuses_commits_an_interceptor_vetoes() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = InterceptedStore::new(store)
+      .with_interceptor(MaxCommitSize(64))
+      .with_interceptor(BannedEventTypes(EventTypeFilter::new(vec!["Closed"])));
+    let aggregate_id = Uuid::new_v4();
+    store.commit(&commit_attempt(aggregate_id, 0, b"[\"Opened\"]")).unwrap();
+
+    let err = store
+      .commit(&commit_attempt(aggregate_id, 1, b"[\"Closed\"]"))
+      .unwrap_err();
+    assert_eq!(
+      err.error_type(),
+      StoreErrorType::Rejected(String::from("Closed events are not allowed"))
+    );
+    let oversized = format!("[\"{}\"]", "x".repeat(64));
+    let err = store
+      .commit(&commit_attempt(aggregate_id, 1, oversized.as_bytes()))
+      .unwrap_err();
+    assert_eq!(
+      err.error_type(),
+      StoreErrorType::Rejected(String::from("commit is 70 bytes, over the limit of 64"))
+    );
+    assert_eq!(store.get_range(aggregate_id, 0, i64::MAX).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn it_refuses_every_commit_of_a_batch_with_a_vetoed_one() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut store = InterceptedStore::new(store).with_interceptor(MaxEventsCount(1));
+    let mut crowded = commit_attempt(Uuid::new_v4(), 0, b"[\"Opened\", \"Opened\"]");
+    crowded.events_count = 2;
+    let fine = commit_attempt(Uuid::new_v4(), 0, b"[\"Opened\"]");
+    match store.commit_all(&[fine.clone(), crowded]) {
+      Err(err) => assert_eq!(
+        err.to_string(),
+        "CommitRejected(commit has 2 events, over the limit of 1)"
+      ),
+      Ok(_) => panic!("the batch was committed"),
+    }
+    assert!(store.get_range(fine.aggregate_id, 0, i64::MAX).unwrap().is_empty());
+  }
+}
@@ -1,45 +1,388 @@
 #[cfg(feature = "dynamo")]
 pub mod dynamodb;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+pub mod intercepted;
+pub mod retention;
+pub mod shared;
+pub mod tenant;
 
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_multi_tenant;
+#[cfg(feature = "sqlite")]
+pub mod uniqueness;
 
+pub mod verify;
+
+use self::retention::RetentionPolicy;
 use super::commit::{Commit, CommitAttempt};
+use super::subscription::EventTypeFilter;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum StorageCommitConflict {
   CommitIdConflict,
   CommitSequenceConflict,
   AggregateVersionConflict,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum StoreErrorType {
   DuplicateWriteError(StorageCommitConflict),
+  Unsupported,
   UnknownError,
+  // A `CommitInterceptor` refused the commit, or a `TenantStore` an operation
+  // outside its tenant, for the reason given.
+  Rejected(String),
+  // A stored commit no longer matches the checksum it was written with.
+  ChecksumMismatch,
 }
 
-pub trait StoreError: error::Error {
+pub trait StoreError: error::Error + Send {
   fn error_type(&self) -> StoreErrorType;
 }
 
+#[derive(Debug)]
+pub struct UnsupportedOperationError {
+  pub operation: &'static str,
+}
+
+impl fmt::Display for UnsupportedOperationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "UnsupportedOperationError({})", self.operation)
+  }
+}
+
+impl error::Error for UnsupportedOperationError {}
+
+impl StoreError for UnsupportedOperationError {
+  fn error_type(&self) -> StoreErrorType {
+    StoreErrorType::Unsupported
+  }
+}
+
+impl From<UnsupportedOperationError> for Box<dyn StoreError> {
+  fn from(error: UnsupportedOperationError) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
+}
+
+#[derive(Debug)]
+pub struct ChecksumMismatchError {
+  pub commit_id: Uuid,
+  pub recorded: u64,
+  pub actual: u64,
+}
+
+impl fmt::Display for ChecksumMismatchError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "ChecksumMismatchError(commit {} recorded {:016x}, read {:016x})",
+      self.commit_id, self.recorded, self.actual
+    )
+  }
+}
+
+impl error::Error for ChecksumMismatchError {}
+
+impl StoreError for ChecksumMismatchError {
+  fn error_type(&self) -> StoreErrorType {
+    StoreErrorType::ChecksumMismatch
+  }
+}
+
+impl From<ChecksumMismatchError> for Box<dyn StoreError> {
+  fn from(error: ChecksumMismatchError) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
+}
+
+// What a store does with a commit that reads back unlike the checksum it was
+// written with: fail the read, or log it and return the commit anyway. Commits
+// written before checksums were recorded are never checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumVerification {
+  #[default]
+  Strict,
+  LogOnly,
+}
+
+impl ChecksumVerification {
+  pub fn verify(self, commit: &Commit, recorded: Option<u64>) -> Result<(), ChecksumMismatchError> {
+    let recorded = match recorded {
+      Some(recorded) => recorded,
+      None => return Ok(()),
+    };
+    let actual = commit.checksum();
+    if actual == recorded {
+      return Ok(());
+    }
+    let error = ChecksumMismatchError {
+      commit_id: commit.commit_id,
+      recorded,
+      actual,
+    };
+    match self {
+      ChecksumVerification::Strict => Err(error),
+      ChecksumVerification::LogOnly => {
+        error!("{}", error);
+        Ok(())
+      }
+    }
+  }
+}
+
 pub trait Store: Sized {
   type Connection;
 
   fn with_connection(connection: Self::Connection) -> Self;
   fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>>;
+  // Writes commits to any number of aggregates all together or not at all,
+  // returning what `commit` would have for each.
+  fn commit_all(
+    &mut self,
+    _commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "commit_all" }.into())
+  }
+  // The commits made against versions `min_version..=max_version`, in
+  // commit_sequence order, which is the order they must be replayed in.
   fn get_range(
     &self,
     aggregate_id: Uuid,
     min_version: i64,
     max_version: i64,
   ) -> Result<Vec<Commit>, Box<dyn StoreError>>;
+  // Like `get_range`, keeping only commits that match `event_types`. Stores that
+  // record event types at commit time should filter there instead.
+  fn get_range_of_event_types(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+    event_types: &EventTypeFilter,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut commits = self.get_range(aggregate_id, min_version, max_version)?;
+    commits.retain(|commit| event_types.matches(commit));
+    Ok(commits)
+  }
   fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>>;
+  // The oldest `limit` undispatched commits. Stores that can should push the
+  // limit down instead of loading every undispatched commit.
+  fn get_undispatched_commits_up_to(
+    &mut self,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut commits = self.get_undispatched_commits()?;
+    commits.truncate(limit.max(0) as usize);
+    Ok(commits)
+  }
   fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>>;
   fn get_commit(&mut self, commit_it: &Uuid) -> Result<Commit, Box<dyn StoreError>>;
+
+  // Fails when the store cannot serve reads, e.g. its connection is gone or it
+  // has not been initialized. Stores with a cheaper probe should override it.
+  fn health_check(&self) -> Result<(), Box<dyn StoreError>> {
+    self.get_range(Uuid::nil(), 0, 0).map(|_| ())
+  }
+
+  // Copies a consistent snapshot of the store to `path` without blocking writers.
+  fn backup_to(&self, _path: &Path) -> Result<(), Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "backup_to" }.into())
+  }
+
+  // Up to `limit` commits numbered after `commit_number`, across every aggregate,
+  // in commit_number order.
+  fn get_commits_after(
+    &self,
+    _commit_number: i64,
+    _limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "get_commits_after" }.into())
+  }
+
+  // Like `get_commits_after`, limited to the aggregates of one category.
+  fn get_category_range(
+    &self,
+    _category: &str,
+    _commit_number: i64,
+    _limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "get_category_range" }.into())
+  }
+
+  // Like `get_commits_after`, limited to the commits of one tenant.
+  fn get_tenant_range(
+    &self,
+    _tenant_id: &str,
+    _commit_number: i64,
+    _limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "get_tenant_range" }.into())
+  }
+
+  // The commit_number a named consumer, such as a projection, has handled through.
+  fn load_checkpoint(&self, _name: &str) -> Result<Option<i64>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "load_checkpoint" }.into())
+  }
+
+  fn save_checkpoint(&mut self, _name: &str, _commit_number: i64) -> Result<(), Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "save_checkpoint" }.into())
+  }
+
+  // Moves a commit that keeps failing to dispatch to the dead letters, which
+  // takes it out of `get_undispatched_commits` until it is requeued.
+  fn mark_commit_as_poisoned(
+    &mut self,
+    _commit_id: Uuid,
+    _attempts: i64,
+    _error: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "mark_commit_as_poisoned" }.into())
+  }
+
+  fn get_poisoned_commits(&mut self) -> Result<Vec<PoisonedCommit>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "get_poisoned_commits" }.into())
+  }
+
+  fn get_poisoned_commit(
+    &mut self,
+    commit_id: Uuid,
+  ) -> Result<Option<PoisonedCommit>, Box<dyn StoreError>> {
+    let poisoned = self.get_poisoned_commits()?;
+    Ok(poisoned.into_iter().find(|poisoned| poisoned.commit_id == commit_id))
+  }
+
+  // Returns a dead letter to the undispatched commits; false if it was not poisoned.
+  fn requeue_poisoned_commit(&mut self, _commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "requeue_poisoned_commit" }.into())
+  }
+
+  // Up to `limit` commits, oldest first, that the named consumer has not
+  // acknowledged. A consumer that has never acknowledged anything gets the whole
+  // history.
+  fn get_unacknowledged_commits(
+    &mut self,
+    _consumer: &str,
+    _limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "get_unacknowledged_commits" }.into())
+  }
+
+  fn acknowledge_commit(&mut self, _commit_id: Uuid, _consumer: &str) -> Result<(), Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "acknowledge_commit" }.into())
+  }
+
+  // The consumers that have acknowledged a commit.
+  fn get_acknowledgements(&mut self, _commit_id: Uuid) -> Result<Vec<String>, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "get_acknowledgements" }.into())
+  }
+
+  // Deletes an aggregate's commits up to and including `commit_sequence`,
+  // returning how many were removed. The aggregate can then only be fetched
+  // through a snapshot covering them; see `retention`.
+  fn truncate_through(
+    &mut self,
+    _aggregate_id: Uuid,
+    _commit_sequence: i64,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "truncate_through" }.into())
+  }
+}
+
+// Settings for a store's background upkeep, shared with the admin endpoints that
+// change them while the server runs. Clones share the same settings.
+#[derive(Clone, Debug, Default)]
+pub struct StoreConfig {
+  retention: Arc<Mutex<BTreeMap<String, RetentionPolicy>>>,
+}
+
+impl StoreConfig {
+  pub fn with_retention(self, category: &str, policy: RetentionPolicy) -> StoreConfig {
+    self.set_retention(category, policy);
+    self
+  }
+
+  pub fn set_retention(&self, category: &str, policy: RetentionPolicy) {
+    self
+      .retention
+      .lock()
+      .unwrap()
+      .insert(String::from(category), policy);
+  }
+
+  // False if the category had no policy.
+  pub fn remove_retention(&self, category: &str) -> bool {
+    self.retention.lock().unwrap().remove(category).is_some()
+  }
+
+  // The policy of each category with one, by category.
+  pub fn retention_policies(&self) -> BTreeMap<String, RetentionPolicy> {
+    self.retention.lock().unwrap().clone()
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PoisonedCommit {
+  pub commit_id: Uuid,
+  pub aggregate_id: Uuid,
+  pub commit_number: i64,
+  pub attempts: i64,
+  pub error: String,
+  pub poisoned_at: DateTime<Utc>,
+}
+
+// A read model updated in the same transaction as each commit, so readers see it
+// as soon as the commit is visible. `T` is the store's transaction handle. An
+// error rolls the commit back, which lets small projections such as uniqueness
+// indexes reject commits.
+pub trait InlineProjection<T: ?Sized> {
+  fn name(&self) -> String;
+  fn apply(&mut self, transaction: &T, commit: &Commit) -> Result<(), String>;
+}
+
+pub trait InlineProjectionStore: Store {
+  type Transaction: ?Sized;
+
+  fn add_inline_projection(
+    &mut self,
+    projection: Box<dyn InlineProjection<Self::Transaction> + Send>,
+  );
+}
+
+#[derive(Debug)]
+pub struct InlineProjectionError {
+  pub projection: String,
+  pub message: String,
+}
+
+impl fmt::Display for InlineProjectionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "InlineProjectionError({}: {})", self.projection, self.message)
+  }
+}
+
+impl error::Error for InlineProjectionError {}
+
+impl StoreError for InlineProjectionError {
+  fn error_type(&self) -> StoreErrorType {
+    StoreErrorType::UnknownError
+  }
+}
+
+impl From<InlineProjectionError> for Box<dyn StoreError> {
+  fn from(error: InlineProjectionError) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
 }
 
 impl fmt::Display for StorageCommitConflict {
@@ -58,7 +401,10 @@ impl fmt::Display for StoreErrorType {
       StoreErrorType::DuplicateWriteError(ref conflict) => {
         write!(f, "DuplicateWriteError({})", conflict)
       }
+      StoreErrorType::Unsupported => write!(f, "Unsupported"),
       StoreErrorType::UnknownError => write!(f, "UnknownError"),
+      StoreErrorType::Rejected(ref reason) => write!(f, "Rejected({})", reason),
+      StoreErrorType::ChecksumMismatch => write!(f, "ChecksumMismatch"),
     }
   }
 }
@@ -0,0 +1,304 @@
+use super::{Store, StoreConfig, StoreError};
+use crate::commit::Commit;
+use crate::snapshot::SnapshotStore;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use uuid::Uuid;
+
+const PAGE_SIZE: i64 = 100;
+
+// How much of the history of each aggregate in a category to keep. Whatever the
+// policy, a commit is only truncated once it is dispatched and covered by the
+// aggregate's latest snapshot, and the aggregate's last commit is always kept so
+// its commit_sequence can still be read.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionPolicy {
+  // Keeps commits younger than this; whole seconds when serialized.
+  MaxAge(#[serde(with = "seconds")] Duration),
+  // Keeps the newest commits of each aggregate, up to this many.
+  MaxCount(i64),
+  // Keeps only the commits after the latest snapshot.
+  KeepFromSnapshot,
+}
+
+mod seconds {
+  use serde::de::{Deserialize, Deserializer};
+  use serde::Serializer;
+  use std::time::Duration;
+
+  pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+  }
+}
+
+impl RetentionPolicy {
+  // The commit_sequence to truncate through, if any of `commits`, given in
+  // commit_sequence order, can go.
+  fn truncation_point(
+    self,
+    commits: &[Commit],
+    snapshot_sequence: i64,
+    now: DateTime<Utc>,
+  ) -> Option<i64> {
+    let kept = match self {
+      RetentionPolicy::MaxCount(count) => count.max(1) as usize,
+      _ => 1,
+    };
+    let removable = commits.len().saturating_sub(kept);
+    commits[..removable]
+      .iter()
+      .take_while(|commit| {
+        commit.dispatched
+          && commit.commit_sequence <= snapshot_sequence
+          && match self {
+            RetentionPolicy::MaxAge(max_age) => now
+              .signed_duration_since(commit.commit_timestamp)
+              .to_std()
+              .is_ok_and(|age| age > max_age),
+            _ => true,
+          }
+      })
+      .last()
+      .map(|commit| commit.commit_sequence)
+  }
+}
+
+// Applies each category's retention policy to every aggregate in it, reading
+// the whole category each time. Aggregates without a snapshot are left alone.
+// Snapshots invalidated afterwards can't be rebuilt from the truncated history:
+// clients fetching the aggregate fail with `ClientError::TruncatedHistory`, and
+// projections refuse to rebuild. Returns how many commits were removed.
+pub fn apply_retention<S: Store, Ss: SnapshotStore>(
+  store: &mut S,
+  snapshot_store: &Ss,
+  config: &StoreConfig,
+  now: DateTime<Utc>,
+) -> Result<usize, Box<dyn StoreError>> {
+  let mut truncated = 0;
+  for (category, policy) in config.retention_policies() {
+    for aggregate_id in category_aggregates(store, &category)? {
+      let snapshot = match snapshot_store.get_latest_snapshot(aggregate_id)? {
+        Some(snapshot) => snapshot,
+        None => continue,
+      };
+      let commits = store.get_range(aggregate_id, 0, i64::MAX)?;
+      if let Some(commit_sequence) =
+        policy.truncation_point(&commits, snapshot.commit_sequence, now)
+      {
+        truncated += store.truncate_through(aggregate_id, commit_sequence)?;
+      }
+    }
+  }
+  Ok(truncated)
+}
+
+fn category_aggregates<S: Store>(
+  store: &S,
+  category: &str,
+) -> Result<Vec<Uuid>, Box<dyn StoreError>> {
+  let mut seen = HashSet::new();
+  let mut aggregate_ids = Vec::new();
+  let mut after = 0;
+  loop {
+    let commits = store.get_category_range(category, after, PAGE_SIZE)?;
+    for commit in &commits {
+      if seen.insert(commit.aggregate_id) {
+        aggregate_ids.push(commit.aggregate_id);
+      }
+    }
+    match commits.last() {
+      Some(last) if commits.len() as i64 == PAGE_SIZE => after = last.commit_number,
+      _ => return Ok(aggregate_ids),
+    }
+  }
+}
+
+// Applies the retention policies of a `StoreConfig` on its own thread, as soon
+// as it is spawned and then every `interval`. Policies changed on the config
+// take effect from the next run.
+pub struct RetentionTask {
+  config: StoreConfig,
+  interval: Duration,
+}
+
+impl RetentionTask {
+  pub fn new(config: StoreConfig) -> RetentionTask {
+    RetentionTask {
+      config,
+      interval: Duration::from_secs(60 * 60),
+    }
+  }
+
+  pub fn with_interval(mut self, interval: Duration) -> RetentionTask {
+    self.interval = interval;
+    self
+  }
+
+  pub fn spawn<S, Ss, F>(self, stores_factory: F) -> RetentionHandle
+  where
+    S: Store,
+    Ss: SnapshotStore,
+    F: FnOnce() -> (S, Ss) + Send + 'static,
+  {
+    let (stop, stopped) = mpsc::channel();
+    let last_error = Arc::new(Mutex::new(None));
+    let thread = {
+      let last_error = Arc::clone(&last_error);
+      thread::spawn(move || {
+        let (mut store, snapshot_store) = stores_factory();
+        loop {
+          if let Err(err) = apply_retention(&mut store, &snapshot_store, &self.config, Utc::now())
+          {
+            *last_error.lock().unwrap() = Some(err.to_string());
+          }
+          match stopped.recv_timeout(self.interval) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            _ => return,
+          }
+        }
+      })
+    };
+    RetentionHandle {
+      stop,
+      last_error,
+      thread,
+    }
+  }
+}
+
+pub struct RetentionHandle {
+  stop: Sender<()>,
+  last_error: Arc<Mutex<Option<String>>>,
+  thread: JoinHandle<()>,
+}
+
+impl RetentionHandle {
+  // The most recent error from a run; later runs go ahead regardless.
+  pub fn last_error(&self) -> Option<String> {
+    self.last_error.lock().unwrap().clone()
+  }
+
+  // Finishes the current run and waits for the thread to exit.
+  pub fn stop(self) {
+    let _ = self.stop.send(());
+    let _panicked = self.thread.join();
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::sqlite::SqliteStore;
+  use super::*;
+  use crate::commit::CommitAttempt;
+  use crate::snapshot::compression::Compression;
+  use crate::snapshot::memory::InMemorySnapshotStore;
+  use crate::snapshot::Snapshot;
+  use chrono::Duration as ChronoDuration;
+
+  fn commit_at(
+    store: &mut SqliteStore,
+    aggregate_id: Uuid,
+    version: i64,
+    commit_timestamp: DateTime<Utc>,
+  ) {
+    let commit_id = Uuid::new_v4();
+    store
+      .commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version: version,
+        category: String::from("order"),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id,
+        commit_timestamp,
+        commit_sequence: version + 1,
+        serialized_metadata: b"{}".to_vec(),
+        serialized_events: b"[\"Placed\"]".to_vec(),
+        events_count: 1,
+      })
+      .unwrap();
+    store.mark_commit_as_dispatched(commit_id).unwrap();
+  }
+
+  fn snapshot_through(aggregate_id: Uuid, commit_sequence: i64) -> Snapshot {
+    Snapshot {
+      aggregate_id,
+      aggregate_version: commit_sequence,
+      commit_sequence,
+      schema_version: 1,
+      snapshot_timestamp: Utc::now(),
+      compression: Compression::None,
+      serialized_state: b"{}".to_vec(),
+    }
+  }
+
+  fn sequences(store: &SqliteStore, aggregate_id: Uuid) -> Vec<i64> {
+    store
+      .get_range(aggregate_id, 0, i64::MAX)
+      .unwrap()
+      .iter()
+      .map(|commit| commit.commit_sequence)
+      .collect()
+  }
+
+  #[test]
+  fn it_never_truncates_past_the_latest_snapshot() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut snapshot_store = InMemorySnapshotStore::default();
+    let aggregate_id = Uuid::new_v4();
+    let unsnapshotted = Uuid::new_v4();
+    let long_ago = Utc::now() - ChronoDuration::days(30);
+    for version in 0..5 {
+      commit_at(&mut store, aggregate_id, version, long_ago);
+      commit_at(&mut store, unsnapshotted, version, long_ago);
+    }
+    snapshot_store
+      .save_snapshot(&snapshot_through(aggregate_id, 3))
+      .unwrap();
+    let config = StoreConfig::default()
+      .with_retention("order", RetentionPolicy::MaxAge(Duration::from_secs(60)));
+
+    assert_eq!(
+      apply_retention(&mut store, &snapshot_store, &config, Utc::now()).unwrap(),
+      3
+    );
+    assert_eq!(sequences(&store, aggregate_id), vec![4, 5]);
+    assert_eq!(sequences(&store, unsnapshotted), vec![1, 2, 3, 4, 5]);
+  }
+
+  #[test]
+  fn it_keeps_what_each_policy_asks_for() {
+    let now = Utc::now();
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..5 {
+      commit_at(&mut store, aggregate_id, version, now - ChronoDuration::hours(5 - version));
+    }
+    let commits = store.get_range(aggregate_id, 0, i64::MAX).unwrap();
+    let policy = |policy: RetentionPolicy, snapshot_sequence| {
+      policy.truncation_point(&commits, snapshot_sequence, now)
+    };
+
+    assert_eq!(policy(RetentionPolicy::KeepFromSnapshot, 5), Some(4));
+    assert_eq!(policy(RetentionPolicy::KeepFromSnapshot, 2), Some(2));
+    assert_eq!(policy(RetentionPolicy::MaxCount(2), 5), Some(3));
+    assert_eq!(policy(RetentionPolicy::MaxCount(5), 5), None);
+    let three_and_a_half_hours = Duration::from_secs(7 * 30 * 60);
+    assert_eq!(policy(RetentionPolicy::MaxAge(three_and_a_half_hours), 5), Some(2));
+    assert_eq!(
+      serde_json::to_value(RetentionPolicy::MaxAge(three_and_a_half_hours)).unwrap(),
+      serde_json::json!({ "max_age": 12600 })
+    );
+  }
+}
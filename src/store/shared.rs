@@ -0,0 +1,243 @@
+use super::{PoisonedCommit, Store, StoreError};
+use crate::commit::{Commit, CommitAttempt};
+use crate::subscription::EventTypeFilter;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// One store handed to everything that clones the handle, such as the requests a
+// server answers concurrently. Each call locks the store for its own duration
+// only, so a store that pools connections behind its methods still serves
+// callers one call at a time, and an initialized schema is shared by all.
+pub struct SharedStore<S: Store> {
+  store: Arc<Mutex<S>>,
+}
+
+impl<S: Store> SharedStore<S> {
+  pub fn new(store: S) -> SharedStore<S> {
+    SharedStore {
+      store: Arc::new(Mutex::new(store)),
+    }
+  }
+}
+
+impl<S: Store> Clone for SharedStore<S> {
+  fn clone(&self) -> SharedStore<S> {
+    SharedStore {
+      store: Arc::clone(&self.store),
+    }
+  }
+}
+
+impl<S: Store> Store for SharedStore<S> {
+  type Connection = S::Connection;
+
+  fn with_connection(connection: Self::Connection) -> Self {
+    SharedStore::new(S::with_connection(connection))
+  }
+
+  fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
+    self.store.lock().unwrap().commit(commit_attempt)
+  }
+
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().commit_all(commit_attempts)
+  }
+
+  fn get_range(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .get_range(aggregate_id, min_version, max_version)
+  }
+
+  fn get_range_of_event_types(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+    event_types: &EventTypeFilter,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .get_range_of_event_types(aggregate_id, min_version, max_version, event_types)
+  }
+
+  fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_undispatched_commits()
+  }
+
+  fn get_undispatched_commits_up_to(
+    &mut self,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_undispatched_commits_up_to(limit)
+  }
+
+  fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    self.store.lock().unwrap().mark_commit_as_dispatched(commit_id)
+  }
+
+  fn get_commit(&mut self, commit_id: &Uuid) -> Result<Commit, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_commit(commit_id)
+  }
+
+  fn health_check(&self) -> Result<(), Box<dyn StoreError>> {
+    self.store.lock().unwrap().health_check()
+  }
+
+  fn backup_to(&self, path: &Path) -> Result<(), Box<dyn StoreError>> {
+    self.store.lock().unwrap().backup_to(path)
+  }
+
+  fn get_commits_after(
+    &self,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_commits_after(commit_number, limit)
+  }
+
+  fn get_category_range(
+    &self,
+    category: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .get_category_range(category, commit_number, limit)
+  }
+
+  fn get_tenant_range(
+    &self,
+    tenant_id: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .get_tenant_range(tenant_id, commit_number, limit)
+  }
+
+  fn load_checkpoint(&self, name: &str) -> Result<Option<i64>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().load_checkpoint(name)
+  }
+
+  fn save_checkpoint(&mut self, name: &str, commit_number: i64) -> Result<(), Box<dyn StoreError>> {
+    self.store.lock().unwrap().save_checkpoint(name, commit_number)
+  }
+
+  fn mark_commit_as_poisoned(
+    &mut self,
+    commit_id: Uuid,
+    attempts: i64,
+    error: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .mark_commit_as_poisoned(commit_id, attempts, error)
+  }
+
+  fn get_poisoned_commits(&mut self) -> Result<Vec<PoisonedCommit>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_poisoned_commits()
+  }
+
+  fn get_poisoned_commit(
+    &mut self,
+    commit_id: Uuid,
+  ) -> Result<Option<PoisonedCommit>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_poisoned_commit(commit_id)
+  }
+
+  fn requeue_poisoned_commit(&mut self, commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    self.store.lock().unwrap().requeue_poisoned_commit(commit_id)
+  }
+
+  fn get_unacknowledged_commits(
+    &mut self,
+    consumer: &str,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .get_unacknowledged_commits(consumer, limit)
+  }
+
+  fn acknowledge_commit(
+    &mut self,
+    commit_id: Uuid,
+    consumer: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self.store.lock().unwrap().acknowledge_commit(commit_id, consumer)
+  }
+
+  fn get_acknowledgements(&mut self, commit_id: Uuid) -> Result<Vec<String>, Box<dyn StoreError>> {
+    self.store.lock().unwrap().get_acknowledgements(commit_id)
+  }
+
+  fn truncate_through(
+    &mut self,
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    self
+      .store
+      .lock()
+      .unwrap()
+      .truncate_through(aggregate_id, commit_sequence)
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::sqlite::SqliteStore;
+  use super::*;
+  use chrono::Utc;
+
+  #[test]
+  fn it_shares_one_store_between_clones() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut writer = SharedStore::new(store);
+    let reader = writer.clone();
+    let aggregate_id = Uuid::new_v4();
+    let attempt = CommitAttempt {
+      aggregate_id,
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: 1,
+      serialized_metadata: b"{}".to_vec(),
+      serialized_events: b"[\"Opened\"]".to_vec(),
+      events_count: 1,
+    };
+
+    writer.commit(&attempt).unwrap();
+    let commits = reader.get_range(aggregate_id, 0, i64::MAX).unwrap();
+    assert_eq!(commits.len(), 1);
+    assert_eq!(commits[0].commit_id, attempt.commit_id);
+  }
+}
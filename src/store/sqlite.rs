@@ -1,18 +1,178 @@
+use super::super::codec;
 use super::super::commit::{Commit, CommitAttempt};
-use super::{StorageCommitConflict, Store, StoreError, StoreErrorType};
-use rusqlite::{Connection as RusqliteConnection, Error as RusqliteError, ToSql};
+use super::super::events::serialized_event_types;
+use super::super::snapshot::SnapshotStore;
+use super::super::subscription::EventTypeFilter;
+use chrono::Utc;
+use super::uniqueness;
+use super::verify::{IntegrityIssue, IntegrityReport};
+use super::{
+  ChecksumVerification, InlineProjection, InlineProjectionError, InlineProjectionStore,
+  PoisonedCommit, StorageCommitConflict, Store, StoreError, StoreErrorType,
+};
+use rusqlite::types::Type;
+use serde::de::IgnoredAny;
+use serde_json::value::RawValue;
+use rusqlite::{
+  ffi, Connection as RusqliteConnection, Error as RusqliteError, ErrorCode, OptionalExtension, Row,
+  ToSql, MAIN_DB,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::path::Path;
+use std::slice;
 use uuid::Uuid;
 use std::error::Error;
 use std::fmt;
 
+// Commits stored in per-event-row mode keep an empty `events` blob; their payload
+// is reassembled from the `events` table in `event_index` order.
+macro_rules! commit_columns {
+  () => {
+    "aggregate_id,
+    aggregate_version,
+    commit_id,
+    commit_timestamp,
+    commit_sequence,
+    commit_number,
+    events_count,
+    metadata,
+    CASE WHEN events_in_rows = 1 THEN CAST((
+      SELECT '[' || COALESCE(group_concat(payload, ','), '') || ']'
+      FROM (SELECT payload FROM events
+        WHERE events.commit_id = commits.commit_id
+        ORDER BY event_index)
+    ) AS BLOB) ELSE events END,
+    dispatched,
+    category,
+    tenant_id,
+    parent_commit_id,
+    checksum"
+  };
+}
+
 pub struct SqliteStore {
   conn: RusqliteConnection,
+  event_rows: bool,
+  checksum_verification: ChecksumVerification,
+  inline_projections: Vec<Box<dyn InlineProjection<RusqliteConnection> + Send>>,
+}
+
+struct Migration {
+  version: i64,
+  description: &'static str,
+  sql: &'static str,
 }
 
+// Migrations are applied in order, each in its own transaction, and recorded in
+// `schema_version`. Never edit a migration that has shipped; append a new one.
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    description: "create commits table",
+    sql: "CREATE TABLE IF NOT EXISTS commits (
+        aggregate_id      VARCHAR(36) NOT NULL,
+        aggregate_version INTEGER NOT NULL,
+        commit_id         VARCHAR(36) NOT NULL,
+        commit_sequence   INTEGER NOT NULL,
+        commit_number     INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        commit_timestamp  DATETIME NOT NULL,
+        events_count      INTEGER NOT NULL,
+        metadata          BLOB NOT NULL,
+        events            BLOB NOT NULL,
+        dispatched        INTEGER NOT NULL DEFAULT 0
+      );
+      CREATE UNIQUE INDEX IF NOT EXISTS commits_commit_id_unique_idx ON commits (commit_id);
+      CREATE UNIQUE INDEX IF NOT EXISTS commits_commit_aggregate_idx ON commits (aggregate_id, aggregate_version);
+      CREATE UNIQUE INDEX IF NOT EXISTS commits_commit_sequence_idx ON commits (aggregate_id, commit_sequence);
+      CREATE INDEX IF NOT EXISTS commits_dispatched_idx ON commits (dispatched);",
+  },
+  Migration {
+    version: 2,
+    description: "add per-event rows",
+    sql: "CREATE TABLE IF NOT EXISTS events (
+        commit_id    VARCHAR(36) NOT NULL,
+        event_index  INTEGER NOT NULL,
+        aggregate_id VARCHAR(36) NOT NULL,
+        payload      TEXT NOT NULL,
+        PRIMARY KEY (commit_id, event_index)
+      );
+      CREATE INDEX IF NOT EXISTS events_aggregate_idx ON events (aggregate_id);
+      ALTER TABLE commits ADD COLUMN events_in_rows INTEGER NOT NULL DEFAULT 0;",
+  },
+  Migration {
+    version: 3,
+    description: "create checkpoints table",
+    sql: "CREATE TABLE IF NOT EXISTS checkpoints (
+        name          TEXT PRIMARY KEY NOT NULL,
+        commit_number INTEGER NOT NULL,
+        updated_at    DATETIME NOT NULL
+      );",
+  },
+  Migration {
+    version: 4,
+    description: "add aggregate categories",
+    sql: "ALTER TABLE commits ADD COLUMN category TEXT NOT NULL DEFAULT '';
+      CREATE INDEX IF NOT EXISTS commits_category_idx ON commits (category, commit_number);",
+  },
+  Migration {
+    version: 5,
+    description: "create unique values table",
+    sql: "CREATE TABLE IF NOT EXISTS unique_values (
+        constraint_name TEXT NOT NULL,
+        value           TEXT NOT NULL,
+        aggregate_id    VARCHAR(36) NOT NULL,
+        reserved_at     DATETIME NOT NULL,
+        PRIMARY KEY (constraint_name, value)
+      );",
+  },
+  Migration {
+    version: 6,
+    description: "create dead letters table",
+    sql: "CREATE TABLE IF NOT EXISTS dead_letters (
+        commit_id   VARCHAR(36) PRIMARY KEY NOT NULL,
+        attempts    INTEGER NOT NULL,
+        error       TEXT NOT NULL,
+        poisoned_at DATETIME NOT NULL
+      );",
+  },
+  Migration {
+    version: 7,
+    description: "create dispatch acknowledgements table",
+    sql: "CREATE TABLE IF NOT EXISTS dispatch_acknowledgements (
+        commit_id       VARCHAR(36) NOT NULL,
+        consumer        TEXT NOT NULL,
+        acknowledged_at DATETIME NOT NULL,
+        PRIMARY KEY (consumer, commit_id)
+      );",
+  },
+  Migration {
+    version: 8,
+    description: "record commit event types",
+    sql: "ALTER TABLE commits ADD COLUMN event_types TEXT;",
+  },
+  Migration {
+    version: 9,
+    description: "add commit tenants",
+    sql: "ALTER TABLE commits ADD COLUMN tenant_id TEXT NOT NULL DEFAULT '';
+      CREATE INDEX IF NOT EXISTS commits_tenant_idx ON commits (tenant_id, commit_number);",
+  },
+  Migration {
+    version: 10,
+    description: "link commits to their parents",
+    sql: "ALTER TABLE commits ADD COLUMN parent_commit_id TEXT;",
+  },
+  Migration {
+    version: 11,
+    description: "checksum commits",
+    sql: "ALTER TABLE commits ADD COLUMN checksum INTEGER;",
+  },
+];
+
 #[derive(Debug)]
 pub struct SqliteStoreError {
-  cause: RusqliteError
+  cause: RusqliteError,
+  conflict: Option<StorageCommitConflict>,
 }
 
 impl fmt::Display for SqliteStoreError {
@@ -29,13 +189,16 @@ impl Error for SqliteStoreError {
 
 impl From<RusqliteError> for SqliteStoreError {
   fn from(cause: RusqliteError) -> Self {
-    SqliteStoreError { cause }
+    SqliteStoreError {
+      cause,
+      conflict: None,
+    }
   }
 }
 
-impl Into<Box<dyn StoreError>> for SqliteStoreError {
-  fn into(self) -> Box<dyn StoreError> {
-    Box::new(self)
+impl From<SqliteStoreError> for Box<dyn StoreError> {
+  fn from(error: SqliteStoreError) -> Box<dyn StoreError> {
+    Box::new(error)
   }
 }
 
@@ -48,51 +211,536 @@ impl SqliteStore {
     Self::with_connection(RusqliteConnection::open(path).unwrap())
   }
 
+  // Stores each event of a JSON-encoded commit as its own row in `events`, where
+  // it can be queried and indexed with the JSON1 functions. Commits whose events
+  // are not a JSON array are still stored as a single blob. `Client` envelopes
+  // its events, so their fields are under `$.data`.
+  pub fn with_event_rows(mut self) -> Self {
+    self.event_rows = true;
+    self
+  }
+
+  // Logs commits that fail their checksum instead of failing the read, e.g. to
+  // get at what is left of a damaged database.
+  pub fn with_checksum_verification(mut self, checksum_verification: ChecksumVerification) -> Self {
+    self.checksum_verification = checksum_verification;
+    self
+  }
+
+  // Collects the commits read by `rows`, checking each against its checksum.
+  fn checked<I>(&self, rows: I) -> Result<Vec<Commit>, Box<dyn StoreError>>
+  where
+    I: Iterator<Item = Result<(Commit, Option<u64>), RusqliteError>>,
+  {
+    let mut commits = Vec::new();
+    for row in rows {
+      let (commit, checksum) = row.map_err(SqliteStoreError::from)?;
+      self.checksum_verification.verify(&commit, checksum)?;
+      commits.push(commit);
+    }
+    Ok(commits)
+  }
+
+  pub fn create_event_index(&self, name: &str, json_path: &str) -> Result<(), SqliteStoreError> {
+    let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name || json_path.contains('\'') {
+      return Err(SqliteStoreError::from(RusqliteError::InvalidParameterName(
+        format!("{} ({})", name, json_path),
+      )));
+    }
+    self.conn.execute_batch(&format!(
+      "CREATE INDEX IF NOT EXISTS {} ON events (json_extract(payload, '{}'));",
+      name, json_path
+    ))?;
+    Ok(())
+  }
+
+  // The aggregate holding `value` under a `store::uniqueness` constraint.
+  pub fn unique_value_owner(
+    &self,
+    constraint: &str,
+    value: &str,
+  ) -> Result<Option<Uuid>, SqliteStoreError> {
+    Ok(uniqueness::owner_of(&self.conn, constraint, value)?)
+  }
+
   pub fn initialize(&self) {
+    self
+      .migrate()
+      .expect("could not migrate sqlite schema");
+  }
+
+  pub fn schema_version(&self) -> Result<i64, SqliteStoreError> {
     self.conn.execute_batch(
-      "CREATE TABLE IF NOT EXISTS commits (
-        aggregate_id      VARCHAR(36) NOT NULL,
-        aggregate_version INTEGER NOT NULL,
-        commit_id         VARCHAR(36) NOT NULL,
-        commit_sequence   INTEGER NOT NULL,
-        commit_number     INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-        commit_timestamp  DATETIME NOT NULL,
-        events_count      INTEGER NOT NULL,
-        metadata          BLOB NOT NULL,
-        events            BLOB NOT NULL,
-        dispatched        INTEGER NOT NULL DEFAULT 0
-      );
-      CREATE UNIQUE INDEX IF NOT EXISTS commits_commit_id_unique_idx ON commits (commit_id);
-      CREATE UNIQUE INDEX IF NOT EXISTS commits_commit_aggregate_idx ON commits (aggregate_id, aggregate_version);
-      CREATE UNIQUE INDEX IF NOT EXISTS commits_commit_sequence_idx ON commits (aggregate_id, commit_sequence);
-      CREATE INDEX IF NOT EXISTS commits_dispatched_idx ON commits (dispatched);"
-    ).expect("could not intiailize sqlite commits table");
+      "CREATE TABLE IF NOT EXISTS schema_version (
+        version     INTEGER PRIMARY KEY NOT NULL,
+        description TEXT NOT NULL,
+        applied_at  DATETIME NOT NULL
+      );",
+    )?;
+    let version: i64 = self.conn.query_row(
+      "SELECT COALESCE(MAX(version), 0) FROM schema_version;",
+      &[] as &[&dyn ToSql],
+      |row| row.get(0),
+    )?;
+    Ok(version)
   }
-}
 
-impl StoreError for SqliteStoreError { 
-  fn error_type(&self) -> StoreErrorType {
-    match self.cause {
-      RusqliteError::SqliteFailure(_, Some(ref msg))
-        if msg == "UNIQUE constraint failed: commits.aggregate_id, commits.commit_sequence" =>
-      {
-        StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitSequenceConflict)
+  pub fn migrate(&self) -> Result<i64, SqliteStoreError> {
+    let current_version = self.schema_version()?;
+    let mut version = current_version;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current_version) {
+      let transaction = self.conn.unchecked_transaction()?;
+      transaction.execute_batch(migration.sql)?;
+      transaction.execute(
+        "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+        [
+          &migration.version as &dyn ToSql,
+          &migration.description,
+          &Utc::now(),
+        ],
+      )?;
+      transaction.commit()?;
+      version = migration.version;
+    }
+    Ok(version)
+  }
+
+  // Moves dispatched commits numbered below `commit_number` into `sink` and deletes
+  // them locally. Only a contiguous prefix of each aggregate's history is moved, so
+  // the commits left behind never have a gap in front of them, and only as far as
+  // the aggregate's latest snapshot in `snapshot_store` covers, so its state can
+  // still be read. Aggregates without a snapshot are left alone, and each
+  // aggregate's last commit is always kept so its commit_sequence can still be
+  // read. Commits the sink already holds (from an interrupted run) are deleted
+  // without being copied again.
+  pub fn archive_before<S: Store, Ss: SnapshotStore>(
+    &mut self,
+    commit_number: i64,
+    snapshot_store: &Ss,
+    sink: &mut S,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    let mut snapshot_sequences = HashMap::new();
+    let mut archived = 0;
+    for commit in self.archivable_commits(commit_number)? {
+      let snapshot_sequence = match snapshot_sequences.entry(commit.aggregate_id) {
+        Entry::Occupied(entry) => *entry.get(),
+        Entry::Vacant(entry) => *entry.insert(
+          snapshot_store
+            .get_latest_snapshot(commit.aggregate_id)?
+            .map(|snapshot| snapshot.commit_sequence),
+        ),
+      };
+      if snapshot_sequence.is_none_or(|sequence| commit.commit_sequence > sequence) {
+        continue;
       }
-      RusqliteError::SqliteFailure(_, Some(ref msg))
-        if msg == "UNIQUE constraint failed: commits.aggregate_id, commits.aggregate_version" =>
-      {
-        StoreErrorType::DuplicateWriteError(StorageCommitConflict::AggregateVersionConflict)
+      if let Err(err) = sink.commit(&CommitAttempt::from(&commit)) {
+        if err.error_type()
+          != StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitIdConflict)
+        {
+          return Err(err);
+        }
+      }
+      let transaction = self
+        .conn
+        .unchecked_transaction()
+        .map_err(SqliteStoreError::from)?;
+      transaction
+        .execute(
+          "DELETE FROM events WHERE commit_id = ?",
+          [&commit.commit_id.to_string()],
+        )
+        .map_err(SqliteStoreError::from)?;
+      transaction
+        .execute(
+          "DELETE FROM commits WHERE commit_number = ?",
+          [&commit.commit_number],
+        )
+        .map_err(SqliteStoreError::from)?;
+      transaction.commit().map_err(SqliteStoreError::from)?;
+      archived += 1;
+    }
+    Ok(archived)
+  }
+
+  fn archivable_commits(&self, commit_number: i64) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut statement = self.conn.prepare(concat!(
+      "SELECT ",
+      commit_columns!(),
+      " FROM commits
+        WHERE commit_number < ?1
+        AND dispatched = 1
+        AND NOT EXISTS (
+          SELECT 1 FROM commits AS retained
+          WHERE retained.aggregate_id = commits.aggregate_id
+          AND retained.commit_sequence < commits.commit_sequence
+          AND (retained.dispatched = 0 OR retained.commit_number >= ?1)
+        )
+        AND EXISTS (
+          SELECT 1 FROM commits AS newer
+          WHERE newer.aggregate_id = commits.aggregate_id
+          AND newer.commit_sequence > commits.commit_sequence
+        )
+        ORDER BY commit_number ASC;"
+    ))
+    .map_err(SqliteStoreError::from)?;
+    let rows = statement
+      .query_map([&commit_number], checked_commit_from_row)
+      .map_err(SqliteStoreError::from)?;
+    self.checked(rows)
+  }
+
+  // Checks the database file itself and then every stored commit: uuids must
+  // parse, versions and sequences must increase along each aggregate's history,
+  // each recorded parent must be the aggregate's previous commit, events_count
+  // must match the number of stored events, and each commit must match its
+  // checksum whatever the store's checksum verification. The first commit kept
+  // after a truncation names a parent that is gone, which is fine.
+  pub fn verify(&self) -> Result<IntegrityReport, SqliteStoreError> {
+    let mut report = IntegrityReport::default();
+    {
+      let mut statement = self.conn.prepare("PRAGMA integrity_check;")?;
+      let results = statement.query_map(&[] as &[&dyn ToSql], |row| row.get::<_, String>(0))?;
+      for result in results {
+        let detail = result?;
+        if detail != "ok" {
+          report.issues.push(IntegrityIssue::DatabaseCorruption { detail });
+        }
+      }
+    }
+
+    let mut statement = self.conn.prepare(concat!(
+      "SELECT ",
+      commit_columns!(),
+      " FROM commits ORDER BY aggregate_id, commit_number ASC;"
+    ))?;
+    let mut rows = statement.query(&[] as &[&dyn ToSql])?;
+    let mut previous: Option<(String, i64, i64, String)> = None;
+    while let Some(row) = rows.next()? {
+      let aggregate_id: String = row.get(0)?;
+      let aggregate_version: i64 = row.get(1)?;
+      let commit_id: String = row.get(2)?;
+      let commit_sequence: i64 = row.get(4)?;
+      let commit_number: i64 = row.get(5)?;
+      let events_count: i64 = row.get(6)?;
+      let serialized_events: Vec<u8> = row.get(8)?;
+      let parent_commit_id: Option<String> = row.get(12)?;
+      let checksum: Option<i64> = row.get(13)?;
+      report.commits_checked += 1;
+
+      let mut uuids = vec![("aggregate_id", &aggregate_id), ("commit_id", &commit_id)];
+      if let Some(ref parent_commit_id) = parent_commit_id {
+        uuids.push(("parent_commit_id", parent_commit_id));
+      }
+      for &(column, value) in &uuids {
+        if Uuid::parse_str(value).is_err() {
+          report.issues.push(IntegrityIssue::InvalidUuid {
+            commit_number,
+            column,
+            value: value.to_string(),
+          });
+        }
       }
-      RusqliteError::SqliteFailure(_, Some(ref msg))
-        if msg == "UNIQUE constraint failed: commits.commit_id" =>
+
+      if let Some((
+        ref previous_aggregate_id,
+        previous_version,
+        previous_sequence,
+        ref previous_id,
+      )) = previous
       {
-        StoreErrorType::DuplicateWriteError(StorageCommitConflict::CommitIdConflict)
+        if *previous_aggregate_id == aggregate_id {
+          if aggregate_version <= previous_version {
+            report.issues.push(IntegrityIssue::NonMonotonicAggregateVersion {
+              commit_number,
+              aggregate_id: aggregate_id.clone(),
+              previous: previous_version,
+              current: aggregate_version,
+            });
+          }
+          if commit_sequence <= previous_sequence {
+            report.issues.push(IntegrityIssue::NonMonotonicCommitSequence {
+              commit_number,
+              aggregate_id: aggregate_id.clone(),
+              previous: previous_sequence,
+              current: commit_sequence,
+            });
+          }
+          // Commits written before commits were linked record no parent.
+          match parent_commit_id {
+            Some(ref parent_commit_id) if parent_commit_id != previous_id => {
+              report.issues.push(IntegrityIssue::BrokenCommitChain {
+                commit_number,
+                aggregate_id: aggregate_id.clone(),
+                parent_commit_id: parent_commit_id.clone(),
+                previous_commit_id: previous_id.clone(),
+              })
+            }
+            _ => (),
+          }
+        }
+      }
+
+      // The store has neither the keys nor the schema registries some payloads
+      // need, so it can't count their events.
+      if codec::decodable_at_rest(&serialized_events) {
+        match codec::decode::<Vec<IgnoredAny>>(&serialized_events) {
+          Ok(ref events) if events.len() as i64 != events_count => {
+            report.issues.push(IntegrityIssue::EventsCountMismatch {
+              commit_number,
+              recorded: events_count,
+              actual: events.len() as i64,
+            })
+          }
+          Ok(_) => (),
+          Err(_) => report
+            .issues
+            .push(IntegrityIssue::UndecodableEvents { commit_number }),
+        }
       }
-      RusqliteError::SqliteFailure(_, Some(ref msg)) => {
-        panic!(msg.clone());
+
+      // A commit with an invalid uuid was reported above and can't be hashed.
+      if let (Some(recorded), Ok(commit)) = (checksum, commit_from_row(row)) {
+        let actual = commit.checksum();
+        if actual != recorded as u64 {
+          report.issues.push(IntegrityIssue::ChecksumMismatch {
+            commit_number,
+            recorded: recorded as u64,
+            actual,
+          });
+        }
       }
-      _ => StoreErrorType::UnknownError,
+
+      previous = Some((aggregate_id, aggregate_version, commit_sequence, commit_id));
     }
+    Ok(report)
+  }
+}
+
+impl StoreError for SqliteStoreError {
+  fn error_type(&self) -> StoreErrorType {
+    match self.conflict {
+      Some(ref conflict) => StoreErrorType::DuplicateWriteError(conflict.clone()),
+      None => StoreErrorType::UnknownError,
+    }
+  }
+}
+
+fn split_event_payloads(serialized_events: &[u8]) -> Option<Vec<Box<RawValue>>> {
+  serde_json::from_slice(serialized_events).ok()
+}
+
+// A commit's event types as a JSON array, or `None` when its events cannot be
+// read. Like commits stored before event types were recorded, those are left
+// `NULL` and filtered by reading their events.
+fn recorded_event_types(serialized_events: &[u8]) -> Option<String> {
+  let event_types = serialized_event_types(serialized_events).ok()?;
+  let event_types: Vec<String> = event_types.into_iter().flatten().collect();
+  serde_json::to_string(&event_types).ok()
+}
+
+fn commit_from_row(row: &Row) -> Result<Commit, RusqliteError> {
+  let aggregate_id: String = row.get(0)?;
+  let commit_id: String = row.get(2)?;
+  Ok(Commit {
+    aggregate_id: Uuid::parse_str(aggregate_id.as_ref())
+      .map_err(|err| RusqliteError::FromSqlConversionFailure(0, Type::Text, Box::new(err)))?,
+    aggregate_version: row.get(1)?,
+    category: row.get(10)?,
+    tenant_id: row.get(11)?,
+    parent_commit_id: match row.get::<_, Option<String>>(12)? {
+      Some(_) => Some(uuid_column(row, 12)?),
+      None => None,
+    },
+    commit_id: Uuid::parse_str(commit_id.as_ref())
+      .map_err(|err| RusqliteError::FromSqlConversionFailure(2, Type::Text, Box::new(err)))?,
+    commit_timestamp: row.get(3)?,
+    commit_sequence: row.get(4)?,
+    commit_number: row.get(5)?,
+    events_count: row.get(6)?,
+    serialized_metadata: row.get(7)?,
+    serialized_events: row.get(8)?,
+    dispatched: row.get(9)?,
+  })
+}
+
+// A commit with the checksum it was written with; `None` for commits written
+// before checksums were recorded.
+fn checked_commit_from_row(row: &Row) -> Result<(Commit, Option<u64>), RusqliteError> {
+  let checksum = row.get::<_, Option<i64>>(13)?;
+  Ok((commit_from_row(row)?, checksum.map(|checksum| checksum as u64)))
+}
+
+fn uuid_column(row: &Row, index: usize) -> Result<Uuid, RusqliteError> {
+  let value: String = row.get(index)?;
+  Uuid::parse_str(value.as_ref())
+    .map_err(|err| RusqliteError::FromSqlConversionFailure(index, Type::Text, Box::new(err)))
+}
+
+// SQLite only reports which index was violated in the English error message,
+// so a unique constraint failure is classified by looking up which of the
+// conflicting rows actually exists instead.
+fn classify_commit_error(
+  conn: &RusqliteConnection,
+  cause: RusqliteError,
+  commit_attempt: &CommitAttempt,
+) -> SqliteStoreError {
+  if !is_unique_constraint_violation(&cause) {
+    return SqliteStoreError::from(cause);
+  }
+  let conflict = conn
+    .query_row(
+      "SELECT
+          EXISTS(SELECT 1 FROM commits WHERE commit_id = ?1),
+          EXISTS(SELECT 1 FROM commits WHERE aggregate_id = ?2 AND aggregate_version = ?3),
+          EXISTS(SELECT 1 FROM commits WHERE aggregate_id = ?2 AND commit_sequence = ?4);",
+      [
+        &commit_attempt.commit_id.to_string() as &dyn ToSql,
+        &commit_attempt.aggregate_id.to_string(),
+        &commit_attempt.aggregate_version,
+        &commit_attempt.commit_sequence,
+      ],
+      |row| {
+        let commit_id_exists: bool = row.get(0)?;
+        let aggregate_version_exists: bool = row.get(1)?;
+        let commit_sequence_exists: bool = row.get(2)?;
+        Ok(if commit_id_exists {
+          Some(StorageCommitConflict::CommitIdConflict)
+        } else if aggregate_version_exists {
+          Some(StorageCommitConflict::AggregateVersionConflict)
+        } else if commit_sequence_exists {
+          Some(StorageCommitConflict::CommitSequenceConflict)
+        } else {
+          None
+        })
+      },
+    )
+    .unwrap_or(None);
+  SqliteStoreError { cause, conflict }
+}
+
+// Inserts a commit, its event rows and its inline projections' updates as part
+// of `transaction`, returning its commit_number.
+fn insert_commit(
+  transaction: &RusqliteConnection,
+  event_rows: bool,
+  inline_projections: &mut [Box<dyn InlineProjection<RusqliteConnection> + Send>],
+  commit_attempt: &CommitAttempt,
+) -> Result<i64, Box<dyn StoreError>> {
+  let event_payloads = if event_rows {
+    split_event_payloads(&commit_attempt.serialized_events)
+  } else {
+    None
+  };
+  {
+    let mut statement = match transaction.prepare(
+      "INSERT INTO commits (
+        aggregate_id,
+        aggregate_version,
+        commit_id,
+        commit_timestamp,
+        commit_sequence,
+        events_count,
+        metadata,
+        events,
+        events_in_rows,
+        category,
+        event_types,
+        tenant_id,
+        parent_commit_id,
+        checksum
+      ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    ) {
+      Ok(result) => result,
+      Err(err) => return Err(SqliteStoreError::from(err).into()),
+    };
+    let events_blob: &[u8] = if event_payloads.is_some() {
+      &[]
+    } else {
+      &commit_attempt.serialized_events
+    };
+    // Events stored as rows read back joined without whitespace, so that is what
+    // their checksum covers.
+    let checksum = match event_payloads {
+      Some(ref payloads) => {
+        let payloads: Vec<&str> = payloads.iter().map(|payload| payload.get()).collect();
+        commit_attempt.checksum_with_events(format!("[{}]", payloads.join(",")).as_bytes())
+      }
+      None => commit_attempt.checksum(),
+    };
+    match statement.execute([
+      &commit_attempt.aggregate_id.to_string(),
+      &commit_attempt.aggregate_version as &dyn ToSql,
+      &commit_attempt.commit_id.to_string(),
+      &commit_attempt.commit_timestamp,
+      &commit_attempt.commit_sequence,
+      &commit_attempt.events_count,
+      &commit_attempt.serialized_metadata,
+      &events_blob,
+      &event_payloads.is_some(),
+      &commit_attempt.category,
+      &recorded_event_types(&commit_attempt.serialized_events),
+      &commit_attempt.tenant_id,
+      &commit_attempt.parent_commit_id.map(|parent_commit_id| parent_commit_id.to_string()),
+      &(checksum as i64),
+    ]) {
+      Ok(_) => (),
+      Err(err) => return Err(classify_commit_error(transaction, err, commit_attempt).into()),
+    };
+  }
+  let commit_number = transaction.last_insert_rowid();
+  if let Some(payloads) = event_payloads {
+    for (event_index, payload) in payloads.iter().enumerate() {
+      match transaction.execute(
+        "INSERT INTO events (commit_id, event_index, aggregate_id, payload) VALUES (?, ?, ?, ?)",
+        [
+          &commit_attempt.commit_id.to_string() as &dyn ToSql,
+          &(event_index as i64),
+          &commit_attempt.aggregate_id.to_string(),
+          &payload.get(),
+        ],
+      ) {
+        Ok(_) => (),
+        Err(err) => return Err(SqliteStoreError::from(err).into()),
+      };
+    }
+  }
+  if !inline_projections.is_empty() {
+    let commit = Commit {
+      aggregate_id: commit_attempt.aggregate_id,
+      aggregate_version: commit_attempt.aggregate_version,
+      category: commit_attempt.category.clone(),
+      tenant_id: commit_attempt.tenant_id.clone(),
+      parent_commit_id: commit_attempt.parent_commit_id,
+      commit_id: commit_attempt.commit_id,
+      commit_timestamp: commit_attempt.commit_timestamp,
+      commit_sequence: commit_attempt.commit_sequence,
+      commit_number,
+      serialized_events: commit_attempt.serialized_events.clone(),
+      serialized_metadata: commit_attempt.serialized_metadata.clone(),
+      events_count: commit_attempt.events_count,
+      dispatched: false,
+    };
+    for projection in inline_projections.iter_mut() {
+      projection
+        .apply(transaction, &commit)
+        .map_err(|message| InlineProjectionError {
+          projection: projection.name(),
+          message,
+        })?;
+    }
+  }
+  Ok(commit_number)
+}
+
+fn is_unique_constraint_violation(error: &RusqliteError) -> bool {
+  match *error {
+    RusqliteError::SqliteFailure(ref failure, _) => {
+      failure.code == ErrorCode::ConstraintViolation
+        && (failure.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE
+          || failure.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY)
+    }
+    _ => false,
   }
 }
 
@@ -100,48 +748,42 @@ impl Store for SqliteStore {
   type Connection = RusqliteConnection;
 
   fn with_connection(connection: Self::Connection) -> Self {
-    SqliteStore { conn: connection }
+    SqliteStore {
+      conn: connection,
+      event_rows: false,
+      checksum_verification: ChecksumVerification::default(),
+      inline_projections: Vec::new(),
+    }
   }
 
   fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
-    {
-      {
-        let mut statement = match self.conn.prepare(
-          "INSERT INTO commits (
-            aggregate_id,
-            aggregate_version,
-            commit_id,
-            commit_timestamp,
-            commit_sequence,
-            events_count,
-            metadata,
-            events
-          ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
-        ) {
-          Ok(result) => result,
-          Err(err) => return Err(SqliteStoreError::from(err).into()),
-        };
-        match statement.execute(&[
-          &commit_attempt.aggregate_id.to_string(),
-          &commit_attempt.aggregate_version as &dyn ToSql,
-          &commit_attempt.commit_id.to_string(),
-          &commit_attempt.commit_timestamp,
-          &commit_attempt.commit_sequence,
-          &commit_attempt.events_count,
-          &commit_attempt.serialized_metadata,
-          &commit_attempt.serialized_events,
-        ]) {
-          Ok(_) => (),
-          Err(err) => return Err(SqliteStoreError::from(err).into()),
-        };
-        match statement.finalize() {
-          Ok(_) => (),
-          Err(err) => return Err(SqliteStoreError::from(err).into()),
-        };
-      }
+    let commit_numbers = self.commit_all(slice::from_ref(commit_attempt))?;
+    Ok(commit_numbers[0])
+  }
+
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    let transaction = match self.conn.unchecked_transaction() {
+      Ok(result) => result,
+      Err(err) => return Err(SqliteStoreError::from(err).into()),
+    };
+    let mut commit_numbers = Vec::with_capacity(commit_attempts.len());
+    for commit_attempt in commit_attempts {
+      commit_numbers.push(insert_commit(
+        &transaction,
+        self.event_rows,
+        &mut self.inline_projections,
+        commit_attempt,
+      )?);
     }
+    match transaction.commit() {
+      Ok(_) => (),
+      Err(err) => return Err(SqliteStoreError::from(err).into()),
+    };
 
-    Ok(self.conn.last_insert_rowid())
+    Ok(commit_numbers)
   }
 
   fn get_range(
@@ -150,104 +792,117 @@ impl Store for SqliteStore {
     min_version: i64,
     max_version: i64,
   ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
-    let mut stmt = match self.conn.prepare(
-      "SELECT
-          aggregate_id,
-          aggregate_version,
-          commit_id,
-          commit_timestamp,
-          commit_sequence,
-          commit_number,
-          events_count,
-          metadata,
-          events,
-          dispatched
-        FROM commits
+    let mut stmt = match self.conn.prepare(concat!(
+      "SELECT ",
+      commit_columns!(),
+      " FROM commits
         WHERE aggregate_version >= ?
         AND aggregate_version <= ?
-        AND aggregate_id = ?;",
+        AND aggregate_id = ?
+        ORDER BY commit_sequence ASC;"
+    )) {
+      Ok(result) => result,
+      Err(err) => return Err(SqliteStoreError::from(err).into()),
+    };
+    let rows = match stmt.query_map(
+      [
+        &min_version,
+        &max_version,
+        &aggregate_id.to_string() as &dyn ToSql,
+      ],
+      checked_commit_from_row,
     ) {
       Ok(result) => result,
       Err(err) => return Err(SqliteStoreError::from(err).into()),
     };
-    let rows = match stmt
+    self.checked(rows)
+  }
+
+  fn get_range_of_event_types(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+    event_types: &EventTypeFilter,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(concat!(
+        "SELECT ",
+        commit_columns!(),
+        ", event_types IS NULL FROM commits
+          WHERE aggregate_version >= ?
+          AND aggregate_version <= ?
+          AND aggregate_id = ?
+          AND (event_types IS NULL OR EXISTS (
+            SELECT 1 FROM json_each(commits.event_types) AS recorded
+            WHERE recorded.value IN (SELECT wanted.value FROM json_each(?) AS wanted)
+          ))
+          ORDER BY commit_sequence ASC;"
+      ))
+      .map_err(SqliteStoreError::from)?;
+    let wanted = serde_json::json!(event_types.event_types()).to_string();
+    let rows = stmt
       .query_map(
-        &[
+        [
           &min_version,
           &max_version,
           &aggregate_id.to_string() as &dyn ToSql,
+          &wanted,
         ],
-        |row| {
-          let aggregate_id_str: String = row.get(0).expect("no aggregate_id result column");
-          let commit_id_str: String = row.get(2).expect("no commit_id result column");
-          Ok(Commit {
-            aggregate_id: Uuid::parse_str(aggregate_id_str.as_ref()).unwrap(),
-            aggregate_version: row.get(1).expect("no aggregate_version result column"),
-            commit_id: Uuid::parse_str(commit_id_str.as_ref()).unwrap(),
-            commit_timestamp: row.get(3).expect("no commit_timestamp result column"),
-            commit_sequence: row.get(4).expect("no commit_sequence result column"),
-            commit_number: row.get(5).expect("no commit_number result column"),
-            events_count: row.get(6).expect("no events_count result column"),
-            serialized_metadata: row.get(7).expect("no serialized_metadat result column"),
-            serialized_events: row.get(8).expect("no serialized_events result column"),
-            dispatched: row.get(9).expect("no dispatched result column"),
-          })
-        },
-      ) {
-        Ok(result) => result,
-        Err(err) => return Err(SqliteStoreError::from(err).into()),
-      }.map(|row| row.unwrap())
-      .collect();
-    Ok(rows)
+        |row| Ok((checked_commit_from_row(row)?, row.get::<_, bool>(14)?)),
+      )
+      .map_err(SqliteStoreError::from)?;
+    let mut commits = Vec::new();
+    for row in rows {
+      let ((commit, checksum), unrecorded) = row.map_err(SqliteStoreError::from)?;
+      self.checksum_verification.verify(&commit, checksum)?;
+      if !unrecorded || event_types.matches(&commit) {
+        commits.push(commit);
+      }
+    }
+    Ok(commits)
   }
 
   fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>> {
-    let mut stmt = match self.conn.prepare(
-      "SELECT
-          aggregate_id,
-          aggregate_version,
-          commit_id,
-          commit_timestamp,
-          commit_sequence,
-          commit_number,
-          events_count,
-          metadata,
-          events,
-          dispatched
-        FROM commits
+    let mut stmt = match self.conn.prepare(concat!(
+      "SELECT ",
+      commit_columns!(),
+      " FROM commits
         WHERE dispatched = 0
-        ORDER BY commit_number ASC;",
-    ) {
+        AND commit_id NOT IN (SELECT commit_id FROM dead_letters)
+        ORDER BY commit_number ASC;"
+    )) {
       Ok(result) => result,
       Err(err) => return Err(SqliteStoreError::from(err).into()),
     };
-    let rows = match stmt
-      .query_map(&vec![] as &Vec<&dyn ToSql>, |row| {
-        let aggregate_id_str: String = row.get(0).expect("no aggregate_id column in result");
-        let commit_id_str: String = row.get(2).expect("no commit_id column in result");
-        Ok(Commit {
-          aggregate_id: Uuid::parse_str(aggregate_id_str.as_ref())
-            .expect("commit_id is not in Uuid format; database may be corrupted."),
-          aggregate_version: row.get(1).expect("no aggregate_version column in result"),
-          commit_id: Uuid::parse_str(commit_id_str.as_ref())
-            .expect("commit_id is not in Uuid format; database may be corrupted."),
-          commit_timestamp: row.get(3).expect("no commit_timestamp column in result"),
-          commit_sequence: row.get(4).expect("no commit_sequence column in result"),
-          commit_number: row.get(5).expect("no commit_number column in result"),
-          events_count: row.get(6).expect("no events_count column in result"),
-          serialized_metadata: row.get(7).expect("no serialized_metadata column in result"),
-          serialized_events: row.get(8).expect("no serialized_events column in result"),
-          dispatched: row.get(9).expect("no dispatched column in result"),
-        })
-      }) {
-        Ok(result) => result,
-        Err(err) => return Err(SqliteStoreError::from(err).into()),
-      }
-      .map(|rows| {
-        rows.expect("Could not read from commits row. If the schema has changed, update the store to read from the appropriate format.")
-      })
-      .collect();
-    Ok(rows)
+    let rows = match stmt.query_map(&[] as &[&dyn ToSql], checked_commit_from_row) {
+      Ok(result) => result,
+      Err(err) => return Err(SqliteStoreError::from(err).into()),
+    };
+    self.checked(rows)
+  }
+
+  fn get_undispatched_commits_up_to(
+    &mut self,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(concat!(
+        "SELECT ",
+        commit_columns!(),
+        " FROM commits
+          WHERE dispatched = 0
+          AND commit_id NOT IN (SELECT commit_id FROM dead_letters)
+          ORDER BY commit_number ASC
+          LIMIT ?;"
+      ))
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map([limit], checked_commit_from_row)
+      .map_err(SqliteStoreError::from)?;
+    self.checked(rows)
   }
 
   fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
@@ -257,7 +912,7 @@ impl Store for SqliteStore {
         Ok(result) => result,
         Err(err) => return Err(SqliteStoreError::from(err).into()),
       };
-    match statement.execute(&[&commit_id.to_string()]) {
+    match statement.execute([&commit_id.to_string()]) {
       Ok(_) => (),
       Err(err) => return Err(SqliteStoreError::from(err).into()),
     };
@@ -268,61 +923,304 @@ impl Store for SqliteStore {
     Ok(())
   }
 
+  fn mark_commit_as_poisoned(
+    &mut self,
+    commit_id: Uuid,
+    attempts: i64,
+    error: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self
+      .conn
+      .execute(
+        "INSERT OR REPLACE INTO dead_letters (commit_id, attempts, error, poisoned_at)
+          VALUES (?, ?, ?, ?);",
+        [
+          &commit_id.to_string() as &dyn ToSql,
+          &attempts,
+          &error,
+          &Utc::now(),
+        ],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(())
+  }
+
+  fn get_poisoned_commits(&mut self) -> Result<Vec<PoisonedCommit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(
+        "SELECT dead_letters.commit_id, commits.aggregate_id, commits.commit_number,
+            dead_letters.attempts, dead_letters.error, dead_letters.poisoned_at
+          FROM dead_letters
+          JOIN commits ON commits.commit_id = dead_letters.commit_id
+          ORDER BY commits.commit_number ASC;",
+      )
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map([], |row| {
+        Ok(PoisonedCommit {
+          commit_id: uuid_column(row, 0)?,
+          aggregate_id: uuid_column(row, 1)?,
+          commit_number: row.get(2)?,
+          attempts: row.get(3)?,
+          error: row.get(4)?,
+          poisoned_at: row.get(5)?,
+        })
+      })
+      .map_err(SqliteStoreError::from)?;
+    let poisoned = rows
+      .collect::<Result<Vec<PoisonedCommit>, RusqliteError>>()
+      .map_err(SqliteStoreError::from)?;
+    Ok(poisoned)
+  }
+
+  fn requeue_poisoned_commit(&mut self, commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    let removed = self
+      .conn
+      .execute(
+        "DELETE FROM dead_letters WHERE commit_id = ?;",
+        [&commit_id.to_string()],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(removed > 0)
+  }
+
+  fn get_unacknowledged_commits(
+    &mut self,
+    consumer: &str,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(concat!(
+        "SELECT ",
+        commit_columns!(),
+        " FROM commits
+          WHERE commit_id NOT IN (
+            SELECT commit_id FROM dispatch_acknowledgements WHERE consumer = ?1
+          )
+          AND commit_id NOT IN (SELECT commit_id FROM dead_letters)
+          ORDER BY commit_number ASC
+          LIMIT ?2;"
+      ))
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map([&consumer as &dyn ToSql, &limit], checked_commit_from_row)
+      .map_err(SqliteStoreError::from)?;
+    self.checked(rows)
+  }
+
+  fn acknowledge_commit(&mut self, commit_id: Uuid, consumer: &str) -> Result<(), Box<dyn StoreError>> {
+    self
+      .conn
+      .execute(
+        "INSERT OR IGNORE INTO dispatch_acknowledgements (commit_id, consumer, acknowledged_at)
+          VALUES (?, ?, ?);",
+        [&commit_id.to_string() as &dyn ToSql, &consumer, &Utc::now()],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(())
+  }
+
+  fn get_acknowledgements(&mut self, commit_id: Uuid) -> Result<Vec<String>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(
+        "SELECT consumer FROM dispatch_acknowledgements
+          WHERE commit_id = ?
+          ORDER BY consumer;",
+      )
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map([&commit_id.to_string()], |row| row.get(0))
+      .map_err(SqliteStoreError::from)?;
+    let consumers = rows
+      .collect::<Result<Vec<String>, RusqliteError>>()
+      .map_err(SqliteStoreError::from)?;
+    Ok(consumers)
+  }
+
+  fn truncate_through(
+    &mut self,
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    let transaction = self
+      .conn
+      .unchecked_transaction()
+      .map_err(SqliteStoreError::from)?;
+    let params = [&aggregate_id.to_string() as &dyn ToSql, &commit_sequence];
+    for table in ["events", "dead_letters", "dispatch_acknowledgements"] {
+      transaction
+        .execute(
+          &format!(
+            "DELETE FROM {} WHERE commit_id IN (
+              SELECT commit_id FROM commits WHERE aggregate_id = ?1 AND commit_sequence <= ?2
+            );",
+            table
+          ),
+          params,
+        )
+        .map_err(SqliteStoreError::from)?;
+    }
+    let truncated = transaction
+      .execute(
+        "DELETE FROM commits WHERE aggregate_id = ?1 AND commit_sequence <= ?2;",
+        params,
+      )
+      .map_err(SqliteStoreError::from)?;
+    transaction.commit().map_err(SqliteStoreError::from)?;
+    Ok(truncated)
+  }
+
   fn get_commit(&mut self, commit_id: &Uuid) -> Result<Commit, Box<dyn StoreError>> {
-    let mut statement = match self.conn.prepare(
-      "SELECT
-          aggregate_id,
-          aggregate_version,
-          commit_id,
-          commit_timestamp,
-          commit_sequence,
-          commit_number,
-          events_count,
-          metadata,
-          events,
-          dispatched
-        FROM commits
+    let mut statement = match self.conn.prepare(concat!(
+      "SELECT ",
+      commit_columns!(),
+      " FROM commits
         WHERE commit_id = ?
-        ORDER BY commit_number ASC;",
-    ) {
-      Ok(result) => result,
-      Err(err) => return Err(SqliteStoreError::from(err).into()),
-    };
-    let commit: Commit = match statement.query_row(&[&commit_id.to_string()], |row| {
-      let aggregate_id: String = row.get(0).expect("no aggregate_id column in result row");
-      let commit_id: String = row.get(2).expect("no commit_id column in result row");
-      Ok(Commit {
-        aggregate_id: Uuid::parse_str(aggregate_id.as_ref()).unwrap(),
-        aggregate_version: row
-          .get(1)
-          .expect("no aggregate_version column in result row"),
-        commit_id: Uuid::parse_str(commit_id.as_ref()).unwrap(),
-        commit_timestamp: row
-          .get(3)
-          .expect("no commit_timestamp column in result row"),
-        commit_sequence: row.get(4).expect("no commit_sequence column in result row"),
-        commit_number: row.get(5).expect("no commit_number column in result row"),
-        events_count: row.get(6).expect("no events_count column in result row"),
-        serialized_metadata: row
-          .get(7)
-          .expect("no serialized_metadata column in result row"),
-        serialized_events: row
-          .get(8)
-          .expect("no serialized_events column in result row"),
-        dispatched: row.get(9).expect("no dispatched column in result row"),
-      })
-    }) {
+        ORDER BY commit_number ASC;"
+    )) {
       Ok(result) => result,
       Err(err) => return Err(SqliteStoreError::from(err).into()),
     };
+    let (commit, checksum) =
+      match statement.query_row([&commit_id.to_string()], checked_commit_from_row) {
+        Ok(result) => result,
+        Err(err) => return Err(SqliteStoreError::from(err).into()),
+      };
+    self.checksum_verification.verify(&commit, checksum)?;
     Ok(commit)
   }
+
+  fn backup_to(&self, path: &Path) -> Result<(), Box<dyn StoreError>> {
+    match self.conn.backup(MAIN_DB, path, None) {
+      Ok(_) => Ok(()),
+      Err(err) => Err(SqliteStoreError::from(err).into()),
+    }
+  }
+
+  fn get_commits_after(
+    &self,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(concat!(
+        "SELECT ",
+        commit_columns!(),
+        " FROM commits
+          WHERE commit_number > ?
+          ORDER BY commit_number ASC
+          LIMIT ?;"
+      ))
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map([commit_number, limit], checked_commit_from_row)
+      .map_err(SqliteStoreError::from)?;
+    self.checked(rows)
+  }
+
+  fn get_category_range(
+    &self,
+    category: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(concat!(
+        "SELECT ",
+        commit_columns!(),
+        " FROM commits
+          WHERE category = ?
+          AND commit_number > ?
+          ORDER BY commit_number ASC
+          LIMIT ?;"
+      ))
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map(
+        [&category as &dyn ToSql, &commit_number, &limit],
+        checked_commit_from_row,
+      )
+      .map_err(SqliteStoreError::from)?;
+    self.checked(rows)
+  }
+
+  fn get_tenant_range(
+    &self,
+    tenant_id: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut stmt = self
+      .conn
+      .prepare(concat!(
+        "SELECT ",
+        commit_columns!(),
+        " FROM commits
+          WHERE tenant_id = ?
+          AND commit_number > ?
+          ORDER BY commit_number ASC
+          LIMIT ?;"
+      ))
+      .map_err(SqliteStoreError::from)?;
+    let rows = stmt
+      .query_map(
+        [&tenant_id as &dyn ToSql, &commit_number, &limit],
+        checked_commit_from_row,
+      )
+      .map_err(SqliteStoreError::from)?;
+    self.checked(rows)
+  }
+
+  fn load_checkpoint(&self, name: &str) -> Result<Option<i64>, Box<dyn StoreError>> {
+    let commit_number = self
+      .conn
+      .query_row(
+        "SELECT commit_number FROM checkpoints WHERE name = ?",
+        [name],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(SqliteStoreError::from)?;
+    Ok(commit_number)
+  }
+
+  fn save_checkpoint(&mut self, name: &str, commit_number: i64) -> Result<(), Box<dyn StoreError>> {
+    self
+      .conn
+      .execute(
+        "INSERT OR REPLACE INTO checkpoints (name, commit_number, updated_at) VALUES (?, ?, ?)",
+        [&name as &dyn ToSql, &commit_number, &Utc::now()],
+      )
+      .map_err(SqliteStoreError::from)?;
+    Ok(())
+  }
+}
+
+impl InlineProjectionStore for SqliteStore {
+  type Transaction = RusqliteConnection;
+
+  fn add_inline_projection(
+    &mut self,
+    projection: Box<dyn InlineProjection<RusqliteConnection> + Send>,
+  ) {
+    self.inline_projections.push(projection);
+  }
 }
 
 #[cfg(test)]
 mod tests {
+  use super::super::super::codec;
   use super::super::super::commit::*;
+  use super::super::super::snapshot::compression::Compression;
+  use super::super::super::snapshot::memory::InMemorySnapshotStore;
+  use super::super::super::snapshot::{Snapshot, SnapshotStore};
   use super::super::super::store::*;
+  use super::super::super::subscription::EventTypeFilter;
   use chrono::Utc;
   use uuid::Uuid;
   #[test]
@@ -332,6 +1230,9 @@ mod tests {
     let commit_attempt = CommitAttempt {
       aggregate_id: Uuid::new_v4(),
       aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: 0,
       commit_timestamp: Utc::now(),
@@ -352,6 +1253,9 @@ mod tests {
     let commit_attempt2 = CommitAttempt {
       aggregate_id: commit_attempt.aggregate_id,
       aggregate_version: commit_attempt.aggregate_version + 1,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: commit_attempt.commit_sequence + 1,
       commit_timestamp: Utc::now(),
@@ -381,6 +1285,9 @@ mod tests {
     let commit_attempt = CommitAttempt {
       aggregate_id: Uuid::new_v4(),
       aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: 0,
       commit_timestamp: Utc::now(),
@@ -401,6 +1308,9 @@ mod tests {
     let commit_attempt2 = CommitAttempt {
       aggregate_id: commit_attempt.aggregate_id,
       aggregate_version: commit_attempt.aggregate_version + 1,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: commit_attempt.commit_sequence,
       commit_timestamp: Utc::now(),
@@ -422,6 +1332,9 @@ mod tests {
     let commit_attempt = CommitAttempt {
       aggregate_id: Uuid::new_v4(),
       aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: 0,
       commit_timestamp: Utc::now(),
@@ -442,6 +1355,9 @@ mod tests {
     let commit_attempt2 = CommitAttempt {
       aggregate_id: commit_attempt.aggregate_id,
       aggregate_version: commit_attempt.aggregate_version,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: commit_attempt.commit_sequence + 1,
       commit_timestamp: Utc::now(),
@@ -462,6 +1378,9 @@ mod tests {
     let commit_attempt = CommitAttempt {
       aggregate_id: Uuid::new_v4(),
       aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: Uuid::new_v4(),
       commit_sequence: 0,
       commit_timestamp: Utc::now(),
@@ -482,6 +1401,9 @@ mod tests {
     let commit_attempt2 = CommitAttempt {
       aggregate_id: commit_attempt.aggregate_id,
       aggregate_version: commit_attempt.aggregate_version + 1,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
       commit_id: commit_attempt.commit_id,
       commit_sequence: commit_attempt.commit_sequence + 1,
       commit_timestamp: Utc::now(),
@@ -495,4 +1417,652 @@ mod tests {
       s.commit(&commit_attempt2).err().unwrap().error_type()
     );
   }
+
+  #[test]
+  fn it_commits_to_several_aggregates_all_or_nothing() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let commit_attempt = |aggregate_id: Uuid, aggregate_version: i64| CommitAttempt {
+      aggregate_id,
+      aggregate_version,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: aggregate_version + 1,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    let order_id = Uuid::new_v4();
+    let stock_id = Uuid::new_v4();
+    let commit_numbers = s
+      .commit_all(&[commit_attempt(order_id, 0), commit_attempt(stock_id, 0)])
+      .unwrap();
+    assert_eq!(commit_numbers, vec![1, 2]);
+
+    let conflicting = s.commit_all(&[commit_attempt(order_id, 1), commit_attempt(stock_id, 0)]);
+    assert_eq!(
+      StoreErrorType::DuplicateWriteError(StorageCommitConflict::AggregateVersionConflict),
+      conflicting.err().unwrap().error_type()
+    );
+    assert_eq!(s.get_range(order_id, 0, i64::MAX).unwrap().len(), 1);
+    assert_eq!(s.get_range(stock_id, 0, i64::MAX).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn it_is_unhealthy_until_initialized() {
+    let s = sqlite::SqliteStore::with_new_in_memory_connection();
+    assert!(s.health_check().is_err());
+    s.initialize();
+    assert!(s.health_check().is_ok());
+  }
+
+  #[test]
+  fn it_migrates_to_the_latest_schema_version() {
+    let s = sqlite::SqliteStore::with_new_in_memory_connection();
+    assert_eq!(s.schema_version().unwrap(), 0);
+    let version = s.migrate().unwrap();
+    assert!(version > 0);
+    assert_eq!(s.schema_version().unwrap(), version);
+    assert_eq!(s.migrate().unwrap(), version);
+  }
+
+  #[test]
+  fn it_returns_ranges_in_commit_sequence_order() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for commit_sequence in &[3, 1, 2] {
+      s.commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version: commit_sequence - 1,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_sequence: *commit_sequence,
+        commit_timestamp: Utc::now(),
+        events_count: 1,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: b"[\"hi\"]".to_vec(),
+      })
+      .unwrap();
+    }
+    let sequences: Vec<i64> = s
+      .get_range(aggregate_id, 0, i64::MAX)
+      .unwrap()
+      .iter()
+      .map(|commit| commit.commit_sequence)
+      .collect();
+    assert_eq!(sequences, vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn it_filters_commits_by_recorded_event_types() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for (version, events) in [r#"["Opened"]"#, r#"[{"Deposited": 5}]"#, r#"["Closed"]"#]
+      .iter()
+      .enumerate()
+    {
+      s.commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version: version as i64,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_sequence: version as i64,
+        commit_timestamp: Utc::now(),
+        events_count: 1,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: events.as_bytes().to_vec(),
+      })
+      .unwrap();
+    }
+    // As if committed before event types were recorded.
+    s.conn
+      .execute("UPDATE commits SET event_types = NULL WHERE aggregate_version = 2", [])
+      .unwrap();
+    let versions = |filter: EventTypeFilter| -> Vec<i64> {
+      s.get_range_of_event_types(aggregate_id, 0, 10, &filter)
+        .unwrap()
+        .iter()
+        .map(|commit| commit.aggregate_version)
+        .collect()
+    };
+
+    assert_eq!(versions(EventTypeFilter::new(vec!["Deposited"])), vec![1]);
+    assert_eq!(versions(EventTypeFilter::new(vec!["Opened", "Closed"])), vec![0, 2]);
+    assert!(versions(EventTypeFilter::new(vec!["Renamed"])).is_empty());
+  }
+
+  #[test]
+  fn it_migrates_databases_created_before_versioning() {
+    let connection = rusqlite::Connection::open_in_memory().unwrap();
+    connection
+      .execute_batch(
+        "CREATE TABLE commits (
+          aggregate_id      VARCHAR(36) NOT NULL,
+          aggregate_version INTEGER NOT NULL,
+          commit_id         VARCHAR(36) NOT NULL,
+          commit_sequence   INTEGER NOT NULL,
+          commit_number     INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+          commit_timestamp  DATETIME NOT NULL,
+          events_count      INTEGER NOT NULL,
+          metadata          BLOB NOT NULL,
+          events            BLOB NOT NULL,
+          dispatched        INTEGER NOT NULL DEFAULT 0
+        );",
+      )
+      .unwrap();
+    let mut s = sqlite::SqliteStore::with_connection(connection);
+    s.migrate().unwrap();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    assert_eq!(s.commit(&commit_attempt).unwrap(), 1);
+  }
+
+  #[test]
+  fn it_reports_non_conflict_failures_as_unknown_errors() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    assert_eq!(
+      StoreErrorType::UnknownError,
+      s.commit(&commit_attempt).err().unwrap().error_type()
+    );
+  }
+
+  #[test]
+  fn it_backs_up_to_a_file() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    s.commit(&commit_attempt).unwrap();
+
+    let path = ::std::env::temp_dir().join(format!("event_source_backup_{}.sqlite", Uuid::new_v4()));
+    s.backup_to(&path).unwrap();
+    let backup = sqlite::SqliteStore::with_new_connection_at_path(&path);
+    let commits = backup.get_range(commit_attempt.aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits.len(), 1);
+    assert_eq!(commits[0].commit_id, commit_attempt.commit_id);
+    ::std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn it_archives_dispatched_prefixes_of_each_aggregate() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let mut archive = sqlite::SqliteStore::with_new_in_memory_connection();
+    archive.initialize();
+    let aggregate_id = Uuid::new_v4();
+    let mut commit_ids = Vec::new();
+    for sequence in 0..3 {
+      let commit_attempt = CommitAttempt {
+        aggregate_id,
+        aggregate_version: sequence,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id: None,
+        commit_id: Uuid::new_v4(),
+        commit_sequence: sequence,
+        commit_timestamp: Utc::now(),
+        events_count: 1,
+        serialized_metadata: String::from("\"metadata\"").into_bytes(),
+        serialized_events: String::from("[\"hi\"]").into_bytes(),
+      };
+      s.commit(&commit_attempt).unwrap();
+      commit_ids.push(commit_attempt.commit_id);
+    }
+    s.mark_commit_as_dispatched(commit_ids[0]).unwrap();
+    s.mark_commit_as_dispatched(commit_ids[2]).unwrap();
+    let mut snapshot_store = InMemorySnapshotStore::default();
+    snapshot_store
+      .save_snapshot(&archive_snapshot(aggregate_id, 2))
+      .unwrap();
+
+    assert_eq!(
+      s.archive_before(10, &snapshot_store, &mut archive).unwrap(),
+      1
+    );
+    assert_eq!(s.get_range(aggregate_id, 0, 10).unwrap().len(), 2);
+    let archived = archive.get_range(aggregate_id, 0, 10).unwrap();
+    assert_eq!(archived.len(), 1);
+    assert_eq!(archived[0].commit_id, commit_ids[0]);
+  }
+
+  #[test]
+  fn it_archives_only_commits_covered_by_a_snapshot() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let mut archive = sqlite::SqliteStore::with_new_in_memory_connection();
+    archive.initialize();
+    let snapshotted_id = Uuid::new_v4();
+    let unsnapshotted_id = Uuid::new_v4();
+    for aggregate_id in [snapshotted_id, unsnapshotted_id] {
+      for sequence in 0..4 {
+        let commit_attempt = CommitAttempt {
+          aggregate_id,
+          aggregate_version: sequence,
+          category: String::new(),
+          tenant_id: String::new(),
+          parent_commit_id: None,
+          commit_id: Uuid::new_v4(),
+          commit_sequence: sequence,
+          commit_timestamp: Utc::now(),
+          events_count: 1,
+          serialized_metadata: String::from("\"metadata\"").into_bytes(),
+          serialized_events: String::from("[\"hi\"]").into_bytes(),
+        };
+        s.commit(&commit_attempt).unwrap();
+        s.mark_commit_as_dispatched(commit_attempt.commit_id).unwrap();
+      }
+    }
+    let mut snapshot_store = InMemorySnapshotStore::default();
+    snapshot_store
+      .save_snapshot(&archive_snapshot(snapshotted_id, 1))
+      .unwrap();
+
+    assert_eq!(
+      s.archive_before(10, &snapshot_store, &mut archive).unwrap(),
+      2
+    );
+    let kept = s.get_range(snapshotted_id, 0, 10).unwrap();
+    assert_eq!(
+      kept.iter().map(|c| c.commit_sequence).collect::<Vec<_>>(),
+      vec![2, 3]
+    );
+    assert_eq!(s.get_range(unsnapshotted_id, 0, 10).unwrap().len(), 4);
+    assert!(archive.get_range(unsnapshotted_id, 0, 10).unwrap().is_empty());
+
+    snapshot_store
+      .save_snapshot(&archive_snapshot(snapshotted_id, 3))
+      .unwrap();
+    assert_eq!(
+      s.archive_before(10, &snapshot_store, &mut archive).unwrap(),
+      1
+    );
+    let kept = s.get_range(snapshotted_id, 0, 10).unwrap();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].commit_sequence, 3);
+  }
+
+  fn archive_snapshot(aggregate_id: Uuid, commit_sequence: i64) -> Snapshot {
+    Snapshot {
+      aggregate_id,
+      aggregate_version: commit_sequence,
+      commit_sequence,
+      schema_version: 0,
+      snapshot_timestamp: Utc::now(),
+      compression: Compression::None,
+      serialized_state: b"{}".to_vec(),
+    }
+  }
+
+  #[test]
+  fn it_detects_commits_corrupted_since_they_were_written() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    s.commit(&commit_attempt).unwrap();
+    assert_eq!(s.get_commit(&commit_attempt.commit_id).unwrap().commit_id, commit_attempt.commit_id);
+
+    s.conn
+      .execute("UPDATE commits SET events = CAST('[\"ho\"]' AS BLOB);", [])
+      .unwrap();
+    let err = s.get_range(commit_attempt.aggregate_id, 0, 1).unwrap_err();
+    assert_eq!(err.error_type(), StoreErrorType::ChecksumMismatch);
+    let err = s.get_commit(&commit_attempt.commit_id).unwrap_err();
+    assert_eq!(err.error_type(), StoreErrorType::ChecksumMismatch);
+    match s.verify().unwrap().issues[..] {
+      [verify::IntegrityIssue::ChecksumMismatch { commit_number: 1, .. }] => (),
+      ref issues => panic!("unexpected issues {:?}", issues),
+    }
+
+    let s = s.with_checksum_verification(ChecksumVerification::LogOnly);
+    let commits = s.get_range(commit_attempt.aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits[0].serialized_events, b"[\"ho\"]");
+
+    // Event rows read back without the whitespace between events.
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection().with_event_rows();
+    s.initialize();
+    s.commit(&CommitAttempt {
+      serialized_events: String::from("[1, 2]").into_bytes(),
+      events_count: 2,
+      ..commit_attempt.clone()
+    })
+    .unwrap();
+    let commits = s.get_range(commit_attempt.aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits[0].serialized_events, b"[1,2]");
+  }
+
+  #[test]
+  fn it_stores_events_as_rows_when_enabled() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection().with_event_rows();
+    s.initialize();
+    s.create_event_index("events_type_idx", "$.type").unwrap();
+    let serialized_events = String::from("[{\"type\":\"Opened\"},{\"type\":\"Renamed\"}]").into_bytes();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 2,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: serialized_events.clone(),
+    };
+    s.commit(&commit_attempt).unwrap();
+
+    let commits = s.get_range(commit_attempt.aggregate_id, 0, 1).unwrap();
+    assert_eq!(commits[0].serialized_events, serialized_events);
+    let renamed: i64 = s
+      .conn
+      .query_row(
+        "SELECT COUNT(*) FROM events WHERE json_extract(payload, '$.type') = 'Renamed'",
+        &[] as &[&dyn rusqlite::ToSql],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert_eq!(renamed, 1);
+  }
+
+  struct UniqueNames;
+
+  impl InlineProjection<rusqlite::Connection> for UniqueNames {
+    fn name(&self) -> String {
+      String::from("unique_names")
+    }
+
+    fn apply(&mut self, transaction: &rusqlite::Connection, commit: &Commit) -> Result<(), String> {
+      transaction
+        .execute(
+          "INSERT INTO names (name) VALUES (?)",
+          [&commit.serialized_metadata],
+        )
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+    }
+  }
+
+  #[test]
+  fn it_applies_inline_projections_in_the_commit_transaction() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    s.conn
+      .execute_batch("CREATE TABLE names (name BLOB PRIMARY KEY NOT NULL);")
+      .unwrap();
+    s.add_inline_projection(Box::new(UniqueNames));
+    let attempt = |metadata: &str| CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 0,
+      serialized_metadata: String::from(metadata).into_bytes(),
+      serialized_events: b"[]".to_vec(),
+    };
+    s.commit(&attempt("\"ann\"")).unwrap();
+    let duplicate = attempt("\"ann\"");
+    let err = s.commit(&duplicate).unwrap_err();
+    assert_eq!(err.error_type(), StoreErrorType::UnknownError);
+    assert!(s
+      .get_range(duplicate.aggregate_id, 0, i64::MAX)
+      .unwrap()
+      .is_empty());
+    s.commit(&attempt("\"bob\"")).unwrap();
+    let names: i64 = s
+      .conn
+      .query_row(
+        "SELECT COUNT(*) FROM names",
+        &[] as &[&dyn rusqlite::ToSql],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert_eq!(names, 2);
+  }
+
+  #[test]
+  fn it_verifies_commit_integrity() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 1,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 1,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    s.commit(&commit_attempt).unwrap();
+    assert!(s.verify().unwrap().is_ok());
+
+    let commit_attempt2 = CommitAttempt {
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 2,
+      events_count: 2,
+      ..commit_attempt.clone()
+    };
+    s.commit(&commit_attempt2).unwrap();
+    let report = s.verify().unwrap();
+    assert_eq!(report.commits_checked, 2);
+    assert_eq!(
+      report.issues,
+      vec![
+        verify::IntegrityIssue::NonMonotonicAggregateVersion {
+          commit_number: 2,
+          aggregate_id: commit_attempt.aggregate_id.to_string(),
+          previous: 1,
+          current: 0,
+        },
+        verify::IntegrityIssue::EventsCountMismatch {
+          commit_number: 2,
+          recorded: 2,
+          actual: 1,
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn it_verifies_encrypted_commits_without_decoding_their_events() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: vec![codec::ENCRYPTED, 0x8e, 0x21, 0x5a],
+    };
+    s.commit(&commit_attempt).unwrap();
+    let shredded = CommitAttempt {
+      aggregate_version: 1,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 1,
+      serialized_events: vec![codec::SHREDDABLE, 0x8e, 0x21, 0x5a],
+      ..commit_attempt.clone()
+    };
+    s.commit(&shredded).unwrap();
+    assert!(s.verify().unwrap().is_ok());
+
+    let unknown = CommitAttempt {
+      aggregate_version: 2,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 2,
+      serialized_events: vec![0x1f],
+      ..commit_attempt.clone()
+    };
+    s.commit(&unknown).unwrap();
+    let undecodable = vec![verify::IntegrityIssue::UndecodableEvents { commit_number: 3 }];
+    assert_eq!(s.verify().unwrap().issues, undecodable);
+
+    #[cfg(feature = "avro")]
+    {
+      let avro = CommitAttempt {
+        aggregate_id: Uuid::new_v4(),
+        commit_id: Uuid::new_v4(),
+        serialized_events: vec![codec::Avro::CONTENT_TYPE, 0, 0, 0, 0, 1, 2],
+        ..commit_attempt.clone()
+      };
+      s.commit(&avro).unwrap();
+      assert_eq!(s.verify().unwrap().issues, undecodable);
+    }
+  }
+
+  #[test]
+  fn it_verifies_commits_stored_as_event_rows() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection()
+      .with_event_rows()
+      .with_checksum_verification(ChecksumVerification::Strict);
+    s.initialize();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 2,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[{\"type\": \"Opened\"}, {\"type\": \"Renamed\"}]")
+        .into_bytes(),
+    };
+    s.commit(&commit_attempt).unwrap();
+
+    let commits = s.get_range(commit_attempt.aggregate_id, 0, 0).unwrap();
+    assert_eq!(commits.len(), 1);
+    assert_ne!(commits[0].serialized_events, commit_attempt.serialized_events);
+    let commit = s.get_commit(&commit_attempt.commit_id).unwrap();
+    assert_eq!(commit.checksum(), commits[0].checksum());
+    let report = s.verify().unwrap();
+    assert_eq!(report.commits_checked, 1);
+    assert!(report.is_ok(), "unexpected issues {:?}", report.issues);
+  }
+
+  #[test]
+  fn it_verifies_each_commit_follows_its_parent() {
+    let mut s = sqlite::SqliteStore::with_new_in_memory_connection();
+    s.initialize();
+    let aggregate_id = Uuid::new_v4();
+    let mut parent_commit_id = None;
+    let mut commit_ids = Vec::new();
+    for version in 0..3 {
+      let commit_id = Uuid::new_v4();
+      s.commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version: version,
+        category: String::new(),
+        tenant_id: String::new(),
+        parent_commit_id,
+        commit_id,
+        commit_sequence: version + 1,
+        commit_timestamp: Utc::now(),
+        events_count: 1,
+        serialized_metadata: String::from("null").into_bytes(),
+        serialized_events: String::from("[\"hi\"]").into_bytes(),
+      })
+      .unwrap();
+      parent_commit_id = Some(commit_id);
+      commit_ids.push(commit_id);
+    }
+    assert_eq!(s.get_commit(&commit_ids[2]).unwrap().parent_commit_id, Some(commit_ids[1]));
+    assert!(s.verify().unwrap().is_ok());
+
+    // A truncated history starts from a commit whose parent is gone.
+    s.truncate_through(aggregate_id, 1).unwrap();
+    assert!(s.verify().unwrap().is_ok());
+
+    // Dropping the checksum leaves the chain as the only fault.
+    let forged = Uuid::new_v4();
+    s.conn
+      .execute(
+        "UPDATE commits SET parent_commit_id = ?, checksum = NULL WHERE commit_id = ?",
+        [&forged.to_string(), &commit_ids[2].to_string()],
+      )
+      .unwrap();
+    assert_eq!(
+      s.verify().unwrap().issues,
+      vec![verify::IntegrityIssue::BrokenCommitChain {
+        commit_number: 3,
+        aggregate_id: aggregate_id.to_string(),
+        parent_commit_id: forged.to_string(),
+        previous_commit_id: commit_ids[1].to_string(),
+      }]
+    );
+  }
 }
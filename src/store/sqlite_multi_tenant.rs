@@ -0,0 +1,118 @@
+use super::sqlite::{SqliteStore, SqliteStoreError};
+use super::Store;
+use rusqlite::{Connection as RusqliteConnection, Error as RusqliteError};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const TENANT_FILE_EXTENSION: &str = "sqlite";
+
+// Keeps one SQLite database file per tenant in a single directory. Each tenant's
+// `SqliteStore` is opened and migrated the first time it is requested.
+pub struct MultiTenantSqliteStore {
+  directory: PathBuf,
+  tenants: HashMap<String, SqliteStore>,
+}
+
+impl MultiTenantSqliteStore {
+  pub fn with_directory(directory: &Path) -> io::Result<Self> {
+    fs::create_dir_all(directory)?;
+    Ok(MultiTenantSqliteStore {
+      directory: directory.to_path_buf(),
+      tenants: HashMap::new(),
+    })
+  }
+
+  pub fn tenant_path(&self, tenant: &str) -> Result<PathBuf, SqliteStoreError> {
+    let valid = !tenant.is_empty()
+      && tenant
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    let path = self
+      .directory
+      .join(format!("{}.{}", tenant, TENANT_FILE_EXTENSION));
+    if !valid {
+      return Err(SqliteStoreError::from(RusqliteError::InvalidPath(path)));
+    }
+    Ok(path)
+  }
+
+  pub fn tenant(&mut self, tenant: &str) -> Result<&mut SqliteStore, SqliteStoreError> {
+    if !self.tenants.contains_key(tenant) {
+      let path = self.tenant_path(tenant)?;
+      let store = SqliteStore::with_connection(RusqliteConnection::open(path)?);
+      store.migrate()?;
+      self.tenants.insert(tenant.to_string(), store);
+    }
+    Ok(self.tenants.get_mut(tenant).unwrap())
+  }
+
+  pub fn tenants(&self) -> io::Result<Vec<String>> {
+    let mut tenants = Vec::new();
+    for entry in fs::read_dir(&self.directory)? {
+      let path = entry?.path();
+      if path.extension().and_then(|e| e.to_str()) != Some(TENANT_FILE_EXTENSION) {
+        continue;
+      }
+      if let Some(tenant) = path.file_stem().and_then(|s| s.to_str()) {
+        tenants.push(tenant.to_string());
+      }
+    }
+    tenants.sort();
+    Ok(tenants)
+  }
+
+  pub fn close_tenant(&mut self, tenant: &str) -> Option<SqliteStore> {
+    self.tenants.remove(tenant)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::super::commit::*;
+  use super::super::super::store::*;
+  use super::MultiTenantSqliteStore;
+  use chrono::Utc;
+  use std::env;
+  use std::fs;
+  use uuid::Uuid;
+
+  #[test]
+  fn it_keeps_tenants_isolated() {
+    let directory = env::temp_dir().join(format!("event_source_tenants_{}", Uuid::new_v4()));
+    let mut stores = MultiTenantSqliteStore::with_directory(&directory).unwrap();
+    let commit_attempt = CommitAttempt {
+      aggregate_id: Uuid::new_v4(),
+      aggregate_version: 0,
+      category: String::new(),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_sequence: 0,
+      commit_timestamp: Utc::now(),
+      events_count: 1,
+      serialized_metadata: String::from("\"metadata\"").into_bytes(),
+      serialized_events: String::from("[\"hi\"]").into_bytes(),
+    };
+    stores.tenant("acme").unwrap().commit(&commit_attempt).unwrap();
+    stores.tenant("globex").unwrap().commit(&commit_attempt).unwrap();
+
+    assert_eq!(
+      stores
+        .tenant("acme")
+        .unwrap()
+        .get_range(commit_attempt.aggregate_id, 0, 1)
+        .unwrap()
+        .len(),
+      1
+    );
+    assert_eq!(
+      stores.tenants().unwrap(),
+      vec![String::from("acme"), String::from("globex")]
+    );
+    assert!(stores.tenant("../escape").is_err());
+
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
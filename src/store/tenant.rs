@@ -0,0 +1,422 @@
+use super::{PoisonedCommit, Store, StoreError, StoreErrorType, UnsupportedOperationError};
+use crate::commit::{Commit, CommitAttempt};
+use crate::subscription::EventTypeFilter;
+use std::error;
+use std::fmt;
+use std::path::Path;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct ForeignTenantError {
+  pub tenant_id: String,
+}
+
+impl fmt::Display for ForeignTenantError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ForeignTenantError({})", self.tenant_id)
+  }
+}
+
+impl error::Error for ForeignTenantError {}
+
+impl StoreError for ForeignTenantError {
+  fn error_type(&self) -> StoreErrorType {
+    StoreErrorType::Rejected(format!("outside tenant {}", self.tenant_id))
+  }
+}
+
+impl From<ForeignTenantError> for Box<dyn StoreError> {
+  fn from(error: ForeignTenantError) -> Box<dyn StoreError> {
+    Box::new(error)
+  }
+}
+
+// A session on a store shared between tenants, which reads and writes only one
+// tenant's commits. Commits are stamped with the tenant; reads leave out other
+// tenants' commits, and operations on them fail with a `ForeignTenantError`.
+// Checkpoints and acknowledgements are kept under names prefixed with the
+// tenant, so each tenant's consumers are apart from every other's.
+pub struct TenantStore<S: Store> {
+  store: S,
+  tenant_id: String,
+}
+
+impl<S: Store> TenantStore<S> {
+  pub fn new(store: S, tenant_id: &str) -> TenantStore<S> {
+    TenantStore {
+      store,
+      tenant_id: String::from(tenant_id),
+    }
+  }
+
+  pub fn tenant_id(&self) -> &str {
+    &self.tenant_id
+  }
+
+  pub fn store(&self) -> &S {
+    &self.store
+  }
+
+  fn foreign(&self) -> Box<dyn StoreError> {
+    ForeignTenantError {
+      tenant_id: self.tenant_id.clone(),
+    }
+    .into()
+  }
+
+  fn owns(&self, commit: &Commit) -> bool {
+    commit.tenant_id == self.tenant_id
+  }
+
+  fn owned(&self, mut commits: Vec<Commit>) -> Vec<Commit> {
+    commits.retain(|commit| self.owns(commit));
+    commits
+  }
+
+  fn prefixed(&self, name: &str) -> String {
+    format!("{}/{}", self.tenant_id, name)
+  }
+
+  // An aggregate belongs to the tenant of its commits. All of them are read, as
+  // its first may have been truncated.
+  fn stamp(&self, commit_attempt: &CommitAttempt) -> Result<CommitAttempt, Box<dyn StoreError>> {
+    if !commit_attempt.tenant_id.is_empty() && commit_attempt.tenant_id != self.tenant_id {
+      return Err(self.foreign());
+    }
+    self.check_aggregate_owned(commit_attempt.aggregate_id)?;
+    let mut stamped = commit_attempt.clone();
+    stamped.tenant_id = self.tenant_id.clone();
+    Ok(stamped)
+  }
+
+  fn check_aggregate_owned(&self, aggregate_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    let commits = self.store.get_range(aggregate_id, 0, i64::MAX)?;
+    if commits.iter().any(|commit| !self.owns(commit)) {
+      return Err(self.foreign());
+    }
+    Ok(())
+  }
+
+  fn check_owned(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    let commit = self.store.get_commit(&commit_id)?;
+    if self.owns(&commit) {
+      Ok(())
+    } else {
+      Err(self.foreign())
+    }
+  }
+
+  fn is_owned(&mut self, commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    let commit = self.store.get_commit(&commit_id)?;
+    Ok(self.owns(&commit))
+  }
+}
+
+impl<S: Store> Store for TenantStore<S> {
+  type Connection = (S::Connection, String);
+
+  fn with_connection((connection, tenant_id): Self::Connection) -> Self {
+    TenantStore::new(S::with_connection(connection), &tenant_id)
+  }
+
+  fn commit(&mut self, commit_attempt: &CommitAttempt) -> Result<i64, Box<dyn StoreError>> {
+    let stamped = self.stamp(commit_attempt)?;
+    self.store.commit(&stamped)
+  }
+
+  fn commit_all(
+    &mut self,
+    commit_attempts: &[CommitAttempt],
+  ) -> Result<Vec<i64>, Box<dyn StoreError>> {
+    let stamped = commit_attempts
+      .iter()
+      .map(|commit_attempt| self.stamp(commit_attempt))
+      .collect::<Result<Vec<CommitAttempt>, Box<dyn StoreError>>>()?;
+    self.store.commit_all(&stamped)
+  }
+
+  fn get_range(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self.store.get_range(aggregate_id, min_version, max_version)?;
+    Ok(self.owned(commits))
+  }
+
+  fn get_range_of_event_types(
+    &self,
+    aggregate_id: Uuid,
+    min_version: i64,
+    max_version: i64,
+    event_types: &EventTypeFilter,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self
+      .store
+      .get_range_of_event_types(aggregate_id, min_version, max_version, event_types)?;
+    Ok(self.owned(commits))
+  }
+
+  fn get_undispatched_commits(&mut self) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let commits = self.store.get_undispatched_commits()?;
+    Ok(self.owned(commits))
+  }
+
+  // Reads every undispatched commit, so other tenants' can't crowd this one's
+  // out of the limit.
+  fn get_undispatched_commits_up_to(
+    &mut self,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut commits = self.get_undispatched_commits()?;
+    commits.truncate(limit.max(0) as usize);
+    Ok(commits)
+  }
+
+  fn mark_commit_as_dispatched(&mut self, commit_id: Uuid) -> Result<(), Box<dyn StoreError>> {
+    self.check_owned(commit_id)?;
+    self.store.mark_commit_as_dispatched(commit_id)
+  }
+
+  fn get_commit(&mut self, commit_id: &Uuid) -> Result<Commit, Box<dyn StoreError>> {
+    let commit = self.store.get_commit(commit_id)?;
+    if self.owns(&commit) {
+      Ok(commit)
+    } else {
+      Err(self.foreign())
+    }
+  }
+
+  fn health_check(&self) -> Result<(), Box<dyn StoreError>> {
+    self.store.health_check()
+  }
+
+  // A backup would hold every tenant's commits.
+  fn backup_to(&self, _path: &Path) -> Result<(), Box<dyn StoreError>> {
+    Err(UnsupportedOperationError { operation: "backup_to" }.into())
+  }
+
+  fn get_commits_after(
+    &self,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    self
+      .store
+      .get_tenant_range(&self.tenant_id, commit_number, limit)
+  }
+
+  // Pages through the category until it has `limit` of this tenant's commits, so
+  // a page of other tenants' commits doesn't read as the end of the category.
+  fn get_category_range(
+    &self,
+    category: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let mut commits = Vec::new();
+    let mut after = commit_number;
+    while (commits.len() as i64) < limit {
+      let page = self.store.get_category_range(category, after, limit)?;
+      let exhausted = (page.len() as i64) < limit;
+      after = match page.last() {
+        Some(commit) => commit.commit_number,
+        None => break,
+      };
+      commits.extend(self.owned(page));
+      if exhausted {
+        break;
+      }
+    }
+    commits.truncate(limit.max(0) as usize);
+    Ok(commits)
+  }
+
+  fn get_tenant_range(
+    &self,
+    tenant_id: &str,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    if tenant_id != self.tenant_id {
+      return Err(self.foreign());
+    }
+    self.store.get_tenant_range(tenant_id, commit_number, limit)
+  }
+
+  fn load_checkpoint(&self, name: &str) -> Result<Option<i64>, Box<dyn StoreError>> {
+    self.store.load_checkpoint(&self.prefixed(name))
+  }
+
+  fn save_checkpoint(&mut self, name: &str, commit_number: i64) -> Result<(), Box<dyn StoreError>> {
+    let name = self.prefixed(name);
+    self.store.save_checkpoint(&name, commit_number)
+  }
+
+  fn mark_commit_as_poisoned(
+    &mut self,
+    commit_id: Uuid,
+    attempts: i64,
+    error: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self.check_owned(commit_id)?;
+    self
+      .store
+      .mark_commit_as_poisoned(commit_id, attempts, error)
+  }
+
+  fn get_poisoned_commits(&mut self) -> Result<Vec<PoisonedCommit>, Box<dyn StoreError>> {
+    let mut owned = Vec::new();
+    for poisoned in self.store.get_poisoned_commits()? {
+      if self.is_owned(poisoned.commit_id)? {
+        owned.push(poisoned);
+      }
+    }
+    Ok(owned)
+  }
+
+  fn get_poisoned_commit(
+    &mut self,
+    commit_id: Uuid,
+  ) -> Result<Option<PoisonedCommit>, Box<dyn StoreError>> {
+    match self.store.get_poisoned_commit(commit_id)? {
+      Some(poisoned) if self.is_owned(commit_id)? => Ok(Some(poisoned)),
+      _ => Ok(None),
+    }
+  }
+
+  fn requeue_poisoned_commit(&mut self, commit_id: Uuid) -> Result<bool, Box<dyn StoreError>> {
+    self.check_owned(commit_id)?;
+    self.store.requeue_poisoned_commit(commit_id)
+  }
+
+  // Like `get_undispatched_commits_up_to`, reads every unacknowledged commit
+  // before taking `limit` of this tenant's.
+  fn get_unacknowledged_commits(
+    &mut self,
+    consumer: &str,
+    limit: i64,
+  ) -> Result<Vec<Commit>, Box<dyn StoreError>> {
+    let consumer = self.prefixed(consumer);
+    let commits = self.store.get_unacknowledged_commits(&consumer, i64::MAX)?;
+    let mut commits = self.owned(commits);
+    commits.truncate(limit.max(0) as usize);
+    Ok(commits)
+  }
+
+  fn acknowledge_commit(
+    &mut self,
+    commit_id: Uuid,
+    consumer: &str,
+  ) -> Result<(), Box<dyn StoreError>> {
+    self.check_owned(commit_id)?;
+    let consumer = self.prefixed(consumer);
+    self.store.acknowledge_commit(commit_id, &consumer)
+  }
+
+  fn get_acknowledgements(&mut self, commit_id: Uuid) -> Result<Vec<String>, Box<dyn StoreError>> {
+    self.check_owned(commit_id)?;
+    let prefix = self.prefixed("");
+    let consumers = self.store.get_acknowledgements(commit_id)?;
+    Ok(
+      consumers
+        .iter()
+        .filter_map(|consumer| consumer.strip_prefix(prefix.as_str()))
+        .map(String::from)
+        .collect(),
+    )
+  }
+
+  fn truncate_through(
+    &mut self,
+    aggregate_id: Uuid,
+    commit_sequence: i64,
+  ) -> Result<usize, Box<dyn StoreError>> {
+    self.check_aggregate_owned(aggregate_id)?;
+    self.store.truncate_through(aggregate_id, commit_sequence)
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::super::sqlite::SqliteStore;
+  use super::*;
+  use chrono::Utc;
+
+  fn commit_attempt(aggregate_id: Uuid, version: i64, category: &str) -> CommitAttempt {
+    CommitAttempt {
+      aggregate_id,
+      aggregate_version: version,
+      category: String::from(category),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: version + 1,
+      serialized_metadata: b"{}".to_vec(),
+      serialized_events: b"[\"Opened\"]".to_vec(),
+      events_count: 1,
+    }
+  }
+
+  fn rejected(err: Box<dyn StoreError>) -> bool {
+    err.error_type() == StoreErrorType::Rejected(String::from("outside tenant acme"))
+  }
+
+  #[test]
+  fn it_keeps_tenants_apart() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut acme = TenantStore::new(store, "acme");
+    let ours = commit_attempt(Uuid::new_v4(), 0, "order");
+    acme.commit(&ours).unwrap();
+    let mut globex = TenantStore::new(acme.store, "globex");
+    let theirs = commit_attempt(Uuid::new_v4(), 0, "order");
+    globex.commit(&theirs).unwrap();
+    let mut acme = TenantStore::new(globex.store, "acme");
+
+    let commits = acme.get_commits_after(0, 10).unwrap();
+    assert_eq!(commits.len(), 1);
+    assert_eq!(commits[0].commit_id, ours.commit_id);
+    assert_eq!(commits[0].tenant_id, "acme");
+    assert!(acme.get_range(theirs.aggregate_id, 0, i64::MAX).unwrap().is_empty());
+    assert_eq!(acme.get_category_range("order", 0, 10).unwrap().len(), 1);
+    assert_eq!(acme.get_undispatched_commits().unwrap().len(), 1);
+    assert!(rejected(acme.get_commit(&theirs.commit_id).unwrap_err()));
+    assert!(rejected(acme.mark_commit_as_dispatched(theirs.commit_id).unwrap_err()));
+    assert!(rejected(acme.get_tenant_range("globex", 0, 10).unwrap_err()));
+    assert!(rejected(
+      acme.commit(&commit_attempt(theirs.aggregate_id, 1, "order")).unwrap_err()
+    ));
+    let mut stamped_for_globex = commit_attempt(Uuid::new_v4(), 0, "order");
+    stamped_for_globex.tenant_id = String::from("globex");
+    assert!(rejected(acme.commit(&stamped_for_globex).unwrap_err()));
+  }
+
+  #[test]
+  fn it_pages_past_other_tenants_commits() {
+    let store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let mut globex = TenantStore::new(store, "globex");
+    for _ in 0..3 {
+      globex.commit(&commit_attempt(Uuid::new_v4(), 0, "order")).unwrap();
+    }
+    let mut acme = TenantStore::new(globex.store, "acme");
+    let ours = commit_attempt(Uuid::new_v4(), 0, "order");
+    acme.commit(&ours).unwrap();
+
+    let commits = acme.get_category_range("order", 0, 2).unwrap();
+    assert_eq!(commits.len(), 1);
+    assert_eq!(commits[0].commit_id, ours.commit_id);
+    acme.save_checkpoint("mailer", 4).unwrap();
+    assert_eq!(acme.load_checkpoint("mailer").unwrap(), Some(4));
+    assert_eq!(acme.store().load_checkpoint("mailer").unwrap(), None);
+    acme.acknowledge_commit(ours.commit_id, "mailer").unwrap();
+    assert!(acme.get_unacknowledged_commits("mailer", 10).unwrap().is_empty());
+    assert_eq!(
+      acme.get_acknowledgements(ours.commit_id).unwrap(),
+      vec![String::from("mailer")]
+    );
+  }
+}
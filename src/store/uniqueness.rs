@@ -0,0 +1,193 @@
+use super::InlineProjection;
+use crate::commit::Commit;
+use chrono::Utc;
+use rusqlite::{
+  Connection as RusqliteConnection, Error as RusqliteError, OptionalExtension, ToSql,
+};
+use uuid::Uuid;
+
+// Claims values such as usernames for the committing aggregate inside the commit
+// transaction. Values live in `unique_values`, created by the `SqliteStore`
+// migrations, keyed by constraint name.
+pub struct Reservations<'a> {
+  transaction: &'a RusqliteConnection,
+  constraint: &'a str,
+  owner: Uuid,
+}
+
+impl<'a> Reservations<'a> {
+  // Fails when another aggregate holds `value`; reserving a value the aggregate
+  // already holds is a no-op.
+  pub fn reserve(&self, value: &str) -> Result<(), String> {
+    match owner_of(self.transaction, self.constraint, value).map_err(|err| err.to_string())? {
+      Some(owner) if owner == self.owner => Ok(()),
+      Some(_) => Err(format!(
+        "{:?} is already reserved in {}",
+        value, self.constraint
+      )),
+      None => self
+        .transaction
+        .execute(
+          "INSERT INTO unique_values (constraint_name, value, aggregate_id, reserved_at)
+            VALUES (?, ?, ?, ?)",
+          [
+            &self.constraint as &dyn ToSql,
+            &value,
+            &self.owner.to_string(),
+            &Utc::now(),
+          ],
+        )
+        .map(|_| ())
+        .map_err(|err| err.to_string()),
+    }
+  }
+
+  // Releases `value` if the committing aggregate holds it.
+  pub fn release(&self, value: &str) -> Result<(), String> {
+    self
+      .transaction
+      .execute(
+        "DELETE FROM unique_values WHERE constraint_name = ? AND value = ? AND aggregate_id = ?",
+        [self.constraint, value, &self.owner.to_string()],
+      )
+      .map(|_| ())
+      .map_err(|err| err.to_string())
+  }
+}
+
+pub fn owner_of(
+  conn: &RusqliteConnection,
+  constraint: &str,
+  value: &str,
+) -> Result<Option<Uuid>, RusqliteError> {
+  let owner: Option<String> = conn
+    .query_row(
+      "SELECT aggregate_id FROM unique_values WHERE constraint_name = ? AND value = ?",
+      [constraint, value],
+      |row| row.get(0),
+    )
+    .optional()?;
+  Ok(owner.and_then(|owner| Uuid::parse_str(&owner).ok()))
+}
+
+// An inline projection enforcing one uniqueness constraint. `on_commit` decides
+// which values each commit reserves or releases; a failed reservation rolls the
+// commit back.
+pub struct UniqueConstraint<F> {
+  name: String,
+  on_commit: F,
+}
+
+impl<F> UniqueConstraint<F>
+where
+  F: FnMut(&Commit, &Reservations) -> Result<(), String>,
+{
+  pub fn new(name: &str, on_commit: F) -> UniqueConstraint<F> {
+    UniqueConstraint {
+      name: String::from(name),
+      on_commit,
+    }
+  }
+}
+
+impl<F> InlineProjection<RusqliteConnection> for UniqueConstraint<F>
+where
+  F: FnMut(&Commit, &Reservations) -> Result<(), String>,
+{
+  fn name(&self) -> String {
+    self.name.clone()
+  }
+
+  fn apply(&mut self, transaction: &RusqliteConnection, commit: &Commit) -> Result<(), String> {
+    let reservations = Reservations {
+      transaction,
+      constraint: &self.name,
+      owner: commit.aggregate_id,
+    };
+    (self.on_commit)(commit, &reservations)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commit::CommitAttempt;
+  use crate::store::sqlite::SqliteStore;
+  use crate::store::{InlineProjectionStore, Store};
+
+  #[derive(Deserialize)]
+  enum UserEvent {
+    Registered(String),
+    Renamed { from: String, to: String },
+  }
+
+  fn attempt(aggregate_id: Uuid, aggregate_version: i64, events: &str) -> CommitAttempt {
+    CommitAttempt {
+      aggregate_id,
+      aggregate_version,
+      category: String::from("user"),
+      tenant_id: String::new(),
+      parent_commit_id: None,
+      commit_id: Uuid::new_v4(),
+      commit_timestamp: Utc::now(),
+      commit_sequence: aggregate_version,
+      serialized_metadata: b"null".to_vec(),
+      serialized_events: events.as_bytes().to_vec(),
+      events_count: 1,
+    }
+  }
+
+  #[test]
+  fn it_reserves_and_releases_values_with_commits() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    store.add_inline_projection(Box::new(UniqueConstraint::new(
+      "usernames",
+      |commit: &Commit, reservations: &Reservations| {
+        let events: Vec<UserEvent> =
+          serde_json::from_slice(&commit.serialized_events).map_err(|err| err.to_string())?;
+        for event in events {
+          match event {
+            UserEvent::Registered(name) => reservations.reserve(&name)?,
+            UserEvent::Renamed { from, to } => {
+              reservations.release(&from)?;
+              reservations.reserve(&to)?;
+            }
+          }
+        }
+        Ok(())
+      },
+    )));
+    let ann = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    store
+      .commit(&attempt(ann, 0, "[{\"Registered\":\"ann\"}]"))
+      .unwrap();
+    assert!(store
+      .commit(&attempt(bob, 0, "[{\"Registered\":\"ann\"}]"))
+      .is_err());
+    assert_eq!(
+      store.unique_value_owner("usernames", "ann").unwrap(),
+      Some(ann)
+    );
+
+    store
+      .commit(&attempt(
+        ann,
+        1,
+        "[{\"Renamed\":{\"from\":\"ann\",\"to\":\"anne\"}}]",
+      ))
+      .unwrap();
+    store
+      .commit(&attempt(bob, 0, "[{\"Registered\":\"ann\"}]"))
+      .unwrap();
+    assert_eq!(
+      store.unique_value_owner("usernames", "ann").unwrap(),
+      Some(bob)
+    );
+    assert_eq!(
+      store.unique_value_owner("usernames", "anne").unwrap(),
+      Some(ann)
+    );
+  }
+}
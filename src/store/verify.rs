@@ -0,0 +1,126 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum IntegrityIssue {
+  DatabaseCorruption {
+    detail: String,
+  },
+  InvalidUuid {
+    commit_number: i64,
+    column: &'static str,
+    value: String,
+  },
+  NonMonotonicAggregateVersion {
+    commit_number: i64,
+    aggregate_id: String,
+    previous: i64,
+    current: i64,
+  },
+  NonMonotonicCommitSequence {
+    commit_number: i64,
+    aggregate_id: String,
+    previous: i64,
+    current: i64,
+  },
+  BrokenCommitChain {
+    commit_number: i64,
+    aggregate_id: String,
+    parent_commit_id: String,
+    previous_commit_id: String,
+  },
+  UndecodableEvents {
+    commit_number: i64,
+  },
+  EventsCountMismatch {
+    commit_number: i64,
+    recorded: i64,
+    actual: i64,
+  },
+  ChecksumMismatch {
+    commit_number: i64,
+    recorded: u64,
+    actual: u64,
+  },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct IntegrityReport {
+  pub commits_checked: i64,
+  pub issues: Vec<IntegrityIssue>,
+}
+
+impl IntegrityReport {
+  pub fn is_ok(&self) -> bool {
+    self.issues.is_empty()
+  }
+}
+
+impl fmt::Display for IntegrityIssue {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      IntegrityIssue::DatabaseCorruption { ref detail } => {
+        write!(f, "database corruption: {}", detail)
+      }
+      IntegrityIssue::InvalidUuid {
+        commit_number,
+        column,
+        ref value,
+      } => write!(
+        f,
+        "commit {}: {} {:?} is not a uuid",
+        commit_number, column, value
+      ),
+      IntegrityIssue::NonMonotonicAggregateVersion {
+        commit_number,
+        ref aggregate_id,
+        previous,
+        current,
+      } => write!(
+        f,
+        "commit {}: aggregate {} version {} does not follow {}",
+        commit_number, aggregate_id, current, previous
+      ),
+      IntegrityIssue::NonMonotonicCommitSequence {
+        commit_number,
+        ref aggregate_id,
+        previous,
+        current,
+      } => write!(
+        f,
+        "commit {}: aggregate {} commit_sequence {} does not follow {}",
+        commit_number, aggregate_id, current, previous
+      ),
+      IntegrityIssue::BrokenCommitChain {
+        commit_number,
+        ref aggregate_id,
+        ref parent_commit_id,
+        ref previous_commit_id,
+      } => write!(
+        f,
+        "commit {}: aggregate {} parent {} is not the previous commit {}",
+        commit_number, aggregate_id, parent_commit_id, previous_commit_id
+      ),
+      IntegrityIssue::UndecodableEvents { commit_number } => {
+        write!(f, "commit {}: events can't be decoded", commit_number)
+      }
+      IntegrityIssue::EventsCountMismatch {
+        commit_number,
+        recorded,
+        actual,
+      } => write!(
+        f,
+        "commit {}: events_count is {} but {} events are stored",
+        commit_number, recorded, actual
+      ),
+      IntegrityIssue::ChecksumMismatch {
+        commit_number,
+        recorded,
+        actual,
+      } => write!(
+        f,
+        "commit {}: checksum is {:016x} but its contents hash to {:016x}",
+        commit_number, recorded, actual
+      ),
+    }
+  }
+}
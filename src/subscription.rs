@@ -0,0 +1,359 @@
+use crate::commit::Commit;
+use crate::dispatch::DispatchDelegate;
+use crate::events::serialized_event_types;
+use crate::store::Store;
+use std::collections::HashSet;
+use std::sync::mpsc;
+
+const DEFAULT_BATCH_SIZE: i64 = 100;
+
+// Matches commits carrying at least one event of the given `Event::event_type`s.
+// Commits whose events cannot be read are matched, so they are never silently
+// dropped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventTypeFilter {
+  event_types: HashSet<String>,
+}
+
+impl EventTypeFilter {
+  pub fn new<I: IntoIterator<Item = T>, T: Into<String>>(event_types: I) -> EventTypeFilter {
+    EventTypeFilter {
+      event_types: event_types.into_iter().map(Into::into).collect(),
+    }
+  }
+
+  // The names matched, sorted.
+  pub fn event_types(&self) -> Vec<&str> {
+    let mut event_types: Vec<&str> = self.event_types.iter().map(String::as_str).collect();
+    event_types.sort_unstable();
+    event_types
+  }
+
+  pub fn matches(&self, commit: &Commit) -> bool {
+    match serialized_event_types(&commit.serialized_events) {
+      Ok(event_types) => event_types
+        .iter()
+        .flatten()
+        .any(|event_type| self.event_types.contains(event_type)),
+      Err(_) => true,
+    }
+  }
+}
+
+// Hands live commits from a `Dispatcher` to a `CatchUpSubscription`. Attach it
+// before the subscription starts replaying so no commit slips between the two.
+#[derive(Clone)]
+pub struct SubscriptionFeed {
+  sender: mpsc::Sender<Commit>,
+}
+
+impl DispatchDelegate for SubscriptionFeed {
+  fn dispatch(&mut self, commit: &Commit) -> Result<(), String> {
+    // A dropped subscription is not a dispatch failure.
+    let _closed = self.sender.send(commit.clone());
+    Ok(())
+  }
+}
+
+// Replays commits out of the store from a given commit_number, then switches to
+// the commits arriving through its `SubscriptionFeed`. Live commits already seen
+// during replay are dropped, and any commit the feed skipped over is read back
+// from the store, so every commit is delivered once and in commit_number order.
+pub struct CatchUpSubscription {
+  position: i64,
+  batch_size: i64,
+  live: bool,
+  receiver: mpsc::Receiver<Commit>,
+  event_types: Option<EventTypeFilter>,
+  category: Option<String>,
+  tenant_id: Option<String>,
+}
+
+impl CatchUpSubscription {
+  pub fn starting_at(commit_number: i64) -> (CatchUpSubscription, SubscriptionFeed) {
+    let (sender, receiver) = mpsc::channel();
+    let subscription = CatchUpSubscription {
+      position: commit_number - 1,
+      batch_size: DEFAULT_BATCH_SIZE,
+      live: false,
+      receiver,
+      event_types: None,
+      category: None,
+      tenant_id: None,
+    };
+    (subscription, SubscriptionFeed { sender })
+  }
+
+  pub fn with_batch_size(mut self, batch_size: i64) -> CatchUpSubscription {
+    self.batch_size = batch_size;
+    self
+  }
+
+  // Only delivers commits matching `event_types`; the position still moves past
+  // the rest.
+  pub fn with_event_types(mut self, event_types: EventTypeFilter) -> CatchUpSubscription {
+    self.event_types = Some(event_types);
+    self
+  }
+
+  // Only follows the aggregates of one category, replaying through
+  // `Store::get_category_range`.
+  pub fn with_category(mut self, category: &str) -> CatchUpSubscription {
+    self.category = Some(String::from(category));
+    self
+  }
+
+  // Only follows one tenant's commits, replaying through
+  // `Store::get_tenant_range`.
+  pub fn with_tenant(mut self, tenant_id: &str) -> CatchUpSubscription {
+    self.tenant_id = Some(String::from(tenant_id));
+    self
+  }
+
+  // The commit_number of the last commit seen.
+  pub fn position(&self) -> i64 {
+    self.position
+  }
+
+  pub fn is_live(&self) -> bool {
+    self.live
+  }
+
+  // The next commits to deliver: a batch of history while replaying, then
+  // whatever the feed has received since the last poll.
+  pub fn poll<S: Store>(&mut self, store: &S) -> Result<Vec<Commit>, String> {
+    let mut commits = self.poll_unfiltered(store)?;
+    if let Some(ref event_types) = self.event_types {
+      commits.retain(|commit| event_types.matches(commit));
+    }
+    Ok(commits)
+  }
+
+  fn poll_unfiltered<S: Store>(&mut self, store: &S) -> Result<Vec<Commit>, String> {
+    if !self.live {
+      let commits = self.read_after(store, self.position, self.batch_size)?;
+      if let Some(last) = commits.last() {
+        self.position = last.commit_number;
+        return Ok(commits);
+      }
+      self.live = true;
+    }
+    let mut commits = Vec::new();
+    for commit in self.receiver.try_iter().collect::<Vec<Commit>>() {
+      let other_category = self
+        .category
+        .as_ref()
+        .is_some_and(|category| *category != commit.category);
+      let other_tenant = self
+        .tenant_id
+        .as_ref()
+        .is_some_and(|tenant_id| *tenant_id != commit.tenant_id);
+      if commit.commit_number <= self.position || other_category || other_tenant {
+        continue;
+      }
+      if commit.commit_number > self.position + 1 {
+        let missed = self.read_after(
+          store,
+          self.position,
+          commit.commit_number - self.position - 1,
+        )?;
+        commits.extend(
+          missed
+            .into_iter()
+            .filter(|missed| missed.commit_number < commit.commit_number),
+        );
+      }
+      self.position = commit.commit_number;
+      commits.push(commit);
+    }
+    Ok(commits)
+  }
+
+  // Following a category for one tenant reads the category and drops other
+  // tenants' commits, moving on to the next page when a whole page is dropped.
+  fn read_after<S: Store>(
+    &self,
+    store: &S,
+    commit_number: i64,
+    limit: i64,
+  ) -> Result<Vec<Commit>, String> {
+    let mut after = commit_number;
+    loop {
+      let mut commits = match (&self.category, &self.tenant_id) {
+        (Some(category), _) => store.get_category_range(category, after, limit),
+        (None, Some(tenant_id)) => store.get_tenant_range(tenant_id, after, limit),
+        (None, None) => store.get_commits_after(after, limit),
+      }
+      .map_err(|err| err.to_string())?;
+      let exhausted = (commits.len() as i64) < limit;
+      after = match commits.last() {
+        Some(last) => last.commit_number,
+        None => return Ok(commits),
+      };
+      if let Some(ref tenant_id) = self.tenant_id {
+        commits.retain(|commit| commit.tenant_id == *tenant_id);
+      }
+      if exhausted || !commits.is_empty() {
+        return Ok(commits);
+      }
+    }
+  }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+  use super::*;
+  use crate::commit::CommitAttempt;
+  use crate::dispatch::Dispatcher;
+  use crate::store::sqlite::SqliteStore;
+  use chrono::Utc;
+  use uuid::Uuid;
+
+  fn commit_to(store: &mut SqliteStore, aggregate_id: Uuid, aggregate_version: i64) -> Commit {
+    commit_events(store, aggregate_id, aggregate_version, "", b"[]")
+  }
+
+  fn commit_events(
+    store: &mut SqliteStore,
+    aggregate_id: Uuid,
+    aggregate_version: i64,
+    category: &str,
+    serialized_events: &[u8],
+  ) -> Commit {
+    commit_for_tenant(store, "", aggregate_id, aggregate_version, category, serialized_events)
+  }
+
+  fn commit_for_tenant(
+    store: &mut SqliteStore,
+    tenant_id: &str,
+    aggregate_id: Uuid,
+    aggregate_version: i64,
+    category: &str,
+    serialized_events: &[u8],
+  ) -> Commit {
+    let commit_id = Uuid::new_v4();
+    store
+      .commit(&CommitAttempt {
+        aggregate_id,
+        aggregate_version,
+        category: String::from(category),
+        tenant_id: String::from(tenant_id),
+        parent_commit_id: None,
+        commit_id,
+        commit_timestamp: Utc::now(),
+        commit_sequence: aggregate_version,
+        serialized_metadata: b"null".to_vec(),
+        serialized_events: serialized_events.to_vec(),
+        events_count: 0,
+      })
+      .unwrap();
+    store.get_commit(&commit_id).unwrap()
+  }
+
+  fn numbers(commits: Vec<Commit>) -> Vec<i64> {
+    commits.iter().map(|commit| commit.commit_number).collect()
+  }
+
+  #[test]
+  fn it_replays_history_then_delivers_live_commits_once() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    for version in 0..3 {
+      commit_to(&mut store, aggregate_id, version);
+    }
+    let (subscription, mut feed) = CatchUpSubscription::starting_at(2);
+    let mut subscription = subscription.with_batch_size(2);
+    let mut dispatcher = Dispatcher::new(feed.clone());
+    dispatcher.dispatch(&mut store).unwrap();
+
+    // Commit 4 lands while replaying, so it is both in the store and the feed.
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![2, 3]);
+    commit_to(&mut store, aggregate_id, 3);
+    dispatcher.dispatch(&mut store).unwrap();
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![4]);
+    assert!(!subscription.is_live());
+    assert_eq!(
+      numbers(subscription.poll(&store).unwrap()),
+      Vec::<i64>::new()
+    );
+    assert!(subscription.is_live());
+
+    // Commit 5 never reaches the feed; it is read back when commit 6 arrives.
+    commit_to(&mut store, aggregate_id, 4);
+    let sixth = commit_to(&mut store, aggregate_id, 5);
+    feed.dispatch(&sixth).unwrap();
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![5, 6]);
+    assert_eq!(subscription.position(), 6);
+  }
+
+  #[test]
+  fn it_only_delivers_commits_with_subscribed_event_types() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    let aggregate_id = Uuid::new_v4();
+    commit_events(&mut store, aggregate_id, 0, "", b"[\"Opened\"]");
+    commit_events(
+      &mut store,
+      aggregate_id,
+      1,
+      "",
+      b"[{\"Deposited\":{\"amount\":3}}]",
+    );
+    commit_events(
+      &mut store,
+      aggregate_id,
+      2,
+      "",
+      b"[\"Renamed\", \"Closed\"]",
+    );
+    let (subscription, _feed) = CatchUpSubscription::starting_at(1);
+    let mut subscription =
+      subscription.with_event_types(EventTypeFilter::new(vec!["Deposited", "Closed"]));
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![2, 3]);
+    assert_eq!(subscription.position(), 3);
+  }
+
+  #[test]
+  fn it_follows_a_single_category() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    commit_events(&mut store, Uuid::new_v4(), 0, "order", b"[]");
+    commit_events(&mut store, Uuid::new_v4(), 0, "user", b"[]");
+    let (subscription, mut feed) = CatchUpSubscription::starting_at(1);
+    let mut subscription = subscription.with_category("order");
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![1]);
+    assert_eq!(
+      numbers(subscription.poll(&store).unwrap()),
+      Vec::<i64>::new()
+    );
+
+    let user = commit_events(&mut store, Uuid::new_v4(), 0, "user", b"[]");
+    let order = commit_events(&mut store, Uuid::new_v4(), 0, "order", b"[]");
+    feed.dispatch(&user).unwrap();
+    feed.dispatch(&order).unwrap();
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![4]);
+  }
+
+  #[test]
+  fn it_follows_a_single_tenant() {
+    let mut store = SqliteStore::with_new_in_memory_connection();
+    store.initialize();
+    for _ in 0..2 {
+      commit_for_tenant(&mut store, "globex", Uuid::new_v4(), 0, "order", b"[]");
+    }
+    commit_for_tenant(&mut store, "acme", Uuid::new_v4(), 0, "order", b"[]");
+    let (subscription, mut feed) = CatchUpSubscription::starting_at(1);
+    let mut subscription = subscription
+      .with_batch_size(2)
+      .with_category("order")
+      .with_tenant("acme");
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![3]);
+
+    let theirs = commit_for_tenant(&mut store, "globex", Uuid::new_v4(), 0, "order", b"[]");
+    let ours = commit_for_tenant(&mut store, "acme", Uuid::new_v4(), 0, "order", b"[]");
+    feed.dispatch(&theirs).unwrap();
+    feed.dispatch(&ours).unwrap();
+    assert_eq!(numbers(subscription.poll(&store).unwrap()), vec![5]);
+  }
+}